@@ -0,0 +1,74 @@
+//! Measures CRC32C throughput against a plain table loop, and checks that
+//! the CPU's CRC32C instruction is used where it exists.
+//!
+//! Run with `cargo bench --bench crc32c`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use btrfs_rs::{crc32c, crc32c_is_accelerated};
+
+const BUFFER: usize = 64 << 20;
+const ROUNDS: usize = 8;
+
+/// The bytewise table loop the crate falls back to, as the baseline.
+fn table_crc32c(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn cpu_has_crc32c() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    return std::arch::is_x86_feature_detected!("sse4.2");
+    #[cfg(target_arch = "aarch64")]
+    return std::arch::is_aarch64_feature_detected!("crc");
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    return false;
+}
+
+/// Throughput of `f` over `data`, in GiB/s.
+fn throughput(data: &[u8], f: impl Fn(&[u8]) -> u32) -> f64 {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(f(black_box(data)));
+    }
+    let bytes = (data.len() * ROUNDS) as f64;
+    bytes / start.elapsed().as_secs_f64() / (1u64 << 30) as f64
+}
+
+fn main() {
+    let data: Vec<u8> = (0..BUFFER as u32).map(|i| (i ^ i >> 11) as u8).collect();
+    assert_eq!(crc32c(&data), table_crc32c(&data));
+
+    assert_eq!(
+        crc32c_is_accelerated(),
+        cpu_has_crc32c(),
+        "the CRC32C instruction is not used on a CPU that has it"
+    );
+
+    let table = throughput(&data, table_crc32c);
+    let crate_path = throughput(&data, crc32c);
+    println!(
+        "crc32c ({}): {crate_path:.2} GiB/s, table: {table:.2} GiB/s",
+        if crc32c_is_accelerated() {
+            "hardware"
+        } else {
+            "table"
+        }
+    );
+}
@@ -0,0 +1,155 @@
+//! Looks up keys scattered over a synthetic fs tree with the node cache
+//! off and at a few capacities, reporting time and hit rate. Blocks are
+//! checksummed and verification stays on, so a miss pays for the read,
+//! the CRC32C and the parse.
+//!
+//! Run with `cargo bench --bench node_cache`.
+
+use std::io::Cursor;
+use std::time::Instant;
+
+use btrfs_rs::{crc32c, Btrfs, Key, KeyType, RootItem, Superblock};
+
+const SUPERBLOCK: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+/// Start of the fixture's SYSTEM chunk, which its first stripe maps to the
+/// same physical offset.
+const SYSTEM_CHUNK: u64 = 22020096;
+const SYSTEM_CHUNK_LEN: u64 = 8 << 20;
+const NODESIZE: usize = 16384;
+const HEADER_SIZE: usize = 101;
+const LEAVES: u64 = 400;
+const ITEMS_PER_LEAF: u64 = 100;
+const ITEM_DATA: usize = 120;
+const LOOKUPS: usize = 20_000;
+
+fn block(n: u64) -> u64 {
+    SYSTEM_CHUNK + n * NODESIZE as u64
+}
+
+fn tree_block(bytenr: u64, owner: u64, nritems: usize, level: u8) -> Vec<u8> {
+    let mut block = vec![0u8; NODESIZE];
+    block[32..48].copy_from_slice(&SUPERBLOCK[32..48]);
+    block[48..56].copy_from_slice(&bytenr.to_le_bytes());
+    block[80..88].copy_from_slice(&6u64.to_le_bytes());
+    block[88..96].copy_from_slice(&owner.to_le_bytes());
+    block[96..100].copy_from_slice(&(nritems as u32).to_le_bytes());
+    block[100] = level;
+    block
+}
+
+fn seal(block: &mut [u8]) {
+    let csum = crc32c(&block[32..]);
+    block[..4].copy_from_slice(&csum.to_le_bytes());
+}
+
+fn put_key(block: &mut [u8], at: usize, key: Key) {
+    block[at..at + 8].copy_from_slice(&key.objectid.to_le_bytes());
+    block[at + 8] = key.item_type;
+    block[at + 9..at + 17].copy_from_slice(&key.offset.to_le_bytes());
+}
+
+fn leaf(bytenr: u64, owner: u64, items: &[(Key, Vec<u8>)]) -> Vec<u8> {
+    let mut block = tree_block(bytenr, owner, items.len(), 0);
+    let mut data_end = NODESIZE - HEADER_SIZE;
+    for (i, (key, data)) in items.iter().enumerate() {
+        data_end -= data.len();
+        let at = HEADER_SIZE + i * 25;
+        put_key(&mut block, at, *key);
+        block[at + 17..at + 21].copy_from_slice(&(data_end as u32).to_le_bytes());
+        block[at + 21..at + 25].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        let start = HEADER_SIZE + data_end;
+        block[start..start + data.len()].copy_from_slice(data);
+    }
+    seal(&mut block);
+    block
+}
+
+fn item_key(n: u64) -> Key {
+    Key::new(256 + n, 1, 0)
+}
+
+/// An image whose root tree points at one fs tree of [`LEAVES`] full
+/// leaves under a single node.
+fn build_image() -> Vec<u8> {
+    let mut image = vec![0u8; (SYSTEM_CHUNK + SYSTEM_CHUNK_LEN) as usize];
+    let mut put = |logical: u64, bytes: &[u8]| {
+        image[logical as usize..logical as usize + bytes.len()].copy_from_slice(bytes);
+    };
+
+    let mut root = vec![0u8; RootItem::SIZE];
+    root[160..168].copy_from_slice(&6u64.to_le_bytes());
+    root[176..184].copy_from_slice(&block(3).to_le_bytes());
+    root[238] = 1;
+    let root_key = Key::new(5, KeyType::RootItem.as_u8(), 0);
+    put(block(2), &leaf(block(2), 1, &[(root_key, root)]));
+
+    let mut node = tree_block(block(3), 5, LEAVES as usize, 1);
+    for n in 0..LEAVES {
+        let items: Vec<_> = (0..ITEMS_PER_LEAF)
+            .map(|i| (item_key(n * ITEMS_PER_LEAF + i), vec![n as u8; ITEM_DATA]))
+            .collect();
+        put(block(4 + n), &leaf(block(4 + n), 5, &items));
+
+        let at = HEADER_SIZE + n as usize * 33;
+        put_key(&mut node, at, items[0].0);
+        node[at + 17..at + 25].copy_from_slice(&block(4 + n).to_le_bytes());
+        node[at + 25..at + 33].copy_from_slice(&6u64.to_le_bytes());
+    }
+    seal(&mut node);
+    put(block(3), &node);
+
+    let mut sb = Superblock::from_bytes(SUPERBLOCK).unwrap();
+    sb.root = block(2);
+    sb.root_level = 0;
+    let sb = sb.to_bytes().unwrap();
+    put(65536, &sb);
+    image
+}
+
+/// Looks up [`LOOKUPS`] keys, skewed towards the low leaves the way a hot
+/// directory would be, from a fixed pseudo-random sequence.
+fn lookups(fs: &Btrfs) -> usize {
+    let root = fs
+        .root_tree()
+        .search(&Key::new(5, KeyType::RootItem.as_u8(), 0))
+        .unwrap()
+        .unwrap();
+    let root = RootItem::from_bytes(&root.1).unwrap();
+    let tree = fs.tree(root.bytenr(), root.level());
+
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut found = 0;
+    for _ in 0..LOOKUPS {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let total = LEAVES * ITEMS_PER_LEAF;
+        // The minimum of two draws favours small item numbers.
+        let n = (state % total).min((state >> 32) % total);
+        found += tree.search(&item_key(n)).unwrap().is_some() as usize;
+    }
+    found
+}
+
+fn measure(image: &[u8], capacity: usize) {
+    let fs = Btrfs::from_reader(Cursor::new(image.to_vec()))
+        .unwrap()
+        .with_cache_capacity(capacity);
+    let start = Instant::now();
+    let found = lookups(&fs);
+    let elapsed = start.elapsed();
+    let stats = fs.cache_stats();
+    println!(
+        "capacity {capacity:>4}: {found} lookups in {elapsed:?}, {} hits, {} misses ({:.1}% hit rate)",
+        stats.hits,
+        stats.misses,
+        stats.hit_rate() * 100.0,
+    );
+}
+
+fn main() {
+    let image = build_image();
+    for capacity in [0, 16, 64, 512] {
+        measure(&image, capacity);
+    }
+}
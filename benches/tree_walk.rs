@@ -0,0 +1,180 @@
+//! Walks every item of a synthetic filesystem through the buffered reader,
+//! through the memory map, and over a device with a fixed cost per read
+//! with and without read-ahead, reporting time and heap allocations.
+//!
+//! Run with `cargo bench --features mmap`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use btrfs_rs::{BlockDevice, Btrfs, BtrfsError, Key, KeyType, RootItem, Superblock};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const SUPERBLOCK: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+/// Start of the fixture's SYSTEM chunk, which its first stripe maps to the
+/// same physical offset.
+const SYSTEM_CHUNK: u64 = 22020096;
+const SYSTEM_CHUNK_LEN: u64 = 8 << 20;
+const NODESIZE: usize = 16384;
+const HEADER_SIZE: usize = 101;
+const LEAVES: u64 = 400;
+const ITEMS_PER_LEAF: usize = 100;
+const ITEM_DATA: usize = 120;
+
+fn block(n: u64) -> u64 {
+    SYSTEM_CHUNK + n * NODESIZE as u64
+}
+
+fn tree_block(bytenr: u64, owner: u64, nritems: usize, level: u8) -> Vec<u8> {
+    let mut block = vec![0u8; NODESIZE];
+    block[32..48].copy_from_slice(&SUPERBLOCK[32..48]);
+    block[48..56].copy_from_slice(&bytenr.to_le_bytes());
+    block[80..88].copy_from_slice(&6u64.to_le_bytes());
+    block[88..96].copy_from_slice(&owner.to_le_bytes());
+    block[96..100].copy_from_slice(&(nritems as u32).to_le_bytes());
+    block[100] = level;
+    block
+}
+
+fn put_key(block: &mut [u8], at: usize, key: Key) {
+    block[at..at + 8].copy_from_slice(&key.objectid.to_le_bytes());
+    block[at + 8] = key.item_type;
+    block[at + 9..at + 17].copy_from_slice(&key.offset.to_le_bytes());
+}
+
+fn leaf(bytenr: u64, owner: u64, items: &[(Key, Vec<u8>)]) -> Vec<u8> {
+    let mut block = tree_block(bytenr, owner, items.len(), 0);
+    let mut data_end = NODESIZE - HEADER_SIZE;
+    for (i, (key, data)) in items.iter().enumerate() {
+        data_end -= data.len();
+        let at = HEADER_SIZE + i * 25;
+        put_key(&mut block, at, *key);
+        block[at + 17..at + 21].copy_from_slice(&(data_end as u32).to_le_bytes());
+        block[at + 21..at + 25].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        let start = HEADER_SIZE + data_end;
+        block[start..start + data.len()].copy_from_slice(data);
+    }
+    block
+}
+
+/// An image whose root tree points at one fs tree of [`LEAVES`] full
+/// leaves under a single node. Tree block checksums are left unset, so
+/// the walk runs with verification off.
+fn build_image() -> Vec<u8> {
+    let mut image = vec![0u8; (SYSTEM_CHUNK + SYSTEM_CHUNK_LEN) as usize];
+    let mut put = |logical: u64, bytes: &[u8]| {
+        image[logical as usize..logical as usize + bytes.len()].copy_from_slice(bytes);
+    };
+
+    let mut root = vec![0u8; RootItem::SIZE];
+    root[160..168].copy_from_slice(&6u64.to_le_bytes());
+    root[176..184].copy_from_slice(&block(3).to_le_bytes());
+    root[238] = 1;
+    let root_key = Key::new(5, KeyType::RootItem.as_u8(), 0);
+    put(block(2), &leaf(block(2), 1, &[(root_key, root)]));
+
+    let mut node = tree_block(block(3), 5, LEAVES as usize, 1);
+    for n in 0..LEAVES {
+        let items: Vec<_> = (0..ITEMS_PER_LEAF)
+            .map(|i| {
+                let key = Key::new(256 + n * ITEMS_PER_LEAF as u64 + i as u64, 1, 0);
+                (key, vec![n as u8; ITEM_DATA])
+            })
+            .collect();
+        put(block(4 + n), &leaf(block(4 + n), 5, &items));
+
+        let at = HEADER_SIZE + n as usize * 33;
+        put_key(&mut node, at, items[0].0);
+        node[at + 17..at + 25].copy_from_slice(&block(4 + n).to_le_bytes());
+        node[at + 25..at + 33].copy_from_slice(&6u64.to_le_bytes());
+    }
+    put(block(3), &node);
+
+    let mut sb = Superblock::from_bytes(SUPERBLOCK).unwrap();
+    sb.root = block(2);
+    sb.root_level = 0;
+    let sb = sb.to_bytes().unwrap();
+    put(65536, &sb);
+    image
+}
+
+/// An image behind a link that adds latency to every request, like a
+/// network block device, where fewer and larger reads pay off.
+struct SlowDevice(Vec<u8>);
+
+impl BlockDevice for SlowDevice {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        std::thread::sleep(Duration::from_micros(100));
+        self.0.read_at(offset, buf)
+    }
+}
+
+/// Visits every item of every tree in the root tree.
+fn walk(fs: &Btrfs) -> usize {
+    let mut items = 0;
+    for item in fs.root_tree().iter() {
+        let (key, data) = item.unwrap();
+        items += 1;
+        if key.item_type() == KeyType::RootItem {
+            let root = RootItem::from_bytes(&data).unwrap();
+            items += fs.tree(root.bytenr(), root.level()).iter().count();
+        }
+    }
+    items
+}
+
+fn measure(name: &str, fs: &Btrfs) {
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED_BYTES.store(0, Ordering::Relaxed);
+    let start = Instant::now();
+    let items = walk(fs);
+    let elapsed = start.elapsed();
+    println!(
+        "{name:>8}: {items} items in {elapsed:?}, {} allocations, {} KiB allocated",
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed) / 1024,
+    );
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("btrfs-rs-bench-{}.img", std::process::id()));
+    let image = build_image();
+    std::fs::write(&path, &image).unwrap();
+
+    let mut buffered = Btrfs::open(&path).unwrap();
+    buffered.set_verify_checksums(false);
+    measure("buffered", &buffered);
+
+    let mut mapped = Btrfs::open_mmap(&path).unwrap();
+    mapped.set_verify_checksums(false);
+    measure("mmap", &mapped);
+
+    for (name, readahead) in [("slow", 0), ("slow+ra", 32)] {
+        let mut slow = Btrfs::from_device(SlowDevice(image.clone())).unwrap();
+        slow.set_verify_checksums(false);
+        slow.set_readahead(readahead);
+        measure(name, &slow);
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
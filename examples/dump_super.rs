@@ -0,0 +1,8 @@
+use std::fs::File;
+
+fn main() -> Result<(), btrfs_rs::BtrfsError> {
+    let path = std::env::args().nth(1).expect("usage: dump_super <device>");
+    let sb = btrfs_rs::Superblock::from_reader(&mut File::open(path)?)?;
+    print!("{sb}");
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Parsers shared by the targets.
+
+use btrfs_rs::{
+    free_space_bitmap, BlockGroupItem, Chunk, DevExtent, DeviceErrorStats, DirItems, ExtentItem,
+    FileExtentItem, FreeSpaceInfo, InodeItem, InodeRef, Key, KeyType, QgroupInfoItem,
+    QgroupLimitItem, QgroupStatusItem, RootItem, RootRef, VerityDescriptorItem, XattrItems,
+};
+
+/// Parses `data` the way a reader of an item keyed by `key` would.
+pub fn parse_item(key: &Key, data: &[u8]) {
+    match key.item_type() {
+        KeyType::InodeItem => {
+            let _ = InodeItem::from_bytes(data);
+        }
+        KeyType::InodeRef => {
+            let _ = InodeRef::parse_refs(key.offset, data);
+        }
+        KeyType::InodeExtref => {
+            let _ = InodeRef::parse_extrefs(data);
+        }
+        KeyType::DirItem | KeyType::DirIndex => {
+            let _ = DirItems::new(data).count();
+        }
+        KeyType::XattrItem => {
+            let _ = XattrItems::new(data).count();
+        }
+        KeyType::ExtentData => {
+            let _ = FileExtentItem::from_bytes(data);
+        }
+        KeyType::ExtentItem | KeyType::MetadataItem => {
+            let _ = ExtentItem::from_bytes(key, data);
+        }
+        KeyType::RootItem => {
+            let _ = RootItem::from_bytes(data);
+        }
+        KeyType::RootRef | KeyType::RootBackref => {
+            let _ = RootRef::from_bytes(data);
+        }
+        KeyType::ChunkItem => {
+            let _ = Chunk::from_bytes(data);
+        }
+        KeyType::BlockGroupItem => {
+            let _ = BlockGroupItem::from_bytes(data);
+        }
+        KeyType::DevExtent => {
+            let _ = DevExtent::from_bytes(data);
+        }
+        KeyType::PersistentItem => {
+            let _ = DeviceErrorStats::from_bytes(data);
+        }
+        KeyType::FreeSpaceInfo => {
+            let _ = FreeSpaceInfo::from_bytes(data);
+        }
+        KeyType::FreeSpaceBitmap => {
+            let _ = free_space_bitmap(key.objectid, 4096, data);
+        }
+        KeyType::QgroupStatus => {
+            let _ = QgroupStatusItem::from_bytes(data);
+        }
+        KeyType::QgroupInfo => {
+            let _ = QgroupInfoItem::from_bytes(data);
+        }
+        KeyType::QgroupLimit => {
+            let _ = QgroupLimitItem::from_bytes(data);
+        }
+        KeyType::VerityDescItem if key.offset == 0 => {
+            let _ = VerityDescriptorItem::from_bytes(data);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,25 @@
+//! Item data on its own: the first 17 bytes pick a key and the rest is the
+//! item, so every parser sees every layout.
+#![no_main]
+
+use std::io::Cursor;
+
+use btrfs_rs::{free_space_bitmap, Key, SendStream, SysChunkArray, VerityInfo};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((key, item)) = data.split_first_chunk::<{ Key::SIZE }>() else {
+        return;
+    };
+    let key = Key::from_disk_bytes(key);
+    common::parse_item(&key, item);
+    // Parsers whose input is not a single item.
+    let _ = SysChunkArray::new(item).count();
+    let _ = VerityInfo::from_descriptor(item);
+    let _ = free_space_bitmap(key.offset, u64::from(key.item_type) << 9, item);
+    if let Ok(stream) = SendStream::from_reader(Cursor::new(item)) {
+        let _ = stream.count();
+    }
+});
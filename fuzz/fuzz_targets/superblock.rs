@@ -0,0 +1,30 @@
+//! Superblock parsing and the bootstrap chunk map, with every check off so
+//! that mutated fields reach the parsers behind them.
+#![no_main]
+
+use std::io::Cursor;
+
+use btrfs_rs::{ChunkMap, ParseOptions, Superblock, BTRFS_SUPER_INFO_OFFSET};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Superblock::from_bytes(data);
+
+    let mut image = vec![0u8; BTRFS_SUPER_INFO_OFFSET as usize];
+    image.extend_from_slice(data);
+    let Ok(sb) = Superblock::from_reader_with(&mut Cursor::new(image), &ParseOptions::lenient())
+    else {
+        return;
+    };
+    let _ = sb.validate_geometry();
+    let _ = sb.label();
+    if let Ok(map) = ChunkMap::from_superblock(&sb) {
+        for range in map.chunks() {
+            for logical in [range.logical.start, range.logical.end, u64::MAX] {
+                let _ = map.to_physical(logical);
+            }
+        }
+        let _ = map.gaps();
+        let _ = map.validate();
+    }
+});
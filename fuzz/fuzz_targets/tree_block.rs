@@ -0,0 +1,24 @@
+//! Tree blocks: the header, then the block as a leaf or internal node, with
+//! each leaf item's data handed to the item parsers its key type selects.
+#![no_main]
+
+use btrfs_rs::{InternalNode, Leaf, NodeHeader};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(header) = NodeHeader::from_bytes(data) else {
+        return;
+    };
+    if header.is_leaf() {
+        let Ok(leaf) = Leaf::new(data) else {
+            return;
+        };
+        for item in leaf.items() {
+            common::parse_item(&item.key(), item.data());
+        }
+    } else if let Ok(node) = InternalNode::new(data) {
+        let _ = node.key_ptrs().count();
+    }
+});
@@ -0,0 +1,174 @@
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+use bitflags::bitflags;
+
+use crate::chunk::BlockGroupFlags;
+use crate::error::BtrfsError;
+
+bitflags! {
+    /// `BTRFS_BALANCE_*`: which block group kinds a balance covers, and
+    /// how it was started.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct BalanceFlags: u64 {
+        const DATA = 1 << 0;
+        const SYSTEM = 1 << 1;
+        const METADATA = 1 << 2;
+        /// Convert even chunks already in the target profile.
+        const FORCE = 1 << 3;
+        const RESUME = 1 << 4;
+
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    /// `BTRFS_BALANCE_ARGS_*`: which filters of a [`DiskBalanceArgs`] are
+    /// in use.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct BalanceArgsFlags: u64 {
+        const PROFILES = 1 << 0;
+        const USAGE = 1 << 1;
+        const DEVID = 1 << 2;
+        const DRANGE = 1 << 3;
+        const VRANGE = 1 << 4;
+        const LIMIT = 1 << 5;
+        const LIMIT_RANGE = 1 << 6;
+        const STRIPES_RANGE = 1 << 7;
+        const CONVERT = 1 << 8;
+        /// With `CONVERT`, skip chunks already in the target profile.
+        const SOFT = 1 << 9;
+        const USAGE_RANGE = 1 << 10;
+
+        const _ = !0;
+    }
+}
+
+/// `btrfs_disk_balance_args`: the filters applied to one block group kind.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct DiskBalanceArgs {
+    /// Block group profiles to balance, with `PROFILES`.
+    pub profiles: u64,
+    /// A usage percentage, or with `USAGE_RANGE` a minimum in the low and
+    /// a maximum in the high 32 bits.
+    pub usage: u64,
+    pub devid: u64,
+    /// Physical range on `devid`, with `DRANGE`.
+    pub pstart: u64,
+    pub pend: u64,
+    /// Logical range, with `VRANGE`.
+    pub vstart: u64,
+    pub vend: u64,
+    /// Profile to convert to, with `CONVERT`.
+    pub target: u64,
+    pub flags: u64,
+    /// A chunk count, or with `LIMIT_RANGE` a minimum and maximum packed
+    /// like `usage`.
+    pub limit: u64,
+    pub stripes_min: u32,
+    pub stripes_max: u32,
+    pub unused: [u64; 6],
+}
+
+impl DiskBalanceArgs {
+    pub const SIZE: usize = 136;
+
+    pub fn flags(&self) -> BalanceArgsFlags {
+        BalanceArgsFlags::from_bits_retain(self.flags)
+    }
+
+    /// The profiles filter, if set.
+    pub fn profiles(&self) -> Option<BlockGroupFlags> {
+        self.flags()
+            .contains(BalanceArgsFlags::PROFILES)
+            .then(|| BlockGroupFlags::from_bits_retain(self.profiles))
+    }
+
+    /// The `usage=N` filter: chunks less than N percent full.
+    pub fn usage(&self) -> Option<u64> {
+        self.flags()
+            .contains(BalanceArgsFlags::USAGE)
+            .then_some(self.usage)
+    }
+
+    /// The `usage=MIN..MAX` filter, in percent.
+    pub fn usage_range(&self) -> Option<(u32, u32)> {
+        self.flags()
+            .contains(BalanceArgsFlags::USAGE_RANGE)
+            .then_some((self.usage as u32, (self.usage >> 32) as u32))
+    }
+
+    /// The profile chunks are converted to, if this is a conversion.
+    pub fn convert_target(&self) -> Option<BlockGroupFlags> {
+        self.flags()
+            .contains(BalanceArgsFlags::CONVERT)
+            .then(|| BlockGroupFlags::from_bits_retain(self.target))
+    }
+}
+
+/// `btrfs_balance_item`: a balance that is running or was interrupted,
+/// keyed `(BALANCE_OBJECTID, TEMPORARY_ITEM, 0)` in the root tree.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct BalanceStatus {
+    pub flags: u64,
+    pub data: DiskBalanceArgs,
+    pub metadata: DiskBalanceArgs,
+    pub system: DiskBalanceArgs,
+    pub unused: [u64; 4],
+}
+
+impl BalanceStatus {
+    pub const SIZE: usize = 448;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BalanceStatus, BtrfsError> {
+        if bytes.len() < BalanceStatus::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: BalanceStatus::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(BalanceStatus::read(&mut Cursor::new(
+            &bytes[..BalanceStatus::SIZE],
+        ))?)
+    }
+
+    pub fn flags(&self) -> BalanceFlags {
+        BalanceFlags::from_bits_retain(self.flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_balance_item() {
+        let mut bytes = [0u8; BalanceStatus::SIZE];
+        bytes[0] = 0x1 | 0x4;
+        // Data: convert to RAID1, skipping chunks 50% full or more.
+        let data = 8;
+        bytes[data + 8] = 50;
+        bytes[data + 56] = 0x10;
+        bytes[data + 64..data + 66].copy_from_slice(&(0x2 | 0x100u16).to_le_bytes());
+        // Metadata: chunks 10 to 20% full.
+        let metadata = data + DiskBalanceArgs::SIZE;
+        bytes[metadata + 8] = 10;
+        bytes[metadata + 12] = 20;
+        bytes[metadata + 64..metadata + 66].copy_from_slice(&0x400u16.to_le_bytes());
+
+        let status = BalanceStatus::from_bytes(&bytes).unwrap();
+        assert_eq!(status.flags(), BalanceFlags::DATA | BalanceFlags::METADATA);
+        assert_eq!(status.data.usage(), Some(50));
+        assert_eq!(status.data.convert_target(), Some(BlockGroupFlags::RAID1));
+        assert_eq!(status.data.profiles(), None);
+        assert_eq!(status.metadata.usage(), None);
+        assert_eq!(status.metadata.usage_range(), Some((10, 20)));
+        assert!(status.system.flags().is_empty());
+
+        assert!(matches!(
+            BalanceStatus::from_bytes(&bytes[..400]),
+            Err(BtrfsError::ShortRead { expected: 448, .. })
+        ));
+    }
+}
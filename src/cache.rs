@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Hit and miss counts of the tree block cache, from
+/// [`Btrfs::cache_stats`](crate::Btrfs::cache_stats), or of the directory
+/// entry cache, from [`Btrfs::dir_cache_stats`](crate::Btrfs::dir_cache_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Block reads served from the cache.
+    pub hits: u64,
+    /// Block reads that went to the device, while the cache was enabled.
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Fraction of reads served from the cache, or 0 before any read.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// A least-recently-used map from `K`, such as a logical address, to `V`,
+/// holding at most `capacity` entries. A capacity of zero disables it.
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    /// Incremented on every access, so it orders entries by last use.
+    clock: u64,
+    entries: HashMap<K, (V, u64)>,
+    /// The key last used at each clock value, oldest first.
+    by_use: BTreeMap<u64, K>,
+    stats: CacheStats,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> LruCache<K, V> {
+        LruCache {
+            capacity,
+            clock: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity != 0
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Whether there is an entry for `key`, without counting a hit or a
+    /// miss or marking it as used.
+    pub(crate) fn contains(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// The entry for `key`, marking it as the most recently used.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        if !self.is_enabled() {
+            return None;
+        }
+        self.clock += 1;
+        let Some((value, used)) = self.entries.get_mut(key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        let key = self.by_use.remove(used).expect("entries are tracked");
+        *used = self.clock;
+        self.by_use.insert(self.clock, key);
+        self.stats.hits += 1;
+        Some(value.clone())
+    }
+
+    /// Adds the entry for `key`, evicting the least recently used one if
+    /// the cache is full.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.clock)) {
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(self.clock, key);
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.by_use.pop_first().expect("entries are tracked");
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Drops every entry, keeping the capacity and counts.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.by_use.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 'a');
+        cache.insert(2, 'b');
+        assert_eq!(cache.get(&1), Some('a'));
+        // 2 is now the oldest.
+        cache.insert(3, 'c');
+        assert_eq!(cache.get(&2), None);
+        assert_eq!((cache.get(&1), cache.get(&3)), (Some('a'), Some('c')));
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 1 });
+
+        let mut off = LruCache::new(0);
+        off.insert(1, 'a');
+        assert_eq!(off.get(&1), None);
+        assert_eq!(off.stats(), CacheStats::default());
+    }
+}
@@ -0,0 +1,399 @@
+use crate::chunk::Chunk;
+use crate::error::BtrfsError;
+use crate::fs::Btrfs;
+use crate::key::KeyType;
+use crate::node::NodeHeader;
+use crate::space::EXTENT_TREE_OBJECTID;
+use crate::superblock::{DevItem, ParseOptions, Superblock, BTRFS_SUPER_INFO_OFFSET};
+use crate::tree::{CHUNK_TREE_OBJECTID, ROOT_TREE_OBJECTID};
+
+/// A tree root whose header generation is not the one recorded for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationMismatch {
+    /// Objectid of the tree: 1 for the root tree, 3 for the chunk tree, 2
+    /// for the extent tree.
+    pub tree: u64,
+    pub bytenr: u64,
+    /// Generation recorded in the superblock or, for the extent tree, its
+    /// root item.
+    pub expected: u64,
+    /// Generation in the block's header, or `None` if no block claiming
+    /// that address could be read there.
+    pub found: Option<u64>,
+}
+
+/// Result of [`Btrfs::check_generations`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenerationReport {
+    pub mismatches: Vec<GenerationMismatch>,
+}
+
+impl GenerationReport {
+    /// Whether every checked root is the one its referrer expects.
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Which passes [`Btrfs::verify_all`] runs. All of them by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VerifyOptions {
+    scrub_data: bool,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> VerifyOptions {
+        VerifyOptions { scrub_data: true }
+    }
+}
+
+impl VerifyOptions {
+    pub fn new() -> VerifyOptions {
+        VerifyOptions::default()
+    }
+
+    /// Read every data extent and check it against the checksum tree, as
+    /// [`Btrfs::scrub`] does. This reads all the data, so it is by far the
+    /// slowest pass.
+    pub fn scrub_data(mut self, scrub: bool) -> VerifyOptions {
+        self.scrub_data = scrub;
+        self
+    }
+}
+
+/// One pass of [`Btrfs::verify_all`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyPass {
+    Superblocks,
+    ChunkTree,
+    TreeRoots,
+    Data,
+}
+
+/// How many items a pass checked, and how many of those failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyCounts {
+    pub checked: u64,
+    pub failed: u64,
+}
+
+/// Result of [`Btrfs::verify_all`].
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The primary superblock of each device.
+    pub superblocks: VerifyCounts,
+    /// Chunk and device items of the chunk tree.
+    pub chunk_tree: VerifyCounts,
+    /// The root blocks of the root, chunk and extent trees.
+    pub tree_roots: VerifyCounts,
+    /// Data sectors with a stored checksum. Zero if the scrub was skipped.
+    pub data: VerifyCounts,
+    /// Every problem found, with the pass that found it, in the order the
+    /// passes ran. A pass that could not run at all, such as a scrub of a
+    /// filesystem without a checksum tree, leaves one error and no counts.
+    pub errors: Vec<(VerifyPass, BtrfsError)>,
+}
+
+impl VerifyReport {
+    /// Whether every pass ran and found nothing wrong.
+    pub fn is_sound(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn record(&mut self, pass: VerifyPass, result: Result<(), BtrfsError>) {
+        let counts = match pass {
+            VerifyPass::Superblocks => &mut self.superblocks,
+            VerifyPass::ChunkTree => &mut self.chunk_tree,
+            VerifyPass::TreeRoots => &mut self.tree_roots,
+            VerifyPass::Data => &mut self.data,
+        };
+        counts.checked += 1;
+        if let Err(err) = result {
+            counts.failed += 1;
+            self.errors.push((pass, err));
+        }
+    }
+}
+
+impl Btrfs {
+    /// Checks as much of the filesystem as `options` asks for, without
+    /// stopping at the first problem:
+    ///
+    /// 1. the primary superblock of each device: magic, checksum and
+    ///    geometry;
+    /// 2. every item of the chunk tree, which must parse;
+    /// 3. the root blocks of the root, chunk and extent trees, whose
+    ///    checksums are verified even if [`Btrfs::set_verify_checksums`]
+    ///    turned checks off;
+    /// 4. unless skipped, every data sector, as [`Btrfs::scrub`] checks it.
+    ///
+    /// The chunk tree pass reads the tree without changing the chunk map.
+    /// The scrub needs the map of data chunks, so
+    /// [`Btrfs::load_chunk_tree`] must have been called for it.
+    ///
+    /// Problems go into the report rather than being returned. An
+    /// unreadable tree block ends the walk of its tree and counts as one
+    /// failed item.
+    pub fn verify_all(&self, options: VerifyOptions) -> Result<VerifyReport, BtrfsError> {
+        let mut report = VerifyReport::default();
+        for devid in self.devids() {
+            let primary = self
+                .read_physical(devid, BTRFS_SUPER_INFO_OFFSET, Superblock::SIZE)
+                .and_then(|block| {
+                    Superblock::check_copy(&block, BTRFS_SUPER_INFO_OFFSET, &ParseOptions::new())
+                });
+            report.record(VerifyPass::Superblocks, primary.map(drop));
+        }
+
+        let sb = self.superblock();
+        for item in self.tree(sb.chunk_root, sb.chunk_root_level).iter() {
+            let checked = item.and_then(|(key, data)| match key.item_type() {
+                KeyType::ChunkItem => Chunk::from_bytes(&data).map(drop),
+                KeyType::DevItem => DevItem::from_bytes(&data).map(drop),
+                _ => Ok(()),
+            });
+            report.record(VerifyPass::ChunkTree, checked);
+        }
+
+        let root_ok = self.verify_tree_root(&mut report, sb.root, sb.root_level);
+        self.verify_tree_root(&mut report, sb.chunk_root, sb.chunk_root_level);
+        if root_ok {
+            match self.root_item(EXTENT_TREE_OBJECTID) {
+                Ok(Some(root)) => {
+                    self.verify_tree_root(&mut report, root.bytenr(), root.level());
+                }
+                Ok(None) => report.record(
+                    VerifyPass::TreeRoots,
+                    Err(BtrfsError::Malformed("root tree has no extent tree")),
+                ),
+                Err(err) => report.record(VerifyPass::TreeRoots, Err(err)),
+            }
+        }
+
+        if options.scrub_data {
+            match self.scrub(|_| {}) {
+                Ok(scrub) => {
+                    let sectorsize = u64::from(sb.sectorsize);
+                    report.data.checked = scrub.bytes_verified / sectorsize;
+                    report.data.failed = scrub.mismatches.len() as u64;
+                    report
+                        .errors
+                        .extend(scrub.mismatches.iter().map(|mismatch| {
+                            let logical = mismatch.logical;
+                            (VerifyPass::Data, BtrfsError::ChecksumMismatch { logical })
+                        }));
+                }
+                Err(err) => report.errors.push((VerifyPass::Data, err)),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Records whether the tree block at `logical` is intact. Returns
+    /// whether it was.
+    fn verify_tree_root(&self, report: &mut VerifyReport, logical: u64, level: u8) -> bool {
+        let checked = self.read_tree_block(logical, level).and_then(|block| {
+            if self.checksum_matches(&block)? {
+                Ok(())
+            } else {
+                Err(BtrfsError::ChecksumMismatch { logical })
+            }
+        });
+        let ok = checked.is_ok();
+        report.record(VerifyPass::TreeRoots, checked);
+        ok
+    }
+
+    /// Compares the header generations of the root, chunk and extent tree
+    /// roots with the generations the superblock and root tree record for
+    /// them. A mismatch means a commit was torn, and an older
+    /// [`RootBackup`](crate::RootBackup) may be the better starting point.
+    ///
+    /// Every root is checked regardless of earlier mismatches. The extent
+    /// root is found through the root tree, so it is skipped when the root
+    /// tree's own root is unreadable.
+    pub fn check_generations(&self) -> Result<GenerationReport, BtrfsError> {
+        let sb = self.superblock();
+        let mut report = GenerationReport::default();
+        let root_ok =
+            self.check_generation(&mut report, ROOT_TREE_OBJECTID, sb.root, sb.generation);
+        self.check_generation(
+            &mut report,
+            CHUNK_TREE_OBJECTID,
+            sb.chunk_root,
+            sb.chunk_root_generation,
+        );
+        if root_ok {
+            if let Some(extent_root) = self.root_item(EXTENT_TREE_OBJECTID)? {
+                self.check_generation(
+                    &mut report,
+                    EXTENT_TREE_OBJECTID,
+                    extent_root.bytenr(),
+                    extent_root.generation,
+                );
+            }
+        }
+        Ok(report)
+    }
+
+    /// Records a mismatch if the block at `bytenr` is not from generation
+    /// `expected`. Returns whether the block could be read.
+    fn check_generation(
+        &self,
+        report: &mut GenerationReport,
+        tree: u64,
+        bytenr: u64,
+        expected: u64,
+    ) -> bool {
+        let found = self
+            .read_logical(bytenr, NodeHeader::SIZE)
+            .and_then(|block| NodeHeader::from_bytes(&block))
+            .ok()
+            .filter(|header| header.bytenr == bytenr)
+            .map(|header| header.generation);
+        if found != Some(expected) {
+            report.mismatches.push(GenerationMismatch {
+                tree,
+                bytenr,
+                expected,
+                found,
+            });
+        }
+        found.is_some()
+    }
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::key::Key;
+    use crate::superblock::Superblock;
+    use crate::testutil::{
+        block, leaf, put_u64, reseal, root_item, TestImage, NODESIZE, SUPERBLOCK,
+    };
+
+    /// A leaf at `bytenr` from `generation`.
+    fn leaf_from(bytenr: u64, owner: u64, generation: u64) -> Vec<u8> {
+        let mut block = leaf::<&[u8]>(bytenr, owner, &[]);
+        put_u64(&mut block, 80, generation);
+        reseal(&mut block);
+        block
+    }
+
+    fn chunk_root_generation() -> u64 {
+        Superblock::from_bytes(SUPERBLOCK)
+            .unwrap()
+            .chunk_root_generation
+    }
+
+    /// A filesystem whose chunk root, if present, is from `chunk_gen` and
+    /// whose extent root is from `extent_gen`. The root tree is from the
+    /// superblock's generation, 6.
+    fn generations_fs(chunk_gen: Option<u64>, extent_gen: u64) -> Btrfs {
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .put_block(
+                block(2),
+                &leaf(
+                    block(2),
+                    1,
+                    &[(Key::new(2, 132, 0), root_item(block(3), 0))],
+                ),
+            )
+            .put_block(block(3), &leaf_from(block(3), 2, extent_gen));
+        if let Some(generation) = chunk_gen {
+            image.put_block(block(1), &leaf_from(block(1), 3, generation));
+        }
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    #[test]
+    fn accepts_matching_root_generations() {
+        let fs = generations_fs(Some(chunk_root_generation()), 6);
+        assert!(fs.check_generations().unwrap().is_consistent());
+    }
+
+    #[test]
+    fn collects_every_mismatch() {
+        let fs = generations_fs(None, 5);
+        let report = fs.check_generations().unwrap();
+        assert_eq!(
+            report.mismatches,
+            [
+                GenerationMismatch {
+                    tree: CHUNK_TREE_OBJECTID,
+                    bytenr: block(1),
+                    expected: chunk_root_generation(),
+                    found: None,
+                },
+                GenerationMismatch {
+                    tree: EXTENT_TREE_OBJECTID,
+                    bytenr: block(3),
+                    expected: 6,
+                    found: Some(5),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn verifies_every_pass_without_stopping() {
+        let fs = generations_fs(Some(chunk_root_generation()), 6);
+        let report = fs
+            .verify_all(VerifyOptions::new().scrub_data(false))
+            .unwrap();
+        assert!(report.is_sound(), "{:?}", report.errors);
+        let clean = |checked| VerifyCounts { checked, failed: 0 };
+        assert_eq!(report.superblocks, clean(1));
+        assert_eq!(report.tree_roots, clean(3));
+        assert_eq!(report.data, VerifyCounts::default());
+
+        // The chunk root is missing and the extent root is corrupt, which
+        // reads with checks off do not notice. There is no checksum tree
+        // to scrub with.
+        let mut extent_root = leaf_from(block(3), 2, 6);
+        extent_root[NODESIZE - 1] ^= 1;
+        let roots = [(Key::new(2, 132, 0), root_item(block(3), 0))];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .put_block(block(2), &leaf(block(2), 1, &roots))
+            .put_block(block(3), &extent_root);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.set_verify_checksums(false);
+
+        let report = fs.verify_all(VerifyOptions::new()).unwrap();
+        let passes: Vec<_> = report.errors.iter().map(|(pass, _)| *pass).collect();
+        assert_eq!(
+            passes,
+            [
+                VerifyPass::ChunkTree,
+                VerifyPass::TreeRoots,
+                VerifyPass::TreeRoots,
+                VerifyPass::Data,
+            ]
+        );
+        assert!(matches!(
+            report.errors[2].1,
+            BtrfsError::ChecksumMismatch { logical } if logical == block(3)
+        ));
+        assert_eq!(report.superblocks, clean(1));
+        assert_eq!(
+            report.chunk_tree,
+            VerifyCounts {
+                checked: 1,
+                failed: 1
+            }
+        );
+        assert_eq!(
+            report.tree_roots,
+            VerifyCounts {
+                checked: 3,
+                failed: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,955 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::str::FromStr;
+
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+use bitflags::bitflags;
+
+use crate::error::{parse_name, BtrfsError, ParseEnumError};
+use crate::key::Key;
+use crate::superblock::Superblock;
+
+/// `btrfs_stripe`: where one copy or strip of a chunk lives on a device.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub struct Stripe {
+    pub devid: u64,
+    /// Physical byte offset on the device.
+    pub offset: u64,
+    pub dev_uuid: [u8; 16],
+}
+
+impl Stripe {
+    pub const SIZE: usize = 32;
+
+    fn physical(&self, offset: u64) -> Option<(u64, u64)> {
+        Some((self.devid, self.offset.checked_add(offset)?))
+    }
+}
+
+/// `btrfs_chunk`: maps a range of logical addresses onto device stripes.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub struct Chunk {
+    /// Size of the logical range this chunk covers.
+    pub length: u64,
+    pub owner: u64,
+    pub stripe_len: u64,
+    pub type_flags: u64,
+    pub io_align: u32,
+    pub io_width: u32,
+    pub sector_size: u32,
+    pub num_stripes: u16,
+    pub sub_stripes: u16,
+    #[br(count = num_stripes)]
+    pub stripes: Vec<Stripe>,
+}
+
+impl Chunk {
+    /// Size of the fixed part of a chunk item, before its stripes.
+    pub const HEADER_SIZE: usize = 48;
+
+    /// Parses a chunk item, checking that all of its stripes fit in `bytes`
+    /// and that there are enough of them for its profile.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, BtrfsError> {
+        let len = Chunk::item_len(bytes)?;
+        let chunk = Chunk::read(&mut Cursor::new(&bytes[..len]))?;
+        if chunk.stripes.is_empty() {
+            return Err(BtrfsError::Malformed("chunk has no stripes"));
+        }
+        if chunk.raid_profile() == RaidProfile::Raid10
+            && chunk.stripes.len() < chunk.sub_stripes as usize
+        {
+            return Err(BtrfsError::Malformed(
+                "RAID10 chunk has fewer stripes than sub_stripes",
+            ));
+        }
+        Ok(chunk)
+    }
+
+    pub fn flags(&self) -> BlockGroupFlags {
+        BlockGroupFlags::from_bits_retain(self.type_flags)
+    }
+
+    pub fn block_group_type(&self) -> BlockGroupType {
+        BlockGroupType::from_flags(self.flags())
+    }
+
+    pub fn raid_profile(&self) -> RaidProfile {
+        raid_profile(self.flags())
+    }
+
+    /// Whether consecutive `stripe_len` runs of the chunk are spread over
+    /// different stripes, rather than each stripe holding all of it.
+    pub fn is_striped(&self) -> bool {
+        matches!(
+            self.raid_profile(),
+            RaidProfile::Raid0 | RaidProfile::Raid10 | RaidProfile::Raid5 | RaidProfile::Raid6
+        )
+    }
+
+    /// Number of stripes holding data rather than RAID5/6 parity, if the
+    /// chunk has any.
+    fn data_stripes(&self) -> Option<u64> {
+        let parity = match self.raid_profile() {
+            RaidProfile::Raid5 => 1,
+            RaidProfile::Raid6 => 2,
+            _ => 0,
+        };
+        (self.stripes.len() as u64)
+            .checked_sub(parity)
+            .filter(|&n| n > 0)
+    }
+
+    /// Splits an offset into the chunk into the number of the `stripe_len`
+    /// run holding it and the offset within that run.
+    fn split_offset(&self, offset: u64) -> Option<(u64, u64)> {
+        let stripe_len = self.stripe_len;
+        (stripe_len != 0).then(|| (offset / stripe_len, offset % stripe_len))
+    }
+
+    /// Length of the chunk item at the start of `bytes`, including stripes.
+    fn item_len(bytes: &[u8]) -> Result<usize, BtrfsError> {
+        if bytes.len() < Chunk::HEADER_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: Chunk::HEADER_SIZE,
+                got: bytes.len(),
+            });
+        }
+        let num_stripes = u16::from_le_bytes([bytes[44], bytes[45]]) as usize;
+        let len = Chunk::HEADER_SIZE + num_stripes * Stripe::SIZE;
+        if bytes.len() < len {
+            return Err(BtrfsError::ShortRead {
+                expected: len,
+                got: bytes.len(),
+            });
+        }
+        Ok(len)
+    }
+}
+
+bitflags! {
+    /// Block group type flags, shared by chunk items and block group items:
+    /// one or more kind bits plus at most one RAID profile bit.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct BlockGroupFlags: u64 {
+        const DATA = 1 << 0;
+        const SYSTEM = 1 << 1;
+        const METADATA = 1 << 2;
+        const RAID0 = 1 << 3;
+        const RAID1 = 1 << 4;
+        const DUP = 1 << 5;
+        const RAID10 = 1 << 6;
+        const RAID5 = 1 << 7;
+        const RAID6 = 1 << 8;
+        const RAID1C3 = 1 << 9;
+        const RAID1C4 = 1 << 10;
+
+        const TYPE_MASK = Self::DATA.bits() | Self::SYSTEM.bits() | Self::METADATA.bits();
+        const PROFILE_MASK = Self::RAID0.bits()
+            | Self::RAID1.bits()
+            | Self::DUP.bits()
+            | Self::RAID10.bits()
+            | Self::RAID5.bits()
+            | Self::RAID6.bits()
+            | Self::RAID1C3.bits()
+            | Self::RAID1C4.bits();
+
+        const _ = !0;
+    }
+}
+
+/// Replication profile of a block group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RaidProfile {
+    /// No profile bit set.
+    Single,
+    Dup,
+    Raid0,
+    Raid1,
+    Raid1C3,
+    Raid1C4,
+    Raid10,
+    Raid5,
+    Raid6,
+}
+
+impl RaidProfile {
+    /// Decodes the profile bits of `flags`. If several are set, which btrfs
+    /// never writes, the first in kernel bit order wins.
+    pub fn from_flags(flags: BlockGroupFlags) -> RaidProfile {
+        const PROFILES: [(BlockGroupFlags, RaidProfile); 8] = [
+            (BlockGroupFlags::RAID0, RaidProfile::Raid0),
+            (BlockGroupFlags::RAID1, RaidProfile::Raid1),
+            (BlockGroupFlags::DUP, RaidProfile::Dup),
+            (BlockGroupFlags::RAID10, RaidProfile::Raid10),
+            (BlockGroupFlags::RAID5, RaidProfile::Raid5),
+            (BlockGroupFlags::RAID6, RaidProfile::Raid6),
+            (BlockGroupFlags::RAID1C3, RaidProfile::Raid1C3),
+            (BlockGroupFlags::RAID1C4, RaidProfile::Raid1C4),
+        ];
+        PROFILES
+            .iter()
+            .find(|(bit, _)| flags.contains(*bit))
+            .map(|&(_, profile)| profile)
+            .unwrap_or(RaidProfile::Single)
+    }
+
+    /// Copies of each byte the profile stores, as the kernel counts them
+    /// for `df`. RAID5 and RAID6 count as one, since their parity overhead
+    /// depends on the stripe count of each chunk.
+    pub fn copies(&self) -> u64 {
+        match self {
+            RaidProfile::Single | RaidProfile::Raid0 | RaidProfile::Raid5 | RaidProfile::Raid6 => 1,
+            RaidProfile::Dup | RaidProfile::Raid1 | RaidProfile::Raid10 => 2,
+            RaidProfile::Raid1C3 => 3,
+            RaidProfile::Raid1C4 => 4,
+        }
+    }
+
+    /// The spelling btrfs-progs uses, e.g. `"RAID1C3"` or `"single"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RaidProfile::Single => "single",
+            RaidProfile::Dup => "DUP",
+            RaidProfile::Raid0 => "RAID0",
+            RaidProfile::Raid1 => "RAID1",
+            RaidProfile::Raid1C3 => "RAID1C3",
+            RaidProfile::Raid1C4 => "RAID1C4",
+            RaidProfile::Raid10 => "RAID10",
+            RaidProfile::Raid5 => "RAID5",
+            RaidProfile::Raid6 => "RAID6",
+        }
+    }
+}
+
+impl fmt::Display for RaidProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Parses the names `btrfs balance -dconvert=` takes, in any case.
+impl FromStr for RaidProfile {
+    type Err = ParseEnumError;
+
+    fn from_str(s: &str) -> Result<RaidProfile, ParseEnumError> {
+        const NAMES: [(&str, RaidProfile); 9] = [
+            ("single", RaidProfile::Single),
+            ("dup", RaidProfile::Dup),
+            ("raid0", RaidProfile::Raid0),
+            ("raid1", RaidProfile::Raid1),
+            ("raid1c3", RaidProfile::Raid1C3),
+            ("raid1c4", RaidProfile::Raid1C4),
+            ("raid10", RaidProfile::Raid10),
+            ("raid5", RaidProfile::Raid5),
+            ("raid6", RaidProfile::Raid6),
+        ];
+        parse_name("RAID profile", &NAMES, s)
+    }
+}
+
+pub fn raid_profile(flags: BlockGroupFlags) -> RaidProfile {
+    RaidProfile::from_flags(flags)
+}
+
+/// What a chunk's block group stores, decoded from its type flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockGroupType {
+    Data,
+    Metadata,
+    System,
+    /// Data and metadata sharing one block group (`MIXED_GROUPS`).
+    Mixed,
+    /// No kind bit, or a combination that btrfs never writes.
+    Unknown(u64),
+}
+
+impl BlockGroupType {
+    pub fn from_flags(flags: BlockGroupFlags) -> BlockGroupType {
+        let kind = flags & BlockGroupFlags::TYPE_MASK;
+        if kind == BlockGroupFlags::DATA {
+            BlockGroupType::Data
+        } else if kind == BlockGroupFlags::METADATA {
+            BlockGroupType::Metadata
+        } else if kind == BlockGroupFlags::SYSTEM {
+            BlockGroupType::System
+        } else if kind == BlockGroupFlags::DATA | BlockGroupFlags::METADATA {
+            BlockGroupType::Mixed
+        } else {
+            BlockGroupType::Unknown(kind.bits())
+        }
+    }
+
+    /// The spelling of `btrfs filesystem df`, e.g. `"Data+Metadata"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockGroupType::Data => "Data",
+            BlockGroupType::Metadata => "Metadata",
+            BlockGroupType::System => "System",
+            BlockGroupType::Mixed => "Data+Metadata",
+            BlockGroupType::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for BlockGroupType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockGroupType::Unknown(bits) => write!(f, "unknown({bits:#x})"),
+            known => f.write_str(known.as_str()),
+        }
+    }
+}
+
+/// Accepts the `Display` spellings and `mixed`, in any case.
+impl FromStr for BlockGroupType {
+    type Err = ParseEnumError;
+
+    fn from_str(s: &str) -> Result<BlockGroupType, ParseEnumError> {
+        const NAMES: [(&str, BlockGroupType); 5] = [
+            ("data", BlockGroupType::Data),
+            ("metadata", BlockGroupType::Metadata),
+            ("system", BlockGroupType::System),
+            ("data+metadata", BlockGroupType::Mixed),
+            ("mixed", BlockGroupType::Mixed),
+        ];
+        parse_name("block group type", &NAMES, s)
+    }
+}
+
+/// `btrfs_block_group_item`: space accounting for the block group starting
+/// at its key's objectid, `offset` bytes long.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct BlockGroupItem {
+    /// Bytes allocated to extents in the block group.
+    pub used: u64,
+    pub chunk_objectid: u64,
+    pub flags: u64,
+}
+
+impl BlockGroupItem {
+    pub const SIZE: usize = 24;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockGroupItem, BtrfsError> {
+        if bytes.len() < BlockGroupItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: BlockGroupItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(BlockGroupItem::read(&mut Cursor::new(
+            &bytes[..BlockGroupItem::SIZE],
+        ))?)
+    }
+
+    pub fn flags(&self) -> BlockGroupFlags {
+        BlockGroupFlags::from_bits_retain(self.flags)
+    }
+}
+
+/// `btrfs_dev_extent`: a range of a device allocated to one chunk stripe,
+/// keyed by `(devid, DEV_EXTENT, physical offset)` in the device tree.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct DevExtent {
+    /// Objectid of the chunk tree, always 3.
+    pub chunk_tree: u64,
+    pub chunk_objectid: u64,
+    /// Logical address of the chunk this extent backs.
+    pub chunk_offset: u64,
+    pub length: u64,
+    pub chunk_tree_uuid: [u8; 16],
+}
+
+impl DevExtent {
+    pub const SIZE: usize = 48;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DevExtent, BtrfsError> {
+        if bytes.len() < DevExtent::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: DevExtent::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(DevExtent::read(&mut Cursor::new(
+            &bytes[..DevExtent::SIZE],
+        ))?)
+    }
+}
+
+/// `btrfs_dev_stats_item`: the persistent error counters of one device,
+/// keyed `(0, PERSISTENT_ITEM, devid)` in the device tree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceErrorStats {
+    pub write_errs: u64,
+    pub read_errs: u64,
+    pub flush_errs: u64,
+    /// Checksum errors, and tree blocks with a bad bytenr or fsid.
+    pub corruption_errs: u64,
+    /// Tree blocks from an unexpected generation.
+    pub generation_errs: u64,
+}
+
+impl DeviceErrorStats {
+    /// Size of the five counters this version of the item holds.
+    pub const SIZE: usize = 40;
+
+    /// Parses the counters. As in the kernel, counters a short item lacks
+    /// read as zero and bytes past the known ones are ignored.
+    pub fn from_bytes(bytes: &[u8]) -> DeviceErrorStats {
+        let counter = |i: usize| {
+            bytes
+                .get(i * 8..i * 8 + 8)
+                .map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()))
+        };
+        DeviceErrorStats {
+            write_errs: counter(0),
+            read_errs: counter(1),
+            flush_errs: counter(2),
+            corruption_errs: counter(3),
+            generation_errs: counter(4),
+        }
+    }
+}
+
+/// Iterator over the `(key, chunk)` records packed into the superblock's
+/// `sys_chunk_array`. These describe the SYSTEM chunks holding the chunk
+/// tree, and are all that is needed to bootstrap logical address mapping.
+pub struct SysChunkArray<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SysChunkArray<'a> {
+    /// `data` must be exactly the `sys_chunk_array_size` valid bytes.
+    pub fn new(data: &'a [u8]) -> SysChunkArray<'a> {
+        SysChunkArray { data }
+    }
+}
+
+impl Iterator for SysChunkArray<'_> {
+    type Item = Result<(Key, Chunk), BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let result = parse_record(self.data);
+        match result {
+            Ok((_, _, len)) => self.data = &self.data[len..],
+            // Stop after the first malformed record.
+            Err(_) => self.data = &[],
+        }
+        Some(result.map(|(key, chunk, _)| (key, chunk)))
+    }
+}
+
+fn parse_record(data: &[u8]) -> Result<(Key, Chunk, usize), BtrfsError> {
+    if data.len() < Key::SIZE {
+        return Err(BtrfsError::ShortRead {
+            expected: Key::SIZE,
+            got: data.len(),
+        });
+    }
+    let key = Key::read(&mut Cursor::new(&data[..Key::SIZE]))?;
+    let chunk_len = Chunk::item_len(&data[Key::SIZE..])?;
+    let chunk = Chunk::from_bytes(&data[Key::SIZE..Key::SIZE + chunk_len])?;
+    Ok((key, chunk, Key::SIZE + chunk_len))
+}
+
+/// The strips of one RAID5/6 full stripe at the same offset, as found by
+/// [`ChunkMap::full_stripe`]. Each is a `(devid, physical)` pair.
+#[cfg(feature = "raid56")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FullStripe {
+    /// The data strips, in the order parity is computed over.
+    pub(crate) data: Vec<(u64, u64)>,
+    /// Index into `data` of the strip holding the requested address.
+    pub(crate) index: usize,
+    pub(crate) p: (u64, u64),
+    /// RAID6 only.
+    pub(crate) q: Option<(u64, u64)>,
+}
+
+/// Logical-to-physical address map built from chunk items.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkMap {
+    /// Chunks keyed by the first logical address they cover.
+    chunks: BTreeMap<u64, Chunk>,
+    /// Addresses where a different chunk was inserted over an earlier one,
+    /// for [`ChunkMap::validate`].
+    conflicts: Vec<u64>,
+}
+
+/// A chunk and the logical addresses it covers, from [`ChunkMap::chunks`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRange<'a> {
+    pub logical: Range<u64>,
+    pub chunk: &'a Chunk,
+}
+
+impl ChunkMap {
+    pub fn new() -> ChunkMap {
+        ChunkMap::default()
+    }
+
+    /// Builds the bootstrap map from the superblock's system chunks. This is
+    /// enough to read the chunk tree, but not the rest of the filesystem.
+    pub fn from_superblock(sb: &Superblock) -> Result<ChunkMap, BtrfsError> {
+        let mut map = ChunkMap::new();
+        for (key, chunk) in sb.sys_chunk_array()? {
+            map.insert(key.offset, chunk);
+        }
+        Ok(map)
+    }
+
+    /// Adds a chunk starting at logical address `logical` (the chunk item's
+    /// key offset). A different chunk already there is replaced, which
+    /// [`ChunkMap::validate`] then reports.
+    pub fn insert(&mut self, logical: u64, chunk: Chunk) {
+        if let Some(old) = self.chunks.insert(logical, chunk) {
+            if old != self.chunks[&logical] {
+                self.conflicts.push(logical);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Every chunk with the logical range it covers, in address order. The
+    /// ranges may overlap if the map is corrupt; see [`ChunkMap::validate`].
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkRange<'_>> + '_ {
+        self.chunks.iter().map(|(&start, chunk)| ChunkRange {
+            logical: start..start.saturating_add(chunk.length),
+            chunk,
+        })
+    }
+
+    /// The logical ranges between the first and last chunk that no chunk
+    /// covers, in address order.
+    pub fn gaps(&self) -> Vec<Range<u64>> {
+        let mut gaps = Vec::new();
+        let mut covered: Option<u64> = None;
+        for range in self.chunks() {
+            if let Some(end) = covered {
+                if range.logical.start > end {
+                    gaps.push(end..range.logical.start);
+                }
+            }
+            covered = Some(covered.map_or(range.logical.end, |end| end.max(range.logical.end)));
+        }
+        gaps
+    }
+
+    /// Checks that no two chunks claim the same logical address, which only
+    /// a corrupt chunk tree or `sys_chunk_array` can cause. The same chunk
+    /// inserted twice, as the system chunks are when the chunk tree is
+    /// loaded over the bootstrap map, is fine.
+    pub fn validate(&self) -> Result<(), BtrfsError> {
+        if let Some(&logical) = self.conflicts.first() {
+            return Err(BtrfsError::OverlappingChunks {
+                first: logical,
+                second: logical,
+            });
+        }
+        // The previous chunk, as `(start, end)`.
+        let mut furthest: Option<(u64, u64)> = None;
+        for range in self.chunks() {
+            let Range { start, end } = range.logical;
+            if range.chunk.length > u64::MAX - start {
+                return Err(BtrfsError::Malformed("chunk ends past u64::MAX"));
+            }
+            match furthest {
+                Some((first, furthest_end)) if furthest_end > start => {
+                    return Err(BtrfsError::OverlappingChunks {
+                        first,
+                        second: start,
+                    });
+                }
+                _ => furthest = Some((start, end)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The chunk covering `logical`, with its starting logical address.
+    pub fn chunk_for(&self, logical: u64) -> Option<(u64, &Chunk)> {
+        let (&start, chunk) = self.chunks.range(..=logical).next_back()?;
+        if logical - start < chunk.length {
+            Some((start, chunk))
+        } else {
+            None
+        }
+    }
+
+    /// Resolves `logical` to one `(devid, physical)` pair per copy of the
+    /// data, in the order of [`ChunkMap::physical_for_copy`]. `None` if no
+    /// chunk covers `logical` or a stripe offset overflows.
+    pub fn to_physical(&self, logical: u64) -> Option<Vec<(u64, u64)>> {
+        (0..self.num_copies(logical))
+            .map(|copy| self.physical_for_copy(logical, copy))
+            .collect::<Option<Vec<_>>>()
+            .filter(|copies| !copies.is_empty())
+    }
+
+    /// How many bytes from `logical` on are stored contiguously on each
+    /// device: to the end of the `stripe_len` run for striped profiles, to
+    /// the end of the chunk otherwise.
+    pub fn contiguous_len(&self, logical: u64) -> Option<u64> {
+        let (start, chunk) = self.chunk_for(logical)?;
+        let offset = logical - start;
+        let to_chunk_end = chunk.length - offset;
+        if !chunk.is_striped() {
+            return Some(to_chunk_end);
+        }
+        let (_, stripe_offset) = chunk.split_offset(offset)?;
+        Some((chunk.stripe_len - stripe_offset).min(to_chunk_end))
+    }
+
+    /// How many copies of `logical` can be read directly: one per stripe for
+    /// DUP and the RAID1 profiles, `sub_stripes` for RAID10, and one
+    /// otherwise. RAID5/6 parity is not counted, as rebuilding from it is
+    /// not supported. 0 if no chunk covers `logical`.
+    pub fn num_copies(&self, logical: u64) -> usize {
+        let Some((_, chunk)) = self.chunk_for(logical) else {
+            return 0;
+        };
+        match chunk.raid_profile() {
+            RaidProfile::Dup | RaidProfile::Raid1 | RaidProfile::Raid1C3 | RaidProfile::Raid1C4 => {
+                chunk.stripes.len()
+            }
+            RaidProfile::Raid10 => (chunk.sub_stripes as usize).max(1).min(chunk.stripes.len()),
+            _ => chunk.stripes.len().min(1),
+        }
+    }
+
+    /// The `(devid, physical)` address of copy `copy` (0-based) of
+    /// `logical`, working out which stripe holds it for RAID0, RAID10 and
+    /// RAID5/6. `None` if there is no such copy, or the chunk's stripe
+    /// layout is corrupt.
+    pub fn physical_for_copy(&self, logical: u64, copy: usize) -> Option<(u64, u64)> {
+        if copy >= self.num_copies(logical) {
+            return None;
+        }
+        let (start, chunk) = self.chunk_for(logical)?;
+        let offset = logical - start;
+        let num_stripes = chunk.stripes.len() as u64;
+        let (index, stripe_nr, stripe_offset) = match chunk.raid_profile() {
+            RaidProfile::Single
+            | RaidProfile::Dup
+            | RaidProfile::Raid1
+            | RaidProfile::Raid1C3
+            | RaidProfile::Raid1C4 => return chunk.stripes[copy].physical(offset),
+            RaidProfile::Raid0 => {
+                let (stripe_nr, stripe_offset) = chunk.split_offset(offset)?;
+                (
+                    stripe_nr % num_stripes,
+                    stripe_nr / num_stripes,
+                    stripe_offset,
+                )
+            }
+            RaidProfile::Raid10 => {
+                let sub_stripes = (chunk.sub_stripes as u64).max(1);
+                let factor = (num_stripes / sub_stripes).max(1);
+                let (stripe_nr, stripe_offset) = chunk.split_offset(offset)?;
+                let index = (stripe_nr % factor) * sub_stripes + copy as u64;
+                (index, stripe_nr / factor, stripe_offset)
+            }
+            RaidProfile::Raid5 | RaidProfile::Raid6 => {
+                let data_stripes = chunk.data_stripes()?;
+                let (stripe_nr, stripe_offset) = chunk.split_offset(offset)?;
+                let full_stripe = stripe_nr / data_stripes;
+                // Parity rotates by one device per full stripe, and the data
+                // rotates with it.
+                let index = (stripe_nr % data_stripes + full_stripe) % num_stripes;
+                (index, full_stripe, stripe_offset)
+            }
+        };
+        let within = stripe_nr
+            .checked_mul(chunk.stripe_len)?
+            .checked_add(stripe_offset)?;
+        chunk.stripes.get(index as usize)?.physical(within)
+    }
+
+    /// Every strip of the RAID5/6 full stripe holding `logical`, at the
+    /// offset of `logical` within its strip. `None` for other profiles.
+    #[cfg(feature = "raid56")]
+    pub(crate) fn full_stripe(&self, logical: u64) -> Option<FullStripe> {
+        let (start, chunk) = self.chunk_for(logical)?;
+        let parity = match chunk.raid_profile() {
+            RaidProfile::Raid5 => 1,
+            RaidProfile::Raid6 => 2,
+            _ => return None,
+        };
+        let num_stripes = chunk.stripes.len() as u64;
+        let data_stripes = chunk.data_stripes()?;
+        let (stripe_nr, stripe_offset) = chunk.split_offset(logical - start)?;
+        let full_stripe = stripe_nr / data_stripes;
+        let within = full_stripe
+            .checked_mul(chunk.stripe_len)?
+            .checked_add(stripe_offset)?;
+        let strip =
+            |n: u64| chunk.stripes[((n + full_stripe) % num_stripes) as usize].physical(within);
+        Some(FullStripe {
+            data: (0..data_stripes).map(strip).collect::<Option<_>>()?,
+            index: (stripe_nr % data_stripes) as usize,
+            p: strip(data_stripes)?,
+            q: if parity == 2 {
+                Some(strip(data_stripes + 1)?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profiles_and_types_in_any_case() {
+        for profile in [
+            RaidProfile::Single,
+            RaidProfile::Dup,
+            RaidProfile::Raid1C3,
+            RaidProfile::Raid10,
+        ] {
+            assert_eq!(profile.to_string().parse(), Ok(profile));
+        }
+        assert_eq!("raid1c3".parse(), Ok(RaidProfile::Raid1C3));
+        assert!("raid1c5".parse::<RaidProfile>().is_err());
+
+        assert_eq!("METADATA".parse(), Ok(BlockGroupType::Metadata));
+        assert_eq!("mixed".parse(), Ok(BlockGroupType::Mixed));
+        assert_eq!(BlockGroupType::Mixed.to_string(), "Data+Metadata");
+        assert_eq!("data+metadata".parse(), Ok(BlockGroupType::Mixed));
+        assert!("unknown".parse::<BlockGroupType>().is_err());
+    }
+
+    #[test]
+    fn decodes_block_group_type() {
+        let flags = |bits| BlockGroupFlags::from_bits_retain(bits);
+        assert_eq!(
+            BlockGroupType::from_flags(flags(0x11)),
+            BlockGroupType::Data
+        );
+        assert_eq!(
+            BlockGroupType::from_flags(flags(0x5)),
+            BlockGroupType::Mixed
+        );
+        assert_eq!(
+            BlockGroupType::from_flags(flags(0x10)),
+            BlockGroupType::Unknown(0)
+        );
+    }
+
+    #[test]
+    fn reads_short_dev_stats_items() {
+        let bytes: Vec<u8> = [1u64, 2, 3].iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert_eq!(
+            DeviceErrorStats::from_bytes(&bytes),
+            DeviceErrorStats {
+                write_errs: 1,
+                read_errs: 2,
+                flush_errs: 3,
+                corruption_errs: 0,
+                generation_errs: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_raid_profile() {
+        let data_raid0 = BlockGroupFlags::DATA | BlockGroupFlags::RAID0;
+        assert_eq!(raid_profile(data_raid0), RaidProfile::Raid0);
+        assert_eq!(
+            raid_profile(BlockGroupFlags::METADATA | BlockGroupFlags::RAID1C3),
+            RaidProfile::Raid1C3
+        );
+        assert_eq!(raid_profile(BlockGroupFlags::DATA), RaidProfile::Single);
+        assert_eq!(RaidProfile::Single.as_str(), "single");
+    }
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+
+    #[test]
+    fn maps_through_system_chunk() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        let map = ChunkMap::from_superblock(&sb).unwrap();
+        assert_eq!(map.len(), 1);
+        let (_, chunk) = map.chunk_for(22020096).unwrap();
+        assert_eq!(chunk.block_group_type(), BlockGroupType::System);
+        assert_eq!(chunk.raid_profile(), RaidProfile::Dup);
+
+        // The DUP system chunk has two copies on device 1.
+        let logical = 22020096 + 0x4000;
+        assert_eq!(
+            map.to_physical(logical).unwrap(),
+            [(1, 22020096 + 0x4000), (1, 30408704 + 0x4000)]
+        );
+        assert_eq!(map.to_physical(22020096 - 1), None);
+        assert_eq!(map.to_physical(22020096 + (8 << 20)), None);
+
+        let mut map = map.clone();
+        let (_, chunk) = map.chunk_for(22020096).unwrap();
+        let mut corrupt = chunk.clone();
+        corrupt.stripes[0].offset = u64::MAX;
+        map.insert(0, corrupt);
+        assert_eq!(map.to_physical(0), Some(vec![(1, u64::MAX), (1, 30408704)]));
+        assert_eq!(map.to_physical(1), None);
+    }
+
+    fn striped_chunk(flags: BlockGroupFlags, num_stripes: u16, sub_stripes: u16) -> Chunk {
+        Chunk {
+            length: 1 << 30,
+            owner: 2,
+            stripe_len: 0x10000,
+            type_flags: (BlockGroupFlags::DATA | flags).bits(),
+            io_align: 0x10000,
+            io_width: 0x10000,
+            sector_size: 4096,
+            num_stripes,
+            sub_stripes,
+            stripes: (0..num_stripes as u64)
+                .map(|devid| Stripe {
+                    devid: devid + 1,
+                    offset: (devid + 1) << 32,
+                    dev_uuid: [0; 16],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn lists_coverage_and_gaps() {
+        let raid1 = striped_chunk(BlockGroupFlags::RAID1, 2, 1);
+        let mut map = ChunkMap::new();
+        map.insert(3 << 30, raid1.clone());
+        map.insert(1 << 30, raid1.clone());
+        map.insert(2 << 30, raid1.clone());
+        map.insert(1 << 30, raid1.clone());
+        map.insert(6 << 30, raid1.clone());
+        map.insert(8 << 30, raid1.clone());
+
+        let ranges: Vec<_> = map.chunks().map(|range| range.logical).collect();
+        assert_eq!(
+            ranges,
+            [
+                (1 << 30)..(2 << 30),
+                (2 << 30)..(3 << 30),
+                (3 << 30)..(4 << 30),
+                (6 << 30)..(7 << 30),
+                (8 << 30)..(9 << 30),
+            ]
+        );
+        assert_eq!(map.gaps(), [(4 << 30)..(6 << 30), (7 << 30)..(8 << 30)]);
+        map.validate().unwrap();
+
+        let mut overlapping = map.clone();
+        let mut long = raid1.clone();
+        long.length = 3 << 30;
+        overlapping.insert(4 << 30, long.clone());
+        assert_eq!(overlapping.gaps(), map.gaps()[1..]);
+        assert!(matches!(
+            overlapping.validate(),
+            Err(BtrfsError::OverlappingChunks { first, second })
+                if (first, second) == (4 << 30, 6 << 30)
+        ));
+
+        map.insert(2 << 30, long);
+        assert!(matches!(
+            map.validate(),
+            Err(BtrfsError::OverlappingChunks { first, second })
+                if first == 2 << 30 && second == 2 << 30
+        ));
+    }
+
+    #[test]
+    fn maps_each_copy_of_striped_profiles() {
+        let mut map = ChunkMap::new();
+        map.insert(0, striped_chunk(BlockGroupFlags::RAID0, 2, 1));
+        map.insert(1 << 30, striped_chunk(BlockGroupFlags::RAID10, 4, 2));
+        map.insert(2 << 30, striped_chunk(BlockGroupFlags::RAID5, 3, 1));
+        map.insert(3 << 30, striped_chunk(BlockGroupFlags::RAID1, 2, 1));
+
+        // RAID0: the third 64KiB run is the second run on the first device.
+        assert_eq!(map.num_copies(0x20000 + 5), 1);
+        assert_eq!(
+            map.physical_for_copy(0x20000 + 5, 0),
+            Some((1, (1 << 32) + 0x10000 + 5))
+        );
+        assert_eq!(map.physical_for_copy(0x10000, 0), Some((2, 2 << 32)));
+        assert_eq!(map.physical_for_copy(0, 1), None);
+        assert_eq!(map.contiguous_len(0x20000 + 5), Some(0x10000 - 5));
+        assert_eq!(map.contiguous_len((4 << 30) - 1), Some(1));
+
+        // RAID10: runs alternate between the mirror pairs (1, 2) and (3, 4).
+        let raid10 = (1 << 30) + 0x10000;
+        assert_eq!(map.num_copies(raid10), 2);
+        assert_eq!(
+            map.to_physical(raid10).unwrap(),
+            [(3, 3 << 32), (4, 4 << 32)]
+        );
+        assert_eq!(
+            map.physical_for_copy(raid10 + 0x10000, 1),
+            Some((2, (2 << 32) + 0x10000))
+        );
+
+        // RAID5 over three devices: two data runs per full stripe, rotating.
+        let raid5 = 2 << 30;
+        assert_eq!(map.physical_for_copy(raid5, 0), Some((1, 1 << 32)));
+        assert_eq!(
+            map.physical_for_copy(raid5 + 0x10000, 0),
+            Some((2, 2 << 32))
+        );
+        assert_eq!(
+            map.physical_for_copy(raid5 + 0x20000, 0),
+            Some((2, (2 << 32) + 0x10000))
+        );
+
+        assert_eq!(map.num_copies(3 << 30), 2);
+        assert_eq!(
+            map.physical_for_copy((3 << 30) + 7, 1),
+            Some((2, (2 << 32) + 7))
+        );
+        assert_eq!(map.num_copies(4 << 30), 0);
+    }
+
+    #[test]
+    fn rejects_raid10_chunks_without_enough_stripes() {
+        let bytes = |chunk: &Chunk| {
+            let mut bytes = Cursor::new(Vec::new());
+            chunk.write(&mut bytes).unwrap();
+            bytes.into_inner()
+        };
+        let empty = striped_chunk(BlockGroupFlags::RAID10, 0, 2);
+        assert!(matches!(
+            Chunk::from_bytes(&bytes(&empty)),
+            Err(BtrfsError::Malformed("chunk has no stripes"))
+        ));
+        let short = striped_chunk(BlockGroupFlags::RAID10, 1, 2);
+        assert!(matches!(
+            Chunk::from_bytes(&bytes(&short)),
+            Err(BtrfsError::Malformed(_))
+        ));
+        assert!(Chunk::from_bytes(&bytes(&striped_chunk(BlockGroupFlags::RAID10, 2, 2))).is_ok());
+
+        // Inserted directly, an empty chunk maps nothing rather than panicking.
+        let mut map = ChunkMap::new();
+        map.insert(0, empty);
+        assert_eq!(map.num_copies(0), 0);
+        assert_eq!(map.to_physical(0), None);
+    }
+}
@@ -0,0 +1,194 @@
+//! Decompression of compressed file extents, with one optional codec per
+//! cargo feature.
+
+#[cfg(any(feature = "zlib", feature = "zstd"))]
+use std::io::Read;
+
+use crate::error::BtrfsError;
+use crate::file_extent::Compression;
+
+/// Largest extent the kernel compresses, before and after compression.
+pub(crate) const MAX_UNCOMPRESSED: usize = 128 * 1024;
+
+/// Decompresses one extent. `ram_bytes` is the extent's uncompressed size;
+/// the on-disk data is padded up to a sector, so the codecs are bounded by
+/// it rather than by the input length. Output that ends early is zero-filled
+/// to `ram_bytes`, as the kernel does.
+#[cfg_attr(
+    not(all(feature = "zlib", feature = "zstd", feature = "lzo")),
+    allow(unused_variables)
+)]
+pub(crate) fn decompress(
+    compression: Compression,
+    input: &[u8],
+    ram_bytes: u64,
+    sectorsize: u32,
+) -> Result<Vec<u8>, BtrfsError> {
+    let ram_bytes = usize::try_from(ram_bytes)
+        .ok()
+        .filter(|&len| len <= MAX_UNCOMPRESSED)
+        .ok_or(BtrfsError::Malformed(
+            "compressed extent larger than 128KiB",
+        ))?;
+    let mut out = match compression {
+        Compression::None => input.to_vec(),
+        #[cfg(feature = "zlib")]
+        Compression::Zlib => zlib(input, ram_bytes)?,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd(input, ram_bytes)?,
+        #[cfg(feature = "lzo")]
+        Compression::Lzo => lzo(input, ram_bytes, sectorsize as usize)?,
+        #[cfg(not(feature = "zlib"))]
+        Compression::Zlib => {
+            return Err(BtrfsError::Unsupported("zlib (enable the `zlib` feature)"))
+        }
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => {
+            return Err(BtrfsError::Unsupported("zstd (enable the `zstd` feature)"))
+        }
+        #[cfg(not(feature = "lzo"))]
+        Compression::Lzo => return Err(BtrfsError::Unsupported("lzo (enable the `lzo` feature)")),
+        Compression::Unknown(_) => return Err(BtrfsError::Unsupported("unknown compression type")),
+    };
+    out.resize(ram_bytes, 0);
+    Ok(out)
+}
+
+#[cfg(feature = "zlib")]
+fn zlib(input: &[u8], ram_bytes: usize) -> Result<Vec<u8>, BtrfsError> {
+    let mut out = Vec::with_capacity(ram_bytes);
+    flate2::read::ZlibDecoder::new(input)
+        .take(ram_bytes as u64)
+        .read_to_end(&mut out)
+        .map_err(|_| BtrfsError::Malformed("corrupt zlib extent"))?;
+    Ok(out)
+}
+
+#[cfg(feature = "zstd")]
+fn zstd(input: &[u8], ram_bytes: usize) -> Result<Vec<u8>, BtrfsError> {
+    let mut out = Vec::with_capacity(ram_bytes);
+    // The frame is followed by zero padding, which is not another frame.
+    zstd::stream::read::Decoder::with_buffer(input)
+        .map_err(BtrfsError::Io)?
+        .single_frame()
+        .take(ram_bytes as u64)
+        .read_to_end(&mut out)
+        .map_err(|_| BtrfsError::Malformed("corrupt zstd extent"))?;
+    Ok(out)
+}
+
+/// btrfs frames LZO data itself: a little-endian `u32` total length, then
+/// segments of a `u32` length plus LZO1X data of at most one sector each.
+/// A segment header never straddles a sector boundary; if fewer than four
+/// bytes are left in the sector, the next header starts at the next sector.
+#[cfg(feature = "lzo")]
+fn lzo(input: &[u8], ram_bytes: usize, sectorsize: usize) -> Result<Vec<u8>, BtrfsError> {
+    const LEN: usize = 4;
+    let read_len = |at: usize| -> Result<usize, BtrfsError> {
+        let bytes = input
+            .get(at..at + LEN)
+            .ok_or(BtrfsError::Malformed("lzo extent is truncated"))?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    };
+
+    let total = read_len(0)?;
+    if total > input.len() || sectorsize == 0 {
+        return Err(BtrfsError::Malformed("lzo extent is truncated"));
+    }
+    let mut out = Vec::with_capacity(ram_bytes);
+    let mut pos = LEN;
+    while pos < total && out.len() < ram_bytes {
+        if sectorsize - pos % sectorsize < LEN {
+            pos = pos.next_multiple_of(sectorsize);
+            if pos >= total {
+                break;
+            }
+        }
+        let segment_len = read_len(pos)?;
+        pos += LEN;
+        let segment = input
+            .get(pos..pos + segment_len)
+            .ok_or(BtrfsError::Malformed("lzo segment runs past the extent"))?;
+        pos += segment_len;
+        let decompressed = lzokay_native::decompress_all(segment, Some(sectorsize))
+            .map_err(|_| BtrfsError::Malformed("corrupt lzo segment"))?;
+        out.extend_from_slice(&decompressed);
+    }
+    out.truncate(ram_bytes);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "zlib", feature = "zstd", feature = "lzo"))]
+    fn sample() -> Vec<u8> {
+        (0..10000u32).map(|i| (i / 7 % 256) as u8).collect()
+    }
+
+    /// Pads compressed data with zeros up to a whole sector, as on disk.
+    #[cfg(any(feature = "zlib", feature = "zstd"))]
+    fn sector_padded(mut data: Vec<u8>) -> Vec<u8> {
+        data.resize(data.len().next_multiple_of(4096), 0);
+        data
+    }
+
+    #[test]
+    fn uncompressed_passes_through() {
+        let out = decompress(Compression::None, b"abc", 5, 4096).unwrap();
+        assert_eq!(out, b"abc\0\0");
+        assert!(matches!(
+            decompress(Compression::Unknown(7), b"abc", 3, 4096),
+            Err(BtrfsError::Unsupported(_))
+        ));
+        assert!(matches!(
+            decompress(Compression::Zstd, b"abc", 1 << 40, 4096),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn decompresses_zlib() {
+        use std::io::Write;
+
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&sample()).unwrap();
+        let input = sector_padded(encoder.finish().unwrap());
+
+        let out = decompress(Compression::Zlib, &input, 10000, 4096).unwrap();
+        assert_eq!(out, sample());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn decompresses_zstd() {
+        let input = sector_padded(zstd::encode_all(&sample()[..], 3).unwrap());
+        let out = decompress(Compression::Zstd, &input, 10000, 4096).unwrap();
+        assert_eq!(out, sample());
+    }
+
+    #[cfg(feature = "lzo")]
+    #[test]
+    fn decompresses_lzo_segments() {
+        let data = sample();
+        let mut input = vec![0u8; 4];
+        for page in data.chunks(4096) {
+            // Skip to the next sector if a header would straddle it.
+            let left = 4096 - input.len() % 4096;
+            if left < 4 {
+                input.resize(input.len() + left, 0);
+            }
+            let segment = lzokay_native::compress(page).unwrap();
+            input.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+            input.extend_from_slice(&segment);
+        }
+        let total = input.len() as u32;
+        input[..4].copy_from_slice(&total.to_le_bytes());
+
+        let out = decompress(Compression::Lzo, &input, 10000, 4096).unwrap();
+        assert_eq!(out, data);
+    }
+}
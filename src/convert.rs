@@ -0,0 +1,164 @@
+//! `TryFrom<&[u8]>` for the on-disk structures, so generic code can parse
+//! them with `T::try_from(bytes)`.
+//!
+//! Each impl parses from the start of the slice, exactly as the type's
+//! `from_bytes` does: the slice must already be the structure, not a
+//! buffer it sits somewhere inside. For [`Superblock`] that means the
+//! 4096-byte block itself, not a device image with the superblock at
+//! offset 65536.
+
+use crate::balance::BalanceStatus;
+use crate::chunk::{BlockGroupItem, Chunk, DevExtent};
+use crate::error::BtrfsError;
+use crate::file_extent::FileExtentItem;
+use crate::free_space::FreeSpaceInfo;
+use crate::inode::InodeItem;
+use crate::key::Key;
+use crate::node::NodeHeader;
+use crate::qgroup::{QgroupInfoItem, QgroupLimitItem, QgroupStatusItem};
+use crate::root::{RootItem, RootRef};
+use crate::superblock::Superblock;
+use crate::verity::VerityDescriptorItem;
+
+macro_rules! try_from_bytes {
+    ($($ty:ty,)*) => {
+        $(
+            impl TryFrom<&[u8]> for $ty {
+                type Error = BtrfsError;
+
+                fn try_from(bytes: &[u8]) -> Result<$ty, BtrfsError> {
+                    <$ty>::from_bytes(bytes)
+                }
+            }
+        )*
+    };
+}
+
+try_from_bytes! {
+    Superblock,
+    NodeHeader,
+    Chunk,
+    BlockGroupItem,
+    DevExtent,
+    RootItem,
+    RootRef,
+    InodeItem,
+    FileExtentItem,
+    FreeSpaceInfo,
+    QgroupStatusItem,
+    QgroupInfoItem,
+    QgroupLimitItem,
+    BalanceStatus,
+    VerityDescriptorItem,
+}
+
+/// Reads the 17 packed bytes at the start of the slice.
+impl TryFrom<&[u8]> for Key {
+    type Error = BtrfsError;
+
+    fn try_from(bytes: &[u8]) -> Result<Key, BtrfsError> {
+        let packed = bytes.get(..Key::SIZE).ok_or(BtrfsError::ShortRead {
+            expected: Key::SIZE,
+            got: bytes.len(),
+        })?;
+        Ok(Key::from_disk_bytes(packed.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binrw::io::{Cursor, Seek};
+    use binrw::{BinRead, BinWrite};
+
+    use super::*;
+    use crate::balance::DiskBalanceArgs;
+    use crate::chunk::Stripe;
+    use crate::dir::DirItemHeader;
+    use crate::inode::Timespec;
+    use crate::node::KeyPtr;
+    use crate::superblock::{DevItem, RootBackup, BTRFS_SUPER_INFO_SIZE};
+    use crate::verity::VerityDescriptorItem;
+
+    /// Reads `T` from zeros and writes it back, checking both take exactly
+    /// `size` bytes.
+    fn assert_layout<T>(name: &str, size: usize)
+    where
+        for<'a> T: BinRead<Args<'a> = ()> + BinWrite<Args<'a> = ()>,
+    {
+        let mut zeros = Cursor::new(vec![0u8; size + 64]);
+        let value = T::read_le(&mut zeros).unwrap();
+        assert_eq!(zeros.stream_position().unwrap(), size as u64, "{name} read");
+        let mut written = Cursor::new(Vec::new());
+        value.write_le(&mut written).unwrap();
+        assert_eq!(written.into_inner().len(), size, "{name} written");
+    }
+
+    macro_rules! assert_layouts {
+        ($($ty:ty => $size:expr,)*) => {
+            $(assert_layout::<$ty>(stringify!($ty), $size);)*
+        };
+    }
+
+    #[test]
+    fn sizes_match_serialized_layout() {
+        assert_layouts! {
+            Superblock => Superblock::SIZE,
+            DevItem => DevItem::SIZE,
+            RootBackup => RootBackup::SIZE,
+            NodeHeader => NodeHeader::SIZE,
+            KeyPtr => KeyPtr::SIZE,
+            Key => Key::SIZE,
+            Chunk => Chunk::HEADER_SIZE,
+            Stripe => Stripe::SIZE,
+            BlockGroupItem => BlockGroupItem::SIZE,
+            DevExtent => DevExtent::SIZE,
+            DirItemHeader => DirItemHeader::SIZE,
+            InodeItem => InodeItem::SIZE,
+            Timespec => Timespec::SIZE,
+            RootItem => RootItem::SIZE,
+            QgroupStatusItem => QgroupStatusItem::SIZE,
+            QgroupInfoItem => QgroupInfoItem::SIZE,
+            QgroupLimitItem => QgroupLimitItem::SIZE,
+            DiskBalanceArgs => DiskBalanceArgs::SIZE,
+            BalanceStatus => BalanceStatus::SIZE,
+            VerityDescriptorItem => VerityDescriptorItem::SIZE,
+        }
+    }
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+
+    fn parse<'a, T: TryFrom<&'a [u8], Error = BtrfsError>>(
+        bytes: &'a [u8],
+    ) -> Result<T, BtrfsError> {
+        T::try_from(bytes)
+    }
+
+    #[test]
+    fn parses_from_the_start_of_the_slice() {
+        let sb: Superblock = parse(FIXTURE).unwrap();
+        assert_eq!(
+            sb.generation,
+            Superblock::from_bytes(FIXTURE).unwrap().generation
+        );
+        assert!(matches!(
+            parse::<Superblock>(&FIXTURE[..BTRFS_SUPER_INFO_SIZE - 1]),
+            Err(BtrfsError::ShortRead { expected: 4096, .. })
+        ));
+
+        let key = Key::new(256, 1, 7);
+        let mut bytes = key.to_disk_bytes().to_vec();
+        bytes.push(0xff);
+        assert_eq!(Key::try_from(&bytes[..]).unwrap(), key);
+        assert!(matches!(
+            Key::try_from(&bytes[..16]),
+            Err(BtrfsError::ShortRead {
+                expected: 17,
+                got: 16
+            })
+        ));
+        assert!(matches!(
+            parse::<InodeItem>(&[0; 100]),
+            Err(BtrfsError::ShortRead { expected: 160, .. })
+        ));
+    }
+}
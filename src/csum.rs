@@ -0,0 +1,287 @@
+use core::fmt;
+use core::str::FromStr;
+
+use crate::error::{parse_name, BtrfsError, ParseEnumError};
+use crate::superblock::BTRFS_CSUM_SIZE;
+
+/// Checksum algorithm used for metadata and data blocks (`csum_type`).
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumType {
+    Crc32c = 0,
+    Xxhash = 1,
+    Sha256 = 2,
+    Blake2 = 3,
+    Unknown(u16),
+}
+
+impl ChecksumType {
+    pub fn from_u16(value: u16) -> ChecksumType {
+        match value {
+            0 => ChecksumType::Crc32c,
+            1 => ChecksumType::Xxhash,
+            2 => ChecksumType::Sha256,
+            3 => ChecksumType::Blake2,
+            other => ChecksumType::Unknown(other),
+        }
+    }
+
+    /// The `csum_type` value stored on disk.
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            ChecksumType::Crc32c => 0,
+            ChecksumType::Xxhash => 1,
+            ChecksumType::Sha256 => 2,
+            ChecksumType::Blake2 => 3,
+            ChecksumType::Unknown(other) => *other,
+        }
+    }
+
+    /// The name btrfs-progs prints for this algorithm.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumType::Crc32c => "crc32c",
+            ChecksumType::Xxhash => "xxhash64",
+            ChecksumType::Sha256 => "sha256",
+            ChecksumType::Blake2 => "blake2",
+            ChecksumType::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Number of meaningful bytes at the start of a checksum field. Unknown
+    /// algorithms are assumed to use the whole field.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            ChecksumType::Crc32c => 4,
+            ChecksumType::Xxhash => 8,
+            ChecksumType::Sha256 => 32,
+            ChecksumType::Blake2 => 32,
+            ChecksumType::Unknown(_) => BTRFS_CSUM_SIZE,
+        }
+    }
+}
+
+impl fmt::Display for ChecksumType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumType::Unknown(value) => write!(f, "unknown({value})"),
+            known => f.write_str(known.name()),
+        }
+    }
+}
+
+/// Accepts the names `mkfs.btrfs --csum` does, in any case: `crc32c`,
+/// `xxhash` or `xxhash64`, `sha256`, and `blake2` or `blake2b`.
+impl FromStr for ChecksumType {
+    type Err = ParseEnumError;
+
+    fn from_str(s: &str) -> Result<ChecksumType, ParseEnumError> {
+        const NAMES: [(&str, ChecksumType); 6] = [
+            ("crc32c", ChecksumType::Crc32c),
+            ("xxhash64", ChecksumType::Xxhash),
+            ("xxhash", ChecksumType::Xxhash),
+            ("sha256", ChecksumType::Sha256),
+            ("blake2", ChecksumType::Blake2),
+            ("blake2b", ChecksumType::Blake2),
+        ];
+        parse_name("checksum type", &NAMES, s)
+    }
+}
+
+/// Computes the checksum of `data` the way btrfs stores it: the digest at
+/// the start of a zeroed `BTRFS_CSUM_SIZE` field.
+#[cfg_attr(
+    not(feature = "crc32c"),
+    allow(unused_variables, unused_mut, unreachable_code)
+)]
+pub fn checksum(csum_type: ChecksumType, data: &[u8]) -> Result<[u8; BTRFS_CSUM_SIZE], BtrfsError> {
+    let mut out = [0u8; BTRFS_CSUM_SIZE];
+    match csum_type {
+        #[cfg(feature = "crc32c")]
+        ChecksumType::Crc32c => out[..4].copy_from_slice(&crc32c(data).to_le_bytes()),
+        other => return Err(BtrfsError::UnsupportedChecksum(other)),
+    }
+    Ok(out)
+}
+
+#[cfg(feature = "crc32c")]
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues a raw (non-inverted) CRC32C over `data`, with the CPU's
+/// CRC32C instruction if it has one and the lookup table otherwise.
+#[cfg(feature = "crc32c")]
+pub(crate) fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    if hw::available() {
+        // SAFETY: `available` checked that the CPU has the instruction.
+        return unsafe { hw::update(crc, data) };
+    }
+    crc32c_update_table(crc, data)
+}
+
+#[cfg(feature = "crc32c")]
+fn crc32c_update_table(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Whether [`crc32c`] runs on the CPU's CRC32C instruction (SSE4.2 on
+/// x86_64, the CRC extension on aarch64) rather than the lookup table.
+/// Without `std` the CPU cannot be probed at run time, so the instruction
+/// is only used when the build targets it, as with `-C target-cpu=native`.
+#[cfg(feature = "crc32c")]
+pub fn crc32c_is_accelerated() -> bool {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    return hw::available();
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    return false;
+}
+
+#[cfg(all(feature = "crc32c", target_arch = "x86_64"))]
+mod hw {
+    use core::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    pub(super) fn available() -> bool {
+        #[cfg(feature = "std")]
+        return std::arch::is_x86_feature_detected!("sse4.2");
+        #[cfg(not(feature = "std"))]
+        return cfg!(target_feature = "sse4.2");
+    }
+
+    /// # Safety
+    ///
+    /// The CPU must support SSE4.2.
+    #[target_feature(enable = "sse4.2")]
+    pub(super) unsafe fn update(crc: u32, data: &[u8]) -> u32 {
+        let mut words = data.chunks_exact(8);
+        let mut wide = crc as u64;
+        for word in &mut words {
+            wide = _mm_crc32_u64(wide, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        let mut crc = wide as u32;
+        for &byte in words.remainder() {
+            crc = _mm_crc32_u8(crc, byte);
+        }
+        crc
+    }
+}
+
+#[cfg(all(feature = "crc32c", target_arch = "aarch64"))]
+mod hw {
+    use core::arch::aarch64::{__crc32cb, __crc32cd};
+
+    pub(super) fn available() -> bool {
+        #[cfg(feature = "std")]
+        return std::arch::is_aarch64_feature_detected!("crc");
+        #[cfg(not(feature = "std"))]
+        return cfg!(target_feature = "crc");
+    }
+
+    /// # Safety
+    ///
+    /// The CPU must have the CRC extension.
+    #[target_feature(enable = "crc")]
+    pub(super) unsafe fn update(mut crc: u32, data: &[u8]) -> u32 {
+        let mut words = data.chunks_exact(8);
+        for word in &mut words {
+            crc = __crc32cd(crc, u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        for &byte in words.remainder() {
+            crc = __crc32cb(crc, byte);
+        }
+        crc
+    }
+}
+
+/// Standard CRC32C (Castagnoli), as used for `ChecksumType::Crc32c`.
+#[cfg(feature = "crc32c")]
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+/// `btrfs_name_hash`: the key offset of a name's `DIR_ITEM` or `XATTR_ITEM`
+/// entry. It is CRC32C seeded with `!1` and without the final inversion.
+#[cfg(feature = "crc32c")]
+pub fn name_hash(name: &[u8]) -> u64 {
+    crc32c_update(!1, name) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_mkfs_names() {
+        assert_eq!("CRC32C".parse(), Ok(ChecksumType::Crc32c));
+        assert_eq!("xxhash".parse(), Ok(ChecksumType::Xxhash));
+        assert_eq!("blake2b".parse(), Ok(ChecksumType::Blake2));
+        assert_eq!(ChecksumType::Xxhash.to_string(), "xxhash64");
+        assert_eq!(ChecksumType::Unknown(7).to_string(), "unknown(7)");
+
+        let err = "md5".parse::<ChecksumType>().unwrap_err();
+        assert_eq!((err.kind(), err.input()), ("checksum type", "md5"));
+        assert_eq!(err.to_string(), r#"unknown checksum type "md5""#);
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn hardware_and_table_paths_agree() {
+        let data: Vec<u8> = (0..1031u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        // Every length and alignment around the 8-byte steps.
+        for start in 0..9 {
+            for end in (start..data.len()).step_by(61).chain([data.len()]) {
+                let part = &data[start..end];
+                assert_eq!(crc32c_update(!0, part), crc32c_update_table(!0, part));
+            }
+        }
+        #[cfg(all(feature = "std", target_arch = "x86_64"))]
+        assert_eq!(
+            crc32c_is_accelerated(),
+            std::arch::is_x86_feature_detected!("sse4.2")
+        );
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn name_hashes_match_disk() {
+        // Key offsets as they appear in `btrfs inspect-internal dump-tree`:
+        // the root tree's entry for the default subvolume, an SELinux label
+        // xattr, and a file named "foo".
+        assert_eq!(name_hash(b"default"), 2378154706);
+        assert_eq!(name_hash(b"security.selinux"), 3817753667);
+        assert_eq!(name_hash(b"foo"), 2507850652);
+    }
+
+    #[test]
+    fn decodes_known_and_unknown_types() {
+        assert_eq!(ChecksumType::from_u16(0), ChecksumType::Crc32c);
+        assert_eq!(ChecksumType::from_u16(3).digest_len(), 32);
+        assert_eq!(ChecksumType::from_u16(9), ChecksumType::Unknown(9));
+    }
+}
@@ -0,0 +1,310 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::error::BtrfsError;
+
+/// A device or image read at explicit offsets, with no cursor to share, so
+/// that several threads can read one [`Btrfs`](crate::Btrfs) at once.
+pub trait BlockDevice: Send + Sync {
+    /// Fills `buf` with the bytes starting at `offset`. A read past the end
+    /// of the device is an error, not a short read.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError>;
+}
+
+#[cfg(unix)]
+impl BlockDevice for File {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        use std::os::unix::fs::FileExt;
+
+        Ok(self.read_exact_at(buf, offset)?)
+    }
+}
+
+#[cfg(windows)]
+impl BlockDevice for File {
+    fn read_at(&self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), BtrfsError> {
+        use std::os::windows::fs::FileExt;
+
+        // `seek_read` moves the file cursor, but every read here names its
+        // own offset, so that does not matter.
+        while !buf.is_empty() {
+            match self.seek_read(buf, offset) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for [u8] {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        let bytes = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..start.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl BlockDevice for Vec<u8> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        self.as_slice().read_at(offset, buf)
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        (**self).read_at(offset, buf)
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Arc<D> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        (**self).read_at(offset, buf)
+    }
+}
+
+/// A [`BlockDevice`] over any seekable reader, such as an
+/// [`OffsetReader`](crate::OffsetReader). Reads take turns behind a lock,
+/// since they share the reader's cursor.
+#[derive(Debug)]
+pub struct SeekDevice<R> {
+    reader: Mutex<R>,
+}
+
+impl<R: Read + Seek + Send> SeekDevice<R> {
+    pub fn new(reader: R) -> SeekDevice<R> {
+        SeekDevice {
+            reader: Mutex::new(reader),
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<R: Read + Seek + Send> BlockDevice for SeekDevice<R> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        // A panic mid-read leaves nothing behind but the cursor position,
+        // which every read sets again.
+        let mut reader = self.reader.lock().unwrap_or_else(PoisonError::into_inner);
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(reader.read_exact(buf)?)
+    }
+}
+
+/// Size of the runs a [`PartialReader`] retries one at a time.
+const SECTOR_SIZE: u64 = 512;
+
+/// A [`BlockDevice`] for salvaging data from a failing drive. Where the
+/// device it wraps cannot read a sector, it returns zeros and records the
+/// sector instead of failing.
+///
+/// Tree blocks that come back partly zeroed fail their checksum, so reads
+/// of them give [`BtrfsError::ChecksumMismatch`] while the rest of the
+/// filesystem stays readable. Wrap the reader in an [`Arc`] to keep a
+/// handle for [`PartialReader::bad_ranges`] after handing it to
+/// [`Btrfs::from_device`](crate::Btrfs::from_device).
+///
+/// Reads past the end of the device still fail.
+#[derive(Debug)]
+pub struct PartialReader<D> {
+    device: D,
+    /// Sorted, disjoint and not adjacent.
+    bad: Mutex<Vec<Range<u64>>>,
+}
+
+impl<D: BlockDevice> PartialReader<D> {
+    pub fn new(device: D) -> PartialReader<D> {
+        PartialReader {
+            device,
+            bad: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The byte ranges that could not be read so far, in order.
+    pub fn bad_ranges(&self) -> Vec<Range<u64>> {
+        self.bad
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn record(&self, range: Range<u64>) {
+        let mut bad = self.bad.lock().unwrap_or_else(PoisonError::into_inner);
+        // The ranges that touch `range` are merged into it.
+        let first = bad.partition_point(|known| known.end < range.start);
+        let last = bad.partition_point(|known| known.start <= range.end);
+        let merged = bad[first..last].iter().fold(range, |merged, known| {
+            merged.start.min(known.start)..merged.end.max(known.end)
+        });
+        bad.splice(first..last, [merged]);
+    }
+}
+
+/// Whether `err` is a failure to read the medium, rather than a read past
+/// its end.
+fn is_unreadable(err: &BtrfsError) -> bool {
+    matches!(err, BtrfsError::Io(err) if err.kind() != io::ErrorKind::UnexpectedEof)
+}
+
+impl<D: BlockDevice> BlockDevice for PartialReader<D> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        match self.device.read_at(offset, buf) {
+            Err(err) if is_unreadable(&err) => {}
+            result => return result,
+        }
+        // Find the bad sectors one at a time.
+        let mut at = 0;
+        while at < buf.len() {
+            let start = offset + at as u64;
+            let sector_end = (start / SECTOR_SIZE + 1) * SECTOR_SIZE;
+            let len = ((sector_end - start) as usize).min(buf.len() - at);
+            let sector = &mut buf[at..at + len];
+            match self.device.read_at(start, sector) {
+                Ok(()) => {}
+                Err(err) if is_unreadable(&err) => {
+                    sector.fill(0);
+                    self.record(start..start + len as u64);
+                }
+                Err(err) => return Err(err),
+            }
+            at += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn reads_at_offsets() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let devices: [Box<dyn BlockDevice>; 2] = [
+            Box::new(bytes.clone()),
+            Box::new(SeekDevice::new(Cursor::new(bytes))),
+        ];
+        for device in devices {
+            let mut buf = [0u8; 4];
+            device.read_at(250, &mut buf[..2]).unwrap();
+            device.read_at(3, &mut buf[2..]).unwrap();
+            assert_eq!(buf, [250, 251, 3, 4]);
+            assert!(matches!(
+                device.read_at(254, &mut buf),
+                Err(BtrfsError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+
+    /// An in-memory device whose bytes in `bad` cannot be read.
+    struct FailingDevice {
+        bytes: Vec<u8>,
+        bad: Vec<Range<u64>>,
+    }
+
+    impl BlockDevice for FailingDevice {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+            let end = offset + buf.len() as u64;
+            if self
+                .bad
+                .iter()
+                .any(|bad| offset < bad.end && bad.start < end)
+            {
+                return Err(io::Error::other("medium error").into());
+            }
+            self.bytes.read_at(offset, buf)
+        }
+    }
+
+    #[test]
+    fn zeroes_and_records_unreadable_sectors() {
+        let reader = PartialReader::new(FailingDevice {
+            bytes: vec![0xaa; 8192],
+            bad: vec![1000..1100, 3000..3001],
+        });
+        let mut buf = vec![0u8; 2048];
+        reader.read_at(256, &mut buf).unwrap();
+        // The bad bytes fall in the sectors at 512 and 1024.
+        assert!(buf[..256].iter().all(|&b| b == 0xaa));
+        assert!(buf[256..1280].iter().all(|&b| b == 0));
+        assert!(buf[1280..].iter().all(|&b| b == 0xaa));
+
+        reader.read_at(1536 - 8, &mut buf[..16]).unwrap();
+        reader.read_at(0, &mut buf[..1024]).unwrap();
+        reader.read_at(2048, &mut buf).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0xaa));
+        assert_eq!(reader.bad_ranges(), [512..1536, 2560..3072]);
+        assert!(reader.read_at(8190, &mut buf[..4]).is_err());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn unreadable_tree_blocks_fail_their_checksum() {
+        use crate::fs::Btrfs;
+        use crate::key::Key;
+        use crate::testutil::{
+            block, leaf, root_item, TestImage, SYSTEM_CHUNK, SYSTEM_CHUNK_PHYSICAL,
+        };
+
+        let roots = [
+            (Key::new(5, 132, 0), root_item(block(3), 0)),
+            (Key::new(7, 132, 0), root_item(block(4), 0)),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .put_block(block(2), &leaf(block(2), 1, &roots))
+            .put_block(block(3), &leaf::<&[u8]>(block(3), 5, &[]))
+            .put_block(block(4), &leaf::<&[u8]>(block(4), 7, &[]));
+        // The fsid in the header of the block at `block(4)`, and some of
+        // the empty space two sectors into the block at `block(3)`.
+        let physical = |logical| SYSTEM_CHUNK_PHYSICAL + logical - SYSTEM_CHUNK;
+        let reader = Arc::new(PartialReader::new(FailingDevice {
+            bytes: image.build(),
+            bad: vec![
+                physical(block(3)) + 1024..physical(block(3)) + 1100,
+                physical(block(4)) + 40..physical(block(4)) + 44,
+            ],
+        }));
+        let fs = Btrfs::from_device(Arc::clone(&reader)).unwrap();
+
+        let first_item = |objectid| {
+            let root = fs.root_item(objectid).unwrap().unwrap();
+            fs.tree(root.bytenr(), root.level()).iter().next()
+        };
+        // Zeros where there were zeros anyway do no harm.
+        assert!(first_item(5).is_none());
+        assert!(matches!(
+            first_item(7),
+            Some(Err(BtrfsError::ChecksumMismatch { logical })) if logical == block(4)
+        ));
+        let sector = |at: u64| at..at + 512;
+        assert_eq!(
+            reader.bad_ranges(),
+            [
+                sector(physical(block(3)) + 1024),
+                sector(physical(block(4)))
+            ]
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+
+use crate::error::BtrfsError;
+use crate::key::Key;
+
+/// `btrfs_dir_item`: the fixed header of one directory entry. It is followed
+/// by `name_len` bytes of name and, for xattrs, `data_len` bytes of value.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub struct DirItemHeader {
+    /// The inode (or subvolume root) the entry points at.
+    pub location: Key,
+    pub transid: u64,
+    pub data_len: u16,
+    pub name_len: u16,
+    pub dir_type: u8,
+}
+
+impl DirItemHeader {
+    pub const SIZE: usize = 30;
+}
+
+/// File type of a directory entry, the `BTRFS_FT_*` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DirEntryType {
+    Unknown,
+    RegularFile,
+    Dir,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Symlink,
+    Xattr,
+    Other(u8),
+}
+
+impl DirEntryType {
+    pub fn from_u8(value: u8) -> DirEntryType {
+        match value {
+            0 => DirEntryType::Unknown,
+            1 => DirEntryType::RegularFile,
+            2 => DirEntryType::Dir,
+            3 => DirEntryType::CharDevice,
+            4 => DirEntryType::BlockDevice,
+            5 => DirEntryType::Fifo,
+            6 => DirEntryType::Socket,
+            7 => DirEntryType::Symlink,
+            8 => DirEntryType::Xattr,
+            other => DirEntryType::Other(other),
+        }
+    }
+}
+
+/// One entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    /// Key of the inode item the entry points at, or of the root item for
+    /// a subvolume.
+    pub inode: Key,
+    pub entry_type: DirEntryType,
+}
+
+/// Iterator over the entries packed into a `DIR_ITEM`, `DIR_INDEX` or
+/// `XATTR_ITEM` item. `DIR_ITEM`s hold several entries when names collide in
+/// the hash that forms their key offset.
+///
+/// Names that are not valid UTF-8 are converted lossily.
+pub struct DirItems<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DirItems<'a> {
+    /// `data` is the whole data of one leaf item.
+    pub fn new(data: &'a [u8]) -> DirItems<'a> {
+        DirItems { data }
+    }
+}
+
+impl Iterator for DirItems<'_> {
+    type Item = Result<(String, Key, DirEntryType), BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let result = parse_entry(self.data);
+        match result {
+            Ok((_, len)) => self.data = &self.data[len..],
+            Err(_) => self.data = &[],
+        }
+        Some(result.map(|(entry, _)| entry))
+    }
+}
+
+fn parse_entry(data: &[u8]) -> Result<((String, Key, DirEntryType), usize), BtrfsError> {
+    let entry = split_entry(data)?;
+    let name = String::from_utf8_lossy(entry.name).into_owned();
+    let entry_type = DirEntryType::from_u8(entry.header.dir_type);
+    Ok(((name, entry.header.location, entry_type), entry.len))
+}
+
+/// One entry of a packed item, not yet decoded.
+pub(crate) struct RawEntry<'a> {
+    pub(crate) header: DirItemHeader,
+    pub(crate) name: &'a [u8],
+    pub(crate) data: &'a [u8],
+    /// Bytes the entry takes up, header included.
+    pub(crate) len: usize,
+}
+
+pub(crate) fn split_entry(data: &[u8]) -> Result<RawEntry<'_>, BtrfsError> {
+    if data.len() < DirItemHeader::SIZE {
+        return Err(BtrfsError::ShortRead {
+            expected: DirItemHeader::SIZE,
+            got: data.len(),
+        });
+    }
+    let header = DirItemHeader::read(&mut Cursor::new(&data[..DirItemHeader::SIZE]))?;
+    let name_end = DirItemHeader::SIZE + header.name_len as usize;
+    let len = name_end + header.data_len as usize;
+    if data.len() < len {
+        return Err(BtrfsError::Malformed("dir item name runs past item data"));
+    }
+    Ok(RawEntry {
+        header,
+        name: &data[DirItemHeader::SIZE..name_end],
+        data: &data[name_end..len],
+        len,
+    })
+}
+
+/// The header of the entry named exactly `name` among those packed into
+/// item `data`. Names are compared as bytes, so that one that is not valid
+/// UTF-8 never matches its lossy conversion.
+#[cfg(all(feature = "std", feature = "crc32c"))]
+pub(crate) fn find_entry(
+    mut data: &[u8],
+    name: &[u8],
+) -> Result<Option<DirItemHeader>, BtrfsError> {
+    while !data.is_empty() {
+        let entry = split_entry(data)?;
+        if entry.name == name {
+            return Ok(Some(entry.header));
+        }
+        data = &data[entry.len..];
+    }
+    Ok(None)
+}
+
+/// Iterator over the `(name, value)` pairs packed into an `XATTR_ITEM`.
+/// These share the directory entry layout, with the value as entry data.
+pub struct XattrItems<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> XattrItems<'a> {
+    pub fn new(data: &'a [u8]) -> XattrItems<'a> {
+        XattrItems { data }
+    }
+}
+
+impl Iterator for XattrItems<'_> {
+    type Item = Result<(String, Vec<u8>), BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let result = split_entry(self.data);
+        let item = match result {
+            Ok(entry) => {
+                let name = String::from_utf8_lossy(entry.name).into_owned();
+                let item = (name, entry.data.to_vec());
+                self.data = &self.data[entry.len..];
+                Ok(item)
+            }
+            Err(err) => {
+                self.data = &[];
+                Err(err)
+            }
+        };
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, objectid: u64, dir_type: u8) -> Vec<u8> {
+        entry_with_data(name, Key::new(objectid, 1, 0), dir_type, b"")
+    }
+
+    fn entry_with_data(name: &str, location: Key, dir_type: u8, data: &[u8]) -> Vec<u8> {
+        let header = DirItemHeader {
+            location,
+            transid: 6,
+            data_len: data.len() as u16,
+            name_len: name.len() as u16,
+            dir_type,
+        };
+        let mut out = Cursor::new(Vec::new());
+        header.write(&mut out).unwrap();
+        let mut bytes = out.into_inner();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn parses_packed_entries() {
+        let mut data = entry("a.txt", 257, 1);
+        data.extend(entry("sub", 258, 2));
+
+        let entries: Vec<_> = DirItems::new(&data).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            entries,
+            [
+                (
+                    "a.txt".into(),
+                    Key::new(257, 1, 0),
+                    DirEntryType::RegularFile
+                ),
+                ("sub".into(), Key::new(258, 1, 0), DirEntryType::Dir),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_name_is_malformed() {
+        let data = entry("name", 257, 7);
+        let mut items = DirItems::new(&data[..data.len() - 1]);
+        assert!(matches!(items.next(), Some(Err(BtrfsError::Malformed(_)))));
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn parses_packed_xattrs() {
+        let mut data = entry_with_data("user.a", Key::new(0, 0, 0), 8, b"1");
+        data.extend(entry_with_data(
+            "security.selinux",
+            Key::new(0, 0, 0),
+            8,
+            b"system_u:object_r:etc_t:s0\0",
+        ));
+
+        let xattrs: Vec<_> = XattrItems::new(&data).collect::<Result<_, _>>().unwrap();
+        assert_eq!(xattrs.len(), 2);
+        assert_eq!(xattrs[0], ("user.a".into(), b"1".to_vec()));
+        assert_eq!(xattrs[1].0, "security.selinux");
+        assert!(xattrs[1].1.ends_with(b"s0\0"));
+    }
+}
@@ -0,0 +1,705 @@
+use std::io::Write;
+
+use crate::chunk::{
+    BlockGroupFlags, BlockGroupItem, BlockGroupType, Chunk, DevExtent, RaidProfile,
+};
+use crate::dir::{split_entry, DirEntryType};
+use crate::error::BtrfsError;
+use crate::extent::{ExtentBackref, ExtentFlags, ExtentItem, ExtentKind};
+use crate::file_extent::{FileExtentItem, FileExtentKind};
+use crate::free_space::FreeSpaceInfo;
+use crate::fs::Btrfs;
+use crate::inode::{InodeItem, InodeRef};
+use crate::key::{Key, KeyType};
+use crate::node::{InternalNode, Leaf, NodeHeader};
+use crate::qgroup::{
+    qgroup_level, qgroup_subvolid, QgroupInfoItem, QgroupLimitItem, QgroupStatusItem,
+};
+use crate::root::{RootItem, RootRef};
+use crate::superblock::fmt_uuid;
+
+/// Size of a leaf's item header and of a node's key pointer.
+const ITEM_SIZE: usize = 25;
+const KEY_PTR_SIZE: usize = 33;
+
+/// `BTRFS_HEADER_FLAG_WRITTEN` and `BTRFS_HEADER_FLAG_RELOC`.
+const HEADER_FLAG_WRITTEN: u64 = 1 << 0;
+const HEADER_FLAG_RELOC: u64 = 1 << 1;
+
+impl Btrfs {
+    /// Prints every block of tree `tree_id` (1 for the root tree, 5 for the
+    /// top-level subvolume, ...) to `w` in the layout of
+    /// `btrfs inspect-internal dump-tree`: each block's header, then its key
+    /// pointers or its items, the latter decoded for the common item types
+    /// and hex-dumped otherwise. Blocks are visited depth first.
+    pub fn dump_tree(&self, tree_id: u64, w: &mut dyn Write) -> Result<(), BtrfsError> {
+        let tree = self.tree_by_id(tree_id)?;
+        self.dump_block(tree.root(), tree.level(), w)
+    }
+
+    fn dump_block(&self, logical: u64, level: u8, w: &mut dyn Write) -> Result<(), BtrfsError> {
+        let block = self.read_tree_block(logical, level)?;
+        let nodesize = block.len();
+        if level == 0 {
+            let leaf = Leaf::new(&block)?;
+            let used: usize = leaf
+                .items()
+                .map(|item| ITEM_SIZE + item.size() as usize)
+                .sum();
+            let free = (nodesize - NodeHeader::SIZE) as i64 - used as i64;
+            let header = leaf.header();
+            writeln!(
+                w,
+                "leaf {} items {} free space {} generation {} owner {}",
+                header.bytenr,
+                header.nritems,
+                free,
+                header.generation,
+                objectid_name(header.owner, 0)
+            )?;
+            write_header_flags(header, w)?;
+            for (i, item) in leaf.items().enumerate() {
+                writeln!(
+                    w,
+                    "\titem {i} key {} itemoff {} itemsize {}",
+                    fmt_key(&item.key()),
+                    item.offset(),
+                    item.size()
+                )?;
+                self.dump_item(&item.key(), item.data(), w)?;
+            }
+        } else {
+            let node = InternalNode::new(&block)?;
+            let header = node.header();
+            let max_ptrs = (nodesize - NodeHeader::SIZE) / KEY_PTR_SIZE;
+            writeln!(
+                w,
+                "node {} level {} items {} free space {} generation {} owner {}",
+                header.bytenr,
+                header.level,
+                header.nritems,
+                max_ptrs.saturating_sub(header.nritems as usize),
+                header.generation,
+                objectid_name(header.owner, 0)
+            )?;
+            write_header_flags(header, w)?;
+            let ptrs: Vec<_> = node.key_ptrs().collect();
+            for ptr in &ptrs {
+                writeln!(
+                    w,
+                    "\tkey {} block {} gen {}",
+                    fmt_key(&ptr.key),
+                    ptr.blockptr,
+                    ptr.generation
+                )?;
+            }
+            for ptr in &ptrs {
+                self.dump_block(ptr.blockptr, level - 1, w)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints the decoded body of one leaf item, indented under its key.
+    fn dump_item(&self, key: &Key, data: &[u8], w: &mut dyn Write) -> Result<(), BtrfsError> {
+        let sb = self.superblock();
+        // A sector's worth of data per checksum in a csum item.
+        let csum_span = (sb.sectorsize as u64, sb.checksum_type().digest_len() as u64);
+        // Items that fail to parse are shown raw rather than ending the dump.
+        if let Err(err) = dump_known_item(key, data, csum_span, w) {
+            if let BtrfsError::Io(err) = err {
+                return Err(BtrfsError::Io(err));
+            }
+            writeln!(w, "\t\tunable to decode item: {err}")?;
+            hex_dump(data, w)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_header_flags(header: &NodeHeader, w: &mut dyn Write) -> Result<(), BtrfsError> {
+    let flags = header.flags & ((1 << 56) - 1);
+    let mut names = Vec::new();
+    if flags & HEADER_FLAG_WRITTEN != 0 {
+        names.push("WRITTEN");
+    }
+    if flags & HEADER_FLAG_RELOC != 0 {
+        names.push("RELOC");
+    }
+    writeln!(
+        w,
+        "{} {} flags {flags:#x}({}) backref revision {}",
+        if header.level == 0 { "leaf" } else { "node" },
+        header.bytenr,
+        names.join("|"),
+        header.flags >> 56
+    )?;
+    writeln!(w, "fs uuid {}", fmt_uuid(&header.fsid))?;
+    writeln!(w, "chunk uuid {}", fmt_uuid(&header.chunk_tree_uuid))?;
+    Ok(())
+}
+
+fn dump_known_item(
+    key: &Key,
+    data: &[u8],
+    (sectorsize, csum_len): (u64, u64),
+    w: &mut dyn Write,
+) -> Result<(), BtrfsError> {
+    match key.item_type() {
+        KeyType::InodeItem => {
+            let inode = InodeItem::from_bytes(data)?;
+            write_inode(&inode, w)?;
+        }
+        KeyType::InodeRef => {
+            for r in InodeRef::parse_refs(key.offset, data)? {
+                writeln!(
+                    w,
+                    "\t\tindex {} namelen {} name: {}",
+                    r.index,
+                    r.name.len(),
+                    r.name
+                )?;
+            }
+        }
+        KeyType::InodeExtref => {
+            for r in InodeRef::parse_extrefs(data)? {
+                writeln!(
+                    w,
+                    "\t\tindex {} parent {} namelen {} name: {}",
+                    r.index,
+                    r.parent_dir,
+                    r.name.len(),
+                    r.name
+                )?;
+            }
+        }
+        KeyType::DirItem | KeyType::DirIndex | KeyType::XattrItem => {
+            write_dir_items(data, w)?;
+        }
+        KeyType::ExtentData => {
+            let extent = FileExtentItem::from_bytes(data)?;
+            let kind = match extent.kind() {
+                FileExtentKind::Inline => "inline",
+                FileExtentKind::Regular => "regular",
+                FileExtentKind::Prealloc => "prealloc",
+            };
+            writeln!(
+                w,
+                "\t\tgeneration {} type {} ({kind})",
+                extent.generation, extent.extent_type
+            )?;
+            let compression = extent.compression();
+            if extent.is_inline() {
+                writeln!(
+                    w,
+                    "\t\tinline extent data size {} ram_bytes {} compression {} ({})",
+                    data.len() - FileExtentItem::HEADER_SIZE,
+                    extent.ram_bytes,
+                    extent.compression,
+                    compression.as_str()
+                )?;
+            } else {
+                writeln!(
+                    w,
+                    "\t\textent data disk byte {} nr {}",
+                    extent.disk_bytenr, extent.disk_num_bytes
+                )?;
+                writeln!(
+                    w,
+                    "\t\textent data offset {} nr {} ram {}",
+                    extent.offset, extent.num_bytes, extent.ram_bytes
+                )?;
+                writeln!(
+                    w,
+                    "\t\textent compression {} ({})",
+                    extent.compression,
+                    compression.as_str()
+                )?;
+            }
+        }
+        KeyType::ExtentCsum => {
+            let length = data.len() as u64 / csum_len.max(1) * sectorsize;
+            writeln!(
+                w,
+                "\t\trange start {} end {} length {length}",
+                key.offset,
+                key.offset.saturating_add(length)
+            )?;
+        }
+        KeyType::RootItem => {
+            let root = RootItem::from_bytes(data)?;
+            writeln!(
+                w,
+                "\t\tgeneration {} root_dirid {} bytenr {} byte_limit {} bytes_used {}",
+                root.generation, root.root_dirid, root.bytenr, root.byte_limit, root.bytes_used
+            )?;
+            writeln!(
+                w,
+                "\t\tlast_snapshot {} flags {:#x} refs {}",
+                root.last_snapshot, root.flags, root.refs
+            )?;
+            writeln!(
+                w,
+                "\t\tdrop_progress key {} drop_level {}",
+                fmt_key(&root.drop_progress),
+                root.drop_level
+            )?;
+            writeln!(
+                w,
+                "\t\tlevel {} generation_v2 {}",
+                root.level, root.generation_v2
+            )?;
+        }
+        KeyType::RootRef | KeyType::RootBackref => {
+            let r = RootRef::from_bytes(data)?;
+            let kind = if key.item_type() == KeyType::RootRef {
+                "ref"
+            } else {
+                "backref"
+            };
+            writeln!(
+                w,
+                "\t\troot {kind} key dirid {} sequence {} name {}",
+                r.dirid, r.sequence, r.name
+            )?;
+        }
+        KeyType::ExtentItem | KeyType::MetadataItem => {
+            let extent = ExtentItem::from_bytes(key, data)?;
+            write_extent(&extent, key, w)?;
+        }
+        KeyType::BlockGroupItem => {
+            let bg = BlockGroupItem::from_bytes(data)?;
+            writeln!(
+                w,
+                "\t\tblock group used {} chunk_objectid {} flags {}",
+                bg.used,
+                bg.chunk_objectid,
+                block_group_flags(bg.flags())
+            )?;
+        }
+        KeyType::ChunkItem => {
+            let chunk = Chunk::from_bytes(data)?;
+            writeln!(
+                w,
+                "\t\tlength {} owner {} stripe_len {} type {}",
+                chunk.length,
+                chunk.owner,
+                chunk.stripe_len,
+                block_group_flags(chunk.flags())
+            )?;
+            writeln!(
+                w,
+                "\t\tio_align {} io_width {} sector_size {}",
+                chunk.io_align, chunk.io_width, chunk.sector_size
+            )?;
+            writeln!(
+                w,
+                "\t\tnum_stripes {} sub_stripes {}",
+                chunk.num_stripes, chunk.sub_stripes
+            )?;
+            for (i, stripe) in chunk.stripes.iter().enumerate() {
+                writeln!(
+                    w,
+                    "\t\t\tstripe {i} devid {} offset {}",
+                    stripe.devid, stripe.offset
+                )?;
+                writeln!(w, "\t\t\tdev_uuid {}", fmt_uuid(&stripe.dev_uuid))?;
+            }
+        }
+        KeyType::DevExtent => {
+            let extent = DevExtent::from_bytes(data)?;
+            writeln!(w, "\t\tdev extent chunk_tree {}", extent.chunk_tree)?;
+            writeln!(
+                w,
+                "\t\tchunk_objectid {} chunk_offset {} length {}",
+                extent.chunk_objectid, extent.chunk_offset, extent.length
+            )?;
+            writeln!(
+                w,
+                "\t\tchunk_tree_uuid {}",
+                fmt_uuid(&extent.chunk_tree_uuid)
+            )?;
+        }
+        KeyType::FreeSpaceInfo => {
+            let info = FreeSpaceInfo::from_bytes(data)?;
+            writeln!(
+                w,
+                "\t\tfree space info extent count {} flags {}",
+                info.extent_count, info.flags
+            )?;
+        }
+        KeyType::FreeSpaceExtent => {}
+        KeyType::FreeSpaceBitmap => writeln!(w, "\t\tfree space bitmap")?,
+        KeyType::OrphanItem => writeln!(w, "\t\torphan item")?,
+        KeyType::QgroupStatus => {
+            let status = QgroupStatusItem::from_bytes(data)?;
+            writeln!(
+                w,
+                "\t\tversion {} generation {} flags {:#x} scan {}",
+                status.version, status.generation, status.flags, status.rescan
+            )?;
+        }
+        KeyType::QgroupInfo => {
+            let info = QgroupInfoItem::from_bytes(data)?;
+            writeln!(w, "\t\tgeneration {}", info.generation)?;
+            writeln!(
+                w,
+                "\t\treferenced {} referenced_compressed {}",
+                info.rfer, info.rfer_cmpr
+            )?;
+            writeln!(
+                w,
+                "\t\texclusive {} exclusive_compressed {}",
+                info.excl, info.excl_cmpr
+            )?;
+        }
+        KeyType::QgroupLimit => {
+            let limit = QgroupLimitItem::from_bytes(data)?;
+            writeln!(w, "\t\tflags {:x}", limit.flags)?;
+            writeln!(
+                w,
+                "\t\tmax_referenced {} max_exclusive {}",
+                limit.max_rfer as i64, limit.max_excl as i64
+            )?;
+            writeln!(
+                w,
+                "\t\trsv_referenced {} rsv_exclusive {}",
+                limit.rsv_rfer as i64, limit.rsv_excl as i64
+            )?;
+        }
+        KeyType::QgroupRelation => {}
+        KeyType::UuidKeySubvol | KeyType::UuidKeyReceivedSubvol => {
+            for id in data.chunks_exact(8) {
+                let id = u64::from_le_bytes(id.try_into().unwrap());
+                writeln!(w, "\t\tsubvol_id {id}")?;
+            }
+        }
+        _ => hex_dump(data, w)?,
+    }
+    Ok(())
+}
+
+fn write_inode(inode: &InodeItem, w: &mut dyn Write) -> Result<(), BtrfsError> {
+    writeln!(
+        w,
+        "\t\tgeneration {} transid {} size {} nbytes {}",
+        inode.generation, inode.transid, inode.size, inode.nbytes
+    )?;
+    writeln!(
+        w,
+        "\t\tblock group {} mode {:o} links {} uid {} gid {} rdev {}",
+        inode.block_group, inode.mode, inode.nlink, inode.uid, inode.gid, inode.rdev
+    )?;
+    writeln!(
+        w,
+        "\t\tsequence {} flags {:#x}",
+        inode.sequence, inode.flags
+    )?;
+    for (name, time) in [
+        ("atime", inode.atime),
+        ("ctime", inode.ctime),
+        ("mtime", inode.mtime),
+        ("otime", inode.otime),
+    ] {
+        writeln!(w, "\t\t{name} {}.{}", time.sec, time.nsec)?;
+    }
+    Ok(())
+}
+
+/// Prints each entry packed into a `DIR_ITEM`, `DIR_INDEX` or `XATTR_ITEM`.
+fn write_dir_items(mut data: &[u8], w: &mut dyn Write) -> Result<(), BtrfsError> {
+    while !data.is_empty() {
+        let entry = split_entry(data)?;
+        let header = &entry.header;
+        writeln!(
+            w,
+            "\t\tlocation key {} type {}",
+            fmt_key(&header.location),
+            dir_type_name(DirEntryType::from_u8(header.dir_type))
+        )?;
+        writeln!(
+            w,
+            "\t\ttransid {} data_len {} name_len {}",
+            header.transid, header.data_len, header.name_len
+        )?;
+        writeln!(w, "\t\tname: {}", String::from_utf8_lossy(entry.name))?;
+        if !entry.data.is_empty() {
+            writeln!(w, "\t\tdata {}", String::from_utf8_lossy(entry.data))?;
+        }
+        data = &data[entry.len..];
+    }
+    Ok(())
+}
+
+fn write_extent(extent: &ExtentItem, key: &Key, w: &mut dyn Write) -> Result<(), BtrfsError> {
+    let flags = extent.flags();
+    let mut names = Vec::new();
+    if flags.contains(ExtentFlags::DATA) {
+        names.push("DATA");
+    }
+    if flags.contains(ExtentFlags::TREE_BLOCK) {
+        names.push("TREE_BLOCK");
+    }
+    if flags.contains(ExtentFlags::FULL_BACKREF) {
+        names.push("FULL_BACKREF");
+    }
+    writeln!(
+        w,
+        "\t\trefs {} gen {} flags {}",
+        extent.refs,
+        extent.generation,
+        names.join("|")
+    )?;
+    if let ExtentKind::Metadata { level } = extent.kind {
+        if key.item_type() == KeyType::MetadataItem {
+            writeln!(w, "\t\ttree block skinny level {level}")?;
+        } else {
+            writeln!(w, "\t\ttree block level {level}")?;
+        }
+    }
+    for backref in &extent.inline_refs {
+        match *backref {
+            ExtentBackref::TreeBlock { root } => {
+                writeln!(w, "\t\ttree block backref root {}", objectid_name(root, 0))?
+            }
+            ExtentBackref::SharedBlock { parent } => {
+                writeln!(w, "\t\tshared block backref parent {parent}")?
+            }
+            ExtentBackref::ExtentData {
+                root,
+                objectid,
+                offset,
+                count,
+            } => writeln!(
+                w,
+                "\t\textent data backref root {} objectid {objectid} offset {offset} count {count}",
+                objectid_name(root, 0)
+            )?,
+            ExtentBackref::SharedData { parent, count } => {
+                writeln!(w, "\t\tshared data backref parent {parent} count {count}")?
+            }
+            ExtentBackref::Owner { root } => {
+                writeln!(w, "\t\textent owner root {}", objectid_name(root, 0))?
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Block group flags as btrfs-progs prints them, e.g. `METADATA|DUP`.
+fn block_group_flags(flags: BlockGroupFlags) -> String {
+    let kind = match BlockGroupType::from_flags(flags) {
+        BlockGroupType::Data => "DATA".to_string(),
+        BlockGroupType::Metadata => "METADATA".to_string(),
+        BlockGroupType::System => "SYSTEM".to_string(),
+        BlockGroupType::Mixed => "DATA|METADATA".to_string(),
+        BlockGroupType::Unknown(bits) => format!("UNKNOWN.{bits:#x}"),
+    };
+    match RaidProfile::from_flags(flags) {
+        RaidProfile::Single => kind,
+        profile => format!("{kind}|{}", profile.as_str()),
+    }
+}
+
+fn dir_type_name(entry_type: DirEntryType) -> String {
+    match entry_type {
+        DirEntryType::Unknown => "UNKNOWN".to_string(),
+        DirEntryType::RegularFile => "FILE".to_string(),
+        DirEntryType::Dir => "DIR".to_string(),
+        DirEntryType::CharDevice => "CHRDEV".to_string(),
+        DirEntryType::BlockDevice => "BLKDEV".to_string(),
+        DirEntryType::Fifo => "FIFO".to_string(),
+        DirEntryType::Socket => "SOCK".to_string(),
+        DirEntryType::Symlink => "SYMLINK".to_string(),
+        DirEntryType::Xattr => "XATTR".to_string(),
+        DirEntryType::Other(value) => format!("UNKNOWN.{value}"),
+    }
+}
+
+/// A key as `(objectid TYPE offset)`.
+fn fmt_key(key: &Key) -> String {
+    let item_type = key.item_type();
+    let offset = match item_type {
+        KeyType::QgroupRelation | KeyType::QgroupInfo | KeyType::QgroupLimit => format!(
+            "{}/{}",
+            qgroup_level(key.offset),
+            qgroup_subvolid(key.offset)
+        ),
+        KeyType::UuidKeySubvol | KeyType::UuidKeyReceivedSubvol => {
+            format!("{:#018x}", key.offset)
+        }
+        _ if key.offset == u64::MAX => "-1".to_string(),
+        _ => key.offset.to_string(),
+    };
+    let objectid = match item_type {
+        KeyType::UuidKeySubvol | KeyType::UuidKeyReceivedSubvol => {
+            format!("{:#018x}", key.objectid)
+        }
+        _ => objectid_name(key.objectid, key.item_type),
+    };
+    format!("({objectid} {} {offset})", type_name(item_type))
+}
+
+/// The btrfs-progs name of an item type, such as `INODE_ITEM`.
+fn type_name(item_type: KeyType) -> String {
+    if let KeyType::Unknown(value) = item_type {
+        return format!("UNKNOWN.{value}");
+    }
+    // The variants are the kernel's names in camel case.
+    let mut name = String::new();
+    for (i, c) in format!("{item_type:?}").chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
+
+/// The symbolic name btrfs-progs gives well-known objectids, such as
+/// `FS_TREE` for 5.
+fn objectid_name(objectid: u64, item_type: u8) -> String {
+    let name = match objectid as i64 {
+        1 if item_type == KeyType::DevItem.as_u8() => "DEV_ITEMS",
+        1 => "ROOT_TREE",
+        2 => "EXTENT_TREE",
+        3 => "CHUNK_TREE",
+        4 => "DEV_TREE",
+        5 => "FS_TREE",
+        6 => "ROOT_TREE_DIR",
+        7 => "CSUM_TREE",
+        8 => "QUOTA_TREE",
+        9 => "UUID_TREE",
+        10 => "FREE_SPACE_TREE",
+        11 => "BLOCK_GROUP_TREE",
+        12 => "RAID_STRIPE_TREE",
+        0 if item_type == KeyType::PersistentItem.as_u8() => "DEV_STATS",
+        256 if item_type == KeyType::ChunkItem.as_u8() => "FIRST_CHUNK_TREE",
+        -4 => "BALANCE",
+        -5 => "ORPHAN",
+        -6 => "TREE_LOG",
+        -7 => "TREE_LOG_FIXUP",
+        -8 => "TREE_RELOC",
+        -9 => "DATA_RELOC_TREE",
+        -10 => "EXTENT_CSUM",
+        -11 => "FREE_SPACE",
+        -12 => "FREE_INO",
+        -255 => "MULTIPLE",
+        -1 => "-1",
+        _ => return objectid.to_string(),
+    };
+    name.to_string()
+}
+
+/// Sixteen bytes per line, each line led by its offset into the item.
+fn hex_dump(data: &[u8], w: &mut dyn Write) -> Result<(), BtrfsError> {
+    for (i, line) in data.chunks(16).enumerate() {
+        let bytes: Vec<_> = line.iter().map(|b| format!("{b:02x}")).collect();
+        writeln!(w, "\t\t{:08x}  {}", i * 16, bytes.join(" "))?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testutil::{block, leaf, node, root_item, TestImage};
+
+    fn dump(fs: &Btrfs, tree_id: u64) -> String {
+        let mut out = Vec::new();
+        fs.dump_tree(tree_id, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn dumps_nodes_and_items() {
+        let mut inode = vec![0u8; InodeItem::SIZE];
+        inode[16..24].copy_from_slice(&5u64.to_le_bytes());
+        inode[52..56].copy_from_slice(&0o100644u32.to_le_bytes());
+        let mut name_ref = 2u64.to_le_bytes().to_vec();
+        name_ref.extend_from_slice(&3u16.to_le_bytes());
+        name_ref.extend_from_slice(b"foo");
+
+        let fs_tree = [
+            (Key::new(257, 1, 0), inode),
+            (Key::new(257, 12, 256), name_ref),
+            (Key::new(257, 77, 0), vec![0xab; 18]),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(
+                block(0),
+                &leaf(
+                    block(0),
+                    1,
+                    &[(Key::new(5, 132, 0), root_item(block(1), 1))],
+                ),
+            )
+            .put_block(
+                block(1),
+                &node(block(1), 5, 1, &[(Key::new(257, 1, 0), block(2))]),
+            )
+            .put_block(block(2), &leaf(block(2), 5, &fs_tree));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let out = dump(&fs, 5);
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "node {} level 1 items 1 free space 492 generation 6 owner FS_TREE",
+                block(1)
+            )
+        );
+        assert_eq!(
+            lines[4],
+            format!("\tkey (257 INODE_ITEM 0) block {} gen 6", block(2))
+        );
+        assert!(lines[5].starts_with(&format!("leaf {} items 3 free space ", block(2))));
+        for expected in [
+            "\titem 0 key (257 INODE_ITEM 0) itemoff 16123 itemsize 160",
+            "\t\tgeneration 0 transid 0 size 5 nbytes 0",
+            "\t\tblock group 0 mode 100644 links 0 uid 0 gid 0 rdev 0",
+            "\titem 1 key (257 INODE_REF 256) itemoff 16110 itemsize 13",
+            "\t\tindex 2 namelen 3 name: foo",
+            "\titem 2 key (257 UNKNOWN.77 0) itemoff 16092 itemsize 18",
+            "\t\t00000000  ab ab ab ab ab ab ab ab ab ab ab ab ab ab ab ab",
+            "\t\t00000010  ab ab",
+        ] {
+            assert!(lines.contains(&expected), "missing {expected:?} in\n{out}");
+        }
+
+        let root = dump(&fs, 1);
+        assert!(root.contains("\titem 0 key (FS_TREE ROOT_ITEM 0) itemoff"));
+        assert!(root.contains(&format!("bytenr {} byte_limit 0", block(1))));
+        assert!(matches!(
+            fs.dump_tree(7, &mut Vec::new()),
+            Err(BtrfsError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn names_keys_like_btrfs_progs() {
+        assert_eq!(
+            fmt_key(&Key::new(-4i64 as u64, 248, 0)),
+            "(BALANCE TEMPORARY_ITEM 0)"
+        );
+        assert_eq!(
+            fmt_key(&Key::new(0, 242, (1 << 48) | 300)),
+            "(0 QGROUP_INFO 1/300)"
+        );
+        assert_eq!(fmt_key(&Key::new(256, 132, u64::MAX)), "(256 ROOT_ITEM -1)");
+        assert_eq!(
+            type_name(KeyType::UuidKeyReceivedSubvol),
+            "UUID_KEY_RECEIVED_SUBVOL"
+        );
+        assert_eq!(
+            block_group_flags(BlockGroupFlags::METADATA | BlockGroupFlags::DUP),
+            "METADATA|DUP"
+        );
+        assert_eq!(block_group_flags(BlockGroupFlags::DATA), "DATA");
+    }
+}
@@ -0,0 +1,281 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt;
+
+use binrw::io;
+
+use crate::csum::ChecksumType;
+
+/// The error type of every fallible operation in this crate.
+#[derive(Debug)]
+pub enum BtrfsError {
+    Io(io::Error),
+    Parse(binrw::Error),
+    /// The input ended before a complete structure could be read.
+    ShortRead {
+        expected: usize,
+        got: usize,
+    },
+    /// The device ends before the primary superblock starts, so it holds
+    /// no copy at all.
+    DeviceTooSmall {
+        size: u64,
+    },
+    /// The superblock does not carry the btrfs magic number.
+    BadMagic {
+        found: u64,
+    },
+    /// An on-disk structure is internally inconsistent.
+    Malformed(&'static str),
+    /// A stored checksum does not match the data it covers. `logical` is the
+    /// block's address: logical for tree blocks, the physical offset for
+    /// superblock copies.
+    ChecksumMismatch {
+        logical: u64,
+    },
+    /// No chunk maps this logical address.
+    Unmapped {
+        logical: u64,
+    },
+    /// The chunks starting at logical addresses `first` and `second`
+    /// overlap, or, if the two are equal, two different chunks were given
+    /// for the same address.
+    OverlappingChunks {
+        first: u64,
+        second: u64,
+    },
+    /// The filesystem has a device that was not supplied.
+    MissingDevice {
+        devid: u64,
+    },
+    /// The supplied devices belong to different filesystems.
+    FsidMismatch,
+    /// A path component or item that was looked up does not exist.
+    NotFound,
+    /// Inode `ino` has no name linking it into a directory: it was unlinked
+    /// while still open, or its refs are missing.
+    Orphan {
+        ino: u64,
+    },
+    /// A symlink operation was given an inode that is not a symlink.
+    NotASymlink,
+    /// Subvolume `id` is referenced, as the default subvolume for one, but
+    /// was deleted.
+    SubvolumeDeleted {
+        id: u64,
+    },
+    /// A new label is `len` bytes long, which leaves no room for the NUL
+    /// that ends it.
+    LabelTooLong {
+        len: usize,
+    },
+    /// A new label contains a character labels cannot hold.
+    InvalidLabel(char),
+    /// The filesystem uses a feature this crate cannot handle yet.
+    Unsupported(&'static str),
+    /// The checksum algorithm is unknown or its backend feature is disabled.
+    UnsupportedChecksum(ChecksumType),
+    /// Reading or checking the tree block at `logical` failed with `source`.
+    ReadNode {
+        logical: u64,
+        source: Box<BtrfsError>,
+    },
+}
+
+impl BtrfsError {
+    /// The innermost error, below any context such as
+    /// [`BtrfsError::ReadNode`], for matching on what went wrong.
+    pub fn root_cause(&self) -> &BtrfsError {
+        let mut err = self;
+        while let BtrfsError::ReadNode { source, .. } = err {
+            err = source;
+        }
+        err
+    }
+
+    /// Adds the address of the tree block being read, unless the error
+    /// already names it.
+    #[cfg(any(feature = "std", test))]
+    pub(crate) fn in_tree_block(self, logical: u64) -> BtrfsError {
+        match self {
+            BtrfsError::ChecksumMismatch { logical: at } | BtrfsError::Unmapped { logical: at }
+                if at == logical =>
+            {
+                self
+            }
+            BtrfsError::ReadNode { logical: at, .. } if at == logical => self,
+            source => BtrfsError::ReadNode {
+                logical,
+                source: Box::new(source),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for BtrfsError {
+    fn from(err: io::Error) -> Self {
+        BtrfsError::Io(err)
+    }
+}
+
+impl From<binrw::Error> for BtrfsError {
+    fn from(err: binrw::Error) -> Self {
+        BtrfsError::Parse(err)
+    }
+}
+
+impl fmt::Display for BtrfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BtrfsError::Io(err) => write!(f, "I/O error: {err}"),
+            BtrfsError::Parse(err) => write!(f, "parse error: {err}"),
+            BtrfsError::ShortRead { expected, got } => {
+                write!(f, "short read: expected {expected} bytes, got {got}")
+            }
+            BtrfsError::DeviceTooSmall { size } => {
+                write!(
+                    f,
+                    "device of {size} bytes is too small to hold a superblock"
+                )
+            }
+            BtrfsError::BadMagic { found } => write!(f, "bad btrfs magic {found:#018x}"),
+            BtrfsError::Malformed(what) => write!(f, "malformed structure: {what}"),
+            BtrfsError::ChecksumMismatch { logical } => {
+                write!(f, "checksum mismatch in block at {logical}")
+            }
+            BtrfsError::Unmapped { logical } => {
+                write!(f, "logical address {logical} is not mapped by any chunk")
+            }
+            BtrfsError::OverlappingChunks { first, second } if first == second => {
+                write!(f, "conflicting chunks at logical address {first}")
+            }
+            BtrfsError::OverlappingChunks { first, second } => {
+                write!(
+                    f,
+                    "chunks at logical addresses {first} and {second} overlap"
+                )
+            }
+            BtrfsError::MissingDevice { devid } => write!(f, "device {devid} is missing"),
+            BtrfsError::FsidMismatch => write!(f, "devices belong to different filesystems"),
+            BtrfsError::NotFound => write!(f, "not found"),
+            BtrfsError::Orphan { ino } => write!(f, "inode {ino} has no inode ref"),
+            BtrfsError::NotASymlink => write!(f, "inode is not a symlink"),
+            BtrfsError::SubvolumeDeleted { id } => write!(f, "subvolume {id} was deleted"),
+            BtrfsError::LabelTooLong { len } => {
+                write!(f, "label of {len} bytes is too long, the limit is 255")
+            }
+            BtrfsError::InvalidLabel(c) => write!(f, "label must not contain {c:?}"),
+            BtrfsError::Unsupported(what) => write!(f, "unsupported: {what}"),
+            BtrfsError::UnsupportedChecksum(csum_type) => {
+                write!(f, "unsupported checksum type {}", csum_type.name())
+            }
+            BtrfsError::ReadNode { logical, source } => {
+                write!(f, "tree block at {logical}: {source}")
+            }
+        }
+    }
+}
+
+/// The wrapped error of `Io`, `Parse` and the context variants is the
+/// source; the I/O and parse errors only implement `Error` with `std`.
+impl core::error::Error for BtrfsError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            BtrfsError::Io(err) => Some(err),
+            #[cfg(feature = "std")]
+            BtrfsError::Parse(err) => Some(err),
+            BtrfsError::ReadNode { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A name that none of the variants of an enum such as
+/// [`ChecksumType`] or [`RaidProfile`](crate::RaidProfile) is spelled as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseEnumError {
+    kind: &'static str,
+    input: String,
+}
+
+impl ParseEnumError {
+    pub(crate) fn new(kind: &'static str, input: &str) -> ParseEnumError {
+        ParseEnumError {
+            kind,
+            input: input.into(),
+        }
+    }
+
+    /// What was being parsed, such as `"checksum type"`.
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    /// The string that did not parse.
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+}
+
+impl fmt::Display for ParseEnumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown {} {:?}", self.kind, self.input)
+    }
+}
+
+impl core::error::Error for ParseEnumError {}
+
+/// Finds `input` among `names`, ignoring ASCII case.
+pub(crate) fn parse_name<T: Copy>(
+    kind: &'static str,
+    names: &[(&str, T)],
+    input: &str,
+) -> Result<T, ParseEnumError> {
+    names
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(input))
+        .map(|&(_, value)| value)
+        .ok_or_else(|| ParseEnumError::new(kind, input))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_with_question_mark() {
+        fn fails() -> Result<(), BtrfsError> {
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+        }
+        assert!(matches!(fails(), Err(BtrfsError::Io(_))));
+
+        let err = BtrfsError::BadMagic { found: 0 };
+        assert_eq!(err.to_string(), "bad btrfs magic 0x0000000000000000");
+    }
+
+    #[test]
+    fn chains_sources_through_context() {
+        use core::error::Error;
+
+        let io = io::Error::from(io::ErrorKind::UnexpectedEof);
+        let err = BtrfsError::from(io).in_tree_block(4096);
+        assert!(err
+            .to_string()
+            .starts_with("tree block at 4096: I/O error: "));
+        assert!(!err.to_string().contains('\n'));
+        let inner = err.source().unwrap();
+        assert!(matches!(
+            inner.downcast_ref::<BtrfsError>(),
+            Some(BtrfsError::Io(_))
+        ));
+        #[cfg(feature = "std")]
+        assert!(inner.source().unwrap().is::<io::Error>());
+        assert!(matches!(err.root_cause(), BtrfsError::Io(_)));
+
+        // Errors that already carry the address are left alone.
+        let mismatch = BtrfsError::ChecksumMismatch { logical: 4096 }.in_tree_block(4096);
+        assert!(matches!(mismatch, BtrfsError::ChecksumMismatch { .. }));
+        assert!(mismatch.source().is_none());
+    }
+}
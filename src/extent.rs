@@ -0,0 +1,364 @@
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+
+use crate::error::BtrfsError;
+use crate::key::{Key, KeyType};
+
+bitflags! {
+    /// Flags of an extent item.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ExtentFlags: u64 {
+        const DATA = 1 << 0;
+        const TREE_BLOCK = 1 << 1;
+        /// Back references of this tree block name the parent block rather
+        /// than the owning tree.
+        const FULL_BACKREF = 1 << 8;
+
+        const _ = !0;
+    }
+}
+
+/// `btrfs_extent_item`: the reference count of an allocated extent in the
+/// extent tree, with the back references stored inline after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentItem {
+    pub refs: u64,
+    pub generation: u64,
+    pub flags: u64,
+    pub kind: ExtentKind,
+    /// Back references packed into the item. References that did not fit
+    /// are stored as items of their own and are not included.
+    pub inline_refs: Vec<ExtentBackref>,
+}
+
+/// What an extent holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ExtentKind {
+    Data,
+    /// A tree block at `level` (0 for a leaf).
+    Metadata {
+        level: u8,
+    },
+}
+
+/// One back reference of an extent: who points at it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentBackref {
+    /// A tree block owned by tree `root`.
+    TreeBlock { root: u64 },
+    /// A tree block referenced by the tree block at `parent`.
+    SharedBlock { parent: u64 },
+    /// `count` file extents of inode `objectid` in tree `root`, at file
+    /// offset `offset` minus the extent's offset field.
+    ExtentData {
+        root: u64,
+        objectid: u64,
+        offset: u64,
+        count: u32,
+    },
+    /// `count` file extents in the leaf at `parent`.
+    SharedData { parent: u64, count: u32 },
+    /// The tree charged for the extent under simple quotas.
+    Owner { root: u64 },
+}
+
+/// Tree levels run from 0 (leaves) to 7.
+const MAX_LEVEL: u8 = 8;
+
+const TREE_BLOCK_REF: u8 = KeyType::TreeBlockRef.as_u8();
+const EXTENT_DATA_REF: u8 = KeyType::ExtentDataRef.as_u8();
+const SHARED_BLOCK_REF: u8 = KeyType::SharedBlockRef.as_u8();
+const SHARED_DATA_REF: u8 = KeyType::SharedDataRef.as_u8();
+const EXTENT_OWNER_REF: u8 = KeyType::ExtentOwnerRef.as_u8();
+
+impl ExtentItem {
+    /// Size of the fields before any tree block info and inline refs.
+    pub const HEADER_SIZE: usize = 24;
+    /// Size of `btrfs_tree_block_info`: the first key and level of a tree
+    /// block, present only in non-skinny metadata extent items.
+    const TREE_BLOCK_INFO_SIZE: usize = Key::SIZE + 1;
+
+    /// Parses the item stored under `key`.
+    ///
+    /// The key decides the layout: an `EXTENT_ITEM` of a tree block embeds a
+    /// tree block info holding the block's level before the inline refs,
+    /// while a skinny `METADATA_ITEM` carries the level in the key offset
+    /// and has no tree block info.
+    pub fn from_bytes(key: &Key, bytes: &[u8]) -> Result<ExtentItem, BtrfsError> {
+        if bytes.len() < ExtentItem::HEADER_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: ExtentItem::HEADER_SIZE,
+                got: bytes.len(),
+            });
+        }
+        let refs = read_u64(bytes, 0)?;
+        let generation = read_u64(bytes, 8)?;
+        let flags = read_u64(bytes, 16)?;
+
+        let mut at = ExtentItem::HEADER_SIZE;
+        let tree_block = ExtentFlags::from_bits_retain(flags).contains(ExtentFlags::TREE_BLOCK);
+        let kind = match key.item_type() {
+            KeyType::MetadataItem => ExtentKind::Metadata {
+                level: u8::try_from(key.offset)
+                    .ok()
+                    .filter(|&level| level < MAX_LEVEL)
+                    .ok_or(BtrfsError::Malformed("metadata item level out of range"))?,
+            },
+            KeyType::ExtentItem if tree_block => {
+                at += ExtentItem::TREE_BLOCK_INFO_SIZE;
+                if bytes.len() < at {
+                    return Err(BtrfsError::ShortRead {
+                        expected: at,
+                        got: bytes.len(),
+                    });
+                }
+                ExtentKind::Metadata {
+                    level: bytes[at - 1],
+                }
+            }
+            KeyType::ExtentItem => ExtentKind::Data,
+            _ => return Err(BtrfsError::Malformed("not an extent item key")),
+        };
+
+        let mut inline_refs = Vec::new();
+        while at < bytes.len() {
+            let ref_type = bytes[at];
+            let (backref, len) = match ref_type {
+                TREE_BLOCK_REF => (
+                    ExtentBackref::TreeBlock {
+                        root: read_u64(bytes, at + 1)?,
+                    },
+                    9,
+                ),
+                SHARED_BLOCK_REF => (
+                    ExtentBackref::SharedBlock {
+                        parent: read_u64(bytes, at + 1)?,
+                    },
+                    9,
+                ),
+                EXTENT_OWNER_REF => (
+                    ExtentBackref::Owner {
+                        root: read_u64(bytes, at + 1)?,
+                    },
+                    9,
+                ),
+                // The data ref takes the place of the offset field.
+                EXTENT_DATA_REF => (
+                    ExtentBackref::ExtentData {
+                        root: read_u64(bytes, at + 1)?,
+                        objectid: read_u64(bytes, at + 9)?,
+                        offset: read_u64(bytes, at + 17)?,
+                        count: read_u32(bytes, at + 25)?,
+                    },
+                    29,
+                ),
+                SHARED_DATA_REF => (
+                    ExtentBackref::SharedData {
+                        parent: read_u64(bytes, at + 1)?,
+                        count: read_u32(bytes, at + 9)?,
+                    },
+                    13,
+                ),
+                _ => return Err(BtrfsError::Malformed("unknown inline extent ref type")),
+            };
+            inline_refs.push(backref);
+            at += len;
+        }
+
+        Ok(ExtentItem {
+            refs,
+            generation,
+            flags,
+            kind,
+            inline_refs,
+        })
+    }
+
+    pub fn flags(&self) -> ExtentFlags {
+        ExtentFlags::from_bits_retain(self.flags)
+    }
+}
+
+impl ExtentBackref {
+    /// Parses a back reference kept as an item of its own, next to the
+    /// extent item, when it did not fit inline. Tree block and shared refs
+    /// carry the root or parent in the key offset.
+    pub fn from_item(key: &Key, bytes: &[u8]) -> Result<ExtentBackref, BtrfsError> {
+        Ok(match key.item_type() {
+            KeyType::TreeBlockRef => ExtentBackref::TreeBlock { root: key.offset },
+            KeyType::SharedBlockRef => ExtentBackref::SharedBlock { parent: key.offset },
+            // Keyed by a hash of the three fields instead.
+            KeyType::ExtentDataRef => ExtentBackref::ExtentData {
+                root: read_u64(bytes, 0)?,
+                objectid: read_u64(bytes, 8)?,
+                offset: read_u64(bytes, 16)?,
+                count: read_u32(bytes, 24)?,
+            },
+            KeyType::SharedDataRef => ExtentBackref::SharedData {
+                parent: key.offset,
+                count: read_u32(bytes, 0)?,
+            },
+            _ => return Err(BtrfsError::Malformed("not an extent ref key")),
+        })
+    }
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Result<u64, BtrfsError> {
+    bytes
+        .get(at..at + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(BtrfsError::Malformed("extent ref runs past item data"))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, BtrfsError> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or(BtrfsError::Malformed("extent ref runs past item data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(refs: u64, flags: u64) -> Vec<u8> {
+        [refs, 7, flags]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn parses_data_extent_refs() {
+        let mut bytes = header(3, 1);
+        bytes.push(EXTENT_DATA_REF);
+        for value in [5u64, 257, 0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.push(SHARED_DATA_REF);
+        bytes.extend_from_slice(&30408704u64.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+
+        let item = ExtentItem::from_bytes(&Key::new(13631488, 168, 4096), &bytes).unwrap();
+        assert_eq!(item.refs, 3);
+        assert_eq!(item.generation, 7);
+        assert_eq!(item.flags(), ExtentFlags::DATA);
+        assert_eq!(item.kind, ExtentKind::Data);
+        assert_eq!(
+            item.inline_refs,
+            [
+                ExtentBackref::ExtentData {
+                    root: 5,
+                    objectid: 257,
+                    offset: 0,
+                    count: 2,
+                },
+                ExtentBackref::SharedData {
+                    parent: 30408704,
+                    count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_level_from_tree_block_info() {
+        let mut bytes = header(1, 2);
+        bytes.extend_from_slice(&[0xaa; Key::SIZE]);
+        bytes.push(1);
+        bytes.push(TREE_BLOCK_REF);
+        bytes.extend_from_slice(&5u64.to_le_bytes());
+
+        let item = ExtentItem::from_bytes(&Key::new(30408704, 168, 16384), &bytes).unwrap();
+        assert_eq!(item.kind, ExtentKind::Metadata { level: 1 });
+        assert_eq!(item.inline_refs, [ExtentBackref::TreeBlock { root: 5 }]);
+
+        assert!(matches!(
+            ExtentItem::from_bytes(&Key::new(30408704, 168, 16384), &bytes[..30]),
+            Err(BtrfsError::ShortRead { expected: 42, .. })
+        ));
+    }
+
+    #[test]
+    fn reads_level_from_skinny_key() {
+        let mut bytes = header(1, 2);
+        bytes.push(TREE_BLOCK_REF);
+        bytes.extend_from_slice(&5u64.to_le_bytes());
+
+        let item = ExtentItem::from_bytes(&Key::new(30408704, 169, 2), &bytes).unwrap();
+        assert_eq!(item.kind, ExtentKind::Metadata { level: 2 });
+        assert_eq!(item.inline_refs, [ExtentBackref::TreeBlock { root: 5 }]);
+
+        assert!(matches!(
+            ExtentItem::from_bytes(&Key::new(30408704, 169, 8), &bytes),
+            Err(BtrfsError::Malformed(_))
+        ));
+        assert!(matches!(
+            ExtentItem::from_bytes(&Key::new(30408704, 192, 0), &bytes),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn parses_keyed_refs() {
+        let mut data_ref = Vec::new();
+        for value in [5u64, 257, 4096] {
+            data_ref.extend_from_slice(&value.to_le_bytes());
+        }
+        data_ref.extend_from_slice(&3u32.to_le_bytes());
+        let parsed = |item_type, offset, bytes: &[u8]| {
+            ExtentBackref::from_item(&Key::new(13631488, item_type, offset), bytes)
+        };
+        assert_eq!(
+            parsed(EXTENT_DATA_REF, 0x1234, &data_ref).unwrap(),
+            ExtentBackref::ExtentData {
+                root: 5,
+                objectid: 257,
+                offset: 4096,
+                count: 3,
+            }
+        );
+        assert_eq!(
+            parsed(SHARED_DATA_REF, 30408704, &2u32.to_le_bytes()).unwrap(),
+            ExtentBackref::SharedData {
+                parent: 30408704,
+                count: 2,
+            }
+        );
+        assert_eq!(
+            parsed(TREE_BLOCK_REF, 256, &[]).unwrap(),
+            ExtentBackref::TreeBlock { root: 256 }
+        );
+        assert!(matches!(
+            parsed(EXTENT_DATA_REF, 0, &data_ref[..20]),
+            Err(BtrfsError::Malformed(_))
+        ));
+        assert!(matches!(
+            parsed(168, 4096, &[]),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_refs() {
+        let key = Key::new(13631488, 168, 4096);
+        assert!(matches!(
+            ExtentItem::from_bytes(&key, &[0; 16]),
+            Err(BtrfsError::ShortRead { expected: 24, .. })
+        ));
+
+        let mut bytes = header(1, 1);
+        bytes.extend_from_slice(&[EXTENT_DATA_REF, 5]);
+        assert!(matches!(
+            ExtentItem::from_bytes(&key, &bytes),
+            Err(BtrfsError::Malformed(_))
+        ));
+        bytes[ExtentItem::HEADER_SIZE] = 99;
+        assert!(matches!(
+            ExtentItem::from_bytes(&key, &bytes),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+}
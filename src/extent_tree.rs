@@ -0,0 +1,456 @@
+use std::collections::BTreeSet;
+
+use crate::error::BtrfsError;
+use crate::extent::{ExtentBackref, ExtentItem, ExtentKind};
+use crate::features::IncompatFlags;
+use crate::file_extent::{Compression, FileExtentData, FileExtentItem};
+use crate::fs::Btrfs;
+use crate::key::{Key, KeyType};
+use crate::space::EXTENT_TREE_OBJECTID;
+use crate::tree::Tree;
+
+const EXTENT_ITEM: u8 = KeyType::ExtentItem.as_u8();
+const EXTENT_DATA: u8 = KeyType::ExtentData.as_u8();
+
+impl Btrfs {
+    pub(crate) fn extent_tree(&self) -> Result<Tree<'_>, BtrfsError> {
+        let root = self
+            .root_item(EXTENT_TREE_OBJECTID)?
+            .ok_or(BtrfsError::Malformed("root tree has no extent tree"))?;
+        Ok(self.tree(root.bytenr(), root.level()))
+    }
+
+    /// Looks up the extent item of the extent starting at logical address
+    /// `bytenr`.
+    ///
+    /// Data extents and, without `SKINNY_METADATA`, tree blocks are keyed
+    /// `(bytenr, EXTENT_ITEM, length)`; skinny tree blocks are keyed
+    /// `(bytenr, METADATA_ITEM, level)`. Either is found, but a
+    /// `METADATA_ITEM` on a filesystem without the flag is rejected.
+    pub fn extent_item(&self, bytenr: u64) -> Result<ExtentItem, BtrfsError> {
+        let min = Key::new(bytenr, EXTENT_ITEM, 0);
+        let max = Key::new(bytenr, KeyType::MetadataItem.as_u8(), u64::MAX);
+        let (key, data) = self
+            .extent_tree()?
+            .search_range(min, max)
+            .next()
+            .ok_or(BtrfsError::NotFound)??;
+        if key.item_type() == KeyType::MetadataItem
+            && !self
+                .superblock()
+                .incompat_flags()
+                .contains(IncompatFlags::SKINNY_METADATA)
+        {
+            return Err(BtrfsError::Malformed(
+                "METADATA_ITEM without the SKINNY_METADATA flag",
+            ));
+        }
+        ExtentItem::from_bytes(&key, &data)
+    }
+
+    /// Number of references to the extent at `disk_bytenr`. A data extent
+    /// with more than one is shared, by reflinks or snapshots.
+    pub fn extent_refcount(&self, disk_bytenr: u64) -> Result<u64, BtrfsError> {
+        Ok(self.extent_item(disk_bytenr)?.refs)
+    }
+
+    /// The back references stored inline in the extent item at
+    /// `disk_bytenr`. Heavily shared extents keep further references in
+    /// separate items, so this may list fewer than
+    /// [`Btrfs::extent_refcount`] counts.
+    pub fn extent_backrefs(&self, disk_bytenr: u64) -> Result<Vec<ExtentBackref>, BtrfsError> {
+        Ok(self.extent_item(disk_bytenr)?.inline_refs)
+    }
+
+    /// The files whose data is at logical address `logical`, as
+    /// `(root, inode, file offset)` triples in order, like `btrfs
+    /// inspect-internal logical-resolve` finds them. `logical` may be
+    /// anywhere inside a data extent; an address outside every data
+    /// extent, such as one in a tree block, gives [`BtrfsError::NotFound`].
+    ///
+    /// After reflinks or snapshots, one extent can have many owners. Each
+    /// of its data back references, inline or in an item of its own, is
+    /// followed to the file extents that point into the extent and cover
+    /// `logical`. A leaf that snapshots share is credited to the trees its
+    /// own back references name; a tree that shares it only through an
+    /// ancestor block it has not rewritten yet is missed.
+    pub fn owners_of(&self, logical: u64) -> Result<Vec<(u64, u64, u64)>, BtrfsError> {
+        let extents = self.extent_tree()?;
+        // No key at the extent's start sorts after its extent item, so the
+        // last key up to this one belongs to the extent covering `logical`,
+        // if any does.
+        let (near, _) = extents
+            .search_le(&Key::new(logical, EXTENT_ITEM, u64::MAX))?
+            .ok_or(BtrfsError::NotFound)?;
+        let start = near.objectid;
+        let (key, data) = extents
+            .search_range(
+                Key::new(start, EXTENT_ITEM, 0),
+                Key::new(start, EXTENT_ITEM, u64::MAX),
+            )
+            .next()
+            .ok_or(BtrfsError::NotFound)??;
+        let item = ExtentItem::from_bytes(&key, &data)?;
+        if item.kind != ExtentKind::Data || logical - start >= key.offset {
+            return Err(BtrfsError::NotFound);
+        }
+
+        let mut owners = Vec::new();
+        for backref in self.all_backrefs(start, item)? {
+            match backref {
+                ExtentBackref::ExtentData {
+                    root,
+                    objectid,
+                    offset,
+                    ..
+                } => {
+                    let files = match self.tree_by_id(root) {
+                        Ok(files) => files,
+                        // A deleted subvolume, not cleaned up yet.
+                        Err(BtrfsError::NotFound) => continue,
+                        Err(err) => return Err(err),
+                    };
+                    // The file offset of each file extent is at least the
+                    // offset its back reference records.
+                    let min = Key::new(objectid, EXTENT_DATA, offset);
+                    let max = Key::new(objectid, EXTENT_DATA, u64::MAX);
+                    for file_extent in files.search_range(min, max) {
+                        let (key, data) = file_extent?;
+                        match file_offset(&key, &data, start, logical)? {
+                            Some((recorded, at)) if recorded == offset => {
+                                owners.push((root, objectid, at))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                ExtentBackref::SharedData { parent, .. } => {
+                    let mut roots = BTreeSet::new();
+                    self.tree_block_roots(parent, &mut roots, &mut BTreeSet::new())?;
+                    for file_extent in self.tree(parent, 0).iter() {
+                        let (key, data) = file_extent?;
+                        if key.item_type() != KeyType::ExtentData {
+                            continue;
+                        }
+                        if let Some((_, at)) = file_offset(&key, &data, start, logical)? {
+                            owners.extend(roots.iter().map(|&root| (root, key.objectid, at)));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        owners.sort_unstable();
+        owners.dedup();
+        Ok(owners)
+    }
+
+    /// Every back reference of the extent at `bytenr` whose extent item is
+    /// `item`: the inline ones, then those stored as items of their own.
+    fn all_backrefs(
+        &self,
+        bytenr: u64,
+        item: ExtentItem,
+    ) -> Result<Vec<ExtentBackref>, BtrfsError> {
+        let mut backrefs = item.inline_refs;
+        let min = Key::new(bytenr, KeyType::TreeBlockRef.as_u8(), 0);
+        let max = Key::new(bytenr, KeyType::SharedDataRef.as_u8(), u64::MAX);
+        for keyed in self.extent_tree()?.search_range(min, max) {
+            let (key, data) = keyed?;
+            backrefs.push(ExtentBackref::from_item(&key, &data)?);
+        }
+        Ok(backrefs)
+    }
+
+    /// Adds the trees that refer to the tree block at `bytenr` to `roots`,
+    /// following shared block references up to the blocks they name.
+    fn tree_block_roots(
+        &self,
+        bytenr: u64,
+        roots: &mut BTreeSet<u64>,
+        seen: &mut BTreeSet<u64>,
+    ) -> Result<(), BtrfsError> {
+        // Guards against a corrupt extent tree whose references loop.
+        if !seen.insert(bytenr) {
+            return Ok(());
+        }
+        let item = self.extent_item(bytenr)?;
+        for backref in self.all_backrefs(bytenr, item)? {
+            match backref {
+                ExtentBackref::TreeBlock { root } => {
+                    roots.insert(root);
+                }
+                ExtentBackref::SharedBlock { parent } => {
+                    self.tree_block_roots(parent, roots, seen)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the file extent item `data` under `key` puts `logical`, if it
+/// points into the data extent at `start` and covers `logical`, together
+/// with the offset its data back reference records.
+fn file_offset(
+    key: &Key,
+    data: &[u8],
+    start: u64,
+    logical: u64,
+) -> Result<Option<(u64, u64)>, BtrfsError> {
+    let item = FileExtentItem::from_bytes(data)?;
+    let FileExtentData::Disk {
+        disk_bytenr,
+        offset,
+        num_bytes,
+    } = item.data()
+    else {
+        return Ok(None);
+    };
+    if disk_bytenr != start {
+        return Ok(None);
+    }
+    let recorded = key.offset.wrapping_sub(offset);
+    // The offset of a compressed extent counts decompressed bytes, which
+    // do not line up with disk addresses; the whole file extent owns it.
+    if item.compression() != Compression::None {
+        return Ok(Some((recorded, key.offset)));
+    }
+    let into = logical - start;
+    let past_end = || BtrfsError::Malformed("file extent ends past u64::MAX");
+    let end = offset.checked_add(num_bytes).ok_or_else(past_end)?;
+    if !(offset..end).contains(&into) {
+        return Ok(None);
+    }
+    let file_offset = key.offset.checked_add(into - offset).ok_or_else(past_end)?;
+    Ok(Some((recorded, file_offset)))
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testutil::{block, leaf, root_item, TestImage, NODESIZE, SYSTEM_CHUNK};
+
+    fn extent(refs: u64, flags: u64, tail: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = [refs, 7, flags]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        bytes.extend_from_slice(tail);
+        bytes
+    }
+
+    fn data_ref(root: u64, objectid: u64, count: u32) -> Vec<u8> {
+        let mut bytes = vec![178];
+        for value in [root, objectid, 0] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes
+    }
+
+    /// Without `flags`, the image keeps the fixture's, which include
+    /// `SKINNY_METADATA`.
+    fn extent_fs_with(flags: Option<IncompatFlags>) -> Btrfs {
+        let root_tree = SYSTEM_CHUNK + NODESIZE as u64;
+        let extent_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+
+        let mut shared = data_ref(5, 257, 1);
+        shared.extend_from_slice(&data_ref(5, 258, 1));
+        let mut tree_block = vec![0u8; 17];
+        tree_block.push(1);
+        tree_block.push(176);
+        tree_block.extend_from_slice(&2u64.to_le_bytes());
+        let mut skinny = vec![176];
+        skinny.extend_from_slice(&5u64.to_le_bytes());
+
+        let items = [
+            (Key::new(13631488, 168, 4096), extent(2, 1, &shared)),
+            (
+                Key::new(13635584, 168, 4096),
+                extent(1, 1, &data_ref(5, 259, 1)),
+            ),
+            (Key::new(30408704, 168, 16384), extent(1, 2, &tree_block)),
+            (Key::new(30425088, 169, 0), extent(1, 2, &skinny)),
+        ];
+        let root = root_item(extent_tree, 0);
+        let mut image = TestImage::new();
+        if let Some(flags) = flags {
+            image.set_incompat_flags(flags);
+        }
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(
+                root_tree,
+                &leaf(root_tree, 1, &[(Key::new(2, 132, 0), &root)]),
+            )
+            .put_block(extent_tree, &leaf(extent_tree, 2, &items));
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    fn extent_fs() -> Btrfs {
+        extent_fs_with(None)
+    }
+
+    #[test]
+    fn counts_shared_data_extents() {
+        let fs = extent_fs();
+        assert_eq!(fs.extent_refcount(13631488).unwrap(), 2);
+        assert_eq!(fs.extent_refcount(13635584).unwrap(), 1);
+        assert_eq!(
+            fs.extent_backrefs(13631488).unwrap(),
+            [
+                ExtentBackref::ExtentData {
+                    root: 5,
+                    objectid: 257,
+                    offset: 0,
+                    count: 1,
+                },
+                ExtentBackref::ExtentData {
+                    root: 5,
+                    objectid: 258,
+                    offset: 0,
+                    count: 1,
+                },
+            ]
+        );
+        assert!(matches!(
+            fs.extent_refcount(13639680),
+            Err(BtrfsError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn finds_classic_and_skinny_tree_blocks() {
+        let fs = extent_fs();
+        assert_eq!(
+            fs.extent_backrefs(30408704).unwrap(),
+            [ExtentBackref::TreeBlock { root: 2 }]
+        );
+        assert_eq!(
+            fs.extent_backrefs(30425088).unwrap(),
+            [ExtentBackref::TreeBlock { root: 5 }]
+        );
+        assert_eq!(
+            fs.extent_item(30408704).unwrap().kind,
+            ExtentKind::Metadata { level: 1 }
+        );
+        assert_eq!(
+            fs.extent_item(30425088).unwrap().kind,
+            ExtentKind::Metadata { level: 0 }
+        );
+        assert_eq!(fs.extent_item(13631488).unwrap().kind, ExtentKind::Data);
+    }
+
+    #[test]
+    fn rejects_metadata_items_without_skinny_metadata() {
+        let fs = extent_fs_with(Some(IncompatFlags::MIXED_BACKREF | IncompatFlags::NO_HOLES));
+        let item = fs.extent_item(30408704).unwrap();
+        assert_eq!(item.kind, ExtentKind::Metadata { level: 1 });
+        assert_eq!(item.inline_refs, [ExtentBackref::TreeBlock { root: 2 }]);
+        assert!(matches!(
+            fs.extent_item(30425088),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    fn disk_extent(disk_bytenr: u64, offset: u64, num_bytes: u64) -> Vec<u8> {
+        let mut bytes: Vec<u8> = [6u64, num_bytes]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        bytes.extend_from_slice(&[0, 0, 0, 0, FileExtentItem::REGULAR]);
+        for value in [disk_bytenr, offset + num_bytes, offset, num_bytes] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn resolves_logical_addresses_to_files() {
+        let data = 13631488;
+        // Inode 258 reflinks the second half of 257's extent, and
+        // snapshots 256 and 257 share the leaf holding inode 259.
+        let shared_leaf = block(6);
+        let mut keyed_data_ref = data_ref(5, 258, 1);
+        keyed_data_ref.remove(0);
+        let mut leaf_ref = vec![176];
+        leaf_ref.extend_from_slice(&256u64.to_le_bytes());
+        let extents = [
+            (
+                Key::new(data, 168, 8192),
+                extent(4, 1, &data_ref(5, 257, 1)),
+            ),
+            (Key::new(data, 178, 0x5eed), keyed_data_ref),
+            (
+                Key::new(data, 184, shared_leaf),
+                1u32.to_le_bytes().to_vec(),
+            ),
+            (Key::new(shared_leaf, 169, 0), extent(2, 2, &leaf_ref)),
+            (Key::new(shared_leaf, 176, 257), Vec::new()),
+        ];
+        let files = [
+            (Key::new(257, 108, 0), disk_extent(data, 0, 8192)),
+            (Key::new(258, 108, 4096), disk_extent(data, 4096, 4096)),
+            (Key::new(258, 108, 8192), disk_extent(data + 8192, 0, 4096)),
+        ];
+        let snapshot = [(Key::new(259, 108, 0), disk_extent(data, 0, 8192))];
+        let roots = [
+            (Key::new(2, 132, 0), root_item(block(3), 0)),
+            (Key::new(5, 132, 0), root_item(block(4), 0)),
+            (Key::new(256, 132, 0), root_item(shared_leaf, 0)),
+            (Key::new(257, 132, 0), root_item(shared_leaf, 0)),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .put_block(block(2), &leaf(block(2), 1, &roots))
+            .put_block(block(3), &leaf(block(3), 2, &extents))
+            .put_block(block(4), &leaf(block(4), 5, &files))
+            .put_block(shared_leaf, &leaf(shared_leaf, 256, &snapshot));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        assert_eq!(
+            fs.owners_of(data + 4196).unwrap(),
+            [
+                (5, 257, 4196),
+                (5, 258, 4196),
+                (256, 259, 4196),
+                (257, 259, 4196)
+            ]
+        );
+        assert_eq!(
+            fs.owners_of(data + 100).unwrap(),
+            [(5, 257, 100), (256, 259, 100), (257, 259, 100)]
+        );
+        for outside in [data - 1, data + 8192, shared_leaf] {
+            assert!(matches!(fs.owners_of(outside), Err(BtrfsError::NotFound)));
+        }
+    }
+
+    #[test]
+    fn rejects_file_extents_past_the_address_space() {
+        let data = 13631488;
+        let mut past_end = disk_extent(data, 0, 8192);
+        past_end[37..45].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+        let key = Key::new(257, 108, 0);
+        assert!(matches!(
+            file_offset(&key, &past_end, data, data + 100),
+            Err(BtrfsError::Malformed("file extent ends past u64::MAX"))
+        ));
+
+        let extent = disk_extent(data, 0, 8192);
+        let key = Key::new(257, 108, u64::MAX - 10);
+        assert!(matches!(
+            file_offset(&key, &extent, data, data + 100),
+            Err(BtrfsError::Malformed("file extent ends past u64::MAX"))
+        ));
+        assert_eq!(
+            file_offset(&key, &extent, data, data + 5).unwrap(),
+            Some((u64::MAX - 10, u64::MAX - 5))
+        );
+    }
+}
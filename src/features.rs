@@ -0,0 +1,119 @@
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+
+bitflags! {
+    /// `incompat_flags`: features a reader must understand to open the
+    /// filesystem at all.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct IncompatFlags: u64 {
+        const MIXED_BACKREF = 1 << 0;
+        const DEFAULT_SUBVOL = 1 << 1;
+        const MIXED_GROUPS = 1 << 2;
+        const COMPRESS_LZO = 1 << 3;
+        const COMPRESS_ZSTD = 1 << 4;
+        const BIG_METADATA = 1 << 5;
+        const EXTENDED_IREF = 1 << 6;
+        /// Experimental RAID5/RAID6 profiles.
+        const RAID56 = 1 << 7;
+        const SKINNY_METADATA = 1 << 8;
+        const NO_HOLES = 1 << 9;
+        const METADATA_UUID = 1 << 10;
+        const RAID1C34 = 1 << 11;
+        const ZONED = 1 << 12;
+        const EXTENT_TREE_V2 = 1 << 13;
+        const RAID_STRIPE_TREE = 1 << 14;
+        const SIMPLE_QUOTA = 1 << 16;
+
+        // Keep bits this crate does not know about.
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    /// `compat_ro_flags`: features a reader may ignore, but a writer must
+    /// understand before mounting read-write.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct CompatRoFlags: u64 {
+        /// Free space is tracked in the free space tree instead of the
+        /// legacy free space cache.
+        const FREE_SPACE_TREE = 1 << 0;
+        /// The free space tree is consistent and may be trusted.
+        const FREE_SPACE_TREE_VALID = 1 << 1;
+        const VERITY = 1 << 2;
+        const BLOCK_GROUP_TREE = 1 << 3;
+
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    /// `compat_flags`: features safe to ignore entirely. No bits are
+    /// currently defined, but any set on disk are kept.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct CompatFlags: u64 {
+        const _ = !0;
+    }
+}
+
+/// Short names of the incompat features, as `mkfs.btrfs -O` spells them
+/// where it takes the feature, else after the kernel's sysfs name.
+const INCOMPAT_NAMES: [(u64, &str); 16] = [
+    (IncompatFlags::MIXED_BACKREF.bits(), "mixed-backref"),
+    (IncompatFlags::DEFAULT_SUBVOL.bits(), "default-subvol"),
+    (IncompatFlags::MIXED_GROUPS.bits(), "mixed-bg"),
+    (IncompatFlags::COMPRESS_LZO.bits(), "compress-lzo"),
+    (IncompatFlags::COMPRESS_ZSTD.bits(), "compress-zstd"),
+    (IncompatFlags::BIG_METADATA.bits(), "big-metadata"),
+    (IncompatFlags::EXTENDED_IREF.bits(), "extref"),
+    (IncompatFlags::RAID56.bits(), "raid56"),
+    (IncompatFlags::SKINNY_METADATA.bits(), "skinny-metadata"),
+    (IncompatFlags::NO_HOLES.bits(), "no-holes"),
+    (IncompatFlags::METADATA_UUID.bits(), "metadata-uuid"),
+    (IncompatFlags::RAID1C34.bits(), "raid1c34"),
+    (IncompatFlags::ZONED.bits(), "zoned"),
+    (IncompatFlags::EXTENT_TREE_V2.bits(), "extent-tree-v2"),
+    (IncompatFlags::RAID_STRIPE_TREE.bits(), "raid-stripe-tree"),
+    (IncompatFlags::SIMPLE_QUOTA.bits(), "squota"),
+];
+
+const COMPAT_RO_NAMES: [(u64, &str); 4] = [
+    (CompatRoFlags::FREE_SPACE_TREE.bits(), "free-space-tree"),
+    (
+        CompatRoFlags::FREE_SPACE_TREE_VALID.bits(),
+        "free-space-tree-valid",
+    ),
+    (CompatRoFlags::VERITY.bits(), "verity"),
+    (CompatRoFlags::BLOCK_GROUP_TREE.bits(), "block-group-tree"),
+];
+
+/// Appends the names of the features set in `bits`, then any bits without
+/// a name, together, as `unknown:0x<hex>`.
+fn push_names(names: &mut Vec<Cow<'static, str>>, bits: u64, known: &[(u64, &'static str)]) {
+    let mut unknown = bits;
+    for &(flag, name) in known {
+        if bits & flag != 0 {
+            names.push(Cow::Borrowed(name));
+        }
+        unknown &= !flag;
+    }
+    if unknown != 0 {
+        names.push(Cow::Owned(format!("unknown:{unknown:#x}")));
+    }
+}
+
+/// The names of every feature set in the three flag fields, incompat first,
+/// then compat_ro, then compat.
+pub(crate) fn feature_names(
+    incompat: IncompatFlags,
+    compat_ro: CompatRoFlags,
+    compat: CompatFlags,
+) -> Vec<Cow<'static, str>> {
+    let mut names = Vec::new();
+    push_names(&mut names, incompat.bits(), &INCOMPAT_NAMES);
+    push_names(&mut names, compat_ro.bits(), &COMPAT_RO_NAMES);
+    push_names(&mut names, compat.bits(), &[]);
+    names
+}
@@ -0,0 +1,1506 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::PoisonError;
+use std::vec;
+
+use crate::compress::{decompress, MAX_UNCOMPRESSED};
+#[cfg(feature = "crc32c")]
+use crate::csum::name_hash;
+#[cfg(feature = "crc32c")]
+use crate::dir::find_entry;
+use crate::dir::{DirEntry, DirItems, XattrItems};
+use crate::error::BtrfsError;
+use crate::features::IncompatFlags;
+use crate::file_extent::{
+    Compression, DiskUsage, ExtentMapping, ExtentMappingKind, FileExtentData, FileExtentItem,
+    FileExtentKind,
+};
+use crate::fs::{zeroed, Btrfs};
+use crate::inode::{InodeItem, InodeRef};
+use crate::key::{Key, KeyType};
+use crate::subvol::MAX_PATH_COMPONENTS;
+use crate::tree::Tree;
+use crate::verity::{VerityDescriptorItem, VerityInfo};
+
+impl Btrfs {
+    /// The fs tree of subvolume `subvol`, with its root directory inode.
+    fn subvolume_tree(&self, subvol: u64) -> Result<(Tree<'_>, u64), BtrfsError> {
+        let root = self.root_item(subvol)?.ok_or(BtrfsError::NotFound)?;
+        Ok((self.tree(root.bytenr(), root.level()), root.root_dirid))
+    }
+
+    /// Reads the inode item of inode `objectid` in `tree`.
+    fn inode_item(&self, tree: &Tree<'_>, objectid: u64) -> Result<InodeItem, BtrfsError> {
+        let key = Key::new(objectid, KeyType::InodeItem.as_u8(), 0);
+        let (_, data) = tree.search(&key)?.ok_or(BtrfsError::NotFound)?;
+        InodeItem::from_bytes(&data)
+    }
+
+    /// Resolves a `/`-separated path, relative to the root directory of
+    /// subvolume `subvol_root` (5 for the top level), to its inode. Entries
+    /// that are subvolumes are followed into the subvolume's own tree.
+    ///
+    /// Empty and `.` components are skipped; `..` is not supported.
+    ///
+    /// See [`Btrfs::with_dir_cache_capacity`] to resolve names in the same
+    /// directories repeatedly without searching for them each time.
+    #[cfg(feature = "crc32c")]
+    pub fn lookup_path(&self, subvol_root: u64, path: &str) -> Result<InodeItem, BtrfsError> {
+        let (mut tree, mut inode) = self.subvolume_tree(subvol_root)?;
+        let mut subvol = subvol_root;
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let location = self.dir_entry_location(subvol, &tree, inode, name)?;
+            if location.item_type() == KeyType::RootItem {
+                subvol = location.objectid;
+                (tree, inode) = self.subvolume_tree(subvol)?;
+            } else {
+                inode = location.objectid;
+            }
+        }
+        self.inode_item(&tree, inode)
+    }
+
+    /// The location of entry `name` of directory `dir` in subvolume
+    /// `subvol`, whose tree is `tree`, from the directory entry cache or
+    /// else the entry's `DIR_ITEM`.
+    #[cfg(feature = "crc32c")]
+    fn dir_entry_location(
+        &self,
+        subvol: u64,
+        tree: &Tree<'_>,
+        dir: u64,
+        name: &str,
+    ) -> Result<Key, BtrfsError> {
+        let cache_key = (subvol, dir, name.to_owned());
+        let cached = self
+            .dir_cache()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&cache_key);
+        if let Some(location) = cached {
+            return Ok(location);
+        }
+
+        let key = Key::new(dir, KeyType::DirItem.as_u8(), name_hash(name.as_bytes()));
+        let (_, data) = tree.search(&key)?.ok_or(BtrfsError::NotFound)?;
+        // Names whose hashes collide share the item.
+        let location = find_entry(&data, name.as_bytes())?
+            .ok_or(BtrfsError::NotFound)?
+            .location;
+        self.dir_cache()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(cache_key, location);
+        Ok(location)
+    }
+
+    /// Maps file `inode_key` of subvolume `subvol` from offset 0 to the end
+    /// of its last extent or its size, whichever is larger, so that a sparse
+    /// copy can skip the holes.
+    ///
+    /// Holes are reported whether they are stored as extents with a zero
+    /// `disk_bytenr` or, on filesystems with the `NO_HOLES` incompat flag,
+    /// left as gaps between extent items; adjacent holes are merged.
+    pub fn extents(&self, subvol: u64, inode_key: &Key) -> Result<Vec<ExtentMapping>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let size = self.inode_item(&tree, inode_key.objectid)?.size;
+
+        let mut mappings: Vec<ExtentMapping> = Vec::new();
+        let mut push = |logical_file_offset: u64, length: u64, kind: ExtentMappingKind| {
+            if let Some(last) = mappings.last_mut() {
+                if kind == ExtentMappingKind::Hole && last.kind == ExtentMappingKind::Hole {
+                    last.length += length;
+                    return;
+                }
+            }
+            mappings.push(ExtentMapping {
+                logical_file_offset,
+                length,
+                kind,
+            });
+        };
+
+        let extent_data = KeyType::ExtentData.as_u8();
+        let min = Key::new(inode_key.objectid, extent_data, 0);
+        let max = Key::new(inode_key.objectid, extent_data, u64::MAX);
+        let mut end = 0;
+        for item in tree.search_range(min, max) {
+            let (key, data) = item?;
+            let extent = FileExtentItem::from_bytes(&data)?;
+            if key.offset < end {
+                return Err(BtrfsError::Malformed("overlapping file extents"));
+            }
+            if key.offset > end {
+                push(end, key.offset - end, ExtentMappingKind::Hole);
+            }
+            let (length, kind) = match extent.kind() {
+                FileExtentKind::Inline => (extent.ram_bytes, ExtentMappingKind::Inline),
+                _ if extent.disk_bytenr == 0 => (extent.num_bytes, ExtentMappingKind::Hole),
+                FileExtentKind::Prealloc => (
+                    extent.num_bytes,
+                    ExtentMappingKind::Prealloc {
+                        disk_bytenr: extent.disk_bytenr,
+                        offset: extent.offset,
+                    },
+                ),
+                FileExtentKind::Regular => (
+                    extent.num_bytes,
+                    ExtentMappingKind::Regular {
+                        disk_bytenr: extent.disk_bytenr,
+                        disk_num_bytes: extent.disk_num_bytes,
+                        offset: extent.offset,
+                        compression: extent.compression(),
+                    },
+                ),
+            };
+            push(key.offset, length, kind);
+            end = key
+                .offset
+                .checked_add(length)
+                .ok_or(BtrfsError::Malformed("file extent ends past u64::MAX"))?;
+        }
+        if size > end {
+            push(end, size - end, ExtentMappingKind::Hole);
+        }
+        Ok(mappings)
+    }
+
+    /// Adds up the disk space of file `inode_key` in subvolume `subvol`.
+    /// Each extent counts once at its full on-disk size, compressed if it
+    /// is, however many ranges of the file use it and however little of it
+    /// they use. Preallocated extents count although nothing was written to
+    /// them.
+    ///
+    /// An extent is shared when its extent item has more references than
+    /// the file's own extent items, which a partly overwritten extent can
+    /// have several of. A snapshot shares the file's tree blocks rather
+    /// than adding references, so extents shared only with an unmodified
+    /// snapshot are not counted as shared.
+    pub fn disk_usage(&self, subvol: u64, inode_key: &Key) -> Result<DiskUsage, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let mut usage = DiskUsage {
+            logical_size: self.inode_item(&tree, inode_key.objectid)?.size,
+            ..DiskUsage::default()
+        };
+
+        // Size and reference count within the file, by disk address.
+        let mut extents: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+        let extent_data = KeyType::ExtentData.as_u8();
+        let min = Key::new(inode_key.objectid, extent_data, 0);
+        let max = Key::new(inode_key.objectid, extent_data, u64::MAX);
+        for item in tree.search_range(min, max) {
+            let (_, data) = item?;
+            let extent = FileExtentItem::from_bytes(&data)?;
+            if extent.is_inline() {
+                usage.physical_allocated += data.len() as u64;
+            } else if extent.disk_bytenr != 0 {
+                extents
+                    .entry(extent.disk_bytenr)
+                    .or_insert((extent.disk_num_bytes, 0))
+                    .1 += 1;
+            }
+        }
+        for (disk_bytenr, (disk_num_bytes, refs)) in extents {
+            usage.physical_allocated += disk_num_bytes;
+            if self.extent_refcount(disk_bytenr)? > refs {
+                usage.shared += disk_num_bytes;
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Reads the contents of file `inode_key` in subvolume `subvol`, up to
+    /// `inode.size` bytes. Holes, preallocated ranges and any tail not
+    /// covered by an extent read as zeros.
+    ///
+    /// Compressed extents, inline ones of small files included, need the
+    /// matching codec feature (`zlib`, `zstd` or `lzo`); encrypted extents
+    /// are not supported.
+    pub fn read_file(
+        &self,
+        subvol: u64,
+        inode: &InodeItem,
+        inode_key: &Key,
+    ) -> Result<Vec<u8>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let size = usize::try_from(inode.size)
+            .map_err(|_| BtrfsError::Malformed("file size exceeds the address space"))?;
+        let mut contents = zeroed(size)?;
+        let sectorsize = self.superblock().sectorsize;
+
+        let extent_data = KeyType::ExtentData.as_u8();
+        let min = Key::new(inode_key.objectid, extent_data, 0);
+        let max = Key::new(inode_key.objectid, extent_data, u64::MAX);
+        for item in tree.search_range(min, max) {
+            let (key, data) = item?;
+            if key.offset >= inode.size {
+                break;
+            }
+            let start = key.offset as usize;
+            let extent = FileExtentItem::from_bytes(&data)?;
+            let compression = extent.compression();
+            if extent.encryption != 0 || extent.other_encoding != 0 {
+                return Err(BtrfsError::Unsupported("encoded file extents"));
+            }
+
+            match extent.data() {
+                FileExtentData::Inline(bytes) => {
+                    let bytes = if compression == Compression::None {
+                        bytes.to_vec()
+                    } else {
+                        decompress(compression, bytes, extent.ram_bytes, sectorsize)?
+                    };
+                    let len = bytes.len().min(size - start);
+                    contents[start..start + len].copy_from_slice(&bytes[..len]);
+                }
+                FileExtentData::Disk {
+                    disk_bytenr,
+                    offset,
+                    num_bytes,
+                } => {
+                    // Holes and unwritten preallocated space are zeros already;
+                    // the disk blocks behind a prealloc extent are stale.
+                    if disk_bytenr == 0 || extent.kind() == FileExtentKind::Prealloc {
+                        continue;
+                    }
+                    let len = num_bytes.min(inode.size - key.offset) as usize;
+                    if compression == Compression::None {
+                        let logical = disk_bytenr
+                            .checked_add(offset)
+                            .ok_or(BtrfsError::Malformed("file extent ends past u64::MAX"))?;
+                        let bytes = self.read_logical(logical, len)?;
+                        contents[start..start + len].copy_from_slice(&bytes);
+                        continue;
+                    }
+                    // `offset` and `num_bytes` index the decompressed extent,
+                    // which has to be read and decoded as a whole.
+                    let disk_len = usize::try_from(extent.disk_num_bytes)
+                        .ok()
+                        .filter(|&len| len <= MAX_UNCOMPRESSED)
+                        .ok_or(BtrfsError::Malformed(
+                            "compressed extent larger than 128KiB",
+                        ))?;
+                    let compressed = self.read_logical(disk_bytenr, disk_len)?;
+                    let bytes = decompress(compression, &compressed, extent.ram_bytes, sectorsize)?;
+                    let range = usize::try_from(offset)
+                        .ok()
+                        .and_then(|offset| bytes.get(offset..offset.checked_add(len)?))
+                        .ok_or(BtrfsError::Malformed(
+                            "file extent range exceeds its extent",
+                        ))?;
+                    contents[start..start + len].copy_from_slice(range);
+                }
+            }
+        }
+        Ok(contents)
+    }
+
+    /// The extended attributes of inode `inode_key` in subvolume `subvol`,
+    /// as `(name, value)` pairs ordered by name hash. Values are raw bytes.
+    pub fn xattrs(
+        &self,
+        subvol: u64,
+        inode_key: &Key,
+    ) -> Result<Vec<(String, Vec<u8>)>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let xattr_item = KeyType::XattrItem.as_u8();
+        let min = Key::new(inode_key.objectid, xattr_item, 0);
+        let max = Key::new(inode_key.objectid, xattr_item, u64::MAX);
+        let mut xattrs = Vec::new();
+        for item in tree.search_range(min, max) {
+            let (_, data) = item?;
+            for xattr in XattrItems::new(&data) {
+                xattrs.push(xattr?);
+            }
+        }
+        Ok(xattrs)
+    }
+
+    /// The target of symlink `inode_key` in subvolume `subvol`, which btrfs
+    /// stores as the inline data of the inode's only extent.
+    pub fn read_symlink(
+        &self,
+        subvol: u64,
+        inode: &InodeItem,
+        inode_key: &Key,
+    ) -> Result<PathBuf, BtrfsError> {
+        if !inode.is_symlink() {
+            return Err(BtrfsError::NotASymlink);
+        }
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let key = Key::new(inode_key.objectid, KeyType::ExtentData.as_u8(), 0);
+        let (_, data) = tree.search(&key)?.ok_or(BtrfsError::NotFound)?;
+        let extent = FileExtentItem::from_bytes(&data)?;
+        let FileExtentData::Inline(target) = extent.data() else {
+            return Err(BtrfsError::Malformed("symlink target is not inline"));
+        };
+        if extent.compression() != Compression::None {
+            return Err(BtrfsError::Malformed("symlink target is compressed"));
+        }
+        Ok(path_from_bytes(target))
+    }
+
+    /// Lists directory `dir_inode_key` of subvolume `subvol` in `DIR_INDEX`
+    /// order, which is creation order and, unlike the hash-ordered
+    /// `DIR_ITEM`s, holds each entry exactly once. `.` and `..` are not
+    /// stored and so not listed.
+    pub fn readdir(&self, subvol: u64, dir_inode_key: &Key) -> Result<Vec<DirEntry>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let dir = dir_inode_key.objectid;
+        let entries = dir_entries(&tree, dir)?;
+
+        let mut cache = self
+            .dir_cache()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if cache.is_enabled() {
+            for entry in &entries {
+                cache.insert((subvol, dir, entry.name.clone()), entry.inode);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Every name of inode `inode_key` in subvolume `subvol`: one per hard
+    /// link, from its `INODE_REF` items and, with `EXTENDED_IREF`, its
+    /// `INODE_EXTREF` items. The root directory of a subvolume has none.
+    pub fn inode_refs(&self, subvol: u64, inode_key: &Key) -> Result<Vec<InodeRef>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        let extended = self
+            .superblock()
+            .incompat_flags()
+            .contains(IncompatFlags::EXTENDED_IREF);
+        let inode_ref = KeyType::InodeRef.as_u8();
+        let max_type = if extended {
+            KeyType::InodeExtref.as_u8()
+        } else {
+            inode_ref
+        };
+        let min = Key::new(inode_key.objectid, inode_ref, 0);
+        let max = Key::new(inode_key.objectid, max_type, u64::MAX);
+
+        let mut refs = Vec::new();
+        for item in tree.search_range(min, max) {
+            let (key, data) = item?;
+            if key.item_type == inode_ref {
+                refs.extend(InodeRef::parse_refs(key.offset, &data)?);
+            } else {
+                refs.extend(InodeRef::parse_extrefs(&data)?);
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Path of inode `ino` relative to the root directory of subvolume
+    /// `subvol_root`, found by following inode refs up to that directory.
+    ///
+    /// Of several hard links, the one with the lowest directory index is
+    /// used at each step. An inode without refs gives
+    /// [`BtrfsError::Orphan`].
+    pub fn inode_path(&self, subvol_root: u64, ino: u64) -> Result<PathBuf, BtrfsError> {
+        let (tree, root_dir) = self.subvolume_tree(subvol_root)?;
+        self.inode_item(&tree, ino)?;
+        let inode_item = KeyType::InodeItem.as_u8();
+        let mut components = Vec::new();
+        let mut current = ino;
+        while current != root_dir {
+            if components.len() >= MAX_PATH_COMPONENTS {
+                return Err(BtrfsError::Malformed("directory references form a loop"));
+            }
+            let name = self
+                .inode_refs(subvol_root, &Key::new(current, inode_item, 0))?
+                .into_iter()
+                .min_by_key(|inode_ref| inode_ref.index)
+                .ok_or(BtrfsError::Orphan { ino: current })?;
+            current = name.parent_dir;
+            components.push(name.name);
+        }
+        Ok(components.iter().rev().collect())
+    }
+
+    /// Every path of inode `ino` relative to the root directory of
+    /// subvolume `subvol_root`, one per hard link and in order, as `btrfs
+    /// inspect-internal inode-resolve` lists them. The root directory
+    /// itself has only the empty path.
+    ///
+    /// Directories cannot be hard linked, so each name's directory is found
+    /// as by [`Btrfs::inode_path`].
+    pub fn resolve_inode(&self, subvol_root: u64, ino: u64) -> Result<Vec<PathBuf>, BtrfsError> {
+        let (tree, root_dir) = self.subvolume_tree(subvol_root)?;
+        self.inode_item(&tree, ino)?;
+        if ino == root_dir {
+            return Ok(vec![PathBuf::new()]);
+        }
+        let refs = self.inode_refs(subvol_root, &Key::new(ino, KeyType::InodeItem.as_u8(), 0))?;
+        if refs.is_empty() {
+            return Err(BtrfsError::Orphan { ino });
+        }
+        let mut dirs = BTreeMap::new();
+        let mut paths = Vec::with_capacity(refs.len());
+        for name in refs {
+            let dir = match dirs.get(&name.parent_dir) {
+                Some(dir) => dir,
+                None => {
+                    let dir = self.inode_path(subvol_root, name.parent_dir)?;
+                    dirs.entry(name.parent_dir).or_insert(dir)
+                }
+            };
+            paths.push(dir.join(name.name));
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// The fs-verity descriptor of file `inode_key` in subvolume `subvol`,
+    /// or `None` if verity is not enabled on it.
+    pub fn verity_info(
+        &self,
+        subvol: u64,
+        inode_key: &Key,
+    ) -> Result<Option<VerityInfo>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        if !self.inode_item(&tree, inode_key.objectid)?.is_verity() {
+            return Ok(None);
+        }
+        let desc_item = KeyType::VerityDescItem.as_u8();
+        let key = Key::new(inode_key.objectid, desc_item, 0);
+        let (_, data) = tree
+            .search(&key)?
+            .ok_or(BtrfsError::Malformed("verity inode has no descriptor item"))?;
+        let item = VerityDescriptorItem::from_bytes(&data)?;
+        if item.encryption != 0 {
+            return Err(BtrfsError::Unsupported("encrypted verity descriptor"));
+        }
+        let descriptor = item_bytes(&tree, inode_key.objectid, desc_item, 1)?;
+        if descriptor.len() as u64 != item.size {
+            return Err(BtrfsError::Malformed("verity descriptor size mismatch"));
+        }
+        VerityInfo::from_descriptor(&descriptor).map(Some)
+    }
+
+    /// The Merkle tree of fs-verity file `inode_key` in subvolume `subvol`,
+    /// as stored in its `VERITY_MERKLE_ITEM`s: empty if verity is not
+    /// enabled on it.
+    pub fn verity_merkle_tree(&self, subvol: u64, inode_key: &Key) -> Result<Vec<u8>, BtrfsError> {
+        let (tree, _) = self.subvolume_tree(subvol)?;
+        item_bytes(
+            &tree,
+            inode_key.objectid,
+            KeyType::VerityMerkleItem.as_u8(),
+            0,
+        )
+    }
+
+    /// Walks subvolume `subvol_root` depth-first, yielding every file,
+    /// directory and other inode below its root directory together with its
+    /// path relative to that root. Nested subvolumes are skipped unless
+    /// [`Walk::cross_subvolumes`] is set.
+    pub fn walk(&self, subvol_root: u64) -> Walk<'_> {
+        Walk {
+            fs: self,
+            subvol: subvol_root,
+            cross_subvolumes: false,
+            started: false,
+            done: false,
+            stack: Vec::new(),
+            visited: HashSet::new(),
+        }
+    }
+}
+
+/// The `DIR_INDEX` entries of directory `dir` in `tree`.
+fn dir_entries(tree: &Tree<'_>, dir: u64) -> Result<Vec<DirEntry>, BtrfsError> {
+    let dir_index = KeyType::DirIndex.as_u8();
+    let min = Key::new(dir, dir_index, 0);
+    let max = Key::new(dir, dir_index, u64::MAX);
+    let mut entries = Vec::new();
+    for item in tree.search_range(min, max) {
+        let (_, data) = item?;
+        for entry in DirItems::new(&data) {
+            let (name, inode, entry_type) = entry?;
+            entries.push(DirEntry {
+                name,
+                inode,
+                entry_type,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Concatenates the `item_type` items of `objectid`, whose key offsets are
+/// byte positions counted from `first` and must leave no gaps.
+fn item_bytes(
+    tree: &Tree<'_>,
+    objectid: u64,
+    item_type: u8,
+    first: u64,
+) -> Result<Vec<u8>, BtrfsError> {
+    let min = Key::new(objectid, item_type, first);
+    let max = Key::new(objectid, item_type, u64::MAX);
+    let mut bytes = Vec::new();
+    for item in tree.search_range(min, max) {
+        let (key, data) = item?;
+        if key.offset - first != bytes.len() as u64 {
+            return Err(BtrfsError::Malformed("gap between verity items"));
+        }
+        bytes.extend_from_slice(&data);
+    }
+    Ok(bytes)
+}
+
+/// Depth-first iterator over a subvolume, returned by [`Btrfs::walk`].
+///
+/// Directories are yielded before their contents. Each directory is entered
+/// at most once, so a corrupted tree whose entries loop back to an ancestor
+/// cannot make the walk run forever. The iterator ends after the first
+/// error.
+pub struct Walk<'a> {
+    fs: &'a Btrfs,
+    subvol: u64,
+    cross_subvolumes: bool,
+    started: bool,
+    done: bool,
+    stack: Vec<WalkDir<'a>>,
+    /// `(subvolume, inode)` of every directory entered so far.
+    visited: HashSet<(u64, u64)>,
+}
+
+struct WalkDir<'a> {
+    tree: Tree<'a>,
+    subvol: u64,
+    path: PathBuf,
+    entries: vec::IntoIter<DirEntry>,
+}
+
+impl<'a> Walk<'a> {
+    /// Also descend into nested subvolumes, yielding the root directory of
+    /// each at the path it is mounted under.
+    pub fn cross_subvolumes(mut self, cross: bool) -> Self {
+        self.cross_subvolumes = cross;
+        self
+    }
+
+    /// Enters directory `dir` of `tree`, or returns `false` if it was
+    /// entered before.
+    fn enter(
+        &mut self,
+        tree: Tree<'a>,
+        subvol: u64,
+        dir: u64,
+        path: PathBuf,
+    ) -> Result<bool, BtrfsError> {
+        if !self.visited.insert((subvol, dir)) {
+            return Ok(false);
+        }
+        let entries = dir_entries(&tree, dir)?.into_iter();
+        self.stack.push(WalkDir {
+            tree,
+            subvol,
+            path,
+            entries,
+        });
+        Ok(true)
+    }
+
+    fn step(&mut self) -> Result<Option<(PathBuf, InodeItem)>, BtrfsError> {
+        if !self.started {
+            self.started = true;
+            let (tree, dir) = self.fs.subvolume_tree(self.subvol)?;
+            self.enter(tree, self.subvol, dir, PathBuf::new())?;
+        }
+        loop {
+            let Some(frame) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            let Some(entry) = frame.entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            let path = frame.path.join(&entry.name);
+            let (mut tree, mut subvol) = (frame.tree, frame.subvol);
+
+            let mut objectid = entry.inode.objectid;
+            if entry.inode.item_type() == KeyType::RootItem {
+                if !self.cross_subvolumes {
+                    continue;
+                }
+                subvol = entry.inode.objectid;
+                (tree, objectid) = self.fs.subvolume_tree(subvol)?;
+            }
+            let inode = self.fs.inode_item(&tree, objectid)?;
+            if inode.is_dir() && !self.enter(tree, subvol, objectid, path.clone())? {
+                continue;
+            }
+            return Ok(Some((path, inode)));
+        }
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Result<(PathBuf, InodeItem), BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+/// Off Unix, paths must be valid Unicode, so invalid UTF-8 is replaced.
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::BinWrite;
+
+    use super::*;
+    use crate::cache::CacheStats;
+    use crate::dir::{DirEntryType, DirItemHeader};
+    use crate::testutil::{block, chunk_item, leaf, put_u64, root_item, test_chunk, TestImage};
+    use crate::verity::VerityHashAlgorithm;
+
+    /// An image whose only subvolume is the top-level one, holding `items`.
+    fn top_level_image<D: AsRef<[u8]>>(items: &[(Key, D)]) -> TestImage {
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(
+                block(0),
+                &leaf(
+                    block(0),
+                    1,
+                    &[(Key::new(5, 132, 0), root_item(block(1), 0))],
+                ),
+            )
+            .put_block(block(1), &leaf(block(1), 5, items));
+        image
+    }
+
+    fn top_level_fs<D: AsRef<[u8]>>(items: &[(Key, D)]) -> Btrfs {
+        Btrfs::from_reader(Cursor::new(top_level_image(items).build())).unwrap()
+    }
+
+    fn inode(mode: u32, size: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; InodeItem::SIZE];
+        bytes[16..24].copy_from_slice(&size.to_le_bytes());
+        bytes[52..56].copy_from_slice(&mode.to_le_bytes());
+        bytes
+    }
+
+    fn dir_item(name: &str, location: Key, dir_type: u8) -> Vec<u8> {
+        let header = DirItemHeader {
+            location,
+            transid: 6,
+            data_len: 0,
+            name_len: name.len() as u16,
+            dir_type,
+        };
+        let mut bytes = Cursor::new(Vec::new());
+        header.write(&mut bytes).unwrap();
+        let mut bytes = bytes.into_inner();
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    fn dir_key(dir: u64, name: &str) -> Key {
+        Key::new(dir, 84, name_hash(name.as_bytes()))
+    }
+
+    fn ref_bytes(index: u64, name: &str) -> Vec<u8> {
+        let mut bytes = index.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    fn inode_ref(ino: u64, parent_dir: u64, index: u64, name: &str) -> (Key, Vec<u8>) {
+        (Key::new(ino, 12, parent_dir), ref_bytes(index, name))
+    }
+
+    /// Extrefs are keyed by a hash of parent and name; tests only need the
+    /// key to sort after the inode's refs.
+    fn inode_extref(ino: u64, parent_dir: u64, index: u64, name: &str) -> (Key, Vec<u8>) {
+        let mut bytes = parent_dir.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&ref_bytes(index, name));
+        (Key::new(ino, 13, index), bytes)
+    }
+
+    fn dir_index(dir: u64, index: u64, name: &str, location: Key, dir_type: u8) -> (Key, Vec<u8>) {
+        (Key::new(dir, 96, index), dir_item(name, location, dir_type))
+    }
+
+    /// Top level: /etc (257) holding /etc/hosts (258), and subvolume "vol"
+    /// (256) holding "file" (257 in its own tree).
+    fn test_fs() -> Btrfs {
+        let root_items = [
+            (Key::new(5, 132, 0), root_item(block(1), 0)),
+            (Key::new(256, 132, 0), root_item(block(2), 0)),
+        ];
+
+        let mut top = [
+            (Key::new(256, 1, 0), inode(0o40755, 0)),
+            (dir_key(256, "etc"), dir_item("etc", Key::new(257, 1, 0), 2)),
+            (
+                dir_key(256, "vol"),
+                dir_item("vol", Key::new(256, 132, u64::MAX), 2),
+            ),
+            (Key::new(257, 1, 0), inode(0o40755, 0)),
+            (
+                dir_key(257, "hosts"),
+                dir_item("hosts", Key::new(258, 1, 0), 1),
+            ),
+            (Key::new(258, 1, 0), inode(0o100644, 42)),
+            dir_index(256, 2, "etc", Key::new(257, 1, 0), 2),
+            dir_index(256, 3, "vol", Key::new(256, 132, u64::MAX), 2),
+            dir_index(257, 2, "hosts", Key::new(258, 1, 0), 1),
+            inode_ref(257, 256, 2, "etc"),
+            inode_ref(258, 257, 2, "hosts"),
+            inode_ref(258, 256, 4, "hosts.lnk"),
+            inode_extref(258, 256, 5, "hosts.bak"),
+        ];
+        top.sort_by_key(|(key, _)| *key);
+
+        let vol = [
+            (Key::new(256, 1, 0), inode(0o40755, 0)),
+            (
+                dir_key(256, "file"),
+                dir_item("file", Key::new(257, 1, 0), 1),
+            ),
+            dir_index(256, 2, "file", Key::new(257, 1, 0), 1),
+            (Key::new(257, 1, 0), inode(0o100600, 7)),
+        ];
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(block(0), &leaf(block(0), 1, &root_items))
+            .put_block(block(1), &leaf(block(1), 5, &top))
+            .put_block(block(2), &leaf(block(2), 256, &vol));
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    fn inline_extent(data: &[u8], compression: u8) -> Vec<u8> {
+        let mut bytes = [6u64, data.len() as u64]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        bytes.extend_from_slice(&[compression, 0, 0, 0, FileExtentItem::INLINE]);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn disk_extent(extent_type: u8, disk_bytenr: u64, offset: u64, num_bytes: u64) -> Vec<u8> {
+        let mut bytes = [6u64, num_bytes]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        bytes.extend_from_slice(&[0, 0, 0, 0, extent_type]);
+        for value in [disk_bytenr, offset + num_bytes, offset, num_bytes] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Files directly in the top-level tree: 257 is a 10000 byte file of
+    /// one data extent, an explicit hole, a preallocated range and an
+    /// implicit hole; 258 is inline; 259 has a zlib-compressed extent; 260
+    /// starts with a hole left implicit as under `NO_HOLES`.
+    fn files_fs() -> Btrfs {
+        let data_at = block(8);
+        let data: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        let mut files = [
+            (Key::new(257, 1, 0), inode(0o100644, 10000)),
+            (Key::new(258, 1, 0), inode(0o100644, 5)),
+            (Key::new(260, 1, 0), inode(0o100644, 12288)),
+            (
+                Key::new(260, 108, 8192),
+                disk_extent(FileExtentItem::REGULAR, data_at, 0, 4096),
+            ),
+            (
+                Key::new(257, 108, 0),
+                disk_extent(FileExtentItem::REGULAR, data_at, 1024, 4096),
+            ),
+            (
+                Key::new(257, 108, 4096),
+                disk_extent(FileExtentItem::REGULAR, 0, 0, 2048),
+            ),
+            (
+                Key::new(257, 108, 6144),
+                disk_extent(FileExtentItem::PREALLOC, data_at, 0, 2048),
+            ),
+            (Key::new(258, 108, 0), inline_extent(b"hello", 0)),
+            (Key::new(259, 108, 0), inline_extent(b"x\x9c", 1)),
+        ];
+        files.sort_by_key(|(key, _)| *key);
+
+        let mut image = top_level_image(&files);
+        image.put_block(data_at, &data);
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    fn file_inode(size: u64) -> InodeItem {
+        InodeItem::from_bytes(&inode(0o100644, size)).unwrap()
+    }
+
+    #[test]
+    fn reads_regular_extents_and_holes() {
+        let fs = files_fs();
+        let contents = fs
+            .read_file(5, &file_inode(10000), &Key::new(257, 1, 0))
+            .unwrap();
+        assert_eq!(contents.len(), 10000);
+        let expected: Vec<u8> = (1024..5120u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(&contents[..4096], &expected[..]);
+        assert!(contents[4096..].iter().all(|&b| b == 0));
+
+        // A size below the end of the extents truncates.
+        let head = fs
+            .read_file(5, &file_inode(100), &Key::new(257, 1, 0))
+            .unwrap();
+        assert_eq!(head, &expected[..100]);
+    }
+
+    #[test]
+    fn reads_files_on_raid0_data_chunks() {
+        // Two 64KiB-striped devices, both backed by the one image: runs
+        // alternate between 40MiB and 48MiB on it.
+        let data_chunk = 1u64 << 30;
+        let chunk = test_chunk(0x9, &[(1, 40 << 20), (1, 48 << 20)]);
+
+        let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 241) as u8).collect();
+        let extent = disk_extent(FileExtentItem::REGULAR, data_chunk, 4096, 200_000);
+        let files = [
+            (Key::new(257, 1, 0), inode(0o100644, 200_000)),
+            (Key::new(257, 108, 0), extent),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(
+                block(0),
+                &leaf(
+                    block(0),
+                    1,
+                    &[(Key::new(5, 132, 0), root_item(block(2), 0))],
+                ),
+            )
+            .put_block(
+                block(1),
+                &leaf(
+                    block(1),
+                    3,
+                    &[(Key::new(256, 228, data_chunk), chunk_item(&chunk))],
+                ),
+            )
+            .put_block(block(2), &leaf(block(2), 5, &files));
+        // Lay the extent out run by run, starting 4KiB into the first run.
+        let mut on_disk = vec![0u8; 4096];
+        on_disk.extend_from_slice(&contents);
+        for (run, bytes) in on_disk.chunks(65536).enumerate() {
+            let device = [40u64 << 20, 48 << 20][run % 2];
+            image.put_physical(device + (run as u64 / 2) * 65536, bytes);
+        }
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+
+        let read = fs
+            .read_file(5, &file_inode(200_000), &Key::new(257, 1, 0))
+            .unwrap();
+        assert!(read == contents);
+    }
+
+    #[test]
+    fn maps_extents_and_holes() {
+        let fs = files_fs();
+        let data_at = block(8);
+        let mapping = |logical_file_offset, length, kind| ExtentMapping {
+            logical_file_offset,
+            length,
+            kind,
+        };
+        assert_eq!(
+            fs.extents(5, &Key::new(257, 1, 0)).unwrap(),
+            [
+                mapping(
+                    0,
+                    4096,
+                    ExtentMappingKind::Regular {
+                        disk_bytenr: data_at,
+                        disk_num_bytes: 5120,
+                        offset: 1024,
+                        compression: Compression::None,
+                    }
+                ),
+                mapping(4096, 2048, ExtentMappingKind::Hole),
+                mapping(
+                    6144,
+                    2048,
+                    ExtentMappingKind::Prealloc {
+                        disk_bytenr: data_at,
+                        offset: 0,
+                    }
+                ),
+                mapping(8192, 1808, ExtentMappingKind::Hole),
+            ]
+        );
+        assert_eq!(
+            fs.extents(5, &Key::new(258, 1, 0)).unwrap(),
+            [mapping(0, 5, ExtentMappingKind::Inline)]
+        );
+        // NO_HOLES: the gap before the only extent is a hole too.
+        assert_eq!(
+            fs.extents(5, &Key::new(260, 1, 0)).unwrap(),
+            [
+                mapping(0, 8192, ExtentMappingKind::Hole),
+                mapping(
+                    8192,
+                    4096,
+                    ExtentMappingKind::Regular {
+                        disk_bytenr: data_at,
+                        disk_num_bytes: 4096,
+                        offset: 0,
+                        compression: Compression::None,
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn prealloc_reads_as_zeros_but_maps_as_prealloc() {
+        // The disk blocks behind 257's prealloc extent hold non-zero bytes.
+        let fs = files_fs();
+        let stale = fs.read_logical(block(8), 2048).unwrap();
+        assert!(stale.iter().any(|&b| b != 0));
+
+        let contents = fs
+            .read_file(5, &file_inode(10000), &Key::new(257, 1, 0))
+            .unwrap();
+        assert!(contents[6144..8192].iter().all(|&b| b == 0));
+
+        let extents = fs.extents(5, &Key::new(257, 1, 0)).unwrap();
+        assert_eq!(extents[2].logical_file_offset, 6144);
+        assert!(matches!(
+            extents[2].kind,
+            ExtentMappingKind::Prealloc { .. }
+        ));
+    }
+
+    #[test]
+    fn counts_distinct_and_shared_extents() {
+        let (a, b, c) = (1 << 30, (1 << 30) + 8192, (1 << 30) + 12288);
+        let mut halves = [
+            disk_extent(FileExtentItem::REGULAR, a, 0, 4096),
+            disk_extent(FileExtentItem::REGULAR, a, 4096, 4096),
+        ];
+        for half in &mut halves {
+            put_u64(half, 29, 8192);
+        }
+        let [front, back] = halves;
+        let files = [
+            (Key::new(257, 1, 0), inode(0o100644, 20000)),
+            (Key::new(257, 108, 0), front),
+            (
+                Key::new(257, 108, 4096),
+                disk_extent(FileExtentItem::REGULAR, 0, 0, 4096),
+            ),
+            (Key::new(257, 108, 8192), back),
+            (
+                Key::new(257, 108, 12288),
+                disk_extent(FileExtentItem::REGULAR, b, 0, 4096),
+            ),
+            (
+                Key::new(257, 108, 16384),
+                disk_extent(FileExtentItem::PREALLOC, c, 0, 4096),
+            ),
+            (Key::new(258, 1, 0), inode(0o100644, 5)),
+            (Key::new(258, 108, 0), inline_extent(b"hello", 0)),
+        ];
+        // `a` is only referenced by the two halves of 257 using it, while
+        // `b` is reflinked from elsewhere too.
+        let data_extent = |refs: u64| -> Vec<u8> {
+            [refs, 6, 1]
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect()
+        };
+        let extents = [
+            (Key::new(a, 168, 8192), data_extent(2)),
+            (Key::new(b, 168, 4096), data_extent(3)),
+            (Key::new(c, 168, 4096), data_extent(1)),
+        ];
+        let roots = [
+            (Key::new(2, 132, 0), root_item(block(2), 0)),
+            (Key::new(5, 132, 0), root_item(block(1), 0)),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(block(0), &leaf(block(0), 1, &roots))
+            .put_block(block(1), &leaf(block(1), 5, &files))
+            .put_block(block(2), &leaf(block(2), 2, &extents));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        assert_eq!(
+            fs.disk_usage(5, &Key::new(257, 1, 0)).unwrap(),
+            DiskUsage {
+                logical_size: 20000,
+                physical_allocated: 8192 + 4096 + 4096,
+                shared: 4096,
+            }
+        );
+        let inline_item = FileExtentItem::HEADER_SIZE as u64 + 5;
+        assert_eq!(
+            fs.disk_usage(5, &Key::new(258, 1, 0)).unwrap(),
+            DiskUsage {
+                logical_size: 5,
+                physical_allocated: inline_item,
+                shared: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn reads_inline_extents() {
+        let fs = files_fs();
+        let contents = fs
+            .read_file(5, &file_inode(5), &Key::new(258, 1, 0))
+            .unwrap();
+        assert_eq!(contents, b"hello");
+
+        #[cfg(not(feature = "zlib"))]
+        assert!(matches!(
+            fs.read_file(5, &file_inode(2), &Key::new(259, 1, 0)),
+            Err(BtrfsError::Unsupported(_))
+        ));
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn reads_compressed_extents() {
+        use std::io::Write;
+
+        let zlib = |data: &[u8]| {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let data: Vec<u8> = (0..12000u32).map(|i| (i / 5 % 256) as u8).collect();
+        let mut compressed = zlib(&data);
+        compressed.resize(compressed.len().next_multiple_of(4096), 0);
+
+        // The file references 8192 bytes from offset 1000 of the extent.
+        let mut regular = [6u64, 12000]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        regular.extend_from_slice(&[1, 0, 0, 0, FileExtentItem::REGULAR]);
+        for value in [block(8), compressed.len() as u64, 1000, 8192] {
+            regular.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut inline = inline_extent(&zlib(b"hello hello hello"), 1);
+        inline[8..16].copy_from_slice(&17u64.to_le_bytes());
+        // The same extent from an offset that overflows when added to.
+        let mut past_end = regular.clone();
+        past_end[37..45].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+
+        let files = [
+            (Key::new(257, 108, 0), regular),
+            (Key::new(258, 108, 0), inline),
+            (Key::new(259, 108, 0), past_end),
+        ];
+        let mut image = top_level_image(&files);
+        image.put_block(block(8), &compressed);
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let contents = fs
+            .read_file(5, &file_inode(8192), &Key::new(257, 1, 0))
+            .unwrap();
+        assert_eq!(contents, &data[1000..9192]);
+        let contents = fs
+            .read_file(5, &file_inode(17), &Key::new(258, 1, 0))
+            .unwrap();
+        assert_eq!(contents, b"hello hello hello");
+        assert!(matches!(
+            fs.read_file(5, &file_inode(8192), &Key::new(259, 1, 0)),
+            Err(BtrfsError::Malformed(
+                "file extent range exceeds its extent"
+            ))
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn reads_zstd_compressed_inline_extents() {
+        // A small compressed file keeps its only extent inline, compressed
+        // with no padding, and `ram_bytes` gives the decompressed length.
+        let data = b"inline and compressed\n".repeat(40);
+        let len = data.len() as u64;
+        let mut inline = inline_extent(&zstd::encode_all(&data[..], 3).unwrap(), 3);
+        inline[8..16].copy_from_slice(&len.to_le_bytes());
+        assert!(inline.len() < data.len());
+
+        let files = [
+            (Key::new(257, 1, 0), inode(0o100644, len)),
+            (Key::new(257, 108, 0), inline),
+        ];
+        let fs = top_level_fs(&files);
+
+        let key = Key::new(257, 1, 0);
+        assert_eq!(fs.read_file(5, &file_inode(len), &key).unwrap(), data);
+        assert_eq!(
+            fs.extents(5, &key).unwrap(),
+            [ExtentMapping {
+                logical_file_offset: 0,
+                length: len,
+                kind: ExtentMappingKind::Inline,
+            }]
+        );
+        // A file truncated after the extent was written reads short.
+        assert_eq!(
+            fs.read_file(5, &file_inode(100), &key).unwrap(),
+            &data[..100]
+        );
+    }
+
+    #[test]
+    fn reads_symlink_targets() {
+        let fs = files_fs();
+        let symlink = InodeItem::from_bytes(&inode(0o120777, 5)).unwrap();
+        let target = fs.read_symlink(5, &symlink, &Key::new(258, 1, 0)).unwrap();
+        assert_eq!(target, PathBuf::from("hello"));
+
+        assert!(matches!(
+            fs.read_symlink(5, &file_inode(5), &Key::new(258, 1, 0)),
+            Err(BtrfsError::NotASymlink)
+        ));
+        assert!(matches!(
+            fs.read_symlink(5, &symlink, &Key::new(257, 1, 0)),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn lists_hard_link_names() {
+        let fs = test_fs();
+        let name_ref = |parent_dir, name: &str, index| InodeRef {
+            parent_dir,
+            name: name.into(),
+            index,
+        };
+        assert_eq!(
+            fs.inode_refs(5, &Key::new(258, 1, 0)).unwrap(),
+            [
+                name_ref(256, "hosts.lnk", 4),
+                name_ref(257, "hosts", 2),
+                name_ref(256, "hosts.bak", 5),
+            ]
+        );
+        assert_eq!(
+            fs.inode_refs(5, &Key::new(257, 1, 0)).unwrap(),
+            [name_ref(256, "etc", 2)]
+        );
+        assert!(fs.inode_refs(5, &Key::new(256, 1, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resolves_paths_from_inode_numbers() {
+        let fs = test_fs();
+        assert_eq!(fs.inode_path(5, 258).unwrap(), PathBuf::from("etc/hosts"));
+        assert_eq!(fs.inode_path(5, 257).unwrap(), PathBuf::from("etc"));
+        assert_eq!(fs.inode_path(5, 256).unwrap(), PathBuf::new());
+        assert!(matches!(
+            fs.inode_path(256, 257),
+            Err(BtrfsError::Orphan { ino: 257 })
+        ));
+        assert!(matches!(fs.inode_path(5, 300), Err(BtrfsError::NotFound)));
+    }
+
+    #[test]
+    fn resolves_every_hard_link() {
+        let fs = test_fs();
+        assert_eq!(
+            fs.resolve_inode(5, 258).unwrap(),
+            [
+                PathBuf::from("etc/hosts"),
+                PathBuf::from("hosts.bak"),
+                PathBuf::from("hosts.lnk")
+            ]
+        );
+        assert_eq!(fs.resolve_inode(5, 257).unwrap(), [PathBuf::from("etc")]);
+        assert_eq!(fs.resolve_inode(5, 256).unwrap(), [PathBuf::new()]);
+        assert!(matches!(
+            fs.resolve_inode(256, 257),
+            Err(BtrfsError::Orphan { ino: 257 })
+        ));
+        assert!(matches!(
+            fs.resolve_inode(5, 300),
+            Err(BtrfsError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn lists_directories_in_index_order() {
+        let mut items = [
+            (Key::new(256, 1, 0), inode(0o40755, 0)),
+            (dir_key(256, "b"), dir_item("b", Key::new(257, 1, 0), 1)),
+            (dir_key(256, "a"), dir_item("a", Key::new(258, 1, 0), 2)),
+            (Key::new(256, 96, 2), dir_item("b", Key::new(257, 1, 0), 1)),
+            (Key::new(256, 96, 3), dir_item("a", Key::new(258, 1, 0), 2)),
+            (
+                Key::new(256, 96, 4),
+                dir_item("vol", Key::new(256, 132, u64::MAX), 2),
+            ),
+            (Key::new(258, 96, 2), dir_item("c", Key::new(259, 1, 0), 7)),
+        ];
+        items.sort_by_key(|(key, _)| *key);
+
+        let fs = top_level_fs(&items);
+
+        let entries = fs.readdir(5, &Key::new(256, 1, 0)).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["b", "a", "vol"]);
+        assert_eq!(
+            entries[1],
+            DirEntry {
+                name: "a".into(),
+                inode: Key::new(258, 1, 0),
+                entry_type: DirEntryType::Dir,
+            }
+        );
+        assert_eq!(entries[2].inode.item_type(), KeyType::RootItem);
+
+        let entries = fs.readdir(5, &Key::new(258, 1, 0)).unwrap();
+        assert_eq!(entries[0].entry_type, DirEntryType::Symlink);
+        assert!(fs.readdir(5, &Key::new(259, 1, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_verity_descriptors() {
+        let mut verity_inode = inode(0o100644, 10000);
+        verity_inode[64..72].copy_from_slice(&(1u64 << 32).to_le_bytes());
+        let mut descriptor = vec![0u8; VerityInfo::DESCRIPTOR_SIZE];
+        descriptor[..4].copy_from_slice(&[1, 2, 12, 0]);
+        descriptor[16..80].fill(0x5a);
+        let mut desc_item = vec![0u8; VerityDescriptorItem::SIZE];
+        desc_item[..8].copy_from_slice(&256u64.to_le_bytes());
+        let items = [
+            (Key::new(257, 1, 0), inode(0o100644, 10000)),
+            (Key::new(258, 1, 0), verity_inode),
+            (Key::new(258, 36, 0), desc_item),
+            (Key::new(258, 36, 1), descriptor[..200].to_vec()),
+            (Key::new(258, 36, 201), descriptor[200..].to_vec()),
+            (Key::new(258, 37, 0), vec![1; 64]),
+            (Key::new(258, 37, 64), vec![2; 64]),
+        ];
+        let fs = top_level_fs(&items);
+
+        assert_eq!(fs.verity_info(5, &Key::new(257, 1, 0)).unwrap(), None);
+        let info = fs.verity_info(5, &Key::new(258, 1, 0)).unwrap().unwrap();
+        assert_eq!(info.hash_algorithm, VerityHashAlgorithm::Sha512);
+        assert_eq!(info.block_size, 4096);
+        assert_eq!(info.root_hash, Some(vec![0x5a; 64]));
+        assert!(info.salt.is_empty() && info.signature.is_empty());
+
+        let merkle = fs.verity_merkle_tree(5, &Key::new(258, 1, 0)).unwrap();
+        assert_eq!(merkle.len(), 128);
+        assert_eq!((merkle[63], merkle[64]), (1, 2));
+    }
+
+    fn walk_paths(walk: Walk<'_>) -> Vec<(String, u64)> {
+        walk.map(|item| {
+            let (path, inode) = item.unwrap();
+            (path.to_str().unwrap().to_owned(), inode.size)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn walks_subvolumes() {
+        let fs = test_fs();
+        assert_eq!(
+            walk_paths(fs.walk(5)),
+            [("etc".into(), 0), ("etc/hosts".into(), 42)]
+        );
+        assert_eq!(
+            walk_paths(fs.walk(5).cross_subvolumes(true)),
+            [
+                ("etc".into(), 0),
+                ("etc/hosts".into(), 42),
+                ("vol".into(), 0),
+                ("vol/file".into(), 7),
+            ]
+        );
+        assert_eq!(walk_paths(fs.walk(256)), [("file".into(), 7)]);
+
+        let mut walk = fs.walk(300);
+        assert!(matches!(walk.next(), Some(Err(BtrfsError::NotFound))));
+        assert!(walk.next().is_none());
+    }
+
+    #[test]
+    fn walk_enters_each_directory_once() {
+        let mut items = [
+            (Key::new(256, 1, 0), inode(0o40755, 0)),
+            dir_index(256, 2, "a", Key::new(257, 1, 0), 2),
+            (Key::new(257, 1, 0), inode(0o40755, 0)),
+            dir_index(257, 2, "up", Key::new(256, 1, 0), 2),
+            dir_index(257, 3, "self", Key::new(257, 1, 0), 2),
+        ];
+        items.sort_by_key(|(key, _)| *key);
+
+        let fs = top_level_fs(&items);
+        assert_eq!(walk_paths(fs.walk(5)), [("a".into(), 0)]);
+    }
+
+    #[test]
+    fn lists_xattrs() {
+        let xattr = |name: &str, value: &[u8]| {
+            let mut bytes = dir_item(name, Key::new(0, 0, 0), 8);
+            bytes[25..27].copy_from_slice(&(value.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(value);
+            bytes
+        };
+        let mut collided = xattr("user.b", b"\xff\x00");
+        collided.extend(xattr("user.c", b"3"));
+        let mut items = [
+            (
+                Key::new(257, 24, name_hash(b"user.a")),
+                xattr("user.a", b"1"),
+            ),
+            (Key::new(257, 24, name_hash(b"user.b")), collided),
+            (
+                Key::new(258, 24, name_hash(b"user.z")),
+                xattr("user.z", b"z"),
+            ),
+        ];
+        items.sort_by_key(|(key, _)| *key);
+
+        let fs = top_level_fs(&items);
+
+        let mut xattrs = fs.xattrs(5, &Key::new(257, 1, 0)).unwrap();
+        xattrs.sort();
+        assert_eq!(
+            xattrs,
+            [
+                ("user.a".to_string(), b"1".to_vec()),
+                ("user.b".to_string(), b"\xff\x00".to_vec()),
+                ("user.c".to_string(), b"3".to_vec()),
+            ]
+        );
+        assert!(fs.xattrs(5, &Key::new(259, 1, 0)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resolves_paths() {
+        let fs = test_fs();
+        assert!(fs.lookup_path(5, "/").unwrap().is_dir());
+        assert!(fs.lookup_path(5, "etc").unwrap().is_dir());
+
+        let hosts = fs.lookup_path(5, "/etc/./hosts").unwrap();
+        assert!(hosts.is_regular_file());
+        assert_eq!(hosts.size, 42);
+    }
+
+    #[test]
+    fn resolves_names_sharing_a_dir_item() {
+        // The first two names have the same hash, so one item holds both.
+        // The third sits with a name whose lossy conversion it equals.
+        let colliding = ["file1371838", "file2000402"];
+        assert_eq!(
+            name_hash(colliding[0].as_bytes()),
+            name_hash(colliding[1].as_bytes())
+        );
+        let mut packed = dir_item(colliding[0], Key::new(257, 1, 0), 1);
+        packed.extend(dir_item(colliding[1], Key::new(258, 1, 0), 1));
+        let mut lossy = dir_item("x\u{fffd}", Key::new(259, 1, 0), 1);
+        let mut invalid = dir_item("x\u{fffd}", Key::new(260, 1, 0), 1);
+        invalid.truncate(DirItemHeader::SIZE);
+        invalid[27..29].copy_from_slice(&2u16.to_le_bytes());
+        invalid.extend_from_slice(b"x\xff");
+        invalid.append(&mut lossy);
+
+        let mut items = vec![
+            (Key::new(256, 1, 0), inode(0o40755, 0)),
+            (dir_key(256, colliding[0]), packed),
+            (dir_key(256, "x\u{fffd}"), invalid),
+            (Key::new(257, 1, 0), inode(0o100644, 1)),
+            (Key::new(258, 1, 0), inode(0o100644, 2)),
+            (Key::new(259, 1, 0), inode(0o100644, 3)),
+            (Key::new(260, 1, 0), inode(0o100644, 4)),
+        ];
+        items.sort_by_key(|(key, _)| *key);
+        let fs = top_level_fs(&items);
+
+        let size = |path| fs.lookup_path(5, path).unwrap().size;
+        assert_eq!(size(colliding[0]), 1);
+        assert_eq!(size(colliding[1]), 2);
+        assert_eq!(size("x\u{fffd}"), 3);
+        assert!(matches!(
+            fs.lookup_path(5, "file1371839"),
+            Err(BtrfsError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn caches_directory_entries() {
+        let fs = test_fs().with_dir_cache_capacity(16);
+        let stats = |hits, misses| CacheStats { hits, misses };
+
+        assert_eq!(fs.lookup_path(5, "etc/hosts").unwrap().size, 42);
+        assert_eq!(fs.dir_cache_stats(), stats(0, 2));
+        assert_eq!(fs.lookup_path(5, "/etc/hosts").unwrap().size, 42);
+        assert_eq!(fs.dir_cache_stats(), stats(2, 2));
+        // Entries are keyed by subvolume too: both root directories are 256.
+        assert_eq!(fs.lookup_path(5, "vol/file").unwrap().size, 7);
+        assert_eq!(fs.dir_cache_stats(), stats(2, 4));
+        assert_eq!(fs.lookup_path(256, "file").unwrap().size, 7);
+        assert_eq!(fs.dir_cache_stats(), stats(3, 4));
+        assert!(fs.lookup_path(256, "etc").is_err());
+        assert_eq!(fs.dir_cache_stats(), stats(3, 5));
+
+        fs.clear_dir_cache();
+        fs.readdir(5, &Key::new(257, 1, 0)).unwrap();
+        fs.lookup_path(5, "etc/hosts").unwrap();
+        assert_eq!(fs.dir_cache_stats(), stats(4, 6));
+    }
+
+    #[test]
+    fn crosses_into_subvolumes() {
+        let fs = test_fs();
+        assert_eq!(fs.lookup_path(5, "vol/file").unwrap().size, 7);
+        assert_eq!(fs.lookup_path(256, "file").unwrap().size, 7);
+    }
+
+    #[test]
+    fn missing_components_are_not_found() {
+        let fs = test_fs();
+        for path in ["nope", "etc/nope", "etc/hosts/deeper", "vol/hosts"] {
+            assert!(
+                matches!(fs.lookup_path(5, path), Err(BtrfsError::NotFound)),
+                "{path}"
+            );
+        }
+        assert!(matches!(
+            fs.lookup_path(300, "file"),
+            Err(BtrfsError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,352 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use binrw::io::Cursor;
+use binrw::BinRead;
+
+use crate::error::{parse_name, BtrfsError, ParseEnumError};
+use crate::features::IncompatFlags;
+
+/// `btrfs_file_extent_item`: one extent of a file's data, keyed by its offset
+/// in the file.
+///
+/// Inline extents carry their (possibly compressed) data in the item itself
+/// and have no disk fields; those read as zero.
+#[derive(BinRead, Debug, Clone, PartialEq, Eq)]
+#[br(little)]
+pub struct FileExtentItem {
+    pub generation: u64,
+    /// Size of the extent once decompressed.
+    pub ram_bytes: u64,
+    pub compression: u8,
+    pub encryption: u8,
+    pub other_encoding: u16,
+    pub extent_type: u8,
+    /// Logical address of the extent on disk; zero for a hole.
+    #[br(if(extent_type != FileExtentItem::INLINE))]
+    pub disk_bytenr: u64,
+    #[br(if(extent_type != FileExtentItem::INLINE))]
+    pub disk_num_bytes: u64,
+    /// Offset into the (decompressed) disk extent where this file range starts.
+    #[br(if(extent_type != FileExtentItem::INLINE))]
+    pub offset: u64,
+    #[br(if(extent_type != FileExtentItem::INLINE))]
+    pub num_bytes: u64,
+    #[br(ignore)]
+    inline: Vec<u8>,
+}
+
+/// Compression algorithm of a file extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Compression {
+    None = 0,
+    Zlib = 1,
+    Lzo = 2,
+    Zstd = 3,
+    Unknown(u8),
+}
+
+impl Compression {
+    pub fn from_u8(value: u8) -> Compression {
+        match value {
+            0 => Compression::None,
+            1 => Compression::Zlib,
+            2 => Compression::Lzo,
+            3 => Compression::Zstd,
+            other => Compression::Unknown(other),
+        }
+    }
+
+    /// The name used by the `compress` mount option.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zlib => "zlib",
+            Compression::Lzo => "lzo",
+            Compression::Zstd => "zstd",
+            Compression::Unknown(_) => "unknown",
+        }
+    }
+
+    /// The incompat flag a filesystem must set before extents may use this
+    /// algorithm. zlib predates the flags and needs none.
+    pub fn incompat_flag(&self) -> Option<IncompatFlags> {
+        match self {
+            Compression::Lzo => Some(IncompatFlags::COMPRESS_LZO),
+            Compression::Zstd => Some(IncompatFlags::COMPRESS_ZSTD),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Unknown(value) => write!(f, "unknown({value})"),
+            known => f.write_str(known.as_str()),
+        }
+    }
+}
+
+/// Parses a `compress=` mount option value, in any case: `zlib`, `lzo` or
+/// `zstd`, or `none`, `no` or `off`. zlib and zstd may carry a level, as
+/// in `zlib:9` or `zstd:-3`, which is checked to be a number and dropped.
+impl FromStr for Compression {
+    type Err = ParseEnumError;
+
+    fn from_str(s: &str) -> Result<Compression, ParseEnumError> {
+        const NAMES: [(&str, Compression); 6] = [
+            ("zlib", Compression::Zlib),
+            ("lzo", Compression::Lzo),
+            ("zstd", Compression::Zstd),
+            ("none", Compression::None),
+            ("no", Compression::None),
+            ("off", Compression::None),
+        ];
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        let compression = parse_name("compression", &NAMES, name)
+            .map_err(|_| ParseEnumError::new("compression", s))?;
+        match (compression, level) {
+            (_, None) => Ok(compression),
+            (Compression::Zlib | Compression::Zstd, Some(level))
+                if level.parse::<i32>().is_ok() =>
+            {
+                Ok(compression)
+            }
+            _ => Err(ParseEnumError::new("compression", s)),
+        }
+    }
+}
+
+/// The type of a file extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileExtentKind {
+    /// The data follows the item header in the leaf.
+    Inline,
+    /// The data is in a disk extent, or the extent is an explicit hole if
+    /// its `disk_bytenr` is zero.
+    Regular,
+    /// Space reserved by `fallocate` and not written since. The disk extent
+    /// holds whatever was there before, so the range must read as zeros;
+    /// only a write turns it into a regular extent.
+    Prealloc,
+}
+
+/// Where the bytes of a file extent are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileExtentData<'a> {
+    Inline(&'a [u8]),
+    Disk {
+        disk_bytenr: u64,
+        offset: u64,
+        num_bytes: u64,
+    },
+}
+
+/// A range of a file and what backs it, as listed by `Btrfs::extents`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentMapping {
+    pub logical_file_offset: u64,
+    pub length: u64,
+    pub kind: ExtentMappingKind,
+}
+
+/// What backs an [`ExtentMapping`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentMappingKind {
+    /// No data is stored; the range reads as zeros.
+    Hole,
+    /// The data is stored in the extent item itself.
+    Inline,
+    /// `length` bytes at `offset` into the (decompressed) extent of
+    /// `disk_num_bytes` bytes at logical address `disk_bytenr`.
+    Regular {
+        disk_bytenr: u64,
+        disk_num_bytes: u64,
+        offset: u64,
+        compression: Compression,
+    },
+    /// Allocated but never written; reads as zeros.
+    Prealloc { disk_bytenr: u64, offset: u64 },
+}
+
+/// How much disk space a file takes, as returned by `Btrfs::disk_usage`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// The inode's size.
+    pub logical_size: u64,
+    /// The full on-disk size of each extent the file refers to, counted
+    /// once, and the size of its inline extent items.
+    pub physical_allocated: u64,
+    /// The part of `physical_allocated` in extents that something other
+    /// than this file refers to as well.
+    pub shared: u64,
+}
+
+impl FileExtentItem {
+    pub const INLINE: u8 = 0;
+    pub const REGULAR: u8 = 1;
+    pub const PREALLOC: u8 = 2;
+
+    /// Size of the fields shared by every extent type; inline data follows.
+    pub const HEADER_SIZE: usize = 21;
+    /// Size of a regular or preallocated extent item.
+    pub const SIZE: usize = 53;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<FileExtentItem, BtrfsError> {
+        if bytes.len() < FileExtentItem::HEADER_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: FileExtentItem::HEADER_SIZE,
+                got: bytes.len(),
+            });
+        }
+        match bytes[20] {
+            FileExtentItem::INLINE => {
+                let mut item = FileExtentItem::read(&mut Cursor::new(bytes))?;
+                item.inline = bytes[FileExtentItem::HEADER_SIZE..].to_vec();
+                Ok(item)
+            }
+            FileExtentItem::REGULAR | FileExtentItem::PREALLOC => {
+                if bytes.len() < FileExtentItem::SIZE {
+                    return Err(BtrfsError::ShortRead {
+                        expected: FileExtentItem::SIZE,
+                        got: bytes.len(),
+                    });
+                }
+                Ok(FileExtentItem::read(&mut Cursor::new(bytes))?)
+            }
+            _ => Err(BtrfsError::Malformed("unknown file extent type")),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        Compression::from_u8(self.compression)
+    }
+
+    /// The extent type. [`FileExtentItem::from_bytes`] rejects types other
+    /// than the three known ones.
+    pub fn kind(&self) -> FileExtentKind {
+        match self.extent_type {
+            FileExtentItem::INLINE => FileExtentKind::Inline,
+            FileExtentItem::PREALLOC => FileExtentKind::Prealloc,
+            _ => FileExtentKind::Regular,
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.extent_type == FileExtentItem::INLINE
+    }
+
+    pub fn is_prealloc(&self) -> bool {
+        self.extent_type == FileExtentItem::PREALLOC
+    }
+
+    /// The inline bytes, or the disk range backing this extent.
+    pub fn data(&self) -> FileExtentData<'_> {
+        if self.is_inline() {
+            FileExtentData::Inline(&self.inline)
+        } else {
+            FileExtentData::Disk {
+                disk_bytenr: self.disk_bytenr,
+                offset: self.offset,
+                num_bytes: self.num_bytes,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mount_option_spellings() {
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd));
+        assert_eq!("ZLIB:9".parse(), Ok(Compression::Zlib));
+        assert_eq!("zstd:-3".parse(), Ok(Compression::Zstd));
+        assert_eq!("off".parse(), Ok(Compression::None));
+        for bad in ["zlib:", "zlib:high", "lzo:1", "none:1", "brotli"] {
+            let err = bad.parse::<Compression>().unwrap_err();
+            assert_eq!(err.input(), bad);
+        }
+        assert_eq!(Compression::Lzo.to_string(), "lzo");
+    }
+
+    fn header(extent_type: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&6u64.to_le_bytes());
+        bytes.extend_from_slice(&5u64.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0, extent_type]);
+        bytes
+    }
+
+    #[test]
+    fn parses_inline_extent() {
+        let mut bytes = header(FileExtentItem::INLINE);
+        bytes.extend_from_slice(b"hello");
+
+        let item = FileExtentItem::from_bytes(&bytes).unwrap();
+        assert!(item.is_inline());
+        assert_eq!(item.kind(), FileExtentKind::Inline);
+        assert_eq!(item.ram_bytes, 5);
+        assert_eq!(item.disk_bytenr, 0);
+        assert_eq!(item.data(), FileExtentData::Inline(b"hello"));
+    }
+
+    #[test]
+    fn decodes_compression() {
+        let mut bytes = header(FileExtentItem::INLINE);
+        bytes[16] = 3;
+        let item = FileExtentItem::from_bytes(&bytes).unwrap();
+        assert_eq!(item.compression(), Compression::Zstd);
+        assert_eq!(item.compression().as_str(), "zstd");
+        assert_eq!(
+            item.compression().incompat_flag(),
+            Some(IncompatFlags::COMPRESS_ZSTD)
+        );
+
+        assert_eq!(Compression::from_u8(0), Compression::None);
+        assert_eq!(Compression::from_u8(9), Compression::Unknown(9));
+        assert_eq!(Compression::Zlib.incompat_flag(), None);
+    }
+
+    #[test]
+    fn parses_regular_extent() {
+        let mut bytes = header(FileExtentItem::REGULAR);
+        for value in [13631488u64, 4096, 0, 4096] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let item = FileExtentItem::from_bytes(&bytes).unwrap();
+        assert!(!item.is_inline());
+        assert_eq!(item.kind(), FileExtentKind::Regular);
+        assert_eq!(item.disk_num_bytes, 4096);
+        assert_eq!(
+            item.data(),
+            FileExtentData::Disk {
+                disk_bytenr: 13631488,
+                offset: 0,
+                num_bytes: 4096
+            }
+        );
+
+        assert!(matches!(
+            FileExtentItem::from_bytes(&bytes[..40]),
+            Err(BtrfsError::ShortRead { expected: 53, .. })
+        ));
+        bytes[20] = FileExtentItem::PREALLOC;
+        let item = FileExtentItem::from_bytes(&bytes).unwrap();
+        assert!(item.is_prealloc());
+        assert_eq!(item.kind(), FileExtentKind::Prealloc);
+
+        assert!(matches!(
+            FileExtentItem::from_bytes(&header(3)),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+}
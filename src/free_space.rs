@@ -0,0 +1,112 @@
+use alloc::vec::Vec;
+
+use bitflags::bitflags;
+
+use crate::error::BtrfsError;
+
+bitflags! {
+    /// Flags of a free space info item.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct FreeSpaceFlags: u32 {
+        /// The block group's free space is stored as bitmaps rather than
+        /// extents.
+        const USING_BITMAPS = 1 << 0;
+
+        const _ = !0;
+    }
+}
+
+/// `btrfs_free_space_info`: the header of one block group's free space in
+/// the free space tree, keyed `(start, FREE_SPACE_INFO, length)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeSpaceInfo {
+    /// Number of free extents, however they are stored.
+    pub extent_count: u32,
+    pub flags: u32,
+}
+
+impl FreeSpaceInfo {
+    pub const SIZE: usize = 8;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<FreeSpaceInfo, BtrfsError> {
+        if bytes.len() < FreeSpaceInfo::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: FreeSpaceInfo::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(FreeSpaceInfo {
+            extent_count: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            flags: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        })
+    }
+
+    pub fn flags(&self) -> FreeSpaceFlags {
+        FreeSpaceFlags::from_bits_retain(self.flags)
+    }
+}
+
+/// Decodes a `FREE_SPACE_BITMAP` item covering the space from `start`: bit
+/// `i`, least significant first within each byte, is set when the sector at
+/// `start + i * sectorsize` is free. Returns the free `(start, length)` runs.
+pub fn free_space_bitmap(start: u64, sectorsize: u64, bitmap: &[u8]) -> Vec<(u64, u64)> {
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    let bits = bitmap
+        .iter()
+        .flat_map(|&byte| (0..8).map(move |bit| byte & (1 << bit) != 0));
+    for (i, free) in bits.enumerate() {
+        if !free {
+            continue;
+        }
+        // Sectors past the end of the address space cannot be free.
+        let Some(sector) = (i as u64)
+            .checked_mul(sectorsize)
+            .and_then(|offset| offset.checked_add(start))
+            .filter(|sector| sector.checked_add(sectorsize).is_some())
+        else {
+            break;
+        };
+        match runs.last_mut() {
+            Some((run_start, len)) if *run_start + *len == sector => *len += sectorsize,
+            _ => runs.push((sector, sectorsize)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_free_space_info() {
+        let info = FreeSpaceInfo::from_bytes(&[3, 0, 0, 0, 1, 0, 0, 0]).unwrap();
+        assert_eq!(info.extent_count, 3);
+        assert_eq!(info.flags(), FreeSpaceFlags::USING_BITMAPS);
+        assert!(matches!(
+            FreeSpaceInfo::from_bytes(&[0; 4]),
+            Err(BtrfsError::ShortRead { expected: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn decodes_bitmap_runs() {
+        // Sectors 0-1, 7-9 and 15 are free.
+        let runs = free_space_bitmap(1 << 20, 4096, &[0b1000_0011, 0b1000_0011]);
+        assert_eq!(
+            runs,
+            [
+                (1 << 20, 2 * 4096),
+                ((1 << 20) + 7 * 4096, 3 * 4096),
+                ((1 << 20) + 15 * 4096, 4096),
+            ]
+        );
+        assert!(free_space_bitmap(0, 4096, &[0, 0]).is_empty());
+        // Sectors past the end of the address space are dropped.
+        let near_end = u64::MAX - 2 * 4096 + 1;
+        assert_eq!(
+            free_space_bitmap(near_end, 4096, &[0xff]),
+            [(near_end, 4096)]
+        );
+    }
+}
@@ -0,0 +1,1229 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::str::Utf8Error;
+use std::sync::{Mutex, PoisonError};
+
+use crate::balance::BalanceStatus;
+use crate::cache::{CacheStats, LruCache};
+use crate::chunk::{BlockGroupType, Chunk, ChunkMap, ChunkRange};
+use crate::device::{BlockDevice, SeekDevice};
+use crate::error::BtrfsError;
+use crate::key::{Key, KeyType};
+use crate::offset_reader::OffsetReader;
+#[cfg(feature = "raid56")]
+use crate::raid56;
+use crate::root::RootItem;
+use crate::superblock::{
+    rewrite_copies, DevItem, ParseOptions, Superblock, Warning, WriteExt, BTRFS_FSID_SIZE,
+    BTRFS_SUPER_INFO_OFFSET, BTRFS_SUPER_INFO_SIZE,
+};
+use crate::tree::{CachedBlock, Tree};
+
+/// Objectid of the balance item in the root tree.
+const BALANCE_OBJECTID: u64 = -4i64 as u64;
+
+/// A seekable byte source a filesystem can be read from.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Geometry of a zoned filesystem, from [`Btrfs::zoned_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZonedInfo {
+    /// Size of one device zone in bytes.
+    pub zone_size: u64,
+}
+
+/// Where the bytes of one device come from.
+enum Device {
+    Block(Box<dyn BlockDevice>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+/// An opened btrfs filesystem, possibly spanning several devices.
+pub struct Btrfs {
+    superblock: Superblock,
+    /// Devices keyed by the devid in each device's superblock.
+    devices: BTreeMap<u64, Device>,
+    /// The fsid of each opened device that belongs to a seed this
+    /// filesystem was sprouted from, by devid.
+    seeds: BTreeMap<u64, [u8; BTRFS_FSID_SIZE]>,
+    chunk_map: ChunkMap,
+    /// Device items from the chunk tree, once loaded.
+    dev_items: BTreeMap<u64, DevItem>,
+    verify_checksums: bool,
+    node_cache: Mutex<LruCache<u64, CachedBlock>>,
+    /// Number of sibling leaves tree iterators read ahead.
+    readahead: usize,
+    /// Whether tree block reads compare header generations with the
+    /// superblock's, and the warnings they gave.
+    warn_future_generations: bool,
+    warnings: Mutex<Vec<Warning>>,
+    /// Locations of directory entries, by subvolume, directory inode and
+    /// name.
+    dir_cache: Mutex<LruCache<DirCacheKey, Key>>,
+}
+
+pub(crate) type DirCacheKey = (u64, u64, String);
+
+impl Btrfs {
+    /// Opens a single-device btrfs image or block device.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Btrfs, BtrfsError> {
+        Btrfs::from_device(File::open(path)?)
+    }
+
+    /// Opens a filesystem that starts `partition_offset` bytes into the image
+    /// or disk at `path`, such as a partition of a whole-disk image. All
+    /// superblock and device offsets are taken relative to that start.
+    pub fn open_with_offset<P: AsRef<Path>>(
+        path: P,
+        partition_offset: u64,
+    ) -> Result<Btrfs, BtrfsError> {
+        Btrfs::from_reader(OffsetReader::new(File::open(path)?, partition_offset)?)
+    }
+
+    /// Opens every device of a multi-device filesystem.
+    pub fn open_devices<P: AsRef<Path>>(paths: &[P]) -> Result<Btrfs, BtrfsError> {
+        let files = paths
+            .iter()
+            .map(|path| Ok(Box::new(File::open(path)?) as Box<dyn BlockDevice>))
+            .collect::<Result<Vec<_>, BtrfsError>>()?;
+        Btrfs::from_block_devices(files)
+    }
+
+    /// Opens a filesystem from any seekable reader, such as an in-memory
+    /// image. The primary superblock must pass its magic, checksum and
+    /// [geometry](Superblock::validate_geometry) checks.
+    ///
+    /// Reads share the reader's cursor, so they are serialized; see
+    /// [`Btrfs::from_device`] for reads from several threads at once.
+    pub fn from_reader<R: Read + Seek + Send + 'static>(reader: R) -> Result<Btrfs, BtrfsError> {
+        Btrfs::from_readers(vec![reader])
+    }
+
+    /// Opens a filesystem from one reader per device. All devices must carry
+    /// the same fsid, and there must be at least `num_devices` of them. The
+    /// superblock with the highest generation is used for the filesystem.
+    ///
+    /// The exception is a sprouted filesystem, whose seed devices keep the
+    /// seed's fsid. Devices with the `SEEDING` flag are taken as its seeds
+    /// if any device without it is given; see [`Btrfs::seed_devids`].
+    pub fn from_readers<R: Read + Seek + Send + 'static>(
+        readers: Vec<R>,
+    ) -> Result<Btrfs, BtrfsError> {
+        let mut devices = Vec::with_capacity(readers.len());
+        for mut reader in readers {
+            let sb = Superblock::from_reader(&mut reader)?;
+            devices.push((sb, Device::Block(Box::new(SeekDevice::new(reader)))));
+        }
+        Btrfs::assemble(devices)
+    }
+
+    /// Opens a filesystem from a device read at explicit offsets, such as
+    /// a [`File`] or an in-memory image. Nothing is locked around reads,
+    /// so a `Btrfs` shared between threads reads in parallel.
+    pub fn from_device<D: BlockDevice + 'static>(device: D) -> Result<Btrfs, BtrfsError> {
+        Btrfs::from_block_devices(vec![Box::new(device)])
+    }
+
+    /// Like [`Btrfs::from_readers`], with one [`BlockDevice`] per device.
+    /// Any backend works, such as a [`File`], an in-memory image, or a
+    /// device that injects faults for a test. Each is matched to its devid
+    /// by the superblock it holds, in whatever order they are given, and
+    /// reads of chunk stripes go to the device with the stripe's devid.
+    pub fn from_block_devices(devices: Vec<Box<dyn BlockDevice>>) -> Result<Btrfs, BtrfsError> {
+        let mut opened = Vec::with_capacity(devices.len());
+        for device in devices {
+            let mut block = [0u8; BTRFS_SUPER_INFO_SIZE];
+            device.read_at(BTRFS_SUPER_INFO_OFFSET, &mut block)?;
+            let sb =
+                Superblock::check_copy(&block, BTRFS_SUPER_INFO_OFFSET, &ParseOptions::default())?;
+            opened.push((sb, Device::Block(device)));
+        }
+        Btrfs::assemble(opened)
+    }
+
+    /// Opens a single-device image or block device through a read-only
+    /// memory map. Block reads then borrow from the map instead of going
+    /// through `Read` and a fresh buffer; see [`Btrfs::logical_bytes`].
+    ///
+    /// The map is only sound while nothing else modifies the file, so do
+    /// not use this on a mounted filesystem.
+    #[cfg(feature = "mmap")]
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Btrfs, BtrfsError> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only and the caller promises the file is
+        // not modified while it is open.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        let sb = Superblock::from_reader(&mut std::io::Cursor::new(&map[..]))?;
+        Btrfs::assemble(vec![(sb, Device::Mapped(map))])
+    }
+
+    fn assemble(opened: Vec<(Superblock, Device)>) -> Result<Btrfs, BtrfsError> {
+        // Seed devices only count as such under a sprout; given alone, they
+        // are the filesystem.
+        let sprouted = opened.iter().any(|(sb, _)| !sb.is_seeding());
+        let mut superblock: Option<Superblock> = None;
+        let mut devices = BTreeMap::new();
+        let mut seeds = BTreeMap::new();
+
+        for (sb, device) in opened {
+            let devid = sb.dev_item.devid;
+            if devices.insert(devid, device).is_some() {
+                return Err(BtrfsError::Malformed("two devices share a devid"));
+            }
+            if sprouted && sb.is_seeding() {
+                seeds.insert(devid, sb.fsid);
+                continue;
+            }
+            if let Some(first) = &superblock {
+                if first.fsid != sb.fsid {
+                    return Err(BtrfsError::FsidMismatch);
+                }
+            }
+            if superblock
+                .as_ref()
+                .is_none_or(|s| sb.generation > s.generation)
+            {
+                superblock = Some(sb);
+            }
+        }
+
+        let superblock = superblock.ok_or(BtrfsError::MissingDevice { devid: 1 })?;
+        if (devices.len() as u64) < superblock.num_devices {
+            // Devids are normally allocated from 1, so report the first gap.
+            let devid = (1..).find(|id| !devices.contains_key(id)).unwrap_or(1);
+            return Err(BtrfsError::MissingDevice { devid });
+        }
+
+        let chunk_map = ChunkMap::from_superblock(&superblock)?;
+        Ok(Btrfs {
+            superblock,
+            devices,
+            seeds,
+            chunk_map,
+            dev_items: BTreeMap::new(),
+            verify_checksums: true,
+            node_cache: Mutex::new(LruCache::new(0)),
+            readahead: 0,
+            warn_future_generations: false,
+            warnings: Mutex::new(Vec::new()),
+            dir_cache: Mutex::new(LruCache::new(0)),
+        })
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
+
+    pub fn label(&self) -> Result<&str, Utf8Error> {
+        self.superblock.label()
+    }
+
+    /// Changes the label, like `btrfs filesystem label` on an unmounted
+    /// filesystem, by rewriting every superblock copy as
+    /// [`WriteExt::write_label`] does. `devices` are the filesystem's
+    /// devices opened for writing, in any order, and must include each one
+    /// it was opened with, apart from seeds, which keep their own label.
+    ///
+    /// The label is checked and every device identified before anything
+    /// is written.
+    pub fn set_label<W: WriteExt>(
+        &mut self,
+        label: &str,
+        devices: &mut [W],
+    ) -> Result<(), BtrfsError> {
+        self.rewrite_superblocks(devices, |sb| sb.set_label(label))
+    }
+
+    /// Changes the fsid of a filesystem with the METADATA_UUID flag, such
+    /// as a clone that `btrfstune -m` has already given a fsid of its own,
+    /// by rewriting the superblocks of `devices` as [`Btrfs::set_label`]
+    /// does. Tree blocks keep the metadata UUID; see
+    /// [`Superblock::set_fsid`].
+    ///
+    /// The filesystem must not be mounted, or the kernel writes its old
+    /// fsid back. A write that fails part way leaves the devices with
+    /// different fsids, so that they no longer open together until this
+    /// is run again on the rest.
+    #[cfg(feature = "uuid")]
+    pub fn set_fsid<W: WriteExt>(
+        &mut self,
+        new: uuid::Uuid,
+        devices: &mut [W],
+    ) -> Result<(), BtrfsError> {
+        self.rewrite_superblocks(devices, |sb| sb.set_fsid(new.into_bytes()))
+    }
+
+    /// Applies `update` to the superblock of each of `devices` once all of
+    /// them are known to be this filesystem's, and to the one in memory.
+    fn rewrite_superblocks<W: WriteExt>(
+        &mut self,
+        devices: &mut [W],
+        update: impl Fn(&mut Superblock) -> Result<(), BtrfsError>,
+    ) -> Result<(), BtrfsError> {
+        let mut superblock = self.superblock.clone();
+        update(&mut superblock)?;
+        let mut devids = Vec::with_capacity(devices.len());
+        for device in devices.iter_mut() {
+            let sb = Superblock::from_reader(device)?;
+            if sb.fsid != self.superblock.fsid {
+                return Err(BtrfsError::FsidMismatch);
+            }
+            devids.push(sb.dev_item.devid);
+        }
+        let missing = self
+            .devids()
+            .find(|devid| !self.seeds.contains_key(devid) && !devids.contains(devid));
+        if let Some(devid) = missing {
+            return Err(BtrfsError::MissingDevice { devid });
+        }
+
+        for device in devices {
+            rewrite_copies(device, &update)?;
+        }
+        self.superblock = superblock;
+        Ok(())
+    }
+
+    /// Whether the filesystem read is itself a seed, opened without any
+    /// sprout. Seeds are read-only; the kernel only writes to one through
+    /// a sprout device added on top.
+    pub fn is_seed(&self) -> bool {
+        self.superblock.is_seeding()
+    }
+
+    /// The devids of the opened devices that belong to the seed this
+    /// filesystem was sprouted from. Their tree blocks carry the seed's
+    /// fsid, which reads accept as well as the sprout's own.
+    pub fn seed_devids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.seeds.keys().copied()
+    }
+
+    /// Whether tree blocks stamped with `fsid` belong to this filesystem,
+    /// either to the sprout or to a seed under it.
+    pub(crate) fn owns_metadata(&self, fsid: &[u8; BTRFS_FSID_SIZE]) -> bool {
+        *fsid == self.superblock.effective_metadata_uuid()
+            || self.seeds.values().any(|seed| seed == fsid)
+    }
+
+    /// Whether tree block checksums are verified on read, which is the
+    /// default. Turning it off lets a damaged filesystem be read anyway, or
+    /// one whose checksum algorithm has no backend enabled; the `bytenr` and
+    /// fsid checks still apply.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+        // Blocks cached unverified must not be served once checks are on.
+        self.node_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    pub fn verify_checksums(&self) -> bool {
+        self.verify_checksums
+    }
+
+    /// Keeps up to `capacity` parsed tree blocks in memory, evicting the
+    /// least recently used, so that repeated descents through the same
+    /// nodes skip the read, the checksum and the parse. Off (zero) by
+    /// default. Each entry holds one block, `nodesize` bytes and a little
+    /// more once parsed.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Btrfs {
+        self.node_cache = Mutex::new(LruCache::new(capacity));
+        self
+    }
+
+    /// Hits and misses of the node cache since it was set up.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.node_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stats()
+    }
+
+    pub(crate) fn node_cache(&self) -> &Mutex<LruCache<u64, CachedBlock>> {
+        &self.node_cache
+    }
+
+    /// Makes tree iterators, on reaching a leaf they have to read, also
+    /// read up to `blocks` of the leaves after it under the same parent,
+    /// so that a walk over a slow device takes fewer, larger reads. Leaves
+    /// that lie back to back on a device are read together. Off (zero) by
+    /// default, and of little use on a memory-mapped device.
+    pub fn set_readahead(&mut self, blocks: usize) {
+        self.readahead = blocks;
+    }
+
+    pub fn readahead(&self) -> usize {
+        self.readahead
+    }
+
+    /// Makes every tree block read from the device compare the generation
+    /// in its header with the superblock's, and record a
+    /// [`Warning::FutureGeneration`] for a newer one instead of failing.
+    /// Off by default. Blocks of the log tree, written after the last
+    /// commit by design, may be one generation ahead. Blocks served from
+    /// the node cache were checked when first read.
+    pub fn set_warn_future_generations(&mut self, warn: bool) {
+        self.warn_future_generations = warn;
+    }
+
+    pub fn warn_future_generations(&self) -> bool {
+        self.warn_future_generations
+    }
+
+    /// Takes the warnings tree block and UUID tree reads have recorded
+    /// since the last call, each once, in the order they were found.
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        std::mem::take(&mut *self.warnings.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Records `warning` unless it is already waiting to be taken.
+    pub(crate) fn warn(&self, warning: Warning) {
+        let mut warnings = self.warnings.lock().unwrap_or_else(PoisonError::into_inner);
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+
+    /// Remembers up to `capacity` directory entries, by subvolume,
+    /// directory and name, so that `lookup_path` skips the `DIR_ITEM`
+    /// search for a name it has resolved before or that
+    /// [`Btrfs::readdir`] has listed. Off (zero) by default.
+    ///
+    /// Entries are never invalidated, which is only correct while the
+    /// image does not change, as for an unmounted or read-only filesystem.
+    /// Call [`Btrfs::clear_dir_cache`] if it might have.
+    pub fn with_dir_cache_capacity(mut self, capacity: usize) -> Btrfs {
+        self.dir_cache = Mutex::new(LruCache::new(capacity));
+        self
+    }
+
+    /// Forgets every cached directory entry, keeping the capacity.
+    pub fn clear_dir_cache(&self) {
+        self.dir_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Hits and misses of the directory entry cache since it was set up.
+    pub fn dir_cache_stats(&self) -> CacheStats {
+        self.dir_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stats()
+    }
+
+    pub(crate) fn dir_cache(&self) -> &Mutex<LruCache<DirCacheKey, Key>> {
+        &self.dir_cache
+    }
+
+    pub fn chunk_map(&self) -> &ChunkMap {
+        &self.chunk_map
+    }
+
+    /// Reads the chunk tree, extending the bootstrap map built from the
+    /// superblock's `sys_chunk_array` to every chunk of the filesystem, and
+    /// records the device items stored there.
+    ///
+    /// On a zoned filesystem, data written with zone append lands wherever
+    /// the zone's write pointer was, so data chunks with more than one
+    /// stripe are only addressable through the raid stripe tree. That tree
+    /// is not modeled, and such chunks are rejected as unsupported rather
+    /// than mapped linearly to the wrong place.
+    pub fn load_chunk_tree(&mut self) -> Result<(), BtrfsError> {
+        let zoned = self.superblock.is_zoned();
+        let tree = self.tree(self.superblock.chunk_root, self.superblock.chunk_root_level);
+        let mut chunk_map = self.chunk_map.clone();
+        let mut dev_items = BTreeMap::new();
+        for item in tree.iter() {
+            let (key, data) = item?;
+            match key.item_type() {
+                KeyType::ChunkItem => {
+                    let chunk = Chunk::from_bytes(&data)?;
+                    if zoned && chunk.num_stripes > 1 && is_data(&chunk) {
+                        return Err(BtrfsError::Unsupported(
+                            "zoned data chunk mapped by the raid stripe tree",
+                        ));
+                    }
+                    chunk_map.insert(key.offset, chunk);
+                }
+                KeyType::DevItem => {
+                    let dev_item = DevItem::from_bytes(&data)?;
+                    // A seed's devices are recorded with the seed's fsid.
+                    if let Some(fsid) = self.seeds.get(&dev_item.devid()) {
+                        if dev_item.fsid != *fsid {
+                            return Err(BtrfsError::FsidMismatch);
+                        }
+                    }
+                    dev_items.insert(dev_item.devid(), dev_item);
+                }
+                _ => {}
+            }
+        }
+        self.chunk_map = chunk_map;
+        self.dev_items = dev_items;
+        Ok(())
+    }
+
+    /// The zone size of a zoned filesystem, or `None` if it is not zoned.
+    ///
+    /// Btrfs does not record the zone size on disk, since the kernel asks
+    /// the device. Every block group spans exactly one zone per device,
+    /// though, so it is the per-device length of any chunk that is not
+    /// striped. Chunks come from the superblock until
+    /// [`Btrfs::load_chunk_tree`] is called, which is enough.
+    ///
+    /// Superblocks are read at their fixed offsets, which is where an
+    /// image of a zoned filesystem on a regular file keeps them. Real zoned
+    /// devices keep them in log zones, which this crate does not read.
+    pub fn zoned_info(&self) -> Result<Option<ZonedInfo>, BtrfsError> {
+        if !self.superblock.is_zoned() {
+            return Ok(None);
+        }
+        let mut zone_size = None;
+        for ChunkRange { chunk, .. } in self.chunk_map.chunks() {
+            if chunk.is_striped() {
+                continue;
+            }
+            match zone_size {
+                None => zone_size = Some(chunk.length),
+                Some(size) if size != chunk.length => {
+                    return Err(BtrfsError::Malformed("zoned chunks differ in size"));
+                }
+                Some(_) => {}
+            }
+        }
+        let zone_size = zone_size.ok_or(BtrfsError::Malformed("zoned filesystem has no chunks"))?;
+        Ok(Some(ZonedInfo { zone_size }))
+    }
+
+    /// The root tree, which holds a root item for every other tree.
+    pub fn root_tree(&self) -> Tree<'_> {
+        self.tree(self.superblock.root, self.superblock.root_level)
+    }
+
+    /// The root item of tree `objectid`. Snapshots and relocated trees can
+    /// leave several; the one with the highest key offset is current.
+    pub fn root_item(&self, objectid: u64) -> Result<Option<RootItem>, BtrfsError> {
+        let min = Key::new(objectid, KeyType::RootItem.as_u8(), 0);
+        let max = Key::new(objectid, KeyType::RootItem.as_u8(), u64::MAX);
+        let mut last = None;
+        for item in self.root_tree().search_range(min, max) {
+            last = Some(item?.1);
+        }
+        last.map(|data| RootItem::from_bytes(&data)).transpose()
+    }
+
+    /// The balance recorded in the root tree, or `None` if none is running.
+    /// An item left on an unmounted filesystem means the balance was
+    /// paused or interrupted and resumes at the next mount.
+    pub fn balance_status(&self) -> Result<Option<BalanceStatus>, BtrfsError> {
+        let key = Key::exact(BALANCE_OBJECTID, KeyType::TemporaryItem, 0);
+        self.root_tree()
+            .search(&key)?
+            .map(|(_, data)| BalanceStatus::from_bytes(&data))
+            .transpose()
+    }
+
+    /// The device items read by [`Btrfs::load_chunk_tree`], keyed by devid.
+    /// Empty until then.
+    pub fn dev_items(&self) -> impl Iterator<Item = &DevItem> + '_ {
+        self.dev_items.values()
+    }
+
+    /// The devids of the opened devices.
+    pub fn devids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.devices.keys().copied()
+    }
+
+    /// The devids the chunk tree has device items for but that no device
+    /// was opened for, such as one given in place of another: opening
+    /// only counts devices against `num_devices`. Reads of their stripes
+    /// fail with [`BtrfsError::MissingDevice`]. Empty until
+    /// [`Btrfs::load_chunk_tree`] is called.
+    pub fn missing_devids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.dev_items
+            .keys()
+            .copied()
+            .filter(|devid| !self.devices.contains_key(devid))
+    }
+
+    /// Reads `len` raw bytes at offset `physical` of device `devid`.
+    pub fn read_physical(
+        &self,
+        devid: u64,
+        physical: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, BtrfsError> {
+        Ok(self.physical_bytes(devid, physical, len)?.into_owned())
+    }
+
+    /// Like [`Btrfs::read_physical`], but borrows the bytes when the device
+    /// is memory-mapped.
+    pub fn physical_bytes(
+        &self,
+        devid: u64,
+        physical: u64,
+        len: usize,
+    ) -> Result<Cow<'_, [u8]>, BtrfsError> {
+        let device = self
+            .devices
+            .get(&devid)
+            .ok_or(BtrfsError::MissingDevice { devid })?;
+        match device {
+            Device::Block(device) => {
+                let mut buf = zeroed(len)?;
+                device.read_at(physical, &mut buf)?;
+                Ok(Cow::Owned(buf))
+            }
+            #[cfg(feature = "mmap")]
+            Device::Mapped(map) => usize::try_from(physical)
+                .ok()
+                .and_then(|start| map.get(start..start.checked_add(len)?))
+                .map(Cow::Borrowed)
+                .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    /// Reads `len` bytes at logical address `logical`, from the first stripe
+    /// whose device is present. The range must lie within one chunk.
+    pub fn read_logical(&self, logical: u64, len: usize) -> Result<Vec<u8>, BtrfsError> {
+        Ok(self.logical_bytes(logical, len)?.into_owned())
+    }
+
+    /// Like [`Btrfs::read_logical`], but borrows the bytes when the device
+    /// is memory-mapped, so that a [`Leaf`](crate::Leaf) or
+    /// [`InternalNode`](crate::InternalNode) can be parsed in place. The
+    /// borrow keeps the filesystem, and so the map, alive.
+    pub fn logical_bytes(&self, logical: u64, len: usize) -> Result<Cow<'_, [u8]>, BtrfsError> {
+        let (start, chunk) = self
+            .chunk_map
+            .chunk_for(logical)
+            .ok_or(BtrfsError::Unmapped { logical })?;
+        let end = (logical - start).checked_add(len as u64);
+        if end.is_none_or(|end| end > chunk.length) {
+            return Err(BtrfsError::Malformed("read crosses a chunk boundary"));
+        }
+
+        // Striped profiles place each `stripe_len` run on a different
+        // device, so a longer read is gathered run by run.
+        let contiguous = |at| {
+            self.chunk_map
+                .contiguous_len(at)
+                .filter(|&len| len != 0)
+                .ok_or(BtrfsError::Malformed("chunk has a zero stripe length"))
+        };
+        if len as u64 <= contiguous(logical)? {
+            return self.copy_bytes(logical, len);
+        }
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len)
+            .map_err(|_| BtrfsError::Malformed("length too large to allocate"))?;
+        while buf.len() < len {
+            let at = logical + buf.len() as u64;
+            let piece = contiguous(at)?.min((len - buf.len()) as u64) as usize;
+            buf.extend_from_slice(&self.copy_bytes(at, piece)?);
+        }
+        Ok(Cow::Owned(buf))
+    }
+
+    /// Reads `len` bytes at `logical` from the first copy whose device is
+    /// present. The range must lie within one stripe.
+    pub(crate) fn copy_bytes(&self, logical: u64, len: usize) -> Result<Cow<'_, [u8]>, BtrfsError> {
+        let stripes = self
+            .chunk_map
+            .to_physical(logical)
+            .ok_or(BtrfsError::Unmapped { logical })?;
+        let Some((devid, physical)) = stripes
+            .iter()
+            .copied()
+            .find(|(devid, _)| self.devices.contains_key(devid))
+        else {
+            #[cfg(feature = "raid56")]
+            if let Some(bytes) = self.rebuild_raid56(logical, len) {
+                return Ok(Cow::Owned(bytes));
+            }
+            return Err(BtrfsError::MissingDevice {
+                devid: stripes.first().map_or(0, |&(devid, _)| devid),
+            });
+        };
+        self.physical_bytes(devid, physical, len)
+    }
+
+    /// Rebuilds `len` bytes at `logical` in a RAID5/6 chunk from the rest
+    /// of its full stripe, as if the strip holding them were lost. This is
+    /// best effort, like RAID56 support itself: `None` if the chunk is not
+    /// RAID5/6, the range spans strips, or too few strips can be read.
+    #[cfg(feature = "raid56")]
+    pub(crate) fn rebuild_raid56(&self, logical: u64, len: usize) -> Option<Vec<u8>> {
+        if self.chunk_map.contiguous_len(logical)? < len as u64 {
+            return None;
+        }
+        let stripe = self.chunk_map.full_stripe(logical)?;
+        let read = |(devid, physical)| {
+            self.physical_bytes(devid, physical, len)
+                .ok()
+                .map(Cow::into_owned)
+        };
+        let mut data: Vec<_> = stripe
+            .data
+            .iter()
+            .enumerate()
+            .map(|(i, &strip)| if i == stripe.index { None } else { read(strip) })
+            .collect();
+        let p = read(stripe.p);
+        let q = stripe.q.and_then(read);
+        if !raid56::rebuild(&mut data, p.as_deref(), q.as_deref()) {
+            return None;
+        }
+        data.swap_remove(stripe.index)
+    }
+}
+
+/// A zeroed buffer of `len` bytes. Lengths come from disk, so one that
+/// cannot be allocated is an error rather than an abort.
+pub(crate) fn zeroed(len: usize) -> Result<Vec<u8>, BtrfsError> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len)
+        .map_err(|_| BtrfsError::Malformed("length too large to allocate"))?;
+    buf.resize(len, 0);
+    Ok(buf)
+}
+
+fn is_data(chunk: &Chunk) -> bool {
+    matches!(
+        chunk.block_group_type(),
+        BlockGroupType::Data | BlockGroupType::Mixed
+    )
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::balance::BalanceFlags;
+    use crate::features::IncompatFlags;
+    use crate::key::Key;
+    use crate::superblock::{BTRFS_SUPER_INFO_OFFSET, BTRFS_SUPER_MIRROR_OFFSETS};
+    use crate::testutil::{
+        chunk_item, device_image, leaf, put_u64, reseal, root_item, test_chunk, TestImage,
+        NODESIZE, SUPERBLOCK, SYSTEM_CHUNK,
+    };
+
+    const IMAGE_SIZE: usize = 1 << 20;
+
+    /// The fixture superblock as device `devid` of `num_devices`.
+    fn member_superblock(devid: u64, num_devices: u64) -> Vec<u8> {
+        let mut sb = SUPERBLOCK.to_vec();
+        put_u64(&mut sb, 136, num_devices);
+        put_u64(&mut sb, 201, devid);
+        reseal(&mut sb);
+        sb
+    }
+
+    #[test]
+    fn opens_image_file() {
+        let path = std::env::temp_dir().join(format!("btrfs-rs-open-{}.img", std::process::id()));
+        std::fs::write(&path, device_image(SUPERBLOCK, IMAGE_SIZE)).unwrap();
+        let fs = Btrfs::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let fs = fs.unwrap();
+        assert_eq!(fs.label().unwrap(), "testfs");
+        assert_eq!(fs.superblock().nodesize, 16384);
+        let magic = fs
+            .read_physical(1, BTRFS_SUPER_INFO_OFFSET + 64, 8)
+            .unwrap();
+        assert_eq!(magic, b"_BHRfS_M");
+    }
+
+    #[test]
+    fn opens_partition_at_offset() {
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let items = [(Key::new(2, 132, 0), root_item(0x100000, 1))];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(root_tree, &leaf(root_tree, 1, &items));
+        let mut disk = vec![0u8; 1 << 20];
+        disk.extend_from_slice(&image.build());
+
+        let path = std::env::temp_dir().join(format!("btrfs-rs-part-{}.img", std::process::id()));
+        std::fs::write(&path, &disk).unwrap();
+        let fs = Btrfs::open_with_offset(&path, 1 << 20);
+        std::fs::remove_file(&path).unwrap();
+
+        let fs = fs.unwrap();
+        assert_eq!(fs.label().unwrap(), "testfs");
+        assert_eq!(fs.root_item(2).unwrap().unwrap().bytenr(), 0x100000);
+        assert!(Btrfs::from_reader(Cursor::new(disk)).is_err());
+    }
+
+    #[test]
+    fn reads_one_device_from_several_threads() {
+        fn shared<T: Send + Sync>(value: T) -> T {
+            value
+        }
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let items = [(Key::new(2, 132, 0), root_item(0x100000, 1))];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(root_tree, &leaf(root_tree, 1, &items));
+        let fs = shared(
+            Btrfs::from_device(image.build())
+                .unwrap()
+                .with_cache_capacity(4),
+        );
+
+        std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| fs.root_item(2).unwrap().unwrap().bytenr()))
+                .collect();
+            for reader in readers {
+                assert_eq!(reader.join().unwrap(), 0x100000);
+            }
+        });
+        let stats = fs.cache_stats();
+        assert_eq!(stats.hits + stats.misses, 4);
+
+        assert!(matches!(
+            Btrfs::from_device(vec![0u8; 4096]),
+            Err(BtrfsError::Io(_))
+        ));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn reads_through_memory_map() {
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let items = [(Key::new(2, 132, 0), root_item(0x100000, 1))];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(root_tree, &leaf(root_tree, 1, &items));
+
+        let path = std::env::temp_dir().join(format!("btrfs-rs-mmap-{}.img", std::process::id()));
+        std::fs::write(&path, image.build()).unwrap();
+        let fs = Btrfs::open_mmap(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let fs = fs.unwrap();
+        assert_eq!(fs.root_item(2).unwrap().unwrap().bytenr(), 0x100000);
+        let block = fs.logical_bytes(root_tree, NODESIZE).unwrap();
+        assert!(matches!(block, Cow::Borrowed(_)));
+        assert_eq!(crate::node::Leaf::new(&block).unwrap().len(), 1);
+        assert!(fs.read_physical(1, u64::MAX - 10, 100).is_err());
+    }
+
+    #[test]
+    fn rejects_corrupt_superblock() {
+        let mut image = device_image(SUPERBLOCK, IMAGE_SIZE);
+        image[BTRFS_SUPER_INFO_OFFSET as usize + 500] ^= 1;
+        assert!(matches!(
+            Btrfs::from_reader(Cursor::new(image)),
+            Err(BtrfsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn opens_all_devices() {
+        let devices = vec![
+            Cursor::new(device_image(&member_superblock(2, 2), IMAGE_SIZE)),
+            Cursor::new(device_image(&member_superblock(1, 2), IMAGE_SIZE)),
+        ];
+        let fs = Btrfs::from_readers(devices).unwrap();
+        assert_eq!(fs.devids().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn reports_missing_and_foreign_devices() {
+        let devices = vec![Cursor::new(device_image(
+            &member_superblock(1, 2),
+            IMAGE_SIZE,
+        ))];
+        assert!(matches!(
+            Btrfs::from_readers(devices),
+            Err(BtrfsError::MissingDevice { devid: 2 })
+        ));
+
+        let mut foreign = member_superblock(2, 2);
+        foreign[32] ^= 0xff;
+        reseal(&mut foreign);
+        let devices = vec![
+            Cursor::new(device_image(&member_superblock(1, 2), IMAGE_SIZE)),
+            Cursor::new(device_image(&foreign, IMAGE_SIZE)),
+        ];
+        assert!(matches!(
+            Btrfs::from_readers(devices),
+            Err(BtrfsError::FsidMismatch)
+        ));
+    }
+
+    #[test]
+    fn opens_sprout_over_seed() {
+        // A seed, device 1, whose trees the sprout, device 2, still uses
+        // as they were. Its chunk tree lists both devices.
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let fs_tree = root_tree + NODESIZE as u64;
+        let sprout_fsid = [0x5e; 16];
+        let seed_dev = &SUPERBLOCK[201..201 + DevItem::SIZE];
+        let mut sprout_dev = seed_dev.to_vec();
+        put_u64(&mut sprout_dev, 0, 2);
+        sprout_dev[66..82].copy_from_slice(&sprout_fsid);
+        let dev_items = [
+            (Key::new(1, 216, 1), seed_dev),
+            (Key::new(1, 216, 2), &sprout_dev[..]),
+        ];
+        let roots = [(Key::new(5, 132, 0), root_item(fs_tree, 0))];
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(chunk_root, &leaf(chunk_root, 3, &dev_items))
+            .put_block(root_tree, &leaf(root_tree, 1, &roots))
+            .put_block(fs_tree, &leaf::<&[u8]>(fs_tree, 5, &[]));
+        let mut seed = image.build();
+        let start = BTRFS_SUPER_INFO_OFFSET as usize;
+        let superblock = start..start + BTRFS_SUPER_INFO_SIZE;
+        seed[start + 60] |= 1;
+        reseal(&mut seed[superblock.clone()]);
+
+        let mut sprout = seed[superblock].to_vec();
+        sprout[60] &= !1;
+        sprout[32..48].copy_from_slice(&sprout_fsid);
+        put_u64(&mut sprout, 72, 7);
+        put_u64(&mut sprout, 136, 2);
+        sprout[201..201 + DevItem::SIZE].copy_from_slice(&sprout_dev);
+        reseal(&mut sprout);
+        let sprout = device_image(&sprout, IMAGE_SIZE);
+
+        let mut fs =
+            Btrfs::from_readers(vec![Cursor::new(sprout.clone()), Cursor::new(seed.clone())])
+                .unwrap();
+        assert!(!fs.is_seed());
+        assert_eq!(fs.superblock().fsid, sprout_fsid);
+        assert_eq!(fs.devids().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(fs.seed_devids().collect::<Vec<_>>(), [1]);
+        assert!(fs.root_item(5).unwrap().is_some());
+        fs.load_chunk_tree().unwrap();
+        assert_eq!(fs.dev_items().count(), 2);
+
+        let fs = Btrfs::from_readers(vec![Cursor::new(seed)]).unwrap();
+        assert!(fs.is_seed());
+        assert_eq!(fs.seed_devids().count(), 0);
+        assert!(matches!(
+            Btrfs::from_readers(vec![Cursor::new(sprout)]),
+            Err(BtrfsError::MissingDevice { devid: 1 })
+        ));
+    }
+
+    #[test]
+    fn rewrites_label_in_every_copy() {
+        // Large enough for the first mirror, at 64MiB, but not the second.
+        let size = (64 << 20) + BTRFS_SUPER_INFO_SIZE;
+        let mut image = TestImage::new().build();
+        image.resize(size, 0);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.clone())).unwrap();
+        let mut devices = [Cursor::new(image)];
+        assert!(matches!(
+            fs.set_label(&"x".repeat(256), &mut devices),
+            Err(BtrfsError::LabelTooLong { len: 256 })
+        ));
+        assert!(matches!(
+            fs.set_label("a/b", &mut devices),
+            Err(BtrfsError::InvalidLabel('/'))
+        ));
+        assert!(matches!(
+            fs.set_label::<Cursor<Vec<u8>>>("backup", &mut []),
+            Err(BtrfsError::MissingDevice { devid: 1 })
+        ));
+
+        fs.set_label("backup", &mut devices).unwrap();
+        assert_eq!(fs.label().unwrap(), "backup");
+        let [mut device] = devices;
+        let copies = Superblock::copies_with_generation(&mut device, size as u64);
+        assert_eq!(copies.len(), 2);
+        assert!(copies.iter().all(|(_, copy)| matches!(copy, Ok((_, true)))));
+        let copies = Superblock::read_all_copies(&mut device, size as u64);
+        for (copy, offset) in copies.into_iter().zip(BTRFS_SUPER_MIRROR_OFFSETS) {
+            let sb = copy.unwrap();
+            assert_eq!((sb.label().unwrap(), sb.bytenr), ("backup", offset));
+        }
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn changes_fsid_through_metadata_uuid() {
+        use uuid::Uuid;
+
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let roots = [(Key::new(5, 132, 0), root_item(root_tree, 0))];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(root_tree, &leaf(root_tree, 1, &roots));
+        let original = Uuid::from_bytes(SUPERBLOCK[32..48].try_into().unwrap());
+        let clone = Uuid::from_bytes([0x77; 16]);
+
+        let plain = image.build();
+        let mut fs = Btrfs::from_reader(Cursor::new(plain.clone())).unwrap();
+        assert!(matches!(
+            fs.set_fsid(clone, &mut [Cursor::new(plain)]),
+            Err(BtrfsError::Unsupported(_))
+        ));
+
+        let changed = image.change_fsid([0x42; 16]).build();
+        let mut fs = Btrfs::from_reader(Cursor::new(changed.clone())).unwrap();
+        let mut devices = [Cursor::new(changed)];
+        fs.set_fsid(clone, &mut devices).unwrap();
+        assert_eq!(fs.superblock().fsid(), clone);
+        let [device] = devices;
+        let mut fs = Btrfs::from_reader(Cursor::new(device.get_ref().clone())).unwrap();
+        assert_eq!(fs.superblock().fsid(), clone);
+        assert_eq!(fs.superblock().metadata_uuid(), original);
+        assert!(fs.root_item(5).unwrap().is_some());
+
+        // Back to the UUID the blocks carry, which needs no flag.
+        let mut devices = [device];
+        fs.set_fsid(original, &mut devices).unwrap();
+        let [device] = devices;
+        let fs = Btrfs::from_reader(device).unwrap();
+        assert_eq!(fs.superblock().fsid(), original);
+        assert!(!fs
+            .superblock()
+            .incompat_flags()
+            .contains(IncompatFlags::METADATA_UUID));
+        assert!(fs.root_item(5).unwrap().is_some());
+    }
+
+    #[test]
+    fn loads_chunk_tree() {
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let data_chunk = chunk_item(&test_chunk(0x1, &[(1, 40 << 20)]));
+        let dev_item = &SUPERBLOCK[201..201 + DevItem::SIZE];
+        let items = [
+            (Key::new(1, 216, 1), dev_item),
+            (Key::new(256, 228, 1 << 30), &data_chunk[..]),
+        ];
+
+        let mut image = TestImage::new();
+        image.put_block(chunk_root, &leaf(chunk_root, 3, &items));
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert!(fs.chunk_map().to_physical(1 << 30).is_none());
+        assert_eq!(fs.dev_items().count(), 0);
+
+        fs.load_chunk_tree().unwrap();
+        assert_eq!(fs.chunk_map().len(), 2);
+        assert_eq!(
+            fs.chunk_map().to_physical((1 << 30) + 4096).unwrap(),
+            [(1, (40 << 20) + 4096)]
+        );
+        let devices: Vec<_> = fs.dev_items().collect();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].devid(), 1);
+        assert_eq!(devices[0].total_bytes(), 1 << 30);
+    }
+
+    #[test]
+    fn reports_devices_without_a_backend() {
+        // Device 2 was replaced by device 3, but device 2 is still given.
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let dev_item = |devid| {
+            let mut item = SUPERBLOCK[201..201 + DevItem::SIZE].to_vec();
+            put_u64(&mut item, 0, devid);
+            item
+        };
+        let items = [
+            (Key::new(1, 216, 1), dev_item(1)),
+            (Key::new(1, 216, 3), dev_item(3)),
+        ];
+        let mut image = TestImage::new();
+        image.put_block(chunk_root, &leaf(chunk_root, 3, &items));
+        let mut first = image.build();
+        let start = BTRFS_SUPER_INFO_OFFSET as usize;
+        put_u64(&mut first, start + 136, 2);
+        reseal(&mut first[start..start + BTRFS_SUPER_INFO_SIZE]);
+        let devices: Vec<Box<dyn BlockDevice>> = vec![
+            Box::new(first),
+            Box::new(device_image(&member_superblock(2, 2), IMAGE_SIZE)),
+        ];
+
+        let mut fs = Btrfs::from_block_devices(devices).unwrap();
+        fs.load_chunk_tree().unwrap();
+        assert_eq!(fs.missing_devids().collect::<Vec<_>>(), [3]);
+        assert_eq!(fs.read_physical(2, 0, 4).unwrap(), [0; 4]);
+        assert!(matches!(
+            fs.read_physical(3, 0, 4),
+            Err(BtrfsError::MissingDevice { devid: 3 })
+        ));
+    }
+
+    #[test]
+    fn gathers_reads_across_raid0_stripes() {
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let raid0 = test_chunk(0x9, &[(1, 40 << 20), (1, 48 << 20)]);
+        let items = [(Key::new(256, 228, 1 << 30), chunk_item(&raid0))];
+
+        let mut image = TestImage::new();
+        image
+            .put_block(chunk_root, &leaf(chunk_root, 3, &items))
+            .put_physical(40 << 20, &[0xaa; 65536])
+            .put_physical((40 << 20) + 65536, &[0xcc; 65536])
+            .put_physical(48 << 20, &[0xbb; 65536]);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+
+        let bytes = fs.read_logical((1 << 30) + 0x8000, 0x20000).unwrap();
+        assert!(bytes[..0x8000].iter().all(|&b| b == 0xaa));
+        assert!(bytes[0x8000..0x18000].iter().all(|&b| b == 0xbb));
+        assert!(bytes[0x18000..].iter().all(|&b| b == 0xcc));
+    }
+
+    /// A RAID5 chunk of three 64KiB-striped stripes at `stripes`.
+    #[cfg(feature = "raid56")]
+    fn raid5_chunk(stripes: [(u64, u64); 3]) -> Vec<u8> {
+        chunk_item(&test_chunk(0x84, &stripes))
+    }
+
+    #[cfg(feature = "raid56")]
+    #[test]
+    fn rebuilds_raid5_strips_from_parity() {
+        use crate::raid56;
+
+        // Chunk A lives on device 1 alone; chunk B has its second stripe on
+        // device 2, which is missing.
+        let (a, b) = (1u64 << 30, 2u64 << 30);
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let items = [
+            (
+                Key::new(256, 228, a),
+                raid5_chunk([(1, 40 << 20), (1, 44 << 20), (1, 48 << 20)]),
+            ),
+            (
+                Key::new(256, 228, b),
+                raid5_chunk([(1, 52 << 20), (2, 0), (1, 56 << 20)]),
+            ),
+        ];
+        let mut image = TestImage::new();
+        image.put_block(chunk_root, &leaf(chunk_root, 3, &items));
+
+        // In the first full stripe the data strips are stripes 0 and 1, and
+        // stripe 2 holds their parity. Each data strip starts with a leaf.
+        let leaf_a = leaf(a, 5, &[(Key::new(256, 1, 0), [7u8; 16])]);
+        let leaf_b = leaf(b + 65536, 5, &[(Key::new(257, 1, 0), [9u8; 16])]);
+        let other = vec![0x5a; NODESIZE];
+        let (p_a, _) = raid56::parity(&[&leaf_a, &other]);
+        let (p_b, _) = raid56::parity(&[&other, &leaf_b]);
+        image
+            // The leaf in chunk A is zeroed out, leaving only its parity.
+            .put_physical(40 << 20, &vec![0; NODESIZE])
+            .put_physical(44 << 20, &other)
+            .put_physical(48 << 20, &p_a)
+            .put_physical(52 << 20, &other)
+            .put_physical(56 << 20, &p_b);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+
+        let items: Vec<_> = fs.tree(a, 0).iter().map(Result::unwrap).collect();
+        assert_eq!(items, [(Key::new(256, 1, 0), vec![7u8; 16])]);
+        assert_eq!(fs.read_logical(b + 65536, NODESIZE).unwrap(), leaf_b);
+
+        // Without parity to fall back on the damage shows.
+        image.put_physical(48 << 20, &vec![0; NODESIZE]);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+        assert!(matches!(
+            fs.tree(a, 0).iter().next(),
+            Some(Err(BtrfsError::ChecksumMismatch { logical })) if logical == a
+        ));
+    }
+
+    #[test]
+    fn reports_zone_size() {
+        let fs = Btrfs::from_reader(Cursor::new(TestImage::new().build())).unwrap();
+        assert!(!fs.superblock().is_zoned());
+        assert_eq!(fs.zoned_info().unwrap(), None);
+
+        let mut image = TestImage::new();
+        image.set_incompat_flags(IncompatFlags::ZONED | IncompatFlags::NO_HOLES);
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert!(fs.superblock().is_zoned());
+        assert_eq!(
+            fs.zoned_info().unwrap(),
+            Some(ZonedInfo { zone_size: 8 << 20 })
+        );
+    }
+
+    #[test]
+    fn rejects_mirrored_zoned_data_chunks() {
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let data_chunk = test_chunk(0x21, &[(1, 40 << 20), (1, 48 << 20)]);
+        let items = [(Key::new(256, 228, 1 << 30), chunk_item(&data_chunk))];
+
+        let mut image = TestImage::new();
+        image.put_block(chunk_root, &leaf(chunk_root, 3, &items));
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+        assert_eq!(fs.chunk_map().len(), 2);
+
+        image.set_incompat_flags(IncompatFlags::ZONED);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert!(matches!(
+            fs.load_chunk_tree(),
+            Err(BtrfsError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn finds_root_items() {
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let items = [
+            (Key::new(2, 132, 0), &root_item(0x100000, 1)[..]),
+            (Key::new(256, 132, 0), &root_item(0x200000, 0)[..]),
+            (Key::new(256, 132, 9), &root_item(0x300000, 2)[..]),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(root_tree, &leaf(root_tree, 1, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        assert_eq!(fs.root_item(2).unwrap().unwrap().bytenr(), 0x100000);
+        let subvol = fs.root_item(256).unwrap().unwrap();
+        assert_eq!((subvol.bytenr(), subvol.level()), (0x300000, 2));
+        assert!(fs.root_item(7).unwrap().is_none());
+        assert_eq!(fs.balance_status().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_interrupted_balance() {
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let mut balance = vec![0u8; BalanceStatus::SIZE];
+        balance[0] = 0x2 | 0x4;
+        let items = [
+            (Key::new(2, 132, 0), root_item(0x100000, 1)),
+            (Key::new(BALANCE_OBJECTID, 248, 0), balance),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(root_tree, &leaf(root_tree, 1, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let status = fs.balance_status().unwrap().unwrap();
+        assert_eq!(
+            status.flags(),
+            BalanceFlags::SYSTEM | BalanceFlags::METADATA
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+use bitflags::bitflags;
+
+use crate::error::BtrfsError;
+
+/// `btrfs_timespec`: seconds and nanoseconds since the Unix epoch.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[brw(little)]
+pub struct Timespec {
+    pub sec: u64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    pub const SIZE: usize = 12;
+}
+
+/// `btrfs_inode_item`: the stat data of a file, directory or subvolume root.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq, Default)]
+#[brw(little)]
+pub struct InodeItem {
+    pub generation: u64,
+    pub transid: u64,
+    pub size: u64,
+    /// Bytes allocated on disk, including inline data.
+    pub nbytes: u64,
+    pub block_group: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub rdev: u64,
+    pub flags: u64,
+    pub sequence: u64,
+    pub reserved: [u64; 4],
+    pub atime: Timespec,
+    pub ctime: Timespec,
+    pub mtime: Timespec,
+    pub otime: Timespec,
+}
+
+bitflags! {
+    /// Flags of an inode item, as `chattr` and mount options set them.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct InodeFlags: u64 {
+        /// Data is written without checksums, so none are in the csum tree.
+        const NODATASUM = 1 << 0;
+        /// Data is overwritten in place (`chattr +C`); implies NODATASUM.
+        const NODATACOW = 1 << 1;
+        const READONLY = 1 << 2;
+        /// Never compress data (`chattr +m`).
+        const NOCOMPRESS = 1 << 3;
+        /// Has preallocated extents past its size.
+        const PREALLOC = 1 << 4;
+        const SYNC = 1 << 5;
+        const IMMUTABLE = 1 << 6;
+        const APPEND = 1 << 7;
+        const NODUMP = 1 << 8;
+        const NOATIME = 1 << 9;
+        const DIRSYNC = 1 << 10;
+        /// Always compress data (`chattr +c`).
+        const COMPRESS = 1 << 11;
+        /// Set on the inode embedded in a root item once it is initialized.
+        const ROOT_ITEM_INIT = 1 << 31;
+        /// fs-verity is enabled; the first of the read-only-compatible
+        /// flags kept in the upper half.
+        const RO_VERITY = 1 << 32;
+
+        const _ = !0;
+    }
+}
+
+/// File type bits of `mode`, as in `st_mode`.
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+impl InodeItem {
+    pub const SIZE: usize = 160;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<InodeItem, BtrfsError> {
+        if bytes.len() < InodeItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: InodeItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(InodeItem::read(&mut Cursor::new(
+            &bytes[..InodeItem::SIZE],
+        ))?)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_regular_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub fn flags(&self) -> InodeFlags {
+        InodeFlags::from_bits_retain(self.flags)
+    }
+
+    /// Whether fs-verity is enabled on the file.
+    pub fn is_verity(&self) -> bool {
+        self.flags().contains(InodeFlags::RO_VERITY)
+    }
+
+    /// Whether the file's data has checksums in the csum tree.
+    pub fn has_data_checksums(&self) -> bool {
+        !self.flags().contains(InodeFlags::NODATASUM)
+    }
+
+    /// Time of the last modification of the contents.
+    pub fn mtime(&self) -> Timespec {
+        self.mtime
+    }
+}
+
+/// One name of an inode: a `btrfs_inode_ref`, or a `btrfs_inode_extref`
+/// for links that did not fit (`EXTENDED_IREF`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InodeRef {
+    /// Objectid of the directory holding the name.
+    pub parent_dir: u64,
+    /// Converted lossily if not valid UTF-8.
+    pub name: String,
+    /// The entry's `DIR_INDEX` sequence number.
+    pub index: u64,
+}
+
+impl InodeRef {
+    /// Size of the fixed part of an inode ref, before the name.
+    pub const HEADER_SIZE: usize = 10;
+    /// Size of the fixed part of an inode extref, before the name.
+    pub const EXTREF_HEADER_SIZE: usize = 18;
+
+    /// Parses every ref packed into an `INODE_REF` item. All of them are
+    /// names in `parent_dir`, the item's key offset.
+    pub fn parse_refs(parent_dir: u64, bytes: &[u8]) -> Result<Vec<InodeRef>, BtrfsError> {
+        parse_all(bytes, InodeRef::HEADER_SIZE, |header| {
+            let index = u64::from_le_bytes(header[0..8].try_into().unwrap());
+            let name_len = u16::from_le_bytes([header[8], header[9]]);
+            (parent_dir, index, name_len)
+        })
+    }
+
+    /// Parses every extref packed into an `INODE_EXTREF` item. The key
+    /// offset is a hash, so each one names its directory itself.
+    pub fn parse_extrefs(bytes: &[u8]) -> Result<Vec<InodeRef>, BtrfsError> {
+        parse_all(bytes, InodeRef::EXTREF_HEADER_SIZE, |header| {
+            let parent_dir = u64::from_le_bytes(header[0..8].try_into().unwrap());
+            let index = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            let name_len = u16::from_le_bytes([header[16], header[17]]);
+            (parent_dir, index, name_len)
+        })
+    }
+}
+
+/// Splits `bytes` into back-to-back entries of a `header_size` header,
+/// decoded by `header` into `(parent_dir, index, name_len)`, and a name.
+fn parse_all(
+    mut bytes: &[u8],
+    header_size: usize,
+    header: impl Fn(&[u8]) -> (u64, u64, u16),
+) -> Result<Vec<InodeRef>, BtrfsError> {
+    let mut refs = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < header_size {
+            return Err(BtrfsError::ShortRead {
+                expected: header_size,
+                got: bytes.len(),
+            });
+        }
+        let (parent_dir, index, name_len) = header(&bytes[..header_size]);
+        let end = header_size + name_len as usize;
+        let name = bytes
+            .get(header_size..end)
+            .ok_or(BtrfsError::Malformed("inode ref name runs past item data"))?;
+        refs.push(InodeRef {
+            parent_dir,
+            name: String::from_utf8_lossy(name).into_owned(),
+            index,
+        });
+        bytes = &bytes[end..];
+    }
+    Ok(refs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_mode_and_times() {
+        let mut bytes = [0u8; InodeItem::SIZE];
+        bytes[16..24].copy_from_slice(&4096u64.to_le_bytes());
+        bytes[40..44].copy_from_slice(&1u32.to_le_bytes());
+        bytes[52..56].copy_from_slice(&0o100644u32.to_le_bytes());
+        // mtime
+        bytes[136..144].copy_from_slice(&1_700_000_000u64.to_le_bytes());
+        bytes[144..148].copy_from_slice(&500u32.to_le_bytes());
+
+        let inode = InodeItem::from_bytes(&bytes).unwrap();
+        assert_eq!(inode.size, 4096);
+        assert_eq!(inode.nlink, 1);
+        assert!(inode.is_regular_file());
+        assert!(!inode.is_dir());
+        assert!(!inode.is_symlink());
+        assert_eq!(
+            inode.mtime(),
+            Timespec {
+                sec: 1_700_000_000,
+                nsec: 500
+            }
+        );
+
+        let symlink = InodeItem {
+            mode: 0o120777,
+            ..inode.clone()
+        };
+        assert!(symlink.is_symlink());
+        let dir = InodeItem {
+            mode: 0o40755,
+            ..inode
+        };
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    fn decodes_inode_flags() {
+        let mut bytes = [0u8; InodeItem::SIZE];
+        bytes[64..72].copy_from_slice(&(0x803u64 | 1 << 32 | 1 << 40).to_le_bytes());
+        let inode = InodeItem::from_bytes(&bytes).unwrap();
+        let flags = inode.flags();
+        assert!(flags.contains(
+            InodeFlags::NODATASUM
+                | InodeFlags::NODATACOW
+                | InodeFlags::COMPRESS
+                | InodeFlags::RO_VERITY
+        ));
+        assert!(!flags.contains(InodeFlags::NOCOMPRESS));
+        assert_eq!(flags.bits(), inode.flags);
+        assert!(inode.is_verity());
+        assert!(!inode.has_data_checksums());
+        assert!(InodeItem::default().has_data_checksums());
+    }
+
+    #[test]
+    fn parses_packed_refs_and_extrefs() {
+        let mut refs = Vec::new();
+        for (index, name) in [(2u64, "a"), (5, "b-link")] {
+            refs.extend_from_slice(&index.to_le_bytes());
+            refs.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            refs.extend_from_slice(name.as_bytes());
+        }
+        let parsed = InodeRef::parse_refs(256, &refs).unwrap();
+        assert_eq!(
+            parsed,
+            [
+                InodeRef {
+                    parent_dir: 256,
+                    name: "a".into(),
+                    index: 2,
+                },
+                InodeRef {
+                    parent_dir: 256,
+                    name: "b-link".into(),
+                    index: 5,
+                },
+            ]
+        );
+        assert!(matches!(
+            InodeRef::parse_refs(256, &refs[..refs.len() - 1]),
+            Err(BtrfsError::Malformed(_))
+        ));
+        assert!(matches!(
+            InodeRef::parse_refs(256, &refs[..5]),
+            Err(BtrfsError::ShortRead { expected: 10, .. })
+        ));
+
+        let mut extref = Vec::new();
+        extref.extend_from_slice(&300u64.to_le_bytes());
+        extref.extend_from_slice(&9u64.to_le_bytes());
+        extref.extend_from_slice(&1u16.to_le_bytes());
+        extref.push(b'c');
+        assert_eq!(
+            InodeRef::parse_extrefs(&extref).unwrap(),
+            [InodeRef {
+                parent_dir: 300,
+                name: "c".into(),
+                index: 9,
+            }]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use binrw::{BinRead, BinWrite};
+
+/// `btrfs_disk_key`: the `(objectid, type, offset)` triple every tree item
+/// is addressed by.
+///
+/// Keys order by objectid, then type, then offset, which is the order items
+/// are sorted in within a tree. The derived `Ord` relies on the field order,
+/// so a `BTreeMap<Key, _>` ranges over keys as a tree search would.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[brw(little)]
+pub struct Key {
+    pub objectid: u64,
+    pub item_type: u8,
+    pub offset: u64,
+}
+
+impl Key {
+    /// Size of a key on disk.
+    pub const SIZE: usize = 17;
+
+    /// The lowest possible key.
+    pub const MIN: Key = Key {
+        objectid: 0,
+        item_type: 0,
+        offset: 0,
+    };
+    /// The highest possible key.
+    pub const MAX: Key = Key {
+        objectid: u64::MAX,
+        item_type: u8::MAX,
+        offset: u64::MAX,
+    };
+
+    pub fn new(objectid: u64, item_type: u8, offset: u64) -> Key {
+        Key {
+            objectid,
+            item_type,
+            offset,
+        }
+    }
+
+    /// The packed on-disk form: objectid and offset little-endian around
+    /// the type byte, with no padding. Matches the key's `BinWrite`.
+    pub fn to_disk_bytes(&self) -> [u8; Key::SIZE] {
+        let mut bytes = [0u8; Key::SIZE];
+        bytes[..8].copy_from_slice(&self.objectid.to_le_bytes());
+        bytes[8] = self.item_type;
+        bytes[9..].copy_from_slice(&self.offset.to_le_bytes());
+        bytes
+    }
+
+    /// The inverse of [`Key::to_disk_bytes`].
+    pub fn from_disk_bytes(bytes: &[u8; Key::SIZE]) -> Key {
+        Key {
+            objectid: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            item_type: bytes[8],
+            offset: u64::from_le_bytes(bytes[9..].try_into().unwrap()),
+        }
+    }
+
+    /// [`Key::MIN`], for building search bounds.
+    pub const fn min() -> Key {
+        Key::MIN
+    }
+
+    /// [`Key::MAX`], for building search bounds.
+    pub const fn max() -> Key {
+        Key::MAX
+    }
+
+    /// A key with a typed item type.
+    pub const fn exact(objectid: u64, item_type: KeyType, offset: u64) -> Key {
+        Key {
+            objectid,
+            item_type: item_type.as_u8(),
+            offset,
+        }
+    }
+
+    /// The lowest and highest keys of `objectid`, as bounds for a range
+    /// search covering all of its items.
+    pub const fn for_objectid(objectid: u64) -> (Key, Key) {
+        (
+            Key {
+                objectid,
+                item_type: 0,
+                offset: 0,
+            },
+            Key {
+                objectid,
+                item_type: u8::MAX,
+                offset: u64::MAX,
+            },
+        )
+    }
+
+    pub fn item_type(&self) -> KeyType {
+        KeyType::from_u8(self.item_type)
+    }
+
+    /// The smallest key greater than this one, or `None` for [`Key::MAX`].
+    /// The offset is incremented first, carrying into the type and then
+    /// the objectid.
+    pub fn next(&self) -> Option<Key> {
+        if let Some(offset) = self.offset.checked_add(1) {
+            return Some(Key { offset, ..*self });
+        }
+        if let Some(item_type) = self.item_type.checked_add(1) {
+            return Some(Key::new(self.objectid, item_type, 0));
+        }
+        Some(Key::new(self.objectid.checked_add(1)?, 0, 0))
+    }
+}
+
+/// Defines [`KeyType`] from `Variant = value` pairs, with the conversions
+/// to and from the type byte kept in step.
+macro_rules! key_types {
+    ($($(#[$doc:meta])* $variant:ident = $value:literal,)*) => {
+        /// The item type byte of a [`Key`], covering every type the kernel
+        /// defines.
+        #[repr(u8)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum KeyType {
+            $($(#[$doc])* $variant = $value,)*
+            Unknown(u8),
+        }
+
+        impl KeyType {
+            pub const fn from_u8(value: u8) -> KeyType {
+                match value {
+                    $($value => KeyType::$variant,)*
+                    other => KeyType::Unknown(other),
+                }
+            }
+
+            pub const fn as_u8(&self) -> u8 {
+                match *self {
+                    $(KeyType::$variant => $value,)*
+                    KeyType::Unknown(other) => other,
+                }
+            }
+        }
+    };
+}
+
+key_types! {
+    InodeItem = 1,
+    InodeRef = 12,
+    /// Hard link names that did not fit an `INODE_REF` (`EXTENDED_IREF`).
+    InodeExtref = 13,
+    XattrItem = 24,
+    VerityDescItem = 36,
+    VerityMerkleItem = 37,
+    OrphanItem = 48,
+    DirLogItem = 60,
+    DirLogIndex = 72,
+    DirItem = 84,
+    DirIndex = 96,
+    ExtentData = 108,
+    /// Data checksums, keyed by the logical address of the first sector.
+    ExtentCsum = 128,
+    RootItem = 132,
+    RootBackref = 144,
+    RootRef = 156,
+    ExtentItem = 168,
+    /// A tree block in the extent tree of a `SKINNY_METADATA` filesystem,
+    /// keyed by level instead of size.
+    MetadataItem = 169,
+    ExtentOwnerRef = 172,
+    TreeBlockRef = 176,
+    ExtentDataRef = 178,
+    SharedBlockRef = 182,
+    SharedDataRef = 184,
+    BlockGroupItem = 192,
+    FreeSpaceInfo = 198,
+    FreeSpaceExtent = 199,
+    FreeSpaceBitmap = 200,
+    DevExtent = 204,
+    DevItem = 216,
+    ChunkItem = 228,
+    RaidStripe = 230,
+    QgroupStatus = 240,
+    QgroupInfo = 242,
+    QgroupLimit = 244,
+    QgroupRelation = 246,
+    /// Meaning depends on the objectid; the balance status
+    /// (`BALANCE_ITEM`) is one.
+    TemporaryItem = 248,
+    /// Meaning depends on the objectid; device statistics (`DEV_STATS`)
+    /// are one.
+    PersistentItem = 249,
+    DevReplace = 250,
+    UuidKeySubvol = 251,
+    UuidKeyReceivedSubvol = 252,
+    StringItem = 253,
+}
+
+impl From<KeyType> for u8 {
+    fn from(key_type: KeyType) -> u8 {
+        key_type.as_u8()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_objectid_type_offset() {
+        let mut keys = vec![
+            Key::new(2, 1, 0),
+            Key::new(1, 84, 5),
+            Key::new(1, 84, 2),
+            Key::new(1, 1, u64::MAX),
+        ];
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                Key::new(1, 1, u64::MAX),
+                Key::new(1, 84, 2),
+                Key::new(1, 84, 5),
+                Key::new(2, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_work_as_map_keys() {
+        use std::collections::{BTreeMap, HashSet};
+
+        let seen: HashSet<Key> = [Key::new(256, 1, 0), Key::new(256, 1, 0)].into();
+        assert_eq!(seen.len(), 1);
+
+        let map: BTreeMap<Key, &str> = [
+            (Key::new(256, 1, 0), "inode"),
+            (Key::new(256, 12, 5), "ref"),
+            (Key::new(257, 1, 0), "next"),
+        ]
+        .into();
+        let (min, max) = Key::for_objectid(256);
+        let items: Vec<_> = map.range(min..=max).map(|(_, v)| *v).collect();
+        assert_eq!(items, ["inode", "ref"]);
+    }
+
+    #[test]
+    fn packs_keys_without_padding() {
+        let key = Key::new(0x0102030405060708, 0x84, 0x1112131415161718);
+        let expected = [
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, 0x84, 0x18, 0x17, 0x16, 0x15, 0x14,
+            0x13, 0x12, 0x11,
+        ];
+        assert_eq!(key.to_disk_bytes(), expected);
+        assert_eq!(Key::from_disk_bytes(&expected), key);
+
+        let mut written = binrw::io::Cursor::new(Vec::new());
+        key.write(&mut written).unwrap();
+        assert_eq!(written.into_inner(), expected);
+    }
+
+    #[test]
+    fn builds_search_bounds() {
+        assert_eq!(Key::min(), Key::new(0, 0, 0));
+        assert_eq!(Key::max(), Key::new(u64::MAX, 255, u64::MAX));
+        assert_eq!(Key::exact(256, KeyType::InodeItem, 0), Key::new(256, 1, 0));
+        let (min, max) = Key::for_objectid(256);
+        assert!(min < Key::new(256, 1, 0) && Key::new(256, 108, 4096) < max);
+        assert!(Key::new(255, 255, u64::MAX) < min && max < Key::new(257, 0, 0));
+    }
+
+    #[test]
+    fn steps_to_the_next_key() {
+        assert_eq!(Key::new(1, 1, 5).next(), Some(Key::new(1, 1, 6)));
+        assert_eq!(Key::new(1, 1, u64::MAX).next(), Some(Key::new(1, 2, 0)));
+        assert_eq!(Key::new(1, 255, u64::MAX).next(), Some(Key::new(2, 0, 0)));
+        assert_eq!(Key::MAX.next(), None);
+    }
+
+    #[test]
+    fn decodes_item_type() {
+        assert_eq!(Key::new(256, 228, 0).item_type(), KeyType::ChunkItem);
+        assert_eq!(Key::new(256, 255, 0).item_type(), KeyType::Unknown(255));
+        assert_eq!(u8::from(KeyType::DirIndex), 96);
+        for value in 0..=u8::MAX {
+            assert_eq!(KeyType::from_u8(value).as_u8(), value);
+        }
+        assert_eq!(KeyType::from_u8(13), KeyType::InodeExtref);
+        assert_eq!(KeyType::from_u8(251), KeyType::UuidKeySubvol);
+    }
+}
@@ -1,3 +1,127 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+mod balance;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+mod check;
+mod chunk;
+#[cfg(feature = "std")]
+mod compress;
+mod convert;
+mod csum;
+#[cfg(feature = "std")]
+mod device;
+mod dir;
+#[cfg(feature = "std")]
+mod dump;
+mod error;
+mod extent;
+#[cfg(feature = "std")]
+mod extent_tree;
+mod features;
+#[cfg(feature = "std")]
+mod file;
+mod file_extent;
+mod free_space;
+#[cfg(feature = "std")]
+mod fs;
+mod inode;
+mod key;
+#[cfg(feature = "std")]
+mod log_tree;
+mod node;
+#[cfg(feature = "std")]
+mod offset_reader;
+mod qgroup;
+#[cfg(feature = "std")]
+mod qgroup_tree;
+#[cfg(feature = "raid56")]
+mod raid56;
+mod root;
+#[cfg(feature = "std")]
+mod scrub;
+#[cfg(feature = "std")]
+mod send;
+#[cfg(feature = "serde")]
+mod ser;
+#[cfg(feature = "std")]
+mod space;
+#[cfg(feature = "std")]
+mod subvol;
+mod superblock;
+#[cfg(all(test, feature = "crc32c"))]
+mod testutil;
+#[cfg(feature = "std")]
+mod tree;
+mod units;
+mod verity;
+
+pub use balance::{BalanceArgsFlags, BalanceFlags, BalanceStatus, DiskBalanceArgs};
+#[cfg(feature = "std")]
+pub use cache::CacheStats;
+#[cfg(feature = "std")]
+pub use check::{
+    GenerationMismatch, GenerationReport, VerifyCounts, VerifyOptions, VerifyPass, VerifyReport,
+};
+pub use chunk::{
+    raid_profile, BlockGroupFlags, BlockGroupItem, BlockGroupType, Chunk, ChunkMap, ChunkRange,
+    DevExtent, DeviceErrorStats, RaidProfile, Stripe, SysChunkArray,
+};
+pub use csum::ChecksumType;
+#[cfg(feature = "crc32c")]
+pub use csum::{crc32c, crc32c_is_accelerated, name_hash};
+#[cfg(feature = "std")]
+pub use device::{BlockDevice, PartialReader, SeekDevice};
+pub use dir::{DirEntry, DirEntryType, DirItemHeader, DirItems, XattrItems};
+pub use error::{BtrfsError, ParseEnumError};
+pub use extent::{ExtentBackref, ExtentFlags, ExtentItem, ExtentKind};
+pub use features::{CompatFlags, CompatRoFlags, IncompatFlags};
+#[cfg(feature = "std")]
+pub use file::Walk;
+pub use file_extent::{
+    Compression, DiskUsage, ExtentMapping, ExtentMappingKind, FileExtentData, FileExtentItem,
+    FileExtentKind,
+};
+pub use free_space::{free_space_bitmap, FreeSpaceFlags, FreeSpaceInfo};
+#[cfg(feature = "std")]
+pub use fs::{Btrfs, ReadSeek, ZonedInfo};
+pub use inode::{InodeFlags, InodeItem, InodeRef, Timespec};
+pub use key::{Key, KeyType};
+#[cfg(feature = "std")]
+pub use log_tree::{LogTree, LoggedSubvolume};
+pub use node::{InternalNode, KeyPtr, Leaf, LeafItem, NodeHeader};
+#[cfg(feature = "std")]
+pub use offset_reader::OffsetReader;
+pub use qgroup::{
+    qgroup_level, qgroup_subvolid, QgroupInfoItem, QgroupLimitFlags, QgroupLimitItem,
+    QgroupStatusFlags, QgroupStatusItem,
+};
+#[cfg(feature = "std")]
+pub use qgroup_tree::QgroupInfo;
+pub use root::{RootFlags, RootItem, RootRef};
+#[cfg(feature = "std")]
+pub use scrub::{ScrubMismatch, ScrubProgress, ScrubReport};
+#[cfg(all(feature = "std", feature = "crc32c"))]
+pub use send::SendStreamWriter;
+#[cfg(feature = "std")]
+pub use send::{SendCommand, SendStream};
+#[cfg(feature = "std")]
+pub use space::{DeviceStats, FreeRange, SpaceInfo, SpaceUsage, StatFs};
+#[cfg(feature = "std")]
+pub use subvol::SubvolumeInfo;
+pub use superblock::*;
+#[cfg(feature = "std")]
+pub use tree::{Tree, TreeCursor, TreeIter, TreeRoot, TreeRoots};
+pub use units::{ByteSize, Generation, LogicalAddr};
+/// The UUID type the fsid and other UUID accessors return, for comparing
+/// them without depending on `uuid` directly.
+#[cfg(feature = "uuid")]
+pub use uuid::Uuid;
+pub use verity::{VerityDescriptorItem, VerityHashAlgorithm, VerityInfo};
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
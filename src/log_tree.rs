@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+
+use crate::dir::{DirEntry, DirItems};
+use crate::error::BtrfsError;
+use crate::file_extent::FileExtentItem;
+use crate::fs::Btrfs;
+use crate::inode::InodeItem;
+use crate::key::{Key, KeyType};
+use crate::root::RootItem;
+
+/// Objectid of the log root tree's root items (`-6`).
+pub(crate) const TREE_LOG_OBJECTID: u64 = -6i64 as u64;
+
+/// The tree log: changes fsynced since the last transaction commit, which
+/// the kernel replays at the next mount. Returned by [`Btrfs::log_tree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogTree {
+    /// One log per subvolume with logged changes, ordered by subvolume id.
+    pub subvolumes: Vec<LoggedSubvolume>,
+}
+
+impl LogTree {
+    /// Number of inodes with logged changes, over all subvolumes.
+    pub fn logged_inode_count(&self) -> usize {
+        self.subvolumes.iter().map(|log| log.inodes.len()).sum()
+    }
+}
+
+/// The logged changes of one subvolume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedSubvolume {
+    /// Id of the subvolume the changes belong to.
+    pub subvolume: u64,
+    /// Stat data of each logged inode, by inode number.
+    pub inodes: BTreeMap<u64, InodeItem>,
+    /// File extents, keyed by inode number and file offset.
+    pub extents: BTreeMap<(u64, u64), FileExtentItem>,
+    /// Directory entries from `DIR_INDEX` items, keyed by directory and
+    /// index.
+    pub dir_entries: BTreeMap<(u64, u64), DirEntry>,
+    /// Every item of the log as stored, including those decoded above and
+    /// the ones that are not, such as refs, `DIR_LOG` ranges and checksums.
+    pub items: Vec<(Key, Vec<u8>)>,
+}
+
+impl Btrfs {
+    /// The tree log at `log_root`, or `None` if the filesystem was cleanly
+    /// committed and has none.
+    ///
+    /// This only reads the log. Nothing is replayed, so the other trees
+    /// read through this `Btrfs` still show the state of the last commit,
+    /// without the logged changes.
+    pub fn log_tree(&self) -> Result<Option<LogTree>, BtrfsError> {
+        let sb = self.superblock();
+        if sb.log_root == 0 {
+            return Ok(None);
+        }
+        let log_roots = self.tree(sb.log_root, sb.log_root_level);
+        let root_item = KeyType::RootItem.as_u8();
+        let range = log_roots.search_range(
+            Key::new(TREE_LOG_OBJECTID, root_item, 0),
+            Key::new(TREE_LOG_OBJECTID, root_item, u64::MAX),
+        );
+
+        let mut subvolumes = Vec::new();
+        for item in range {
+            let (key, data) = item?;
+            let root = RootItem::from_bytes(&data)?;
+            subvolumes.push(self.logged_subvolume(key.offset, &root)?);
+        }
+        Ok(Some(LogTree { subvolumes }))
+    }
+
+    fn logged_subvolume(
+        &self,
+        subvolume: u64,
+        root: &RootItem,
+    ) -> Result<LoggedSubvolume, BtrfsError> {
+        let mut log = LoggedSubvolume {
+            subvolume,
+            inodes: BTreeMap::new(),
+            extents: BTreeMap::new(),
+            dir_entries: BTreeMap::new(),
+            items: Vec::new(),
+        };
+        for item in self.tree(root.bytenr(), root.level()).iter() {
+            let (key, data) = item?;
+            match key.item_type() {
+                KeyType::InodeItem => {
+                    log.inodes
+                        .insert(key.objectid, InodeItem::from_bytes(&data)?);
+                }
+                KeyType::ExtentData => {
+                    log.extents.insert(
+                        (key.objectid, key.offset),
+                        FileExtentItem::from_bytes(&data)?,
+                    );
+                }
+                KeyType::DirIndex => {
+                    let (name, inode, entry_type) = DirItems::new(&data)
+                        .next()
+                        .ok_or(BtrfsError::Malformed("dir index item is empty"))??;
+                    log.dir_entries.insert(
+                        (key.objectid, key.offset),
+                        DirEntry {
+                            name,
+                            inode,
+                            entry_type,
+                        },
+                    );
+                }
+                _ => {}
+            }
+            log.items.push((key, data));
+        }
+        Ok(log)
+    }
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::dir::DirEntryType;
+    use crate::testutil::{block, leaf, root_item, TestImage};
+
+    fn inode(mode: u32, size: u64) -> Vec<u8> {
+        let mut bytes = vec![0u8; InodeItem::SIZE];
+        bytes[16..24].copy_from_slice(&size.to_le_bytes());
+        bytes[52..56].copy_from_slice(&mode.to_le_bytes());
+        bytes
+    }
+
+    fn dir_item(name: &str, location: Key, dir_type: u8) -> Vec<u8> {
+        let mut bytes = location.to_disk_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 8]);
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.push(dir_type);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_logged_changes_without_replaying() {
+        // A file 257 created in the top-level directory and fsynced, with
+        // its stat data, name, inline data and the directory's new entry.
+        let mut inline = vec![0u8; FileExtentItem::HEADER_SIZE];
+        inline[8..16].copy_from_slice(&5u64.to_le_bytes());
+        inline.extend_from_slice(b"hello");
+        let file = Key::new(257, 1, 0);
+        let items = [
+            (Key::new(256, 1, 0), inode(0o40755, 10)),
+            (Key::new(256, 72, 2), 3u64.to_le_bytes().to_vec()),
+            (Key::new(256, 96, 2), dir_item("new", file, 1)),
+            (file, inode(0o100644, 5)),
+            (
+                Key::new(257, 12, 256),
+                vec![2, 0, 0, 0, 0, 0, 0, 0, 3, 0, b'n', b'e', b'w'],
+            ),
+            (Key::new(257, 108, 0), inline),
+        ];
+        let log_root = root_item(block(1), 0);
+        let roots = [(Key::new(TREE_LOG_OBJECTID, 132, 5), &log_root)];
+
+        let mut image = TestImage::new();
+        image
+            .set_log_root(block(0), 0)
+            .put_block(block(0), &leaf(block(0), TREE_LOG_OBJECTID, &roots))
+            .put_block(block(1), &leaf(block(1), TREE_LOG_OBJECTID, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let log = fs.log_tree().unwrap().unwrap();
+        assert_eq!(log.logged_inode_count(), 2);
+        let top = &log.subvolumes[0];
+        assert_eq!(top.subvolume, 5);
+        assert_eq!(top.inodes[&257].size, 5);
+        assert!(top.extents[&(257, 0)].is_inline());
+        let entry = &top.dir_entries[&(256, 2)];
+        assert_eq!((entry.name.as_str(), entry.inode), ("new", file));
+        assert_eq!(entry.entry_type, DirEntryType::RegularFile);
+        assert_eq!(top.items.len(), items.len());
+
+        assert!(Btrfs::from_reader(Cursor::new(TestImage::new().build()))
+            .unwrap()
+            .log_tree()
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,330 @@
+use alloc::vec::Vec;
+
+use binrw::io::{Cursor, Read, Seek};
+use binrw::{BinRead, BinWrite};
+
+use crate::error::BtrfsError;
+use crate::key::Key;
+use crate::superblock::{BTRFS_CSUM_SIZE, BTRFS_FSID_SIZE, BTRFS_UUID_SIZE};
+
+/// `btrfs_header`: the start of every tree block, leaf or internal node.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub struct NodeHeader {
+    /// Checksum of the rest of the block.
+    pub csum: [u8; BTRFS_CSUM_SIZE],
+    /// FSID (or metadata UUID) of the filesystem that wrote the block.
+    pub fsid: [u8; BTRFS_FSID_SIZE],
+    /// Logical address the block was written for.
+    pub bytenr: u64,
+    pub flags: u64,
+    pub chunk_tree_uuid: [u8; BTRFS_UUID_SIZE],
+    pub generation: u64,
+    /// Objectid of the tree that owns the block.
+    pub owner: u64,
+    /// Number of items (leaf) or key pointers (internal node).
+    pub nritems: u32,
+    /// 0 for leaves.
+    pub level: u8,
+}
+
+impl NodeHeader {
+    /// Size of the header on disk.
+    pub const SIZE: usize = 101;
+
+    /// Reads a header at the reader's current position.
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<NodeHeader, BtrfsError> {
+        Ok(NodeHeader::read(reader)?)
+    }
+
+    /// Parses the header at the start of a tree block.
+    pub fn from_bytes(bytes: &[u8]) -> Result<NodeHeader, BtrfsError> {
+        if bytes.len() < NodeHeader::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: NodeHeader::SIZE,
+                got: bytes.len(),
+            });
+        }
+        NodeHeader::from_reader(&mut Cursor::new(&bytes[..NodeHeader::SIZE]))
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.level == 0
+    }
+}
+
+/// `btrfs_item`: a leaf's pointer to one item's data.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+struct ItemHeader {
+    key: Key,
+    /// Offset of the data, relative to the end of the node header.
+    offset: u32,
+    size: u32,
+}
+
+impl ItemHeader {
+    const SIZE: usize = 25;
+}
+
+/// A level 0 tree block. Item headers grow forward from the node header
+/// while item data is packed backward from the end of the block.
+pub struct Leaf<'a> {
+    header: NodeHeader,
+    block: &'a [u8],
+    items: Vec<ItemHeader>,
+}
+
+impl<'a> Leaf<'a> {
+    /// Parses a leaf, checking that every item lies inside `block`.
+    pub fn new(block: &'a [u8]) -> Result<Leaf<'a>, BtrfsError> {
+        let header = NodeHeader::from_bytes(block)?;
+        if !header.is_leaf() {
+            return Err(BtrfsError::Malformed(
+                "expected a leaf, found an internal node",
+            ));
+        }
+
+        let nritems = header.nritems as usize;
+        let headers_end = nritems
+            .checked_mul(ItemHeader::SIZE)
+            .and_then(|len| len.checked_add(NodeHeader::SIZE))
+            .filter(|&end| end <= block.len())
+            .ok_or(BtrfsError::Malformed("leaf item count exceeds block size"))?;
+
+        let mut cursor = Cursor::new(&block[NodeHeader::SIZE..headers_end]);
+        let mut items = Vec::with_capacity(nritems);
+        for _ in 0..nritems {
+            let item = ItemHeader::read(&mut cursor)?;
+            let end = NodeHeader::SIZE as u64 + item.offset as u64 + item.size as u64;
+            if end > block.len() as u64 {
+                return Err(BtrfsError::Malformed("leaf item data out of bounds"));
+            }
+            items.push(item);
+        }
+
+        Ok(Leaf {
+            header,
+            block,
+            items,
+        })
+    }
+
+    pub fn header(&self) -> &NodeHeader {
+        &self.header
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The items in key order.
+    pub fn items(&self) -> impl Iterator<Item = LeafItem<'a>> + '_ {
+        let block = self.block;
+        self.items.iter().map(move |item| {
+            let start = NodeHeader::SIZE + item.offset as usize;
+            LeafItem {
+                key: item.key,
+                offset: item.offset,
+                data: &block[start..start + item.size as usize],
+            }
+        })
+    }
+}
+
+/// One item of a [`Leaf`].
+#[derive(Debug, Clone, Copy)]
+pub struct LeafItem<'a> {
+    key: Key,
+    offset: u32,
+    data: &'a [u8],
+}
+
+impl<'a> LeafItem<'a> {
+    pub fn key(&self) -> Key {
+        self.key
+    }
+
+    /// Offset of the data relative to the end of the node header.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// `btrfs_key_ptr`: an internal node's pointer to a child block.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct KeyPtr {
+    /// Lowest key stored under the child.
+    pub key: Key,
+    /// Logical address of the child block.
+    pub blockptr: u64,
+    /// Generation the child was written in.
+    pub generation: u64,
+}
+
+impl KeyPtr {
+    pub const SIZE: usize = 33;
+}
+
+/// A tree block with `level > 0`, holding pointers to child blocks.
+pub struct InternalNode {
+    header: NodeHeader,
+    ptrs: Vec<KeyPtr>,
+}
+
+impl InternalNode {
+    /// Parses an internal node, checking that `nritems` fits in `block`.
+    pub fn new(block: &[u8]) -> Result<InternalNode, BtrfsError> {
+        let header = NodeHeader::from_bytes(block)?;
+        if header.is_leaf() {
+            return Err(BtrfsError::Malformed(
+                "expected an internal node, found a leaf",
+            ));
+        }
+
+        let nritems = header.nritems as usize;
+        let ptrs_end = nritems
+            .checked_mul(KeyPtr::SIZE)
+            .and_then(|len| len.checked_add(NodeHeader::SIZE))
+            .filter(|&end| end <= block.len())
+            .ok_or(BtrfsError::Malformed(
+                "node pointer count exceeds block size",
+            ))?;
+
+        let mut cursor = Cursor::new(&block[NodeHeader::SIZE..ptrs_end]);
+        let ptrs = (0..nritems)
+            .map(|_| KeyPtr::read(&mut cursor))
+            .collect::<Result<_, _>>()?;
+
+        Ok(InternalNode { header, ptrs })
+    }
+
+    pub fn header(&self) -> &NodeHeader {
+        &self.header
+    }
+
+    pub fn key_ptrs(&self) -> impl Iterator<Item = KeyPtr> + '_ {
+        self.ptrs.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4KiB leaf holding two items, laid out the way the kernel would.
+    fn leaf_block() -> Vec<u8> {
+        let mut block = vec![0u8; 4096];
+        block[48..56].copy_from_slice(&0x1504000u64.to_le_bytes()); // bytenr
+        block[96..100].copy_from_slice(&2u32.to_le_bytes()); // nritems
+
+        let items: [(Key, &[u8]); 2] = [
+            (Key::new(1, 216, 1), b"first item"),
+            (Key::new(256, 228, 22020096), b"second"),
+        ];
+        let mut data_end = 4096 - NodeHeader::SIZE;
+        for (i, (key, data)) in items.iter().enumerate() {
+            data_end -= data.len();
+            let item = ItemHeader {
+                key: *key,
+                offset: data_end as u32,
+                size: data.len() as u32,
+            };
+            let at = NodeHeader::SIZE + i * ItemHeader::SIZE;
+            let mut cursor = Cursor::new(&mut block[at..at + ItemHeader::SIZE]);
+            item.write(&mut cursor).unwrap();
+            let start = NodeHeader::SIZE + data_end;
+            block[start..start + data.len()].copy_from_slice(data);
+        }
+        block
+    }
+
+    #[test]
+    fn header_is_101_bytes() {
+        let mut block = [0u8; NodeHeader::SIZE];
+        block[48] = 0x40; // bytenr
+        block[96] = 3; // nritems
+        block[100] = 1; // level
+
+        let header = NodeHeader::from_bytes(&block).unwrap();
+        assert_eq!(header.bytenr, 0x40);
+        assert_eq!(header.nritems, 3);
+        assert!(!header.is_leaf());
+
+        let mut out = Cursor::new(Vec::new());
+        header.write(&mut out).unwrap();
+        assert_eq!(out.into_inner(), block);
+    }
+
+    #[test]
+    fn iterates_leaf_items() {
+        let block = leaf_block();
+        let leaf = Leaf::new(&block).unwrap();
+        assert_eq!(leaf.header().bytenr, 0x1504000);
+
+        let items: Vec<_> = leaf.items().collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].key(), Key::new(1, 216, 1));
+        assert_eq!(items[0].data(), b"first item");
+        assert_eq!(items[0].offset() as usize, 4096 - NodeHeader::SIZE - 10);
+        assert_eq!(items[1].data(), b"second");
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_items() {
+        let mut block = leaf_block();
+        block[96..100].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(Leaf::new(&block), Err(BtrfsError::Malformed(_))));
+
+        let mut block = leaf_block();
+        // Grow the first item's size past the end of the block.
+        block[NodeHeader::SIZE + 21..NodeHeader::SIZE + 25].copy_from_slice(&64u32.to_le_bytes());
+        assert!(matches!(Leaf::new(&block), Err(BtrfsError::Malformed(_))));
+    }
+
+    #[test]
+    fn iterates_key_ptrs() {
+        let mut block = vec![0u8; 4096];
+        block[96..100].copy_from_slice(&2u32.to_le_bytes());
+        block[100] = 1;
+        let ptrs = [
+            KeyPtr {
+                key: Key::new(1, 1, 0),
+                blockptr: 0x10000,
+                generation: 7,
+            },
+            KeyPtr {
+                key: Key::new(300, 1, 0),
+                blockptr: 0x20000,
+                generation: 9,
+            },
+        ];
+        let mut cursor = Cursor::new(&mut block[NodeHeader::SIZE..]);
+        for ptr in &ptrs {
+            ptr.write(&mut cursor).unwrap();
+        }
+
+        let node = InternalNode::new(&block).unwrap();
+        assert_eq!(node.key_ptrs().collect::<Vec<_>>(), ptrs);
+
+        // 4096 bytes can hold at most 121 key pointers.
+        block[96..100].copy_from_slice(&122u32.to_le_bytes());
+        assert!(matches!(
+            InternalNode::new(&block),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+}
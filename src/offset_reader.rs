@@ -0,0 +1,86 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A reader over the part of `R` starting at a fixed offset, such as a
+/// partition inside a whole-disk image. Offset 0 of the wrapper is
+/// `partition_offset` of the inner reader, and seeking before it fails.
+#[derive(Debug)]
+pub struct OffsetReader<R> {
+    inner: R,
+    partition_offset: u64,
+}
+
+impl<R: Seek> OffsetReader<R> {
+    /// Wraps `inner` and seeks it to the start of the partition.
+    pub fn new(mut inner: R, partition_offset: u64) -> io::Result<OffsetReader<R>> {
+        inner.seek(SeekFrom::Start(partition_offset))?;
+        Ok(OffsetReader {
+            inner,
+            partition_offset,
+        })
+    }
+}
+
+impl<R> OffsetReader<R> {
+    pub fn partition_offset(&self) -> u64 {
+        self.partition_offset
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for OffsetReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => {
+                let offset = offset
+                    .checked_add(self.partition_offset)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek overflow"))?;
+                SeekFrom::Start(offset)
+            }
+            relative => relative,
+        };
+        let before = self.inner.stream_position()?;
+        let absolute = self.inner.seek(pos)?;
+        if absolute < self.partition_offset {
+            self.inner.seek(SeekFrom::Start(before))?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the partition",
+            ));
+        }
+        Ok(absolute - self.partition_offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn shifts_every_position() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let mut reader = OffsetReader::new(Cursor::new(bytes), 16).unwrap();
+        let mut buf = [0u8; 2];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [16, 17]);
+
+        assert_eq!(reader.seek(SeekFrom::Start(4)).unwrap(), 4);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [20, 21]);
+        assert_eq!(reader.seek(SeekFrom::Current(-6)).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), 239);
+
+        assert!(reader.seek(SeekFrom::Current(-240)).is_err());
+        assert_eq!(reader.stream_position().unwrap(), 239);
+    }
+}
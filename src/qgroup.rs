@@ -0,0 +1,205 @@
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+use bitflags::bitflags;
+
+use crate::error::BtrfsError;
+
+bitflags! {
+    /// Flags of the quota tree's status item.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct QgroupStatusFlags: u64 {
+        /// Quotas are enabled.
+        const ON = 1 << 0;
+        /// A rescan is rebuilding the counts.
+        const RESCAN = 1 << 1;
+        /// The counts are known to be wrong until the next rescan.
+        const INCONSISTENT = 1 << 2;
+        /// Simple quotas, which charge extents to their creator only.
+        const SIMPLE_MODE = 1 << 3;
+
+        const _ = !0;
+    }
+}
+
+bitflags! {
+    /// Which limits of a qgroup limit item are set.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct QgroupLimitFlags: u64 {
+        const MAX_RFER = 1 << 0;
+        const MAX_EXCL = 1 << 1;
+        const RSV_RFER = 1 << 2;
+        const RSV_EXCL = 1 << 3;
+        const RFER_CMPR = 1 << 4;
+        const EXCL_CMPR = 1 << 5;
+
+        const _ = !0;
+    }
+}
+
+/// `btrfs_qgroup_status_item`, keyed `(0, QGROUP_STATUS, 0)`.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct QgroupStatusItem {
+    pub version: u64,
+    /// Generation the counts were last consistent in.
+    pub generation: u64,
+    pub flags: u64,
+    /// Objectid the running rescan has reached.
+    pub rescan: u64,
+}
+
+impl QgroupStatusItem {
+    /// Size without the `enable_gen` field that simple quotas append.
+    pub const SIZE: usize = 32;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<QgroupStatusItem, BtrfsError> {
+        if bytes.len() < QgroupStatusItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: QgroupStatusItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(QgroupStatusItem::read(&mut Cursor::new(
+            &bytes[..QgroupStatusItem::SIZE],
+        ))?)
+    }
+
+    pub fn flags(&self) -> QgroupStatusFlags {
+        QgroupStatusFlags::from_bits_retain(self.flags)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.flags().contains(QgroupStatusFlags::ON)
+    }
+
+    /// Whether the counts can be trusted: no rescan is running and they
+    /// are not marked inconsistent.
+    pub fn is_consistent(&self) -> bool {
+        !self
+            .flags()
+            .intersects(QgroupStatusFlags::RESCAN | QgroupStatusFlags::INCONSISTENT)
+    }
+}
+
+/// `btrfs_qgroup_info_item`: the usage of one qgroup, keyed
+/// `(0, QGROUP_INFO, qgroupid)`.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct QgroupInfoItem {
+    pub generation: u64,
+    /// Bytes referenced by the qgroup.
+    pub rfer: u64,
+    pub rfer_cmpr: u64,
+    /// Bytes referenced by nothing outside the qgroup.
+    pub excl: u64,
+    pub excl_cmpr: u64,
+}
+
+impl QgroupInfoItem {
+    pub const SIZE: usize = 40;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<QgroupInfoItem, BtrfsError> {
+        if bytes.len() < QgroupInfoItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: QgroupInfoItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(QgroupInfoItem::read(&mut Cursor::new(
+            &bytes[..QgroupInfoItem::SIZE],
+        ))?)
+    }
+}
+
+/// `btrfs_qgroup_limit_item`, keyed `(0, QGROUP_LIMIT, qgroupid)`. Only the
+/// limits named in `flags` apply.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct QgroupLimitItem {
+    pub flags: u64,
+    pub max_rfer: u64,
+    pub max_excl: u64,
+    pub rsv_rfer: u64,
+    pub rsv_excl: u64,
+}
+
+impl QgroupLimitItem {
+    pub const SIZE: usize = 40;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<QgroupLimitItem, BtrfsError> {
+        if bytes.len() < QgroupLimitItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: QgroupLimitItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(QgroupLimitItem::read(&mut Cursor::new(
+            &bytes[..QgroupLimitItem::SIZE],
+        ))?)
+    }
+
+    pub fn flags(&self) -> QgroupLimitFlags {
+        QgroupLimitFlags::from_bits_retain(self.flags)
+    }
+
+    /// The referenced-bytes limit, if one is set.
+    pub fn max_referenced(&self) -> Option<u64> {
+        self.flags()
+            .contains(QgroupLimitFlags::MAX_RFER)
+            .then_some(self.max_rfer)
+    }
+
+    /// The exclusive-bytes limit, if one is set.
+    pub fn max_exclusive(&self) -> Option<u64> {
+        self.flags()
+            .contains(QgroupLimitFlags::MAX_EXCL)
+            .then_some(self.max_excl)
+    }
+}
+
+/// The level of a qgroupid: 0 for the qgroup of a single subvolume.
+pub fn qgroup_level(qgroupid: u64) -> u16 {
+    (qgroupid >> 48) as u16
+}
+
+/// The id within its level of a qgroupid; the subvolume id at level 0.
+pub fn qgroup_subvolid(qgroupid: u64) -> u64 {
+    qgroupid & ((1 << 48) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(values: &[u64]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn parses_quota_items() {
+        let status = QgroupStatusItem::from_bytes(&words(&[1, 9, 0x5, 0, 9])).unwrap();
+        assert!(status.is_enabled());
+        assert!(!status.is_consistent());
+        assert!(matches!(
+            QgroupStatusItem::from_bytes(&[0; 24]),
+            Err(BtrfsError::ShortRead { expected: 32, .. })
+        ));
+
+        let info = QgroupInfoItem::from_bytes(&words(&[9, 1 << 20, 1 << 20, 4096, 4096])).unwrap();
+        assert_eq!((info.rfer, info.excl), (1 << 20, 4096));
+
+        let limit = QgroupLimitItem::from_bytes(&words(&[0x2, 7, 1 << 30, 0, 0])).unwrap();
+        assert_eq!(limit.max_referenced(), None);
+        assert_eq!(limit.max_exclusive(), Some(1 << 30));
+    }
+
+    #[test]
+    fn splits_qgroupids() {
+        let id = (1 << 48) | 300;
+        assert_eq!((qgroup_level(id), qgroup_subvolid(id)), (1, 300));
+        assert_eq!((qgroup_level(257), qgroup_subvolid(257)), (0, 257));
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use crate::error::BtrfsError;
+use crate::fs::Btrfs;
+use crate::key::KeyType;
+use crate::qgroup::{
+    qgroup_level, qgroup_subvolid, QgroupInfoItem, QgroupLimitItem, QgroupStatusItem,
+};
+
+/// Objectid of the quota tree in the root tree.
+pub(crate) const QUOTA_TREE_OBJECTID: u64 = 8;
+
+/// Usage and limits of one qgroup, as `btrfs qgroup show` lists them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QgroupInfo {
+    /// The level in the top 16 bits and the id within it below; at level 0
+    /// the id is a subvolume id.
+    pub qgroupid: u64,
+    pub generation: u64,
+    pub referenced: u64,
+    pub referenced_compressed: u64,
+    pub exclusive: u64,
+    pub exclusive_compressed: u64,
+    pub limit: Option<QgroupLimitItem>,
+    /// Qgroups of higher levels this one is a member of.
+    pub parents: Vec<u64>,
+}
+
+impl QgroupInfo {
+    pub fn level(&self) -> u16 {
+        qgroup_level(self.qgroupid)
+    }
+
+    pub fn subvolid(&self) -> u64 {
+        qgroup_subvolid(self.qgroupid)
+    }
+}
+
+impl Btrfs {
+    /// The quota status item, or `None` if quotas were never enabled.
+    pub fn qgroup_status(&self) -> Result<Option<QgroupStatusItem>, BtrfsError> {
+        let Some(root) = self.root_item(QUOTA_TREE_OBJECTID)? else {
+            return Ok(None);
+        };
+        let status = self.tree(root.bytenr(), root.level()).iter().find(|item| {
+            item.as_ref()
+                .map_or(true, |(key, _)| key.item_type() == KeyType::QgroupStatus)
+        });
+        status
+            .transpose()?
+            .map(|(_, data)| QgroupStatusItem::from_bytes(&data))
+            .transpose()
+    }
+
+    /// Every qgroup, ordered by qgroupid, with its limits and parent
+    /// qgroups. Empty if quotas were never enabled; check
+    /// [`Btrfs::qgroup_status`] before trusting the counts.
+    pub fn qgroups(&self) -> Result<Vec<QgroupInfo>, BtrfsError> {
+        let Some(root) = self.root_item(QUOTA_TREE_OBJECTID)? else {
+            return Ok(Vec::new());
+        };
+        let mut qgroups = BTreeMap::new();
+        let mut limits = Vec::new();
+        let mut relations = Vec::new();
+        for item in self.tree(root.bytenr(), root.level()).iter() {
+            let (key, data) = item?;
+            match key.item_type() {
+                KeyType::QgroupInfo => {
+                    let info = QgroupInfoItem::from_bytes(&data)?;
+                    qgroups.insert(
+                        key.offset,
+                        QgroupInfo {
+                            qgroupid: key.offset,
+                            generation: info.generation,
+                            referenced: info.rfer,
+                            referenced_compressed: info.rfer_cmpr,
+                            exclusive: info.excl,
+                            exclusive_compressed: info.excl_cmpr,
+                            limit: None,
+                            parents: Vec::new(),
+                        },
+                    );
+                }
+                KeyType::QgroupLimit => {
+                    limits.push((key.offset, QgroupLimitItem::from_bytes(&data)?));
+                }
+                // Each relation is stored from both ends; the member is the
+                // end with the lower level.
+                KeyType::QgroupRelation
+                    if qgroup_level(key.objectid) < qgroup_level(key.offset) =>
+                {
+                    relations.push((key.objectid, key.offset));
+                }
+                _ => {}
+            }
+        }
+        for (qgroupid, limit) in limits {
+            if let Some(qgroup) = qgroups.get_mut(&qgroupid) {
+                qgroup.limit = Some(limit);
+            }
+        }
+        for (member, parent) in relations {
+            if let Some(qgroup) = qgroups.get_mut(&member) {
+                qgroup.parents.push(parent);
+            }
+        }
+        Ok(qgroups.into_values().collect())
+    }
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::key::Key;
+    use crate::testutil::{block, leaf, root_item, TestImage};
+
+    fn words(values: &[u64]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn lists_qgroups_with_limits_and_parents() {
+        let parent = (1 << 48) | 100;
+        let items = [
+            (Key::new(0, 240, 0), words(&[1, 9, 0x1, 0])),
+            (Key::new(0, 242, 5), words(&[9, 16384, 16384, 16384, 16384])),
+            (
+                Key::new(0, 242, 256),
+                words(&[9, 1 << 20, 1 << 20, 4096, 4096]),
+            ),
+            (
+                Key::new(0, 242, parent),
+                words(&[9, 1 << 20, 1 << 20, 1 << 20, 1 << 20]),
+            ),
+            (Key::new(0, 244, 5), words(&[0, 0, 0, 0, 0])),
+            (Key::new(0, 244, 256), words(&[0x1, 1 << 30, 0, 0, 0])),
+            (Key::new(256, 246, parent), Vec::new()),
+            (Key::new(parent, 246, 256), Vec::new()),
+        ];
+        let root_items = [(Key::new(8, 132, 0), root_item(block(3), 0))];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .put_block(block(2), &leaf(block(2), 1, &root_items))
+            .put_block(block(3), &leaf(block(3), 8, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let status = fs.qgroup_status().unwrap().unwrap();
+        assert!(status.is_enabled() && status.is_consistent());
+
+        let qgroups = fs.qgroups().unwrap();
+        let ids: Vec<_> = qgroups.iter().map(|q| (q.level(), q.subvolid())).collect();
+        assert_eq!(ids, [(0, 5), (0, 256), (1, 100)]);
+        let subvol = &qgroups[1];
+        assert_eq!((subvol.referenced, subvol.exclusive), (1 << 20, 4096));
+        assert_eq!(subvol.limit.unwrap().max_referenced(), Some(1 << 30));
+        assert_eq!(subvol.parents, [parent]);
+        assert!(qgroups[2].parents.is_empty());
+        assert_eq!(qgroups[0].limit.unwrap().max_referenced(), None);
+    }
+
+    #[test]
+    fn quotas_off_without_a_quota_tree() {
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .put_block(block(2), &leaf::<&[u8]>(block(2), 1, &[]));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert_eq!(fs.qgroup_status().unwrap(), None);
+        assert!(fs.qgroups().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+//! Parity math for rebuilding RAID5/6 data strips.
+//!
+//! P is the XOR of the data strips. Q is the Reed-Solomon syndrome the
+//! kernel's raid6 library computes: the sum of `g^i * D_i` over GF(2^8)
+//! with generator `g = 2` and polynomial `0x11d`, `i` counting data strips
+//! in their on-disk order.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// `EXP[i]` is `g^i`, doubled in length so that products of two logarithms
+/// index it without a reduction.
+const EXP: [u8; 510] = {
+    let mut exp = [0u8; 510];
+    let mut value: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = value as u8;
+        exp[i + 255] = value as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+        i += 1;
+    }
+    exp
+};
+
+/// `LOG[x]` is the `i` with `g^i == x`; `LOG[0]` is unused.
+const LOG: [u8; 256] = {
+    let mut log = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        log[EXP[i] as usize] = i as u8;
+        i += 1;
+    }
+    log
+};
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+/// `a / b` for nonzero `b`.
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    EXP[LOG[a as usize] as usize + 255 - LOG[b as usize] as usize]
+}
+
+/// `g^i`.
+fn pow(i: usize) -> u8 {
+    EXP[i % 255]
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+/// Adds `g^i * src` to `dst`.
+fn mul_xor_into(dst: &mut [u8], src: &[u8], i: usize) {
+    let coefficient = pow(i);
+    for (d, &s) in dst.iter_mut().zip(src) {
+        *d ^= mul(coefficient, s);
+    }
+}
+
+/// The P and Q strips of `data`, for writing test images.
+#[cfg(test)]
+pub(crate) fn parity(data: &[&[u8]]) -> (Vec<u8>, Vec<u8>) {
+    let len = data.first().map_or(0, |strip| strip.len());
+    let (mut p, mut q) = (vec![0; len], vec![0; len]);
+    for (i, strip) in data.iter().enumerate() {
+        xor_into(&mut p, strip);
+        mul_xor_into(&mut q, strip, i);
+    }
+    (p, q)
+}
+
+/// Fills in the missing (`None`) entries of `data` from the ones present
+/// and the parity strips. Every strip must be the same length. Returns
+/// `false`, leaving `data` as it was, if too many strips are missing: one
+/// data strip can be rebuilt from P or from Q, two need both.
+pub(crate) fn rebuild(data: &mut [Option<Vec<u8>>], p: Option<&[u8]>, q: Option<&[u8]>) -> bool {
+    let missing: Vec<usize> = (0..data.len()).filter(|&i| data[i].is_none()).collect();
+    let len = match data.iter().flatten().next() {
+        Some(strip) => strip.len(),
+        None => match p.or(q) {
+            Some(parity) => parity.len(),
+            None => return missing.is_empty(),
+        },
+    };
+
+    // What the parity would be with the missing strips zeroed: the
+    // difference to the stored parity is their contribution alone.
+    let partial = |parity: &[u8], weighted: bool| {
+        let mut sum = parity.to_vec();
+        for (i, strip) in data.iter().enumerate() {
+            if let Some(strip) = strip {
+                if weighted {
+                    mul_xor_into(&mut sum, strip, i);
+                } else {
+                    xor_into(&mut sum, strip);
+                }
+            }
+        }
+        sum
+    };
+
+    match (missing.as_slice(), p, q) {
+        ([], _, _) => {}
+        (&[x], Some(p), _) => data[x] = Some(partial(p, false)),
+        (&[x], None, Some(q)) => {
+            // g^x * D_x = Qx
+            let qx = partial(q, true);
+            let scale = pow(x);
+            data[x] = Some(qx.iter().map(|&b| div(b, scale)).collect());
+        }
+        (&[x, y], Some(p), Some(q)) => {
+            // D_x ^ D_y = Pxy and g^x D_x ^ g^y D_y = Qxy, so
+            // D_x = (Qxy ^ g^y Pxy) / (g^x ^ g^y).
+            let pxy = partial(p, false);
+            let qxy = partial(q, true);
+            let (gx, gy) = (pow(x), pow(y));
+            let denominator = gx ^ gy;
+            let mut dx = vec![0u8; len];
+            let mut dy = vec![0u8; len];
+            for i in 0..len {
+                dx[i] = div(qxy[i] ^ mul(gy, pxy[i]), denominator);
+                dy[i] = pxy[i] ^ dx[i];
+            }
+            data[x] = Some(dx);
+            data[y] = Some(dy);
+        }
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strips() -> Vec<Vec<u8>> {
+        (0..4u8)
+            .map(|n| {
+                (0..64u8)
+                    .map(|i| i.wrapping_mul(31) ^ n.wrapping_mul(97))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn field_arithmetic_inverts() {
+        for a in 1..=255u8 {
+            assert_eq!(div(mul(a, 0x53), 0x53), a);
+        }
+        assert_eq!(mul(0x80, 2), 0x1d);
+    }
+
+    #[test]
+    fn rebuilds_any_one_or_two_data_strips() {
+        let data = strips();
+        let refs: Vec<&[u8]> = data.iter().map(|strip| &strip[..]).collect();
+        let (p, q) = parity(&refs);
+
+        for x in 0..data.len() {
+            let mut with_p: Vec<_> = data.iter().cloned().map(Some).collect();
+            with_p[x] = None;
+            let mut with_q = with_p.clone();
+            assert!(rebuild(&mut with_p, Some(&p), None));
+            assert!(rebuild(&mut with_q, None, Some(&q)));
+            assert_eq!(with_p[x].as_ref(), Some(&data[x]));
+            assert_eq!(with_q[x].as_ref(), Some(&data[x]));
+
+            for y in x + 1..data.len() {
+                let mut two: Vec<_> = data.iter().cloned().map(Some).collect();
+                two[x] = None;
+                two[y] = None;
+                assert!(!rebuild(&mut two.clone(), Some(&p), None));
+                assert!(rebuild(&mut two, Some(&p), Some(&q)));
+                assert_eq!(two[x].as_ref(), Some(&data[x]));
+                assert_eq!(two[y].as_ref(), Some(&data[y]));
+            }
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use alloc::string::String;
+
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+use bitflags::bitflags;
+
+use crate::error::BtrfsError;
+use crate::inode::{InodeItem, Timespec};
+use crate::key::Key;
+
+/// `btrfs_root_item`: where a tree lives and, for subvolumes, their identity.
+#[derive(BinRead, BinWrite, Debug, Clone, PartialEq, Eq)]
+#[brw(little)]
+pub struct RootItem {
+    pub inode: InodeItem,
+    pub generation: u64,
+    pub root_dirid: u64,
+    /// Logical address of the tree's root node.
+    pub bytenr: u64,
+    pub byte_limit: u64,
+    pub bytes_used: u64,
+    pub last_snapshot: u64,
+    pub flags: u64,
+    pub refs: u32,
+    pub drop_progress: Key,
+    pub drop_level: u8,
+    pub level: u8,
+    /// Equal to `generation` when the fields after it were written by a
+    /// kernel that knows about them.
+    pub generation_v2: u64,
+    pub uuid: [u8; 16],
+    pub parent_uuid: [u8; 16],
+    pub received_uuid: [u8; 16],
+    pub ctransid: u64,
+    pub otransid: u64,
+    pub stransid: u64,
+    pub rtransid: u64,
+    pub ctime: Timespec,
+    pub otime: Timespec,
+    pub stime: Timespec,
+    pub rtime: Timespec,
+    pub reserved: [u64; 8],
+}
+
+impl RootItem {
+    pub const SIZE: usize = 439;
+    /// Size of root items written before `generation_v2` existed.
+    pub const LEGACY_SIZE: usize = 239;
+
+    /// Parses a root item. Legacy items that stop after `level` are accepted,
+    /// with the missing fields zeroed as the kernel does.
+    pub fn from_bytes(bytes: &[u8]) -> Result<RootItem, BtrfsError> {
+        if bytes.len() < RootItem::LEGACY_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: RootItem::LEGACY_SIZE,
+                got: bytes.len(),
+            });
+        }
+        let mut buf = [0u8; RootItem::SIZE];
+        let len = bytes.len().min(RootItem::SIZE);
+        buf[..len].copy_from_slice(&bytes[..len]);
+        Ok(RootItem::read(&mut Cursor::new(&buf[..]))?)
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    pub fn bytenr(&self) -> u64 {
+        self.bytenr
+    }
+
+    pub fn flags(&self) -> RootFlags {
+        RootFlags::from_bits_retain(self.flags)
+    }
+}
+
+/// `btrfs_root_ref`: links a subvolume to the directory entry naming it in
+/// its parent. Stored twice in the root tree, as `ROOT_REF` under the parent
+/// and `ROOT_BACKREF` under the child.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootRef {
+    /// Inode of the directory in the parent subvolume holding the entry.
+    pub dirid: u64,
+    /// The entry's `DIR_INDEX` sequence number.
+    pub sequence: u64,
+    /// Converted lossily if not valid UTF-8.
+    pub name: String,
+}
+
+impl RootRef {
+    /// Size of the fixed part, before the name.
+    pub const HEADER_SIZE: usize = 18;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<RootRef, BtrfsError> {
+        if bytes.len() < RootRef::HEADER_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: RootRef::HEADER_SIZE,
+                got: bytes.len(),
+            });
+        }
+        let dirid = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let sequence = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let name_len = u16::from_le_bytes([bytes[16], bytes[17]]) as usize;
+        let name = bytes
+            .get(RootRef::HEADER_SIZE..RootRef::HEADER_SIZE + name_len)
+            .ok_or(BtrfsError::Malformed("root ref name runs past item data"))?;
+        Ok(RootRef {
+            dirid,
+            sequence,
+            name: String::from_utf8_lossy(name).into_owned(),
+        })
+    }
+}
+
+bitflags! {
+    /// Flags of a root item.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct RootFlags: u64 {
+        /// The subvolume is read-only.
+        const SUBVOL_RDONLY = 1 << 0;
+        /// The subvolume was deleted and is waiting to be cleaned up.
+        const SUBVOL_DEAD = 1 << 48;
+
+        const _ = !0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root_item_bytes() -> [u8; RootItem::SIZE] {
+        let mut buf = [0u8; RootItem::SIZE];
+        // inode.mode: a directory.
+        buf[52..56].copy_from_slice(&0o40755u32.to_le_bytes());
+        buf[160..168].copy_from_slice(&7u64.to_le_bytes());
+        buf[168..176].copy_from_slice(&256u64.to_le_bytes());
+        buf[176..184].copy_from_slice(&30490624u64.to_le_bytes());
+        buf[208..216].copy_from_slice(&1u64.to_le_bytes());
+        buf[216..220].copy_from_slice(&1u32.to_le_bytes());
+        buf[238] = 1;
+        buf[239..247].copy_from_slice(&7u64.to_le_bytes());
+        buf[247] = 0xab;
+        // otime.sec
+        buf[339..347].copy_from_slice(&1_700_000_000u64.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn parses_root_item() {
+        let root = RootItem::from_bytes(&root_item_bytes()).unwrap();
+        assert_eq!(root.generation, 7);
+        assert_eq!(root.root_dirid, 256);
+        assert_eq!(root.bytenr(), 30490624);
+        assert_eq!(root.level(), 1);
+        assert_eq!(root.refs, 1);
+        assert_eq!(root.flags(), RootFlags::SUBVOL_RDONLY);
+        assert_eq!(root.generation_v2, 7);
+        assert_eq!(root.uuid[0], 0xab);
+        assert_eq!(root.inode.mode, 0o40755);
+        assert_eq!(root.otime.sec, 1_700_000_000);
+    }
+
+    #[test]
+    fn parses_root_ref() {
+        let mut bytes = [0u8; 22];
+        bytes[0..8].copy_from_slice(&256u64.to_le_bytes());
+        bytes[8..16].copy_from_slice(&3u64.to_le_bytes());
+        bytes[16..18].copy_from_slice(&4u16.to_le_bytes());
+        bytes[18..].copy_from_slice(b"snap");
+
+        let root_ref = RootRef::from_bytes(&bytes).unwrap();
+        assert_eq!(root_ref.dirid, 256);
+        assert_eq!(root_ref.sequence, 3);
+        assert_eq!(root_ref.name, "snap");
+        assert!(matches!(
+            RootRef::from_bytes(&bytes[..21]),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn legacy_root_item_is_zero_extended() {
+        let bytes = root_item_bytes();
+        let root = RootItem::from_bytes(&bytes[..RootItem::LEGACY_SIZE]).unwrap();
+        assert_eq!(root.level(), 1);
+        assert_eq!(root.generation_v2, 0);
+        assert_eq!(root.uuid, [0; 16]);
+
+        assert!(matches!(
+            RootItem::from_bytes(&bytes[..100]),
+            Err(BtrfsError::ShortRead { .. })
+        ));
+    }
+}
@@ -0,0 +1,838 @@
+use std::panic;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::thread;
+
+use crate::chunk::BlockGroupType;
+use crate::csum;
+use crate::error::BtrfsError;
+use crate::extent::{ExtentFlags, ExtentItem};
+use crate::fs::Btrfs;
+use crate::inode::InodeItem;
+use crate::key::{Key, KeyType};
+use crate::tree::Tree;
+
+/// Objectid of the checksum tree in the root tree.
+pub(crate) const CSUM_TREE_OBJECTID: u64 = 7;
+/// Objectid of every `EXTENT_CSUM` item (`-10`).
+const EXTENT_CSUM_OBJECTID: u64 = -10i64 as u64;
+
+/// How far a [`Btrfs::scrub`] or [`Btrfs::scrub_parallel`] has got,
+/// reported after each extent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubProgress {
+    pub bytes_done: u64,
+    /// Bytes used in all data block groups.
+    pub bytes_total: u64,
+}
+
+/// A data sector whose checksum did not match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubMismatch {
+    pub logical: u64,
+    /// The device the bad copy was read from.
+    pub devid: u64,
+    pub expected_csum: Vec<u8>,
+    pub found_csum: Vec<u8>,
+    /// Whether another mirror holds a copy that matches.
+    pub good_copy: bool,
+}
+
+/// Outcome of a [`Btrfs::scrub`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// Data bytes checked against a stored checksum.
+    pub bytes_verified: u64,
+    /// Data bytes without a checksum, such as `nodatasum` files, which
+    /// cannot be checked.
+    pub bytes_without_csum: u64,
+    pub mismatches: Vec<ScrubMismatch>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Finds the stored checksum of data sectors, keeping the last checksum
+/// item while consecutive sectors fall inside it.
+struct CsumLookup<'a> {
+    tree: Tree<'a>,
+    sectorsize: u64,
+    digest_len: usize,
+    /// First address and packed digests of the last item found.
+    item: Option<(u64, Vec<u8>)>,
+}
+
+impl<'a> CsumLookup<'a> {
+    fn new(fs: &'a Btrfs) -> Result<CsumLookup<'a>, BtrfsError> {
+        let sb = fs.superblock();
+        if sb.sectorsize == 0 {
+            return Err(BtrfsError::Malformed("sectorsize is zero"));
+        }
+        let root = fs
+            .root_item(CSUM_TREE_OBJECTID)?
+            .ok_or(BtrfsError::Malformed("root tree has no checksum tree"))?;
+        Ok(CsumLookup {
+            tree: fs.tree(root.bytenr(), root.level()),
+            sectorsize: sb.sectorsize as u64,
+            digest_len: sb.checksum_type().digest_len(),
+            item: None,
+        })
+    }
+
+    fn covers(&self, (start, csums): &(u64, Vec<u8>), logical: u64) -> bool {
+        logical >= *start
+            && (logical - start) / self.sectorsize < (csums.len() / self.digest_len) as u64
+    }
+
+    /// The stored checksum of the sector at `logical`, if it has one.
+    fn get(&mut self, logical: u64) -> Result<Option<&[u8]>, BtrfsError> {
+        if !self
+            .item
+            .as_ref()
+            .is_some_and(|item| self.covers(item, logical))
+        {
+            let extent_csum = KeyType::ExtentCsum.as_u8();
+            let key = Key::new(EXTENT_CSUM_OBJECTID, extent_csum, logical);
+            self.item = self
+                .tree
+                .search_le(&key)?
+                .filter(|(key, _)| {
+                    key.objectid == EXTENT_CSUM_OBJECTID && key.item_type == extent_csum
+                })
+                .map(|(key, csums)| (key.offset, csums))
+                .filter(|item| self.covers(item, logical));
+        }
+        Ok(self.item.as_ref().map(|(start, csums)| {
+            let at = ((logical - start) / self.sectorsize) as usize * self.digest_len;
+            &csums[at..at + self.digest_len]
+        }))
+    }
+}
+
+impl Btrfs {
+    /// Checks `data`, read from logical address `disk_bytenr`, against the
+    /// per-sector checksums in the checksum tree. Returns `false` if any
+    /// sector does not match.
+    ///
+    /// `disk_bytenr` and the length of `data` must be multiples of the
+    /// sector size. Sectors without a stored checksum, as written with
+    /// `nodatasum`, give [`BtrfsError::NotFound`]; use
+    /// [`Btrfs::verify_file_checksum`] when the owning inode is known.
+    pub fn verify_data_checksum(&self, disk_bytenr: u64, data: &[u8]) -> Result<bool, BtrfsError> {
+        let mut lookup = CsumLookup::new(self)?;
+        let sectorsize = lookup.sectorsize as usize;
+        if !disk_bytenr.is_multiple_of(sectorsize as u64) || !data.len().is_multiple_of(sectorsize)
+        {
+            return Err(BtrfsError::Malformed(
+                "checksummed range is not sector aligned",
+            ));
+        }
+        let csum_type = self.superblock().checksum_type();
+        let digest_len = csum_type.digest_len();
+
+        for (i, sector) in data.chunks(sectorsize).enumerate() {
+            let logical =
+                disk_bytenr
+                    .checked_add((i * sectorsize) as u64)
+                    .ok_or(BtrfsError::Malformed(
+                        "checksummed range ends past u64::MAX",
+                    ))?;
+            let stored = lookup.get(logical)?.ok_or(BtrfsError::NotFound)?;
+            if csum::checksum(csum_type, sector)?[..digest_len] != *stored {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Like [`Btrfs::verify_data_checksum`] for data of `inode`, returning
+    /// `true` without a lookup if the inode is `NODATASUM` and so has
+    /// nothing to verify.
+    pub fn verify_file_checksum(
+        &self,
+        inode: &InodeItem,
+        disk_bytenr: u64,
+        data: &[u8],
+    ) -> Result<bool, BtrfsError> {
+        if !inode.has_data_checksums() {
+            return Ok(true);
+        }
+        self.verify_data_checksum(disk_bytenr, data)
+    }
+
+    /// Reads every data extent and checks it against the checksum tree,
+    /// without repairing anything. A sector that fails is re-read from the
+    /// other copies of DUP, RAID1-like and RAID10 profiles to see whether a good
+    /// copy exists. `progress` is called after each extent.
+    ///
+    /// Data chunks are not covered by the `sys_chunk_array`, so
+    /// [`Btrfs::load_chunk_tree`] must have been called first.
+    pub fn scrub(
+        &self,
+        mut progress: impl FnMut(ScrubProgress),
+    ) -> Result<ScrubReport, BtrfsError> {
+        let data_groups = self.data_block_groups()?;
+        let mut state = ScrubProgress {
+            bytes_done: 0,
+            bytes_total: data_groups.iter().map(|(_, used)| used).sum(),
+        };
+        let mut lookup = CsumLookup::new(self)?;
+        let extent_tree = self.extent_tree()?;
+
+        let mut report = ScrubReport::default();
+        for (group, _) in &data_groups {
+            self.scrub_block_group(group, &extent_tree, &mut lookup, &mut report, |len| {
+                state.bytes_done += len;
+                progress(state);
+            })?;
+        }
+        Ok(report)
+    }
+
+    /// Like [`Btrfs::scrub`], with data block groups shared out among up
+    /// to `threads` threads (at least one), so that the reads of a
+    /// filesystem spread over several devices overlap. It pays off with a [`BlockDevice`] that
+    /// reads without a lock, as [`Btrfs::open_devices`] uses.
+    ///
+    /// Each thread looks up checksums on its own; the node cache, if
+    /// enabled, is shared, and is only locked while a block is looked up
+    /// or added, never across a read. `progress` is called from whichever
+    /// thread finishes an extent, one call at a time, with `bytes_done`
+    /// increasing. The report lists mismatches in address order, as
+    /// [`Btrfs::scrub`] does. The first error stops every thread.
+    ///
+    /// [`BlockDevice`]: crate::BlockDevice
+    pub fn scrub_parallel(
+        &self,
+        threads: usize,
+        progress: impl Fn(ScrubProgress) + Sync,
+    ) -> Result<ScrubReport, BtrfsError> {
+        let data_groups = self.data_block_groups()?;
+        let state = Mutex::new(ScrubProgress {
+            bytes_done: 0,
+            bytes_total: data_groups.iter().map(|(_, used)| used).sum(),
+        });
+        // Fail before spawning anything if either tree is missing.
+        CsumLookup::new(self)?;
+        let extent_tree = self.extent_tree()?;
+
+        let next_group = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let worker = || -> Result<ScrubReport, BtrfsError> {
+            let mut lookup = CsumLookup::new(self)?;
+            let mut report = ScrubReport::default();
+            while !failed.load(Ordering::Relaxed) {
+                let Some((group, _)) = data_groups.get(next_group.fetch_add(1, Ordering::Relaxed))
+                else {
+                    break;
+                };
+                let scrubbed =
+                    self.scrub_block_group(group, &extent_tree, &mut lookup, &mut report, |len| {
+                        let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+                        state.bytes_done += len;
+                        progress(*state);
+                    });
+                if let Err(err) = scrubbed {
+                    failed.store(true, Ordering::Relaxed);
+                    return Err(err);
+                }
+            }
+            Ok(report)
+        };
+
+        let threads = threads.clamp(1, data_groups.len().max(1));
+        let reports: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads).map(|_| scope.spawn(worker)).collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+
+        let mut report = ScrubReport::default();
+        for part in reports {
+            let part = part?;
+            report.bytes_verified += part.bytes_verified;
+            report.bytes_without_csum += part.bytes_without_csum;
+            report.mismatches.extend(part.mismatches);
+        }
+        report.mismatches.sort_by_key(|mismatch| mismatch.logical);
+        Ok(report)
+    }
+
+    /// The data and mixed block groups with their used bytes.
+    fn data_block_groups(&self) -> Result<Vec<(Key, u64)>, BtrfsError> {
+        Ok(self
+            .block_groups()?
+            .into_iter()
+            .filter(|(_, bg)| {
+                matches!(
+                    BlockGroupType::from_flags(bg.flags()),
+                    BlockGroupType::Data | BlockGroupType::Mixed
+                )
+            })
+            .map(|(key, bg)| (key, bg.used))
+            .collect())
+    }
+
+    /// Scrubs the data extents of the block group `group` into `report`,
+    /// calling `extent_done` with the length of each.
+    fn scrub_block_group(
+        &self,
+        group: &Key,
+        extent_tree: &Tree<'_>,
+        lookup: &mut CsumLookup<'_>,
+        report: &mut ScrubReport,
+        mut extent_done: impl FnMut(u64),
+    ) -> Result<(), BtrfsError> {
+        let csum_type = self.superblock().checksum_type();
+        let digest_len = csum_type.digest_len();
+        let sectorsize = lookup.sectorsize;
+        let extent_item = KeyType::ExtentItem.as_u8();
+
+        let Some(last) = group.objectid.saturating_add(group.offset).checked_sub(1) else {
+            return Ok(());
+        };
+        let min = Key::new(group.objectid, extent_item, 0);
+        let max = Key::new(last, extent_item, u64::MAX);
+        for item in extent_tree.search_range(min, max) {
+            let (key, data) = item?;
+            if key.item_type != extent_item {
+                continue;
+            }
+            if !ExtentItem::from_bytes(&key, &data)?
+                .flags()
+                .contains(ExtentFlags::DATA)
+            {
+                continue;
+            }
+
+            if key.objectid.checked_add(key.offset).is_none() {
+                return Err(BtrfsError::Malformed("extent ends past u64::MAX"));
+            }
+            // Striped profiles place each `stripe_len` run on a different
+            // device, so the extent is read run by run.
+            let mut offset = 0;
+            while offset < key.offset {
+                let at = key.objectid + offset;
+                let run = self
+                    .chunk_map()
+                    .contiguous_len(at)
+                    .ok_or(BtrfsError::Unmapped { logical: at })?;
+                if run == 0 {
+                    return Err(BtrfsError::Malformed("chunk has a zero stripe length"));
+                }
+                let run = run.min(key.offset - offset);
+                let len = usize::try_from(run)
+                    .map_err(|_| BtrfsError::Malformed("extent exceeds the address space"))?;
+                let (copy, devid, physical) = self.first_copy(at)?;
+                let bytes = self.read_physical(devid, physical, len)?;
+
+                for (i, sector) in bytes.chunks(sectorsize as usize).enumerate() {
+                    let logical = at + i as u64 * sectorsize;
+                    let Some(expected) = lookup.get(logical)? else {
+                        report.bytes_without_csum += sector.len() as u64;
+                        continue;
+                    };
+                    let expected = expected.to_vec();
+                    report.bytes_verified += sector.len() as u64;
+                    let found = csum::checksum(csum_type, sector)?[..digest_len].to_vec();
+                    if found == expected {
+                        continue;
+                    }
+                    let good_copy = self.has_good_copy(logical, copy, sector.len(), &expected)?;
+                    report.mismatches.push(ScrubMismatch {
+                        logical,
+                        devid,
+                        expected_csum: expected,
+                        found_csum: found,
+                        good_copy,
+                    });
+                }
+                offset += run;
+            }
+
+            extent_done(key.offset);
+        }
+        Ok(())
+    }
+
+    /// The first copy of the data at `logical` whose device is present, as
+    /// `(copy, devid, physical)`.
+    fn first_copy(&self, logical: u64) -> Result<(usize, u64, u64), BtrfsError> {
+        let map = self.chunk_map();
+        let copies = map.num_copies(logical);
+        if copies == 0 {
+            return Err(BtrfsError::Unmapped { logical });
+        }
+        let mut first_devid = None;
+        for copy in 0..copies {
+            let (devid, physical) = map
+                .physical_for_copy(logical, copy)
+                .ok_or(BtrfsError::Malformed("chunk stripe layout is corrupt"))?;
+            if self.devids().any(|id| id == devid) {
+                return Ok((copy, devid, physical));
+            }
+            first_devid.get_or_insert(devid);
+        }
+        Err(BtrfsError::MissingDevice {
+            devid: first_devid.unwrap_or(0),
+        })
+    }
+
+    /// Whether a copy of the `len`-byte sector at `logical` other than
+    /// `read_copy` matches `expected`: another stripe of DUP, RAID1-like
+    /// and RAID10 profiles, on a present device.
+    fn has_good_copy(
+        &self,
+        logical: u64,
+        read_copy: usize,
+        len: usize,
+        expected: &[u8],
+    ) -> Result<bool, BtrfsError> {
+        let csum_type = self.superblock().checksum_type();
+        let map = self.chunk_map();
+        for copy in (0..map.num_copies(logical)).filter(|&copy| copy != read_copy) {
+            let Some((devid, physical)) = map.physical_for_copy(logical, copy) else {
+                continue;
+            };
+            if !self.devids().any(|id| id == devid) {
+                continue;
+            }
+            let bytes = self.read_physical(devid, physical, len)?;
+            if csum::checksum(csum_type, &bytes)?[..expected.len()] == *expected {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::chunk::Chunk;
+    use crate::inode::InodeFlags;
+    use crate::superblock::{Superblock, BTRFS_SUPER_INFO_OFFSET, BTRFS_SUPER_INFO_SIZE};
+    use crate::testutil::{
+        block, chunk_item, device_image, leaf, put_u64, reseal, root_item, test_chunk, TestImage,
+        NODESIZE, SUPERBLOCK, SYSTEM_CHUNK,
+    };
+
+    const SECTOR: usize = 4096;
+
+    fn sectors(n: usize) -> Vec<u8> {
+        (0..n * SECTOR).map(|i| (i % 253) as u8).collect()
+    }
+
+    fn csums(data: &[u8]) -> Vec<u8> {
+        data.chunks(SECTOR)
+            .flat_map(|sector| csum::crc32c(sector).to_le_bytes())
+            .collect()
+    }
+
+    /// Checksums of the sectors at `data_at()`: 0-1 and 2 in two items, then
+    /// 4 after a one-sector gap.
+    fn csum_fs() -> Btrfs {
+        let root_tree = SYSTEM_CHUNK + NODESIZE as u64;
+        let csum_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let data = sectors(5);
+        let items = [
+            (
+                Key::new(EXTENT_CSUM_OBJECTID, 128, data_at()),
+                csums(&data[..2 * SECTOR]),
+            ),
+            (
+                Key::new(EXTENT_CSUM_OBJECTID, 128, data_at() + 2 * SECTOR as u64),
+                csums(&data[2 * SECTOR..3 * SECTOR]),
+            ),
+            (
+                Key::new(EXTENT_CSUM_OBJECTID, 128, data_at() + 4 * SECTOR as u64),
+                csums(&data[4 * SECTOR..]),
+            ),
+        ];
+        let root = root_item(csum_tree, 0);
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(
+                root_tree,
+                &leaf(root_tree, 1, &[(Key::new(7, 132, 0), &root)]),
+            )
+            .put_block(csum_tree, &leaf(csum_tree, 7, &items));
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    fn data_at() -> u64 {
+        SYSTEM_CHUNK + 4 * NODESIZE as u64
+    }
+
+    #[test]
+    fn verifies_sectors_across_csum_items() {
+        let fs = csum_fs();
+        let data = sectors(5);
+        assert!(fs
+            .verify_data_checksum(data_at(), &data[..3 * SECTOR])
+            .unwrap());
+        assert!(fs
+            .verify_data_checksum(data_at() + SECTOR as u64, &data[SECTOR..3 * SECTOR])
+            .unwrap());
+        assert!(fs
+            .verify_data_checksum(data_at() + 4 * SECTOR as u64, &data[4 * SECTOR..])
+            .unwrap());
+
+        let mut corrupt = data[..3 * SECTOR].to_vec();
+        corrupt[2 * SECTOR + 7] ^= 1;
+        assert!(!fs.verify_data_checksum(data_at(), &corrupt).unwrap());
+    }
+
+    #[test]
+    fn uncovered_and_unaligned_ranges_are_errors() {
+        let fs = csum_fs();
+        let data = sectors(5);
+        assert!(matches!(
+            fs.verify_data_checksum(data_at(), &data),
+            Err(BtrfsError::NotFound)
+        ));
+        assert!(matches!(
+            fs.verify_data_checksum(data_at() - SECTOR as u64, &data[..SECTOR]),
+            Err(BtrfsError::NotFound)
+        ));
+        assert!(matches!(
+            fs.verify_data_checksum(data_at() + 1, &data[..SECTOR]),
+            Err(BtrfsError::Malformed(_))
+        ));
+        assert!(matches!(
+            fs.verify_data_checksum(data_at(), &data[..100]),
+            Err(BtrfsError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn nodatasum_inodes_have_nothing_to_verify() {
+        let fs = csum_fs();
+        let data = sectors(5);
+        let mut inode = InodeItem {
+            flags: (InodeFlags::NODATASUM | InodeFlags::NODATACOW).bits(),
+            ..InodeItem::default()
+        };
+        assert!(fs.verify_file_checksum(&inode, data_at(), &data).unwrap());
+
+        inode.flags = InodeFlags::NOATIME.bits();
+        assert!(matches!(
+            fs.verify_file_checksum(&inode, data_at(), &data),
+            Err(BtrfsError::NotFound)
+        ));
+        assert!(fs
+            .verify_file_checksum(&inode, data_at(), &data[..3 * SECTOR])
+            .unwrap());
+    }
+
+    /// Logical start of the DUP data chunk of [`scrub_fs`].
+    const DATA: u64 = 1 << 30;
+    /// Physical offsets of its two copies, past the SYSTEM chunk.
+    const COPIES: [u64; 2] = [16 << 20, 17 << 20];
+
+    /// A DUP data block group holding a checksummed extent of two sectors
+    /// and a `nodatasum` extent of one. Both copies of sector 0 and the
+    /// first copy of sector 1 are corrupted.
+    fn scrub_fs() -> Btrfs {
+        scrub_fs_with_groups(&[(DATA, 1 << 20, 3 * SECTOR as u64)])
+    }
+
+    /// [`scrub_fs`], with its chunk divided into the block groups
+    /// `(start, length, used)` of `groups`.
+    fn scrub_fs_with_groups(groups: &[(u64, u64, u64)]) -> Btrfs {
+        // The fixture's chunk root is block 1.
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let chunk = Chunk {
+            length: 1 << 20,
+            ..test_chunk(0x1 | 0x20, &COPIES.map(|offset| (1, offset)))
+        };
+
+        let data = sectors(3);
+        let data_extent = |refs: u64| -> Vec<u8> {
+            [refs, 6, 1]
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect()
+        };
+        let mut extents = vec![
+            (Key::new(DATA, 168, 2 * SECTOR as u64), data_extent(1)),
+            (
+                Key::new(DATA + 2 * SECTOR as u64, 168, SECTOR as u64),
+                data_extent(1),
+            ),
+        ];
+        for &(start, length, used) in groups {
+            let block_group = [used, 256, 0x1 | 0x20]
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect();
+            extents.push((Key::new(start, 192, length), block_group));
+        }
+        extents.sort_by_key(|(key, _)| *key);
+        let csums = [(
+            Key::new(EXTENT_CSUM_OBJECTID, 128, DATA),
+            csums(&data[..2 * SECTOR]),
+        )];
+        let roots = [
+            (Key::new(2, 132, 0), root_item(block(4), 0)),
+            (Key::new(7, 132, 0), root_item(block(5), 0)),
+        ];
+
+        let mut first = data.clone();
+        first[3] ^= 1;
+        first[SECTOR + 5] ^= 1;
+        let mut second = data;
+        second[9] ^= 1;
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(3), 0)
+            .put_block(
+                chunk_root,
+                &leaf(
+                    chunk_root,
+                    3,
+                    &[(Key::new(256, 228, DATA), chunk_item(&chunk))],
+                ),
+            )
+            .put_block(block(3), &leaf(block(3), 1, &roots))
+            .put_block(block(4), &leaf(block(4), 2, &extents))
+            .put_block(block(5), &leaf(block(5), 7, &csums))
+            .put_physical(COPIES[0], &first)
+            .put_physical(COPIES[1], &second);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+        fs
+    }
+
+    #[test]
+    fn scrub_reports_mismatches_and_good_mirrors() {
+        let fs = scrub_fs();
+        let mut updates = Vec::new();
+        let report = fs.scrub(|progress| updates.push(progress)).unwrap();
+
+        assert_eq!(report.bytes_verified, 2 * SECTOR as u64);
+        assert_eq!(report.bytes_without_csum, SECTOR as u64);
+        assert!(!report.is_clean());
+        let bad: Vec<_> = report
+            .mismatches
+            .iter()
+            .map(|m| (m.logical, m.devid, m.good_copy))
+            .collect();
+        assert_eq!(bad, [(DATA, 1, false), (DATA + SECTOR as u64, 1, true)]);
+
+        let data = sectors(1);
+        assert_eq!(
+            report.mismatches[0].expected_csum,
+            csum::crc32c(&data).to_le_bytes()
+        );
+        assert_ne!(
+            report.mismatches[0].found_csum,
+            report.mismatches[0].expected_csum
+        );
+
+        let total = 3 * SECTOR as u64;
+        assert_eq!(
+            updates,
+            [
+                ScrubProgress {
+                    bytes_done: 2 * SECTOR as u64,
+                    bytes_total: total,
+                },
+                ScrubProgress {
+                    bytes_done: total,
+                    bytes_total: total,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parallel_scrub_matches_serial_scrub() {
+        let sector = SECTOR as u64;
+        let groups = [
+            (DATA, 2 * sector, 2 * sector),
+            (DATA + 2 * sector, (1 << 20) - 2 * sector, sector),
+        ];
+        let fs = scrub_fs_with_groups(&groups);
+        let serial = fs.scrub(|_| {}).unwrap();
+        assert_eq!(serial.mismatches.len(), 2);
+
+        for threads in [0, 1, 2, 8] {
+            let updates = Mutex::new(Vec::new());
+            let report = fs
+                .scrub_parallel(threads, |progress| updates.lock().unwrap().push(progress))
+                .unwrap();
+            assert_eq!(report, serial);
+            let done: Vec<_> = updates.into_inner().unwrap();
+            assert_eq!(done.len(), 2);
+            assert!(done[0].bytes_done < done[1].bytes_done);
+            assert_eq!(done[1].bytes_done, 3 * sector);
+            assert_eq!(done[1].bytes_total, 3 * sector);
+        }
+    }
+
+    /// Length of a stripe of [`test_chunk`].
+    const STRIPE: usize = 65536;
+
+    /// Devices 1 and 2 of a filesystem with one data block group of type
+    /// `type_flags` over `stripes`, holding a checksummed extent of `len`
+    /// bytes at [`DATA`]. The extent's bytes are written as `placed`, given
+    /// as `(devid, physical, bytes)`.
+    fn striped_scrub_fs(
+        type_flags: u64,
+        stripes: &[(u64, u64)],
+        len: usize,
+        placed: &[(u64, u64, &[u8])],
+    ) -> Btrfs {
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let chunk = test_chunk(type_flags, stripes);
+        let item = |values: [u64; 3]| -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect()
+        };
+        let extents = [
+            (Key::new(DATA, 168, len as u64), item([1, 6, 1])),
+            (
+                Key::new(DATA, 192, chunk.length),
+                item([len as u64, 256, type_flags]),
+            ),
+        ];
+        let csums = [(
+            Key::new(EXTENT_CSUM_OBJECTID, 128, DATA),
+            csums(&sectors(len / SECTOR)),
+        )];
+        let roots = [
+            (Key::new(2, 132, 0), root_item(block(4), 0)),
+            (Key::new(7, 132, 0), root_item(block(5), 0)),
+        ];
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(3), 0)
+            .put_block(
+                chunk_root,
+                &leaf(
+                    chunk_root,
+                    3,
+                    &[(Key::new(256, 228, DATA), chunk_item(&chunk))],
+                ),
+            )
+            .put_block(block(3), &leaf(block(3), 1, &roots))
+            .put_block(block(4), &leaf(block(4), 2, &extents))
+            .put_block(block(5), &leaf(block(5), 7, &csums));
+        let mut second_sb = SUPERBLOCK.to_vec();
+        put_u64(&mut second_sb, 136, 2);
+        put_u64(&mut second_sb, 201, 2);
+        reseal(&mut second_sb);
+        let mut second = device_image(&second_sb, 4 << 20);
+        for &(devid, physical, bytes) in placed {
+            if devid == 1 {
+                image.put_physical(physical, bytes);
+            } else {
+                let at = physical as usize;
+                second[at..at + bytes.len()].copy_from_slice(bytes);
+            }
+        }
+        let mut first = image.build();
+        let start = BTRFS_SUPER_INFO_OFFSET as usize;
+        put_u64(&mut first, start + 136, 2);
+        reseal(&mut first[start..start + BTRFS_SUPER_INFO_SIZE]);
+
+        let mut fs = Btrfs::from_block_devices(vec![Box::new(first), Box::new(second)]).unwrap();
+        fs.load_chunk_tree().unwrap();
+        fs
+    }
+
+    #[test]
+    fn scrubs_extents_across_raid0_stripes() {
+        // Three 64KiB runs alternate between device 1 at 16MiB and device
+        // 2 at 1MiB. One sector is corrupted in each of the last two.
+        let data = sectors(3 * STRIPE / SECTOR);
+        let mut runs: Vec<_> = data.chunks(STRIPE).map(<[u8]>::to_vec).collect();
+        runs[1][SECTOR + 1] ^= 1;
+        runs[2][2 * SECTOR] ^= 1;
+        let placed = [
+            (1, 16 << 20, &runs[0][..]),
+            (2, 1 << 20, &runs[1][..]),
+            (1, (16 << 20) + STRIPE as u64, &runs[2][..]),
+        ];
+        let fs = striped_scrub_fs(
+            0x1 | 0x8,
+            &[(1, 16 << 20), (2, 1 << 20)],
+            data.len(),
+            &placed,
+        );
+
+        let report = fs.scrub(|_| {}).unwrap();
+        assert_eq!(report.bytes_verified, data.len() as u64);
+        let bad: Vec<_> = report
+            .mismatches
+            .iter()
+            .map(|m| (m.logical - DATA, m.devid, m.good_copy))
+            .collect();
+        let stripe = STRIPE as u64;
+        assert_eq!(
+            bad,
+            [
+                (stripe + SECTOR as u64, 2, false),
+                (2 * stripe + 2 * SECTOR as u64, 1, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn retries_the_raid10_mirror_of_each_stripe() {
+        // Stripe 0 is mirrored on devices 1 and 2 at 16MiB and 1MiB, stripe
+        // 1 at 17MiB and 2MiB. The first copy of a sector in each is bad;
+        // in stripe 1, so is the second copy of another.
+        let data = sectors(2 * STRIPE / SECTOR);
+        let (first, second) = data.split_at(STRIPE);
+        let mut bad_first = first.to_vec();
+        bad_first[3] ^= 1;
+        let mut bad_second = second.to_vec();
+        bad_second[SECTOR + 3] ^= 1;
+        bad_second[3 * SECTOR] ^= 1;
+        let mut bad_mirror = second.to_vec();
+        bad_mirror[3 * SECTOR] ^= 1;
+        let stripes = [(1, 16 << 20), (2, 1 << 20), (1, 17 << 20), (2, 2 << 20)];
+        let placed = [
+            (1, 16 << 20, &bad_first[..]),
+            (2, 1 << 20, first),
+            (1, 17 << 20, &bad_second[..]),
+            (2, 2 << 20, &bad_mirror[..]),
+        ];
+        let fs = striped_scrub_fs(0x1 | 0x40, &stripes, data.len(), &placed);
+
+        let report = fs.scrub(|_| {}).unwrap();
+        assert_eq!(report.bytes_verified, data.len() as u64);
+        let bad: Vec<_> = report
+            .mismatches
+            .iter()
+            .map(|m| (m.logical - DATA, m.devid, m.good_copy))
+            .collect();
+        let stripe = STRIPE as u64;
+        assert_eq!(
+            bad,
+            [
+                (0, 1, true),
+                (stripe + SECTOR as u64, 1, true),
+                (stripe + 3 * SECTOR as u64, 1, false)
+            ]
+        );
+    }
+}
@@ -0,0 +1,822 @@
+use std::io::Read;
+#[cfg(feature = "crc32c")]
+use std::io::Write;
+
+use crate::error::BtrfsError;
+use crate::inode::Timespec;
+use crate::superblock::read_full;
+
+/// Magic at the start of every send stream, including its NUL.
+const SEND_STREAM_MAGIC: &[u8; 13] = b"btrfs-stream\0";
+/// Size of a command header: payload length, command and CRC32C.
+const CMD_HEADER_SIZE: usize = 10;
+/// Bound on a command's payload, far above what the kernel emits, so that
+/// a corrupt length cannot cause a huge allocation.
+const MAX_CMD_LEN: usize = 16 << 20;
+
+const CMD_SUBVOL: u16 = 1;
+const CMD_SNAPSHOT: u16 = 2;
+const CMD_MKFILE: u16 = 3;
+const CMD_MKDIR: u16 = 4;
+const CMD_MKNOD: u16 = 5;
+const CMD_MKFIFO: u16 = 6;
+const CMD_MKSOCK: u16 = 7;
+const CMD_SYMLINK: u16 = 8;
+const CMD_RENAME: u16 = 9;
+const CMD_LINK: u16 = 10;
+const CMD_UNLINK: u16 = 11;
+const CMD_RMDIR: u16 = 12;
+const CMD_SET_XATTR: u16 = 13;
+const CMD_REMOVE_XATTR: u16 = 14;
+const CMD_WRITE: u16 = 15;
+const CMD_CLONE: u16 = 16;
+const CMD_TRUNCATE: u16 = 17;
+const CMD_CHMOD: u16 = 18;
+const CMD_CHOWN: u16 = 19;
+const CMD_UTIMES: u16 = 20;
+const CMD_END: u16 = 21;
+const CMD_UPDATE_EXTENT: u16 = 22;
+const CMD_FALLOCATE: u16 = 23;
+const CMD_FILEATTR: u16 = 24;
+const CMD_ENCODED_WRITE: u16 = 25;
+
+const ATTR_UUID: u16 = 1;
+const ATTR_CTRANSID: u16 = 2;
+const ATTR_INO: u16 = 3;
+const ATTR_SIZE: u16 = 4;
+const ATTR_MODE: u16 = 5;
+const ATTR_UID: u16 = 6;
+const ATTR_GID: u16 = 7;
+const ATTR_RDEV: u16 = 8;
+const ATTR_CTIME: u16 = 9;
+const ATTR_MTIME: u16 = 10;
+const ATTR_ATIME: u16 = 11;
+const ATTR_XATTR_NAME: u16 = 13;
+const ATTR_XATTR_DATA: u16 = 14;
+const ATTR_PATH: u16 = 15;
+const ATTR_PATH_TO: u16 = 16;
+const ATTR_PATH_LINK: u16 = 17;
+const ATTR_FILE_OFFSET: u16 = 18;
+const ATTR_DATA: u16 = 19;
+const ATTR_CLONE_UUID: u16 = 20;
+const ATTR_CLONE_CTRANSID: u16 = 21;
+const ATTR_CLONE_PATH: u16 = 22;
+const ATTR_CLONE_OFFSET: u16 = 23;
+const ATTR_CLONE_LEN: u16 = 24;
+const ATTR_FALLOCATE_MODE: u16 = 25;
+const ATTR_FILEATTR: u16 = 26;
+const ATTR_UNENCODED_FILE_LEN: u16 = 27;
+const ATTR_UNENCODED_LEN: u16 = 28;
+const ATTR_UNENCODED_OFFSET: u16 = 29;
+const ATTR_COMPRESSION: u16 = 30;
+const ATTR_ENCRYPTION: u16 = 31;
+
+/// One command of a send stream, with its attributes decoded. Paths are
+/// relative to the subvolume being received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendCommand {
+    /// Start of a full send: create subvolume `path`.
+    Subvol {
+        path: String,
+        uuid: [u8; 16],
+        ctransid: u64,
+    },
+    /// Start of an incremental send: snapshot the parent identified by
+    /// `clone_uuid` and `clone_ctransid` as `path`.
+    Snapshot {
+        path: String,
+        uuid: [u8; 16],
+        ctransid: u64,
+        clone_uuid: [u8; 16],
+        clone_ctransid: u64,
+    },
+    Mkfile {
+        path: String,
+        ino: u64,
+    },
+    Mkdir {
+        path: String,
+        ino: u64,
+    },
+    Mknod {
+        path: String,
+        ino: u64,
+        mode: u64,
+        rdev: u64,
+    },
+    Mkfifo {
+        path: String,
+        ino: u64,
+    },
+    Mksock {
+        path: String,
+        ino: u64,
+    },
+    Symlink {
+        path: String,
+        ino: u64,
+        target: String,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    /// Create `path` as a hard link to `target`.
+    Link {
+        path: String,
+        target: String,
+    },
+    Unlink {
+        path: String,
+    },
+    Rmdir {
+        path: String,
+    },
+    SetXattr {
+        path: String,
+        name: String,
+        data: Vec<u8>,
+    },
+    RemoveXattr {
+        path: String,
+        name: String,
+    },
+    Write {
+        path: String,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Copy `len` bytes at `clone_offset` of `clone_path`, in the
+    /// subvolume identified by `clone_uuid`, to `offset` of `path`.
+    Clone {
+        path: String,
+        offset: u64,
+        len: u64,
+        clone_uuid: [u8; 16],
+        clone_ctransid: u64,
+        clone_path: String,
+        clone_offset: u64,
+    },
+    Truncate {
+        path: String,
+        size: u64,
+    },
+    Chmod {
+        path: String,
+        mode: u64,
+    },
+    Chown {
+        path: String,
+        uid: u64,
+        gid: u64,
+    },
+    Utimes {
+        path: String,
+        atime: Timespec,
+        mtime: Timespec,
+        ctime: Timespec,
+    },
+    /// Sent instead of the data by `btrfs send --no-data`.
+    UpdateExtent {
+        path: String,
+        offset: u64,
+        len: u64,
+    },
+    /// Version 2: preallocate or punch a range, as `fallocate(2)` with
+    /// `mode`.
+    Fallocate {
+        path: String,
+        mode: u32,
+        offset: u64,
+        len: u64,
+    },
+    /// Version 2: the inode flags of `path`.
+    Fileattr {
+        path: String,
+        attr: u64,
+    },
+    /// Version 2: `data` as stored on disk, still compressed. `compression`
+    /// is 0 for none, 1 for zlib, 2 for zstd, and 3 to 7 for LZO with 4KiB
+    /// to 64KiB sectors.
+    EncodedWrite {
+        path: String,
+        offset: u64,
+        unencoded_file_len: u64,
+        unencoded_len: u64,
+        unencoded_offset: u64,
+        compression: u32,
+        encryption: u32,
+        data: Vec<u8>,
+    },
+    /// The last command of a stream.
+    End,
+    /// A command this crate does not decode, with its raw payload.
+    Unknown {
+        cmd: u16,
+        payload: Vec<u8>,
+    },
+}
+
+/// Reader of a `btrfs send` stream, yielding its commands in order.
+///
+/// The stream ends at the `END` command or, if the end of the input comes
+/// first, cleanly at a command boundary. With the `crc32c` feature, each
+/// command's checksum is verified.
+pub struct SendStream<R> {
+    reader: R,
+    version: u32,
+    done: bool,
+}
+
+impl<R: Read> SendStream<R> {
+    /// Reads the stream header. Versions 1 and 2 are supported.
+    pub fn from_reader(mut reader: R) -> Result<SendStream<R>, BtrfsError> {
+        let mut header = [0u8; SEND_STREAM_MAGIC.len() + 4];
+        reader.read_exact(&mut header)?;
+        if &header[..SEND_STREAM_MAGIC.len()] != SEND_STREAM_MAGIC {
+            return Err(BtrfsError::Malformed("not a send stream"));
+        }
+        let version = u32::from_le_bytes(header[SEND_STREAM_MAGIC.len()..].try_into().unwrap());
+        if !(1..=2).contains(&version) {
+            return Err(BtrfsError::Unsupported("send stream version"));
+        }
+        Ok(SendStream {
+            reader,
+            version,
+            done: false,
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Reads the next command, or `None` at the end of the input.
+    fn read_command(&mut self) -> Result<Option<SendCommand>, BtrfsError> {
+        let mut header = [0u8; CMD_HEADER_SIZE];
+        let got = read_full(&mut self.reader, &mut header)?;
+        if got == 0 {
+            return Ok(None);
+        }
+        if got < CMD_HEADER_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: CMD_HEADER_SIZE,
+                got,
+            });
+        }
+        let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+        let cmd = u16::from_le_bytes([header[4], header[5]]);
+        if len > MAX_CMD_LEN {
+            return Err(BtrfsError::Malformed("send command is too large"));
+        }
+        let mut payload = vec![0u8; len];
+        self.reader.read_exact(&mut payload)?;
+
+        #[cfg(feature = "crc32c")]
+        {
+            let stored = u32::from_le_bytes(header[6..10].try_into().unwrap());
+            header[6..10].fill(0);
+            let crc = crate::csum::crc32c_update(0, &header);
+            if crate::csum::crc32c_update(crc, &payload) != stored {
+                return Err(BtrfsError::Malformed("send command checksum mismatch"));
+            }
+        }
+
+        decode(cmd, payload, self.version).map(Some)
+    }
+}
+
+impl<R: Read> Iterator for SendStream<R> {
+    type Item = Result<SendCommand, BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_command();
+        if !matches!(result, Ok(Some(ref cmd)) if *cmd != SendCommand::End) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+}
+
+/// Writer of a version 1 send stream, as `btrfs receive` reads it.
+///
+/// Each `write_*` method emits one command. The stream is only complete
+/// once [`SendStreamWriter::write_end`] has been called.
+#[cfg(feature = "crc32c")]
+pub struct SendStreamWriter<W> {
+    writer: W,
+    /// Payload of the command being built.
+    payload: Vec<u8>,
+}
+
+#[cfg(feature = "crc32c")]
+impl<W: Write> SendStreamWriter<W> {
+    /// Writes the stream header.
+    pub fn new(mut writer: W) -> Result<SendStreamWriter<W>, BtrfsError> {
+        writer.write_all(SEND_STREAM_MAGIC)?;
+        writer.write_all(&1u32.to_le_bytes())?;
+        Ok(SendStreamWriter {
+            writer,
+            payload: Vec::new(),
+        })
+    }
+
+    /// Starts a full send of subvolume `path`.
+    pub fn write_subvol(
+        &mut self,
+        path: &str,
+        uuid: [u8; 16],
+        ctransid: u64,
+    ) -> Result<(), BtrfsError> {
+        self.attr(ATTR_PATH, path.as_bytes())?;
+        self.attr(ATTR_UUID, &uuid)?;
+        self.attr(ATTR_CTRANSID, &ctransid.to_le_bytes())?;
+        self.finish(CMD_SUBVOL)
+    }
+
+    pub fn write_mkfile(&mut self, path: &str, ino: u64) -> Result<(), BtrfsError> {
+        self.attr(ATTR_PATH, path.as_bytes())?;
+        self.attr(ATTR_INO, &ino.to_le_bytes())?;
+        self.finish(CMD_MKFILE)
+    }
+
+    /// Writes `data` at `offset` of `path`. Version 1 attributes are at
+    /// most 64KiB, so larger writes must be split by the caller.
+    pub fn write_write(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<(), BtrfsError> {
+        self.attr(ATTR_PATH, path.as_bytes())?;
+        self.attr(ATTR_FILE_OFFSET, &offset.to_le_bytes())?;
+        self.attr(ATTR_DATA, data)?;
+        self.finish(CMD_WRITE)
+    }
+
+    pub fn write_chown(&mut self, path: &str, uid: u64, gid: u64) -> Result<(), BtrfsError> {
+        self.attr(ATTR_PATH, path.as_bytes())?;
+        self.attr(ATTR_UID, &uid.to_le_bytes())?;
+        self.attr(ATTR_GID, &gid.to_le_bytes())?;
+        self.finish(CMD_CHOWN)
+    }
+
+    /// Ends the stream and flushes the writer.
+    pub fn write_end(&mut self) -> Result<(), BtrfsError> {
+        self.finish(CMD_END)?;
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn attr(&mut self, tag: u16, value: &[u8]) -> Result<(), BtrfsError> {
+        let Ok(len) = u16::try_from(value.len()) else {
+            self.payload.clear();
+            return Err(BtrfsError::Malformed("send attribute is longer than 64KiB"));
+        };
+        self.payload.extend_from_slice(&tag.to_le_bytes());
+        self.payload.extend_from_slice(&len.to_le_bytes());
+        self.payload.extend_from_slice(value);
+        Ok(())
+    }
+
+    /// Writes the buffered attributes as command `cmd`, sealed with the
+    /// CRC32C of the header and payload.
+    fn finish(&mut self, cmd: u16) -> Result<(), BtrfsError> {
+        let mut header = [0u8; CMD_HEADER_SIZE];
+        header[0..4].copy_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        header[4..6].copy_from_slice(&cmd.to_le_bytes());
+        let crc = crate::csum::crc32c_update(0, &header);
+        let crc = crate::csum::crc32c_update(crc, &self.payload);
+        header[6..10].copy_from_slice(&crc.to_le_bytes());
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&self.payload)?;
+        self.payload.clear();
+        Ok(())
+    }
+}
+
+/// The attributes of one command, in stream order.
+struct Attrs<'a> {
+    attrs: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Attrs<'a> {
+    fn parse(payload: &'a [u8], version: u32) -> Result<Attrs<'a>, BtrfsError> {
+        let mut attrs = Vec::new();
+        let mut rest = payload;
+        while !rest.is_empty() {
+            if rest.len() < 2 {
+                return Err(BtrfsError::Malformed("send attribute header is truncated"));
+            }
+            let tag = u16::from_le_bytes([rest[0], rest[1]]);
+            // From version 2, data has no length and runs to the end of the
+            // command, so that it may exceed 64KiB.
+            if version >= 2 && tag == ATTR_DATA {
+                attrs.push((tag, &rest[2..]));
+                break;
+            }
+            if rest.len() < 4 {
+                return Err(BtrfsError::Malformed("send attribute header is truncated"));
+            }
+            let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+            let value = rest.get(4..4 + len).ok_or(BtrfsError::Malformed(
+                "send attribute runs past its command",
+            ))?;
+            attrs.push((tag, value));
+            rest = &rest[4 + len..];
+        }
+        Ok(Attrs { attrs })
+    }
+
+    fn bytes(&self, tag: u16) -> Result<&'a [u8], BtrfsError> {
+        self.attrs
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|&(_, value)| value)
+            .ok_or(BtrfsError::Malformed(
+                "send command is missing an attribute",
+            ))
+    }
+
+    fn string(&self, tag: u16) -> Result<String, BtrfsError> {
+        Ok(String::from_utf8_lossy(self.bytes(tag)?).into_owned())
+    }
+
+    fn fixed<const N: usize>(&self, tag: u16) -> Result<[u8; N], BtrfsError> {
+        self.bytes(tag)?
+            .try_into()
+            .map_err(|_| BtrfsError::Malformed("send attribute has the wrong size"))
+    }
+
+    fn u64(&self, tag: u16) -> Result<u64, BtrfsError> {
+        Ok(u64::from_le_bytes(self.fixed(tag)?))
+    }
+
+    fn u32(&self, tag: u16) -> Result<u32, BtrfsError> {
+        Ok(u32::from_le_bytes(self.fixed(tag)?))
+    }
+
+    fn timespec(&self, tag: u16) -> Result<Timespec, BtrfsError> {
+        let bytes: [u8; Timespec::SIZE] = self.fixed(tag)?;
+        Ok(Timespec {
+            sec: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            nsec: u32::from_le_bytes(bytes[8..].try_into().unwrap()),
+        })
+    }
+}
+
+fn decode(cmd: u16, payload: Vec<u8>, version: u32) -> Result<SendCommand, BtrfsError> {
+    let a = Attrs::parse(&payload, version)?;
+    Ok(match cmd {
+        CMD_SUBVOL => SendCommand::Subvol {
+            path: a.string(ATTR_PATH)?,
+            uuid: a.fixed(ATTR_UUID)?,
+            ctransid: a.u64(ATTR_CTRANSID)?,
+        },
+        CMD_SNAPSHOT => SendCommand::Snapshot {
+            path: a.string(ATTR_PATH)?,
+            uuid: a.fixed(ATTR_UUID)?,
+            ctransid: a.u64(ATTR_CTRANSID)?,
+            clone_uuid: a.fixed(ATTR_CLONE_UUID)?,
+            clone_ctransid: a.u64(ATTR_CLONE_CTRANSID)?,
+        },
+        CMD_MKFILE => SendCommand::Mkfile {
+            path: a.string(ATTR_PATH)?,
+            ino: a.u64(ATTR_INO)?,
+        },
+        CMD_MKDIR => SendCommand::Mkdir {
+            path: a.string(ATTR_PATH)?,
+            ino: a.u64(ATTR_INO)?,
+        },
+        CMD_MKNOD => SendCommand::Mknod {
+            path: a.string(ATTR_PATH)?,
+            ino: a.u64(ATTR_INO)?,
+            mode: a.u64(ATTR_MODE)?,
+            rdev: a.u64(ATTR_RDEV)?,
+        },
+        CMD_MKFIFO => SendCommand::Mkfifo {
+            path: a.string(ATTR_PATH)?,
+            ino: a.u64(ATTR_INO)?,
+        },
+        CMD_MKSOCK => SendCommand::Mksock {
+            path: a.string(ATTR_PATH)?,
+            ino: a.u64(ATTR_INO)?,
+        },
+        CMD_SYMLINK => SendCommand::Symlink {
+            path: a.string(ATTR_PATH)?,
+            ino: a.u64(ATTR_INO)?,
+            target: a.string(ATTR_PATH_LINK)?,
+        },
+        CMD_RENAME => SendCommand::Rename {
+            from: a.string(ATTR_PATH)?,
+            to: a.string(ATTR_PATH_TO)?,
+        },
+        CMD_LINK => SendCommand::Link {
+            path: a.string(ATTR_PATH)?,
+            target: a.string(ATTR_PATH_LINK)?,
+        },
+        CMD_UNLINK => SendCommand::Unlink {
+            path: a.string(ATTR_PATH)?,
+        },
+        CMD_RMDIR => SendCommand::Rmdir {
+            path: a.string(ATTR_PATH)?,
+        },
+        CMD_SET_XATTR => SendCommand::SetXattr {
+            path: a.string(ATTR_PATH)?,
+            name: a.string(ATTR_XATTR_NAME)?,
+            data: a.bytes(ATTR_XATTR_DATA)?.to_vec(),
+        },
+        CMD_REMOVE_XATTR => SendCommand::RemoveXattr {
+            path: a.string(ATTR_PATH)?,
+            name: a.string(ATTR_XATTR_NAME)?,
+        },
+        CMD_WRITE => SendCommand::Write {
+            path: a.string(ATTR_PATH)?,
+            offset: a.u64(ATTR_FILE_OFFSET)?,
+            data: a.bytes(ATTR_DATA)?.to_vec(),
+        },
+        CMD_CLONE => SendCommand::Clone {
+            path: a.string(ATTR_PATH)?,
+            offset: a.u64(ATTR_FILE_OFFSET)?,
+            len: a.u64(ATTR_CLONE_LEN)?,
+            clone_uuid: a.fixed(ATTR_CLONE_UUID)?,
+            clone_ctransid: a.u64(ATTR_CLONE_CTRANSID)?,
+            clone_path: a.string(ATTR_CLONE_PATH)?,
+            clone_offset: a.u64(ATTR_CLONE_OFFSET)?,
+        },
+        CMD_TRUNCATE => SendCommand::Truncate {
+            path: a.string(ATTR_PATH)?,
+            size: a.u64(ATTR_SIZE)?,
+        },
+        CMD_CHMOD => SendCommand::Chmod {
+            path: a.string(ATTR_PATH)?,
+            mode: a.u64(ATTR_MODE)?,
+        },
+        CMD_CHOWN => SendCommand::Chown {
+            path: a.string(ATTR_PATH)?,
+            uid: a.u64(ATTR_UID)?,
+            gid: a.u64(ATTR_GID)?,
+        },
+        CMD_UTIMES => SendCommand::Utimes {
+            path: a.string(ATTR_PATH)?,
+            atime: a.timespec(ATTR_ATIME)?,
+            mtime: a.timespec(ATTR_MTIME)?,
+            ctime: a.timespec(ATTR_CTIME)?,
+        },
+        CMD_UPDATE_EXTENT => SendCommand::UpdateExtent {
+            path: a.string(ATTR_PATH)?,
+            offset: a.u64(ATTR_FILE_OFFSET)?,
+            len: a.u64(ATTR_SIZE)?,
+        },
+        CMD_FALLOCATE if version >= 2 => SendCommand::Fallocate {
+            path: a.string(ATTR_PATH)?,
+            mode: a.u32(ATTR_FALLOCATE_MODE)?,
+            offset: a.u64(ATTR_FILE_OFFSET)?,
+            len: a.u64(ATTR_SIZE)?,
+        },
+        CMD_FILEATTR if version >= 2 => SendCommand::Fileattr {
+            path: a.string(ATTR_PATH)?,
+            attr: a.u64(ATTR_FILEATTR)?,
+        },
+        CMD_ENCODED_WRITE if version >= 2 => SendCommand::EncodedWrite {
+            path: a.string(ATTR_PATH)?,
+            offset: a.u64(ATTR_FILE_OFFSET)?,
+            unencoded_file_len: a.u64(ATTR_UNENCODED_FILE_LEN)?,
+            unencoded_len: a.u64(ATTR_UNENCODED_LEN)?,
+            unencoded_offset: a.u64(ATTR_UNENCODED_OFFSET)?,
+            // Both are optional and default to none.
+            compression: a.u32(ATTR_COMPRESSION).unwrap_or(0),
+            encryption: a.u32(ATTR_ENCRYPTION).unwrap_or(0),
+            data: a.bytes(ATTR_DATA)?.to_vec(),
+        },
+        CMD_END => SendCommand::End,
+        _ => SendCommand::Unknown { cmd, payload },
+    })
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::csum::crc32c_update;
+
+    fn stream(version: u32, commands: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = SEND_STREAM_MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        for command in commands {
+            bytes.extend_from_slice(command);
+        }
+        bytes
+    }
+
+    fn command(cmd: u16, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for (tag, value) in attrs {
+            payload.extend_from_slice(&tag.to_le_bytes());
+            payload.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            payload.extend_from_slice(value);
+        }
+        seal(cmd, payload)
+    }
+
+    fn seal(cmd: u16, payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&cmd.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&payload);
+        let crc = crc32c_update(0, &bytes);
+        bytes[6..10].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    fn parse(bytes: Vec<u8>) -> Result<Vec<SendCommand>, BtrfsError> {
+        SendStream::from_reader(Cursor::new(bytes))?.collect()
+    }
+
+    #[test]
+    fn parses_version_1_commands() {
+        let mut time = 1700000000u64.to_le_bytes().to_vec();
+        time.extend_from_slice(&5u32.to_le_bytes());
+        let bytes = stream(
+            1,
+            &[
+                command(
+                    CMD_SUBVOL,
+                    &[
+                        (ATTR_PATH, b"vol"),
+                        (ATTR_UUID, &[0xab; 16]),
+                        (ATTR_CTRANSID, &7u64.to_le_bytes()),
+                    ],
+                ),
+                command(
+                    CMD_MKFILE,
+                    &[(ATTR_PATH, b"o257-7-0"), (ATTR_INO, &257u64.to_le_bytes())],
+                ),
+                command(
+                    CMD_RENAME,
+                    &[(ATTR_PATH, b"o257-7-0"), (ATTR_PATH_TO, b"hello")],
+                ),
+                command(
+                    CMD_WRITE,
+                    &[
+                        (ATTR_PATH, b"hello"),
+                        (ATTR_FILE_OFFSET, &0u64.to_le_bytes()),
+                        (ATTR_DATA, b"hi\n"),
+                    ],
+                ),
+                command(
+                    CMD_UTIMES,
+                    &[
+                        (ATTR_PATH, b"hello"),
+                        (ATTR_ATIME, &time),
+                        (ATTR_MTIME, &time),
+                        (ATTR_CTIME, &time),
+                    ],
+                ),
+                command(99, &[(ATTR_PATH, b"x")]),
+                command(CMD_END, &[]),
+                command(CMD_UNLINK, &[(ATTR_PATH, b"after end")]),
+            ],
+        );
+        let commands = parse(bytes).unwrap();
+        let time = Timespec {
+            sec: 1700000000,
+            nsec: 5,
+        };
+        assert_eq!(
+            commands[..5],
+            [
+                SendCommand::Subvol {
+                    path: "vol".into(),
+                    uuid: [0xab; 16],
+                    ctransid: 7,
+                },
+                SendCommand::Mkfile {
+                    path: "o257-7-0".into(),
+                    ino: 257,
+                },
+                SendCommand::Rename {
+                    from: "o257-7-0".into(),
+                    to: "hello".into(),
+                },
+                SendCommand::Write {
+                    path: "hello".into(),
+                    offset: 0,
+                    data: b"hi\n".to_vec(),
+                },
+                SendCommand::Utimes {
+                    path: "hello".into(),
+                    atime: time,
+                    mtime: time,
+                    ctime: time,
+                },
+            ]
+        );
+        assert!(matches!(commands[5], SendCommand::Unknown { cmd: 99, .. }));
+        assert_eq!(commands[6..], [SendCommand::End]);
+    }
+
+    #[test]
+    fn reads_unbounded_data_in_version_2() {
+        let data = vec![0x5a; 70000];
+        let mut payload = Vec::new();
+        for (tag, value) in [
+            (ATTR_PATH, &b"big"[..]),
+            (ATTR_FILE_OFFSET, &4096u64.to_le_bytes()),
+        ] {
+            payload.extend_from_slice(&tag.to_le_bytes());
+            payload.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            payload.extend_from_slice(value);
+        }
+        payload.extend_from_slice(&ATTR_DATA.to_le_bytes());
+        payload.extend_from_slice(&data);
+
+        let mut stream =
+            SendStream::from_reader(Cursor::new(stream(2, &[seal(CMD_WRITE, payload)]))).unwrap();
+        assert_eq!(stream.version(), 2);
+        assert_eq!(
+            stream.next().unwrap().unwrap(),
+            SendCommand::Write {
+                path: "big".into(),
+                offset: 4096,
+                data,
+            }
+        );
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn round_trips_written_streams() {
+        let mut writer = SendStreamWriter::new(Vec::new()).unwrap();
+        writer.write_subvol("snap", [3; 16], 12).unwrap();
+        writer.write_mkfile("file", 257).unwrap();
+        writer.write_write("file", 8192, b"contents").unwrap();
+        writer.write_chown("file", 1000, 100).unwrap();
+        assert!(matches!(
+            writer.write_write("file", 0, &vec![0; 70000]),
+            Err(BtrfsError::Malformed(_))
+        ));
+        writer.write_end().unwrap();
+
+        assert_eq!(
+            parse(writer.into_inner()).unwrap(),
+            [
+                SendCommand::Subvol {
+                    path: "snap".into(),
+                    uuid: [3; 16],
+                    ctransid: 12,
+                },
+                SendCommand::Mkfile {
+                    path: "file".into(),
+                    ino: 257,
+                },
+                SendCommand::Write {
+                    path: "file".into(),
+                    offset: 8192,
+                    data: b"contents".to_vec(),
+                },
+                SendCommand::Chown {
+                    path: "file".into(),
+                    uid: 1000,
+                    gid: 100,
+                },
+                SendCommand::End,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_damaged_streams() {
+        assert!(matches!(
+            parse(b"btrfs-strean\0\x01\0\0\0".to_vec()),
+            Err(BtrfsError::Malformed("not a send stream"))
+        ));
+        assert!(matches!(
+            parse(stream(3, &[])),
+            Err(BtrfsError::Unsupported(_))
+        ));
+
+        let mut bytes = stream(1, &[command(CMD_RMDIR, &[(ATTR_PATH, b"dir")])]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            parse(bytes),
+            Err(BtrfsError::Malformed("send command checksum mismatch"))
+        ));
+        assert!(matches!(
+            parse(stream(1, &[command(CMD_RMDIR, &[])])),
+            Err(BtrfsError::Malformed(
+                "send command is missing an attribute"
+            ))
+        ));
+        let mut truncated = stream(1, &[command(CMD_END, &[])]);
+        truncated.truncate(truncated.len() - 3);
+        assert!(matches!(
+            parse(truncated),
+            Err(BtrfsError::ShortRead { got: 7, .. })
+        ));
+    }
+}
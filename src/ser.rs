@@ -0,0 +1,234 @@
+//! `serde::Serialize` for the superblock structures, shaped for readable
+//! JSON: byte arrays become hex strings, UUIDs their canonical form, and flag
+//! words lists of flag names.
+
+use alloc::format;
+use alloc::string::String;
+
+use bitflags::Flags;
+use serde::ser::{SerializeSeq, SerializeStruct};
+use serde::{Serialize, Serializer};
+
+use crate::superblock::{
+    fmt_uuid, hex, named_bits, DevItem, RootBackup, Superblock, SUPER_FLAG_NAMES,
+};
+
+/// Serializes the set flags of `F` by name, plus `"unknown:0x.."` for any
+/// bits without one.
+struct FlagNames<F>(F);
+
+impl<F: Flags<Bits = u64>> Serialize for FlagNames<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let unknown = self.0.bits() & !named_bits::<F>();
+        let mut seq = serializer.serialize_seq(None)?;
+        for (name, _) in self.0.iter_names() {
+            seq.serialize_element(name)?;
+        }
+        if unknown != 0 {
+            seq.serialize_element(&format!("unknown:{unknown:#x}"))?;
+        }
+        seq.end()
+    }
+}
+
+/// Serializes the superblock `flags` word like [`FlagNames`], with the
+/// names the `Display` impl prints.
+struct SuperFlagNames(u64);
+
+impl Serialize for SuperFlagNames {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut unknown = self.0;
+        let mut seq = serializer.serialize_seq(None)?;
+        for (bit, name) in SUPER_FLAG_NAMES {
+            if self.0 & bit != 0 {
+                seq.serialize_element(name)?;
+                unknown &= !bit;
+            }
+        }
+        if unknown != 0 {
+            seq.serialize_element(&format!("unknown:{unknown:#x}"))?;
+        }
+        seq.end()
+    }
+}
+
+struct Magic {
+    valid: bool,
+    raw: String,
+}
+
+impl Serialize for Magic {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Magic", 2)?;
+        s.serialize_field("valid", &self.valid)?;
+        s.serialize_field("raw", &self.raw)?;
+        s.end()
+    }
+}
+
+impl Serialize for Superblock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let csum_type = self.checksum_type();
+        let sys_chunk_array_len =
+            (self.sys_chunk_array_size as usize).min(self.sys_chunk_array.len());
+
+        let mut s = serializer.serialize_struct("Superblock", 40)?;
+        s.serialize_field("csum", &hex(&self.csum[..csum_type.digest_len()]))?;
+        s.serialize_field("fsid", &fmt_uuid(&self.fsid))?;
+        s.serialize_field("bytenr", &self.bytenr)?;
+        s.serialize_field("flags", &SuperFlagNames(self.flags))?;
+        s.serialize_field(
+            "magic",
+            &Magic {
+                valid: self.is_valid_magic(),
+                raw: format!("{:#018x}", self.magic),
+            },
+        )?;
+        s.serialize_field("generation", &self.generation)?;
+        s.serialize_field("root", &self.root)?;
+        s.serialize_field("chunk_root", &self.chunk_root)?;
+        s.serialize_field("log_root", &self.log_root)?;
+        s.serialize_field("log_root_transid", &self.log_root_transid)?;
+        s.serialize_field("total_bytes", &self.total_bytes)?;
+        s.serialize_field("bytes_used", &self.bytes_used)?;
+        s.serialize_field("root_dir_objectid", &self.root_dir_objectid)?;
+        s.serialize_field("num_devices", &self.num_devices)?;
+        s.serialize_field("sectorsize", &self.sectorsize)?;
+        s.serialize_field("nodesize", &self.nodesize)?;
+        s.serialize_field("unused_leafsize", &self.unused_leafsize)?;
+        s.serialize_field("stripesize", &self.stripesize)?;
+        s.serialize_field("sys_chunk_array_size", &self.sys_chunk_array_size)?;
+        s.serialize_field("chunk_root_generation", &self.chunk_root_generation)?;
+        s.serialize_field("compat_flags", &FlagNames(self.compat_flags()))?;
+        s.serialize_field("compat_ro_flags", &FlagNames(self.compat_ro_flags()))?;
+        s.serialize_field("incompat_flags", &FlagNames(self.incompat_flags()))?;
+        s.serialize_field("csum_type", csum_type.name())?;
+        s.serialize_field("root_level", &self.root_level)?;
+        s.serialize_field("chunk_root_level", &self.chunk_root_level)?;
+        s.serialize_field("log_root_level", &self.log_root_level)?;
+        s.serialize_field("dev_item", &self.dev_item)?;
+        s.serialize_field("label", &self.label_lossy())?;
+        s.serialize_field("cache_generation", &self.cache_generation)?;
+        s.serialize_field("uuid_tree_generation", &self.uuid_tree_generation)?;
+        s.serialize_field("metadata_uuid", &fmt_uuid(&self.metadata_uuid))?;
+        s.serialize_field("nr_global_roots", &self.nr_global_roots)?;
+        s.serialize_field("block_group_root", &self.block_group_root)?;
+        s.serialize_field(
+            "block_group_root_generation",
+            &self.block_group_root_generation,
+        )?;
+        s.serialize_field("block_group_root_level", &self.block_group_root_level)?;
+        s.serialize_field(
+            "sys_chunk_array",
+            &hex(&self.sys_chunk_array[..sys_chunk_array_len]),
+        )?;
+        s.serialize_field("super_roots", &self.super_roots)?;
+        s.end()
+    }
+}
+
+impl Serialize for DevItem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("DevItem", 14)?;
+        s.serialize_field("devid", &self.devid)?;
+        s.serialize_field("total_bytes", &self.total_bytes)?;
+        s.serialize_field("bytes_used", &self.bytes_used)?;
+        s.serialize_field("io_align", &self.io_align)?;
+        s.serialize_field("io_width", &self.io_width)?;
+        s.serialize_field("sector_size", &self.sector_size)?;
+        s.serialize_field("type", &self.dev_type)?;
+        s.serialize_field("generation", &self.generation)?;
+        s.serialize_field("start_offset", &self.start_offset)?;
+        s.serialize_field("dev_group", &self.dev_group)?;
+        s.serialize_field("seek_speed", &self.seek_speed)?;
+        s.serialize_field("bandwidth", &self.bandwidth)?;
+        s.serialize_field("uuid", &fmt_uuid(&self.uuid))?;
+        s.serialize_field("fsid", &fmt_uuid(&self.fsid))?;
+        s.end()
+    }
+}
+
+impl Serialize for RootBackup {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("RootBackup", 21)?;
+        s.serialize_field("tree_root", &self.tree_root)?;
+        s.serialize_field("tree_root_gen", &self.tree_root_gen)?;
+        s.serialize_field("tree_root_level", &self.tree_root_level)?;
+        s.serialize_field("chunk_root", &self.chunk_root)?;
+        s.serialize_field("chunk_root_gen", &self.chunk_root_gen)?;
+        s.serialize_field("chunk_root_level", &self.chunk_root_level)?;
+        s.serialize_field("extent_root", &self.extent_root)?;
+        s.serialize_field("extent_root_gen", &self.extent_root_gen)?;
+        s.serialize_field("extent_root_level", &self.extent_root_level)?;
+        s.serialize_field("fs_root", &self.fs_root)?;
+        s.serialize_field("fs_root_gen", &self.fs_root_gen)?;
+        s.serialize_field("fs_root_level", &self.fs_root_level)?;
+        s.serialize_field("dev_root", &self.dev_root)?;
+        s.serialize_field("dev_root_gen", &self.dev_root_gen)?;
+        s.serialize_field("dev_root_level", &self.dev_root_level)?;
+        s.serialize_field("csum_root", &self.csum_root)?;
+        s.serialize_field("csum_root_gen", &self.csum_root_gen)?;
+        s.serialize_field("csum_root_level", &self.csum_root_level)?;
+        s.serialize_field("total_bytes", &self.total_bytes)?;
+        s.serialize_field("bytes_used", &self.bytes_used)?;
+        s.serialize_field("num_devices", &self.num_devices)?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::superblock::{
+        Superblock, BTRFS_SUPER_FLAG_METADUMP, BTRFS_SUPER_FLAG_SEEDING, BTRFS_SUPER_FLAG_WRITTEN,
+    };
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+
+    #[test]
+    fn json_shape() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        let value = serde_json::to_value(&sb).unwrap();
+
+        assert_eq!(value["fsid"], "2d3e9a4c-7b1f-4e0a-9c5d-8f6a1b2c3d4e");
+        assert_eq!(value["csum"], "7051d3bf");
+        assert_eq!(
+            value["magic"],
+            json!({ "valid": true, "raw": "0x4d5f53665248425f" })
+        );
+        assert_eq!(value["label"], "testfs");
+        assert_eq!(value["flags"], json!(["WRITTEN"]));
+        assert_eq!(value["csum_type"], "crc32c");
+        assert_eq!(
+            value["compat_ro_flags"],
+            json!(["FREE_SPACE_TREE", "FREE_SPACE_TREE_VALID"])
+        );
+        assert_eq!(value["dev_item"]["devid"], 1);
+        assert_eq!(
+            value["dev_item"]["uuid"],
+            "a1b2c3d4-e5f6-4789-8abc-def012345678"
+        );
+        assert_eq!(value["super_roots"].as_array().unwrap().len(), 4);
+        assert_eq!(value["super_roots"][1]["tree_root_gen"], 6);
+    }
+
+    #[test]
+    fn names_superblock_flags() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        sb.flags = BTRFS_SUPER_FLAG_WRITTEN
+            | BTRFS_SUPER_FLAG_SEEDING
+            | BTRFS_SUPER_FLAG_METADUMP
+            | 1 << 60;
+        let value = serde_json::to_value(&sb).unwrap();
+        assert_eq!(
+            value["flags"],
+            json!([
+                "WRITTEN",
+                "SEEDING",
+                "METADUMP",
+                "unknown:0x1000000000000000"
+            ])
+        );
+    }
+}
@@ -0,0 +1,740 @@
+use std::collections::BTreeMap;
+
+use crate::chunk::{
+    BlockGroupFlags, BlockGroupItem, BlockGroupType, DevExtent, DeviceErrorStats, RaidProfile,
+};
+use crate::error::BtrfsError;
+use crate::features::{CompatRoFlags, IncompatFlags};
+use crate::free_space::{free_space_bitmap, FreeSpaceInfo};
+use crate::fs::Btrfs;
+use crate::key::{Key, KeyType};
+
+/// Objectid of the extent tree in the root tree.
+pub(crate) const EXTENT_TREE_OBJECTID: u64 = 2;
+/// Objectid of the device tree in the root tree.
+pub(crate) const DEV_TREE_OBJECTID: u64 = 4;
+/// Objectid of the device stats items in the device tree.
+const DEV_STATS_OBJECTID: u64 = 0;
+/// Objectid of the free space tree in the root tree.
+pub(crate) const FREE_SPACE_TREE_OBJECTID: u64 = 10;
+/// Bytes at the start of every device that btrfs never allocates.
+const DEVICE_RESERVED: u64 = 1 << 20;
+
+/// Allocated and used bytes of one kind of block group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceInfo {
+    /// Size of all block groups of this kind.
+    pub total: u64,
+    /// Bytes used by extents inside them.
+    pub used: u64,
+}
+
+/// Space usage by block group kind, as `btrfs filesystem df` reports it.
+///
+/// A filesystem with `MIXED_GROUPS`, as `mkfs.btrfs` makes on small
+/// devices, keeps data and metadata in the same block groups. Its usage is
+/// all in `mixed`, with `data` and `metadata` empty; see
+/// [`SpaceUsage::is_mixed`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpaceUsage {
+    pub data: SpaceInfo,
+    pub metadata: SpaceInfo,
+    pub system: SpaceInfo,
+    /// Block groups holding both data and metadata (`MIXED_GROUPS`).
+    pub mixed: SpaceInfo,
+}
+
+impl SpaceUsage {
+    /// Whether data and metadata share block groups, so that only `mixed`
+    /// and `system` are meaningful.
+    pub fn is_mixed(&self) -> bool {
+        self.mixed.total != 0
+    }
+}
+
+/// The numbers `statfs(2)`, and so `df`, reports for a mounted btrfs, in
+/// blocks of `f_bsize` bytes. Returned by [`Btrfs::statfs`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    /// The sector size.
+    pub f_bsize: u64,
+    /// Size of the filesystem, divided by the data profile's copies.
+    pub f_blocks: u64,
+    /// `f_blocks` less the used bytes of every block group, also divided
+    /// by the data profile's copies.
+    pub f_bfree: u64,
+    /// Room for new file data: what data block groups have left, and what
+    /// the data profile could allocate from unallocated device space.
+    pub f_bavail: u64,
+}
+
+impl StatFs {
+    pub fn total_bytes(&self) -> u64 {
+        self.f_blocks * self.f_bsize
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        (self.f_blocks - self.f_bfree) * self.f_bsize
+    }
+
+    pub fn available_bytes(&self) -> u64 {
+        self.f_bavail * self.f_bsize
+    }
+}
+
+/// Size and allocation of one device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceStats {
+    pub devid: u64,
+    /// Size of the device available to the filesystem, from its device
+    /// item. Zero if the chunk tree has no item for it.
+    pub total_bytes: u64,
+    /// Bytes allocated to chunks, summed from the device tree.
+    pub allocated_bytes: u64,
+}
+
+/// A run of free space inside a block group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeRange {
+    /// Logical start of the block group.
+    pub block_group: u64,
+    pub start: u64,
+    pub length: u64,
+}
+
+impl Btrfs {
+    /// Per-device allocation, from the device extents in the device tree.
+    /// Unlike the `bytes_used` counter of a device item, this cannot be
+    /// stale.
+    ///
+    /// Device sizes come from the chunk tree, so [`Btrfs::load_chunk_tree`]
+    /// must have been called first.
+    pub fn devices(&self) -> Result<Vec<DeviceStats>, BtrfsError> {
+        let mut devices: BTreeMap<u64, DeviceStats> = self
+            .dev_items()
+            .map(|item| {
+                let stats = DeviceStats {
+                    devid: item.devid(),
+                    total_bytes: item.total_bytes(),
+                    allocated_bytes: 0,
+                };
+                (item.devid(), stats)
+            })
+            .collect();
+
+        let root = self
+            .root_item(DEV_TREE_OBJECTID)?
+            .ok_or(BtrfsError::Malformed("root tree has no device tree"))?;
+        let dev_extent = KeyType::DevExtent.as_u8();
+        for item in self.tree(root.bytenr(), root.level()).iter() {
+            let (key, data) = item?;
+            if key.item_type != dev_extent {
+                continue;
+            }
+            let extent = DevExtent::from_bytes(&data)?;
+            devices
+                .entry(key.objectid)
+                .or_insert(DeviceStats {
+                    devid: key.objectid,
+                    total_bytes: 0,
+                    allocated_bytes: 0,
+                })
+                .allocated_bytes += extent.length;
+        }
+        Ok(devices.into_values().collect())
+    }
+
+    /// The error counters `btrfs device stats` shows for device `devid`.
+    /// A device that has never had an error may have no stats item yet;
+    /// its counters are all zero.
+    pub fn device_stats(&self, devid: u64) -> Result<DeviceErrorStats, BtrfsError> {
+        let root = self
+            .root_item(DEV_TREE_OBJECTID)?
+            .ok_or(BtrfsError::Malformed("root tree has no device tree"))?;
+        let key = Key::exact(DEV_STATS_OBJECTID, KeyType::PersistentItem, devid);
+        Ok(self
+            .tree(root.bytenr(), root.level())
+            .search(&key)?
+            .map(|(_, data)| DeviceErrorStats::from_bytes(&data))
+            .unwrap_or_default())
+    }
+
+    /// Sums the block group items of the block group tree, or of the extent
+    /// tree on filesystems without `BLOCK_GROUP_TREE`. With `MIXED_GROUPS`,
+    /// every data or metadata block group counts as mixed, whichever bits
+    /// it has.
+    ///
+    /// Block groups are not covered by the `sys_chunk_array`, so
+    /// [`Btrfs::load_chunk_tree`] must have been called first.
+    pub fn space_usage(&self) -> Result<SpaceUsage, BtrfsError> {
+        let mixed_groups = self
+            .superblock()
+            .incompat_flags()
+            .contains(IncompatFlags::MIXED_GROUPS);
+        let mut usage = SpaceUsage::default();
+        for (key, block_group) in self.block_groups()? {
+            let info = match BlockGroupType::from_flags(block_group.flags()) {
+                BlockGroupType::Data | BlockGroupType::Metadata if mixed_groups => &mut usage.mixed,
+                BlockGroupType::Data => &mut usage.data,
+                BlockGroupType::Metadata => &mut usage.metadata,
+                BlockGroupType::System => &mut usage.system,
+                BlockGroupType::Mixed => &mut usage.mixed,
+                BlockGroupType::Unknown(_) => continue,
+            };
+            info.total += key.offset;
+            info.used += block_group.used;
+        }
+        Ok(usage)
+    }
+
+    /// Free logical ranges of every block group, from the free space tree,
+    /// ordered by address. Bitmap and extent representations are both
+    /// decoded, and adjacent runs are merged.
+    ///
+    /// Filesystems without `FREE_SPACE_TREE` keep free space in the legacy
+    /// cache inodes instead, which is not supported.
+    pub fn free_space(&self) -> Result<Vec<FreeRange>, BtrfsError> {
+        if !self
+            .superblock()
+            .compat_ro_flags()
+            .contains(CompatRoFlags::FREE_SPACE_TREE)
+        {
+            return Err(BtrfsError::Unsupported(
+                "free space cache without a free space tree",
+            ));
+        }
+        let root = self
+            .root_item(FREE_SPACE_TREE_OBJECTID)?
+            .ok_or(BtrfsError::Malformed("root tree has no free space tree"))?;
+        let sectorsize = u64::from(self.superblock().sectorsize);
+
+        let mut ranges: Vec<FreeRange> = Vec::new();
+        let mut push = |block_group: u64, start: u64, length: u64| {
+            if let Some(last) = ranges.last_mut() {
+                if last.block_group == block_group
+                    && last.start.checked_add(last.length) == Some(start)
+                {
+                    last.length += length;
+                    return;
+                }
+            }
+            ranges.push(FreeRange {
+                block_group,
+                start,
+                length,
+            });
+        };
+
+        let mut block_group = None;
+        for item in self.tree(root.bytenr(), root.level()).iter() {
+            let (key, data) = item?;
+            match key.item_type() {
+                KeyType::FreeSpaceInfo => {
+                    FreeSpaceInfo::from_bytes(&data)?;
+                    block_group = Some(key.objectid);
+                }
+                KeyType::FreeSpaceExtent => {
+                    let bg = block_group.ok_or(BtrfsError::Malformed(
+                        "free space extent before its info item",
+                    ))?;
+                    push(bg, key.objectid, key.offset);
+                }
+                KeyType::FreeSpaceBitmap => {
+                    let bg = block_group.ok_or(BtrfsError::Malformed(
+                        "free space bitmap before its info item",
+                    ))?;
+                    for (start, length) in free_space_bitmap(key.objectid, sectorsize, &data) {
+                        // Bits past the item's range are padding.
+                        let end = (start + length).min(key.objectid.saturating_add(key.offset));
+                        if start < end {
+                            push(bg, start, end - start);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Computes what `statfs(2)` would report with the filesystem mounted,
+    /// the way the kernel does. If data block groups have several profiles,
+    /// as during a balance, the one with the most copies is used.
+    ///
+    /// A mounted filesystem also holds back a global block reserve from the
+    /// free counts. That reserve exists only in memory, so it is not
+    /// subtracted here.
+    ///
+    /// Device sizes come from the chunk tree, so [`Btrfs::load_chunk_tree`]
+    /// must have been called first.
+    pub fn statfs(&self) -> Result<StatFs, BtrfsError> {
+        let mut data_profile: Option<RaidProfile> = None;
+        let (mut data_free, mut raw_used) = (0u64, 0u64);
+        for (key, block_group) in self.block_groups()? {
+            let profile = RaidProfile::from_flags(block_group.flags());
+            // `used` is unchecked, so a corrupt item must not overflow.
+            raw_used = raw_used.saturating_add(block_group.used.saturating_mul(profile.copies()));
+            if block_group.flags().contains(BlockGroupFlags::DATA) {
+                data_free = data_free.saturating_add(key.offset.saturating_sub(block_group.used));
+                if data_profile.is_none_or(|data| profile.copies() > data.copies()) {
+                    data_profile = Some(profile);
+                }
+            }
+        }
+        let data_profile = data_profile.unwrap_or(RaidProfile::Single);
+        let copies = data_profile.copies();
+
+        let unallocated: Vec<u64> = self
+            .devices()?
+            .iter()
+            .map(|device| {
+                device
+                    .total_bytes
+                    .saturating_sub(device.allocated_bytes)
+                    .saturating_sub(DEVICE_RESERVED)
+            })
+            .collect();
+        let available = data_free.saturating_add(allocatable(data_profile, unallocated));
+
+        let sb = self.superblock();
+        let f_bsize = u64::from(sb.sectorsize).max(1);
+        let f_blocks = sb.total_bytes().get() / copies / f_bsize;
+        Ok(StatFs {
+            f_bsize,
+            f_blocks,
+            f_bfree: f_blocks.saturating_sub(raw_used / copies / f_bsize),
+            f_bavail: available / f_bsize,
+        })
+    }
+
+    /// Every block group item, keyed `(start, BLOCK_GROUP_ITEM, length)`,
+    /// from whichever tree holds them.
+    pub(crate) fn block_groups(&self) -> Result<Vec<(Key, BlockGroupItem)>, BtrfsError> {
+        let sb = self.superblock();
+        let tree = if let Some(root) = sb.block_group_root() {
+            self.tree(root.get(), sb.block_group_root_level())
+        } else {
+            let root = self
+                .root_item(EXTENT_TREE_OBJECTID)?
+                .ok_or(BtrfsError::Malformed("root tree has no extent tree"))?;
+            self.tree(root.bytenr(), root.level())
+        };
+
+        let block_group_item = KeyType::BlockGroupItem.as_u8();
+        let mut block_groups = Vec::new();
+        for item in tree.iter() {
+            let (key, data) = item?;
+            if key.item_type == block_group_item {
+                block_groups.push((key, BlockGroupItem::from_bytes(&data)?));
+            }
+        }
+        Ok(block_groups)
+    }
+}
+
+/// Bytes of data that chunks of `profile` could hold if allocated from
+/// the `unallocated` space of each device.
+///
+/// The kernel's own estimate pairs up devices in a fixed order, so it can
+/// come out low when devices differ in size. This assumes chunks go to the
+/// devices with the most space left, as the allocator places them.
+fn allocatable(profile: RaidProfile, mut unallocated: Vec<u64>) -> u64 {
+    unallocated.sort_unstable();
+    match profile {
+        RaidProfile::Single | RaidProfile::Raid0 => unallocated
+            .iter()
+            .fold(0, |sum, &free| sum.saturating_add(free)),
+        RaidProfile::Dup => unallocated
+            .iter()
+            .fold(0, |sum, &free| sum.saturating_add(free / 2)),
+        RaidProfile::Raid1 | RaidProfile::Raid10 => mirrored(&unallocated, 2),
+        RaidProfile::Raid1C3 => mirrored(&unallocated, 3),
+        RaidProfile::Raid1C4 => mirrored(&unallocated, 4),
+        RaidProfile::Raid5 => striped(&unallocated, 1),
+        RaidProfile::Raid6 => striped(&unallocated, 2),
+    }
+}
+
+/// The most data that fits with `copies` copies of each byte on distinct
+/// devices: the largest `c` such that the devices, each holding at most
+/// `c`, hold `copies * c` in all. `sizes` is sorted.
+fn mirrored(sizes: &[u64], copies: u64) -> u64 {
+    let mut smaller = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+        // The devices from `i` on each hold up to `c`, the ones before it
+        // hold all they have.
+        let rest = (sizes.len() - i) as u64;
+        if rest < copies {
+            let c = smaller / (copies - rest);
+            if c <= size {
+                return c;
+            }
+        }
+        smaller = smaller.saturating_add(size);
+    }
+    smaller / copies
+}
+
+/// Data in chunks striped over every device with space left, `parity` of
+/// the stripes holding parity. Each chunk is as wide as it can be, so the
+/// smallest device sets how much goes at each width. `sizes` is sorted.
+fn striped(sizes: &[u64], parity: usize) -> u64 {
+    let mut data = 0u64;
+    let mut filled = 0;
+    for (i, &size) in sizes.iter().enumerate() {
+        let width = sizes.len() - i;
+        if width <= parity {
+            break;
+        }
+        data = data.saturating_add((size - filled).saturating_mul((width - parity) as u64));
+        filled = size;
+    }
+    data
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::superblock::{DevItem, Superblock};
+    use crate::testutil::{
+        leaf, put_u64, root_item, TestImage, NODESIZE, SUPERBLOCK, SYSTEM_CHUNK,
+    };
+    use crate::units::LogicalAddr;
+
+    fn block_group(used: u64, flags: u64) -> Vec<u8> {
+        [used, 256, flags]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn block_groups_leaf(bytenr: u64, owner: u64) -> Vec<u8> {
+        let data = block_group(5 << 20, 0x1);
+        let metadata = block_group(1 << 20, 0x4 | 0x20);
+        let system = block_group(16384, 0x2 | 0x20);
+        let more_data = block_group(3 << 20, 0x1);
+        let items = [
+            (Key::new(13631488, 192, 8 << 20), &data[..]),
+            (Key::new(13631488, 168, 4096), &[][..]),
+            (Key::new(22020096, 192, 8 << 20), &system[..]),
+            (Key::new(30408704, 192, 256 << 20), &metadata[..]),
+            (Key::new(298844160, 192, 1 << 30), &more_data[..]),
+        ];
+        leaf(bytenr, owner, &items)
+    }
+
+    fn expected() -> SpaceUsage {
+        SpaceUsage {
+            data: SpaceInfo {
+                total: (8 << 20) + (1 << 30),
+                used: 8 << 20,
+            },
+            metadata: SpaceInfo {
+                total: 256 << 20,
+                used: 1 << 20,
+            },
+            system: SpaceInfo {
+                total: 8 << 20,
+                used: 16384,
+            },
+            mixed: SpaceInfo::default(),
+        }
+    }
+
+    #[test]
+    fn sums_extent_tree_block_groups() {
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let extent_tree = SYSTEM_CHUNK + 3 * NODESIZE as u64;
+        let root = root_item(extent_tree, 0);
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(
+                root_tree,
+                &leaf(root_tree, 1, &[(Key::new(2, 132, 0), &root)]),
+            )
+            .put_block(extent_tree, &block_groups_leaf(extent_tree, 2));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        assert_eq!(fs.superblock().block_group_root(), None);
+        assert_eq!(fs.space_usage().unwrap(), expected());
+    }
+
+    #[test]
+    fn prefers_block_group_tree() {
+        let block_group_tree = SYSTEM_CHUNK + 4 * NODESIZE as u64;
+        let mut image = TestImage::new();
+        image
+            .set_block_group_tree(block_group_tree, 0)
+            .put_block(block_group_tree, &block_groups_leaf(block_group_tree, 11));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        // There is no extent tree to fall back on.
+        let sb = fs.superblock();
+        assert_eq!(sb.block_group_root(), Some(LogicalAddr(block_group_tree)));
+        assert_eq!(sb.block_group_root_level(), 0);
+        assert_eq!(fs.space_usage().unwrap(), expected());
+    }
+
+    #[test]
+    fn reports_mixed_block_groups() {
+        let block_group_tree = SYSTEM_CHUNK + 4 * NODESIZE as u64;
+        let mut image = TestImage::new();
+        let fixture_flags = IncompatFlags::SKINNY_METADATA | IncompatFlags::NO_HOLES;
+        image
+            .set_incompat_flags(fixture_flags | IncompatFlags::MIXED_GROUPS)
+            .set_block_group_tree(block_group_tree, 0)
+            .put_block(block_group_tree, &block_groups_leaf(block_group_tree, 11));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let usage = fs.space_usage().unwrap();
+        assert!(usage.is_mixed());
+        assert_eq!(usage.data, SpaceInfo::default());
+        assert_eq!(usage.metadata, SpaceInfo::default());
+        let expected = expected();
+        assert_eq!(usage.system, expected.system);
+        assert_eq!(
+            usage.mixed,
+            SpaceInfo {
+                total: expected.data.total + expected.metadata.total,
+                used: expected.data.used + expected.metadata.used,
+            }
+        );
+        assert!(!expected.is_mixed());
+    }
+
+    #[test]
+    fn sums_device_extents() {
+        let dev_extent = |chunk_offset: u64, length: u64| {
+            [3, 256, chunk_offset, length]
+                .iter()
+                .flat_map(|value: &u64| value.to_le_bytes())
+                .chain([0; 16])
+                .collect::<Vec<u8>>()
+        };
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let dev_tree = SYSTEM_CHUNK + 3 * NODESIZE as u64;
+        let root = root_item(dev_tree, 0);
+        let stats: Vec<u8> = [0u64, 4, 0, 2, 1]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let dev_items = [
+            (Key::new(0, 249, 2), stats),
+            (Key::new(1, 204, 1 << 20), dev_extent(22020096, 8 << 20)),
+            (Key::new(1, 204, 30408704), dev_extent(22020096, 8 << 20)),
+            (Key::new(2, 204, 1 << 20), dev_extent(1 << 30, 1 << 30)),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(
+                root_tree,
+                &leaf(root_tree, 1, &[(Key::new(4, 132, 0), &root)]),
+            )
+            .put_block(dev_tree, &leaf(dev_tree, 4, &dev_items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        // The chunk tree was not loaded, so no device sizes are known.
+        assert_eq!(
+            fs.devices().unwrap(),
+            [
+                DeviceStats {
+                    devid: 1,
+                    total_bytes: 0,
+                    allocated_bytes: 16 << 20,
+                },
+                DeviceStats {
+                    devid: 2,
+                    total_bytes: 0,
+                    allocated_bytes: 1 << 30,
+                },
+            ]
+        );
+        assert_eq!(fs.superblock().dev_item().total_bytes(), 1 << 30);
+
+        assert_eq!(fs.device_stats(1).unwrap(), DeviceErrorStats::default());
+        let stats = fs.device_stats(2).unwrap();
+        assert_eq!((stats.read_errs, stats.corruption_errs), (4, 2));
+        assert_eq!(stats.generation_errs, 1);
+    }
+
+    #[test]
+    fn sizes_unallocated_space_by_profile() {
+        let allocatable = |profile, sizes: &[u64]| allocatable(profile, sizes.to_vec());
+        assert_eq!(allocatable(RaidProfile::Single, &[5, 3]), 8);
+        assert_eq!(allocatable(RaidProfile::Dup, &[5, 4]), 4);
+        // The large device pairs with each of the small ones in turn.
+        assert_eq!(allocatable(RaidProfile::Raid1, &[10, 5, 5]), 10);
+        assert_eq!(allocatable(RaidProfile::Raid1, &[10, 2]), 2);
+        assert_eq!(allocatable(RaidProfile::Raid1, &[10]), 0);
+        assert_eq!(allocatable(RaidProfile::Raid1C3, &[6, 4, 4, 4]), 6);
+        assert_eq!(allocatable(RaidProfile::Raid1C4, &[6, 4, 4]), 0);
+        // Three wide until the small device is full, then two wide.
+        assert_eq!(allocatable(RaidProfile::Raid5, &[4, 2, 4]), 6);
+        assert_eq!(allocatable(RaidProfile::Raid6, &[4, 2, 4]), 2);
+        assert_eq!(allocatable(RaidProfile::Raid6, &[]), 0);
+        // Device sizes from a corrupt chunk tree saturate.
+        let huge = [u64::MAX, u64::MAX, u64::MAX];
+        for profile in [
+            RaidProfile::Single,
+            RaidProfile::Dup,
+            RaidProfile::Raid1C3,
+            RaidProfile::Raid5,
+        ] {
+            assert!(allocatable(profile, &huge) > 0, "{profile}");
+        }
+    }
+
+    /// Two 512MiB devices holding RAID1 block groups, the data one with
+    /// `data_used` bytes used.
+    fn raid1_fs(data_used: u64) -> Btrfs {
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let dev_item = |devid: u64| {
+            let mut bytes = SUPERBLOCK[201..201 + DevItem::SIZE].to_vec();
+            put_u64(&mut bytes, 0, devid);
+            put_u64(&mut bytes, 8, 512 << 20);
+            bytes
+        };
+        let chunk_items = [
+            (Key::new(1, 216, 1), dev_item(1)),
+            (Key::new(1, 216, 2), dev_item(2)),
+        ];
+
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let extent_tree = SYSTEM_CHUNK + 3 * NODESIZE as u64;
+        let dev_tree = SYSTEM_CHUNK + 4 * NODESIZE as u64;
+        let (extent_root, dev_root) = (root_item(extent_tree, 0), root_item(dev_tree, 0));
+        let roots = [
+            (Key::new(2, 132, 0), &extent_root),
+            (Key::new(4, 132, 0), &dev_root),
+        ];
+        let data = block_group(data_used, 0x1 | 0x10);
+        let metadata = block_group(1 << 20, 0x4 | 0x10);
+        let system = block_group(16384, 0x2 | 0x10);
+        let block_groups = [
+            (Key::new(13631488, 192, 64 << 20), &metadata),
+            (Key::new(SYSTEM_CHUNK, 192, 8 << 20), &system),
+            (Key::new(1 << 30, 192, 256 << 20), &data),
+        ];
+        // Both devices hold a copy of every chunk.
+        let dev_extent = |length: u64| {
+            [3, 256, 0, length]
+                .iter()
+                .flat_map(|value: &u64| value.to_le_bytes())
+                .chain([0; 16])
+                .collect::<Vec<u8>>()
+        };
+        let mut dev_extents = Vec::new();
+        for devid in [1, 2] {
+            for (offset, length) in [
+                (1 << 20, 64 << 20),
+                (65 << 20, 8 << 20),
+                (73 << 20, 256 << 20),
+            ] {
+                dev_extents.push((Key::new(devid, 204, offset), dev_extent(length)));
+            }
+        }
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(chunk_root, &leaf(chunk_root, 3, &chunk_items))
+            .put_block(root_tree, &leaf(root_tree, 1, &roots))
+            .put_block(extent_tree, &leaf(extent_tree, 2, &block_groups))
+            .put_block(dev_tree, &leaf(dev_tree, 4, &dev_extents));
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        fs.load_chunk_tree().unwrap();
+        fs
+    }
+
+    #[test]
+    fn computes_statfs_for_raid1() {
+        let fs = raid1_fs(100 << 20);
+        // Half of the 1GiB of the two devices, of which the block groups
+        // use 101MiB and 16KiB. New data fits in the 156MiB left in the
+        // data block group and in 183MiB more on each device, since the
+        // first MiB is never allocated.
+        let statfs = fs.statfs().unwrap();
+        assert_eq!(fs.superblock().total_bytes().get(), 1 << 30);
+        assert_eq!(statfs.f_bsize, 4096);
+        assert_eq!(statfs.total_bytes(), 512 << 20);
+        assert_eq!(statfs.used_bytes(), (101 << 20) + 16384);
+        assert_eq!(statfs.available_bytes(), 339 << 20);
+        assert_eq!(statfs.f_bavail, (339 << 20) / 4096);
+
+        // A corrupt `used` saturates instead of overflowing.
+        let statfs = raid1_fs(u64::MAX).statfs().unwrap();
+        assert_eq!(statfs.f_bfree, 0);
+        assert_eq!(statfs.available_bytes(), 183 << 20);
+    }
+
+    fn free_space_fs(compat_ro: Option<CompatRoFlags>) -> Btrfs {
+        let root_tree = SYSTEM_CHUNK + 2 * NODESIZE as u64;
+        let free_space_tree = SYSTEM_CHUNK + 3 * NODESIZE as u64;
+        let info = |count: u32, flags: u32| {
+            let mut bytes = count.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&flags.to_le_bytes());
+            bytes
+        };
+        // A block group of extents, then one of two bitmaps whose free runs
+        // meet at the boundary between them.
+        let items = [
+            (Key::new(13631488, 198, 8 << 20), info(2, 0)),
+            (Key::new(13631488, 199, 4096), vec![]),
+            (Key::new(13643776, 199, 8192), vec![]),
+            (Key::new(298844160, 198, 1 << 30), info(2, 1)),
+            (
+                Key::new(298844160, 200, 16 * 4096),
+                vec![0b0000_0110, 0b1000_0000],
+            ),
+            (
+                Key::new(298844160 + 16 * 4096, 200, 12 * 4096),
+                vec![0x03, 0xf0],
+            ),
+        ];
+        let root = root_item(free_space_tree, 0);
+        let mut image = TestImage::new();
+        if let Some(flags) = compat_ro {
+            image.set_compat_ro_flags(flags);
+        }
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(
+                root_tree,
+                &leaf(root_tree, 1, &[(Key::new(10, 132, 0), &root)]),
+            )
+            .put_block(free_space_tree, &leaf(free_space_tree, 10, &items));
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    #[test]
+    fn reads_free_space_extents_and_bitmaps() {
+        let fs = free_space_fs(None);
+        let range = |block_group, start, length| FreeRange {
+            block_group,
+            start,
+            length,
+        };
+        let bitmaps = 298844160;
+        assert_eq!(
+            fs.free_space().unwrap(),
+            [
+                range(13631488, 13631488, 4096),
+                range(13631488, 13643776, 8192),
+                range(bitmaps, bitmaps + 4096, 2 * 4096),
+                // Sector 15 of the first bitmap and 0-1 of the second.
+                range(bitmaps, bitmaps + 15 * 4096, 3 * 4096),
+                // Bits 12-15 of the second bitmap are past its range.
+            ]
+        );
+    }
+
+    #[test]
+    fn free_space_needs_the_free_space_tree() {
+        let fs = free_space_fs(Some(CompatRoFlags::empty()));
+        assert!(matches!(fs.free_space(), Err(BtrfsError::Unsupported(_))));
+    }
+}
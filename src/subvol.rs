@@ -0,0 +1,474 @@
+use std::collections::BTreeMap;
+#[cfg(feature = "uuid")]
+use std::collections::HashMap;
+
+#[cfg(feature = "uuid")]
+use uuid::Uuid;
+
+use crate::dir::DirItems;
+use crate::error::BtrfsError;
+use crate::fs::Btrfs;
+use crate::inode::InodeRef;
+use crate::key::{Key, KeyType};
+use crate::root::{RootFlags, RootItem, RootRef};
+#[cfg(feature = "uuid")]
+use crate::superblock::Warning;
+
+/// Objectid of the top-level subvolume, `FS_TREE`.
+pub(crate) const FS_TREE_OBJECTID: u64 = 5;
+const FIRST_FREE_OBJECTID: u64 = 256;
+const LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+/// Objectid of the UUID tree in the root tree.
+pub(crate) const UUID_TREE_OBJECTID: u64 = 9;
+/// Bound on path components, so that reference loops in a corrupt tree end.
+pub(crate) const MAX_PATH_COMPONENTS: usize = 4096;
+
+/// A subvolume or snapshot, as listed by `btrfs subvolume list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubvolumeInfo {
+    pub id: u64,
+    /// Subvolume holding the directory entry for this one.
+    pub parent_id: u64,
+    /// Inode, in the parent, of the directory holding the entry.
+    pub dirid: u64,
+    pub name: String,
+    /// Path from the top-level subvolume, without a leading `/`.
+    pub path: String,
+    /// Read-only, as snapshots usually are.
+    pub readonly: bool,
+    pub generation: u64,
+    /// Transaction of the last change.
+    pub ctransid: u64,
+    /// Transaction the subvolume was created in.
+    pub otransid: u64,
+    pub uuid: [u8; 16],
+    /// UUID of the subvolume this one is a snapshot of, or zero.
+    pub parent_uuid: [u8; 16],
+    /// UUID of the subvolume sent to create this one, if it was made by
+    /// `btrfs receive`.
+    pub received_uuid: Option<[u8; 16]>,
+    /// Transaction of the sent subvolume when it was sent.
+    pub stransid: u64,
+    /// Transaction in which this subvolume was received.
+    pub rtransid: u64,
+}
+
+impl SubvolumeInfo {
+    /// Whether this subvolume was created by `btrfs receive`, and so may
+    /// serve as the parent of an incremental send.
+    pub fn is_received(&self) -> bool {
+        self.received_uuid.is_some()
+    }
+}
+
+pub(crate) fn is_subvolume(objectid: u64) -> bool {
+    objectid == FS_TREE_OBJECTID || (FIRST_FREE_OBJECTID..=LAST_FREE_OBJECTID).contains(&objectid)
+}
+
+impl Btrfs {
+    /// Lists every subvolume below the top-level one, ordered by id.
+    /// Subvolumes that were deleted but not yet cleaned up are skipped.
+    pub fn subvolumes(&self) -> Result<Vec<SubvolumeInfo>, BtrfsError> {
+        let mut roots = BTreeMap::new();
+        let mut backrefs = BTreeMap::new();
+        for item in self.root_tree().iter() {
+            let (key, data) = item?;
+            if !is_subvolume(key.objectid) {
+                continue;
+            }
+            match key.item_type() {
+                // Keys ascend, so the current root item of each id wins.
+                KeyType::RootItem => {
+                    roots.insert(key.objectid, RootItem::from_bytes(&data)?);
+                }
+                KeyType::RootBackref => {
+                    backrefs.insert(key.objectid, (key.offset, RootRef::from_bytes(&data)?));
+                }
+                _ => {}
+            }
+        }
+
+        let mut subvolumes = Vec::new();
+        for (&id, (parent_id, root_ref)) in &backrefs {
+            let Some(root) = roots.get(&id) else {
+                continue;
+            };
+            subvolumes.push(SubvolumeInfo {
+                id,
+                parent_id: *parent_id,
+                dirid: root_ref.dirid,
+                name: root_ref.name.clone(),
+                path: self.subvolume_path(id, &roots, &backrefs)?,
+                readonly: root.flags().contains(RootFlags::SUBVOL_RDONLY),
+                generation: root.generation,
+                ctransid: root.ctransid,
+                otransid: root.otransid,
+                uuid: root.uuid,
+                parent_uuid: root.parent_uuid,
+                received_uuid: Some(root.received_uuid).filter(|uuid| *uuid != [0; 16]),
+                stransid: root.stransid,
+                rtransid: root.rtransid,
+            });
+        }
+        Ok(subvolumes)
+    }
+
+    /// The id of the subvolume mounted when no `subvol=` option is given:
+    /// the target of the `default` dir item in the root tree directory,
+    /// set by `btrfs subvolume set-default`, or the top-level subvolume (5)
+    /// if it was never set.
+    ///
+    /// A default subvolume that has been deleted, and so could not be
+    /// mounted, is a [`BtrfsError::SubvolumeDeleted`].
+    pub fn default_subvolume(&self) -> Result<u64, BtrfsError> {
+        let dir = self.superblock().root_dir_objectid;
+        let dir_item = KeyType::DirItem.as_u8();
+        // The root tree directory holds just this one name, so scanning
+        // its few dir items needs no name hash.
+        let items = self.root_tree().search_range(
+            Key::new(dir, dir_item, 0),
+            Key::new(dir, dir_item, u64::MAX),
+        );
+        let mut target = None;
+        for item in items {
+            let (_, data) = item?;
+            for entry in DirItems::new(&data) {
+                let (name, location, _) = entry?;
+                if name == "default" {
+                    target = Some(location.objectid);
+                }
+            }
+        }
+        let Some(id) = target else {
+            return Ok(FS_TREE_OBJECTID);
+        };
+        match self.root_item(id)? {
+            Some(root) if !root.flags().contains(RootFlags::SUBVOL_DEAD) => Ok(id),
+            _ => Err(BtrfsError::SubvolumeDeleted { id }),
+        }
+    }
+
+    /// Maps subvolume UUIDs, and the received UUIDs of subvolumes made by
+    /// `btrfs receive`, to subvolume ids, as the UUID tree records them.
+    /// A UUID that is both a subvolume's own and another's received UUID
+    /// maps to the former; one received into several subvolumes maps to
+    /// the first listed. Empty if the filesystem has no UUID tree.
+    ///
+    /// The kernel rebuilds the tree at mount when it may be out of date;
+    /// reading one that is records a [`Warning::StaleUuidTree`] for
+    /// [`Btrfs::take_warnings`].
+    #[cfg(feature = "uuid")]
+    pub fn uuid_tree(&self) -> Result<HashMap<Uuid, u64>, BtrfsError> {
+        let mut map = HashMap::new();
+        let Some(root) = self.root_item(UUID_TREE_OBJECTID)? else {
+            return Ok(map);
+        };
+        if !self.uuid_tree_is_current() {
+            let sb = self.superblock();
+            self.warn(Warning::StaleUuidTree {
+                uuid_tree_gen: sb.uuid_tree_generation,
+                sb_gen: sb.generation,
+            });
+        }
+        let mut received = Vec::new();
+        for item in self.tree(root.bytenr(), root.level()).iter() {
+            let (key, data) = item?;
+            let kind = key.item_type();
+            if !matches!(
+                kind,
+                KeyType::UuidKeySubvol | KeyType::UuidKeyReceivedSubvol
+            ) {
+                continue;
+            }
+            // The UUID is split into two little-endian halves.
+            let mut bytes = [0u8; 16];
+            bytes[..8].copy_from_slice(&key.objectid.to_le_bytes());
+            bytes[8..].copy_from_slice(&key.offset.to_le_bytes());
+            let id = data
+                .get(..8)
+                .ok_or(BtrfsError::Malformed("UUID item holds no subvolume id"))?;
+            let entry = (
+                Uuid::from_bytes(bytes),
+                u64::from_le_bytes(id.try_into().unwrap()),
+            );
+            if kind == KeyType::UuidKeySubvol {
+                map.insert(entry.0, entry.1);
+            } else {
+                received.push(entry);
+            }
+        }
+        for (uuid, id) in received {
+            map.entry(uuid).or_insert(id);
+        }
+        Ok(map)
+    }
+
+    /// Whether the UUID tree was last updated in the superblock's
+    /// generation. A filesystem mounted by a kernel without UUID tree
+    /// support leaves it behind, and the next mount rescans it.
+    #[cfg(feature = "uuid")]
+    pub fn uuid_tree_is_current(&self) -> bool {
+        let sb = self.superblock();
+        sb.uuid_tree_generation == sb.generation
+    }
+
+    /// Joins the names of the subvolumes and directories between the
+    /// top-level subvolume and subvolume `id`.
+    fn subvolume_path(
+        &self,
+        mut id: u64,
+        roots: &BTreeMap<u64, RootItem>,
+        backrefs: &BTreeMap<u64, (u64, RootRef)>,
+    ) -> Result<String, BtrfsError> {
+        // Collected leaf first, then reversed.
+        let mut components = Vec::new();
+        while id != FS_TREE_OBJECTID {
+            if components.len() >= MAX_PATH_COMPONENTS {
+                return Err(BtrfsError::Malformed("subvolume backrefs form a loop"));
+            }
+            let (parent_id, root_ref) = backrefs.get(&id).ok_or(BtrfsError::Malformed(
+                "subvolume has no backref to its parent",
+            ))?;
+            let parent = roots
+                .get(parent_id)
+                .ok_or(BtrfsError::Malformed("subvolume's parent has no root item"))?;
+            components.push(root_ref.name.clone());
+            self.push_dir_components(parent, root_ref.dirid, &mut components)?;
+            id = *parent_id;
+        }
+        components.reverse();
+        Ok(components.join("/"))
+    }
+
+    /// Pushes the names of directory `dirid` and its ancestors, up to but
+    /// excluding the subvolume's root directory, following `INODE_REF`s.
+    fn push_dir_components(
+        &self,
+        subvolume: &RootItem,
+        mut dirid: u64,
+        components: &mut Vec<String>,
+    ) -> Result<(), BtrfsError> {
+        let tree = self.tree(subvolume.bytenr(), subvolume.level());
+        let inode_ref = KeyType::InodeRef.as_u8();
+        while dirid != subvolume.root_dirid {
+            if components.len() >= MAX_PATH_COMPONENTS {
+                return Err(BtrfsError::Malformed("directory references form a loop"));
+            }
+            let min = Key::new(dirid, inode_ref, 0);
+            let max = Key::new(dirid, inode_ref, u64::MAX);
+            let (key, data) = tree
+                .search_range(min, max)
+                .next()
+                .transpose()?
+                .ok_or(BtrfsError::Malformed("directory has no inode ref"))?;
+            let name = InodeRef::parse_refs(key.offset, &data)?
+                .into_iter()
+                .next()
+                .ok_or(BtrfsError::Malformed("inode ref item is empty"))?
+                .name;
+            components.push(name);
+            dirid = key.offset;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::testutil::{block, leaf, put_u64, root_item, TestImage};
+
+    fn root_ref(dirid: u64, name: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&dirid.to_le_bytes());
+        bytes.extend_from_slice(&2u64.to_le_bytes());
+        bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    #[test]
+    fn lists_nested_subvolumes() {
+        // Subvolume 256 "home" sits at the top level. 257 is a read-only
+        // snapshot of it, created in directory "snaps" (inode 258) of 256.
+        // 258 was deleted and only its root item is left.
+        let top = root_item(block(1), 0);
+        let home = root_item(block(2), 0);
+        let mut snap = root_item(block(2), 0);
+        put_u64(&mut snap, 208, 1);
+        put_u64(&mut snap, 303, 9);
+        snap[247] = 0x57;
+        snap[263] = 0x56;
+        snap[279] = 0x42;
+        put_u64(&mut snap, 311, 40);
+        put_u64(&mut snap, 319, 11);
+        let deleted = root_item(block(2), 0);
+        let home_ref = root_ref(256, "home");
+        let snap_ref = root_ref(258, "snap");
+
+        let root_items = [
+            (Key::new(2, 132, 0), &top[..]),
+            (Key::new(5, 132, 0), &top[..]),
+            (Key::new(256, 132, 0), &home[..]),
+            (Key::new(256, 144, 5), &home_ref[..]),
+            (Key::new(256, 156, 257), &snap_ref[..]),
+            (Key::new(257, 132, 9), &snap[..]),
+            (Key::new(257, 144, 256), &snap_ref[..]),
+            (Key::new(258, 132, 0), &deleted[..]),
+        ];
+        let mut snaps_ref = vec![0u8; 10];
+        snaps_ref[8] = 5;
+        snaps_ref.extend_from_slice(b"snaps");
+        let home_items = [(Key::new(258, 12, 256), &snaps_ref[..])];
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(block(0), &leaf(block(0), 1, &root_items))
+            .put_block(block(1), &leaf::<&[u8]>(block(1), 5, &[]))
+            .put_block(block(2), &leaf(block(2), 256, &home_items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let subvolumes = fs.subvolumes().unwrap();
+        assert_eq!(subvolumes.len(), 2);
+
+        let home = &subvolumes[0];
+        assert_eq!((home.id, home.parent_id), (256, 5));
+        assert_eq!(home.path, "home");
+        assert!(!home.readonly);
+        assert!(!home.is_received());
+        assert_eq!(home.received_uuid, None);
+
+        let snap = &subvolumes[1];
+        assert_eq!((snap.id, snap.parent_id, snap.dirid), (257, 256, 258));
+        assert_eq!(snap.name, "snap");
+        assert_eq!(snap.path, "home/snaps/snap");
+        assert!(snap.readonly);
+        assert_eq!(snap.otransid, 9);
+        assert_eq!((snap.uuid[0], snap.parent_uuid[0]), (0x57, 0x56));
+        assert!(snap.is_received());
+        assert_eq!(snap.received_uuid.unwrap()[0], 0x42);
+        assert_eq!((snap.stransid, snap.rtransid), (40, 11));
+    }
+
+    #[test]
+    fn rejects_subvolume_backref_loops() {
+        // 256 claims to sit in 257, and 257 in 256; neither reaches 5.
+        let top = root_item(block(1), 0);
+        let a_ref = root_ref(256, "a");
+        let b_ref = root_ref(256, "b");
+        let root_items = [
+            (Key::new(5, 132, 0), &top[..]),
+            (Key::new(256, 132, 0), &top[..]),
+            (Key::new(256, 144, 257), &a_ref[..]),
+            (Key::new(257, 132, 0), &top[..]),
+            (Key::new(257, 144, 256), &b_ref[..]),
+        ];
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(block(0), &leaf(block(0), 1, &root_items))
+            .put_block(block(1), &leaf::<&[u8]>(block(1), 5, &[]));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert!(matches!(
+            fs.subvolumes(),
+            Err(BtrfsError::Malformed("subvolume backrefs form a loop"))
+        ));
+    }
+
+    #[test]
+    fn resolves_the_default_subvolume() {
+        let mut default = Key::new(256, 132, u64::MAX).to_disk_bytes().to_vec();
+        default.extend_from_slice(&[0; 10]);
+        default.extend_from_slice(&7u16.to_le_bytes());
+        default.push(2);
+        default.extend_from_slice(b"default");
+        let subvol = root_item(block(1), 0);
+        let mut dead = subvol.clone();
+        dead[214] |= 1;
+
+        let fs_with = |items: &[(Key, &[u8])]| {
+            let mut image = TestImage::new();
+            image
+                .set_root_tree(block(0), 0)
+                .put_block(block(0), &leaf(block(0), 1, items));
+            Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+        };
+        let dir_key = Key::new(6, 84, 2378154706);
+
+        assert_eq!(fs_with(&[]).default_subvolume().unwrap(), 5);
+        let fs = fs_with(&[(dir_key, &default), (Key::new(256, 132, 0), &subvol)]);
+        assert_eq!(fs.default_subvolume().unwrap(), 256);
+        assert!(matches!(
+            fs_with(&[(dir_key, &default)]).default_subvolume(),
+            Err(BtrfsError::SubvolumeDeleted { id: 256 })
+        ));
+        let fs = fs_with(&[(dir_key, &default), (Key::new(256, 132, 0), &dead)]);
+        assert!(matches!(
+            fs.default_subvolume(),
+            Err(BtrfsError::SubvolumeDeleted { id: 256 })
+        ));
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn maps_uuids_to_subvolumes() {
+        let uuid_key = |uuid: [u8; 16], item_type| {
+            let half = |at: usize| u64::from_le_bytes(uuid[at..at + 8].try_into().unwrap());
+            Key::new(half(0), item_type, half(8))
+        };
+        let (home, snap, sent) = ([0x11; 16], [0x22; 16], [0x33; 16]);
+        let mut items = vec![
+            (uuid_key(home, 251), 256u64.to_le_bytes().to_vec()),
+            (uuid_key(snap, 251), 257u64.to_le_bytes().to_vec()),
+            // Received twice, and also the UUID of subvolume 256.
+            (
+                uuid_key(sent, 252),
+                [258u64, 259].map(u64::to_le_bytes).concat(),
+            ),
+            (uuid_key(home, 252), 260u64.to_le_bytes().to_vec()),
+        ];
+        items.sort_by_key(|(key, _)| *key);
+        let uuid_root = root_item(block(1), 0);
+
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(0), 0)
+            .put_block(
+                block(0),
+                &leaf(block(0), 1, &[(Key::new(9, 132, 0), &uuid_root)]),
+            )
+            .put_block(block(1), &leaf(block(1), 9, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let map = fs.uuid_tree().unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map[&Uuid::from_bytes(home)], 256);
+        assert_eq!(map[&Uuid::from_bytes(snap)], 257);
+        assert_eq!(map[&Uuid::from_bytes(sent)], 258);
+        assert!(fs.uuid_tree_is_current());
+        assert!(fs.take_warnings().is_empty());
+
+        image.set_uuid_tree_generation(5);
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert!(!fs.uuid_tree_is_current());
+        assert_eq!(fs.uuid_tree().unwrap(), map);
+        let sb_gen = fs.superblock().generation;
+        assert_eq!(
+            fs.take_warnings(),
+            [Warning::StaleUuidTree {
+                uuid_tree_gen: 5,
+                sb_gen,
+            }]
+        );
+
+        let mut bare = TestImage::new();
+        bare.set_root_tree(block(0), 0)
+            .put_block(block(0), &leaf::<&[u8]>(block(0), 1, &[]));
+        let fs = Btrfs::from_reader(Cursor::new(bare.build())).unwrap();
+        assert!(fs.uuid_tree().unwrap().is_empty());
+    }
+}
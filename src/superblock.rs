@@ -0,0 +1,2197 @@
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::Utf8Error;
+
+use binrw::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use binrw::{BinRead, BinWrite};
+use bitflags::Flags;
+#[cfg(feature = "uuid")]
+use uuid::Uuid;
+
+use crate::chunk::{Chunk, SysChunkArray};
+use crate::csum::{self, ChecksumType};
+use crate::error::BtrfsError;
+use crate::features::{self, CompatFlags, CompatRoFlags, IncompatFlags};
+use crate::key::Key;
+use crate::units::{ByteSize, Generation, LogicalAddr};
+
+/// Byte offset of the primary superblock on every device.
+pub const BTRFS_SUPER_INFO_OFFSET: u64 = 65536;
+/// Size of the on-disk superblock block.
+pub const BTRFS_SUPER_INFO_SIZE: usize = 4096;
+/// `"_BHRfS_M"` read as a little-endian `u64`.
+pub const BTRFS_MAGIC: u64 = 0x4D5F53665248425F;
+
+pub const BTRFS_CSUM_SIZE: usize = 32;
+pub const BTRFS_FSID_SIZE: usize = 16;
+pub const BTRFS_UUID_SIZE: usize = 16;
+pub const BTRFS_LABEL_SIZE: usize = 256;
+pub const BTRFS_SYSTEM_CHUNK_ARRAY_SIZE: usize = 2048;
+pub const BTRFS_NUM_BACKUP_ROOTS: usize = 4;
+
+/// Superblock `flags` bit set once the superblock has been written.
+pub const BTRFS_SUPER_FLAG_WRITTEN: u64 = 1 << 0;
+pub const BTRFS_SUPER_FLAG_RELOC: u64 = 1 << 1;
+/// Set by the kernel after it hit an error and forced the filesystem
+/// read-only.
+pub const BTRFS_SUPER_FLAG_ERROR: u64 = 1 << 2;
+/// Superblock `flags` bit of a seed device, a read-only filesystem that
+/// writable sprout devices can be added on top of.
+pub const BTRFS_SUPER_FLAG_SEEDING: u64 = 1 << 32;
+/// Images made by `btrfs-image`, whose data is not included.
+pub const BTRFS_SUPER_FLAG_METADUMP: u64 = 1 << 33;
+pub const BTRFS_SUPER_FLAG_METADUMP_V2: u64 = 1 << 34;
+/// `btrfstune` was interrupted while changing the fsid, the block group
+/// tree or the checksum type.
+pub const BTRFS_SUPER_FLAG_CHANGING_FSID: u64 = 1 << 35;
+pub const BTRFS_SUPER_FLAG_CHANGING_FSID_V2: u64 = 1 << 36;
+pub const BTRFS_SUPER_FLAG_CHANGING_BG_TREE: u64 = 1 << 38;
+pub const BTRFS_SUPER_FLAG_CHANGING_DATA_CSUM: u64 = 1 << 44;
+pub const BTRFS_SUPER_FLAG_CHANGING_META_CSUM: u64 = 1 << 45;
+
+/// The superblock `flags` bits by the names `btrfs inspect-internal
+/// dump-super` gives them.
+pub(crate) const SUPER_FLAG_NAMES: [(u64, &str); 11] = [
+    (BTRFS_SUPER_FLAG_WRITTEN, "WRITTEN"),
+    (BTRFS_SUPER_FLAG_RELOC, "RELOC"),
+    (BTRFS_SUPER_FLAG_ERROR, "ERROR"),
+    (BTRFS_SUPER_FLAG_SEEDING, "SEEDING"),
+    (BTRFS_SUPER_FLAG_METADUMP, "METADUMP"),
+    (BTRFS_SUPER_FLAG_METADUMP_V2, "METADUMP_V2"),
+    (BTRFS_SUPER_FLAG_CHANGING_FSID, "CHANGING_FSID"),
+    (BTRFS_SUPER_FLAG_CHANGING_FSID_V2, "CHANGING_FSID_V2"),
+    (BTRFS_SUPER_FLAG_CHANGING_BG_TREE, "CHANGING_BG_TREE"),
+    (BTRFS_SUPER_FLAG_CHANGING_DATA_CSUM, "CHANGING_DATA_CSUM"),
+    (BTRFS_SUPER_FLAG_CHANGING_META_CSUM, "CHANGING_META_CSUM"),
+];
+
+/// Offsets of the primary superblock and its mirrors. The kernel writes at
+/// most these three copies, each only if the device is large enough.
+pub const BTRFS_SUPER_MIRROR_OFFSETS: [u64; 3] = [
+    BTRFS_SUPER_INFO_OFFSET,
+    64 << 20,  // 64MiB
+    256 << 30, // 256GiB
+];
+
+/// `btrfs_dev_item`: describes the device a superblock was read from.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+pub struct DevItem {
+    pub(crate) devid: u64,
+    pub(crate) total_bytes: u64,
+    pub(crate) bytes_used: u64,
+    pub(crate) io_align: u32,
+    pub(crate) io_width: u32,
+    pub(crate) sector_size: u32,
+    pub(crate) dev_type: u64,
+    pub(crate) generation: u64,
+    pub(crate) start_offset: u64,
+    pub(crate) dev_group: u32,
+    pub(crate) seek_speed: u8,
+    pub(crate) bandwidth: u8,
+    pub(crate) uuid: [u8; BTRFS_UUID_SIZE],
+    pub(crate) fsid: [u8; BTRFS_UUID_SIZE],
+}
+
+impl DevItem {
+    pub const SIZE: usize = 98;
+
+    /// Parses a device item, as stored in the chunk tree.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DevItem, BtrfsError> {
+        if bytes.len() < DevItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: DevItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(DevItem::read(&mut Cursor::new(&bytes[..DevItem::SIZE]))?)
+    }
+
+    pub fn devid(&self) -> u64 {
+        self.devid
+    }
+
+    /// Size of the device available to the filesystem.
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    /// Bytes allocated to chunks on this device.
+    pub fn bytes_used(&self) -> u64 {
+        self.bytes_used
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl DevItem {
+    /// UUID of this device. btrfs stores UUIDs as 16 raw bytes in
+    /// display order, so no RFC 4122 field swapping is applied.
+    pub fn dev_uuid(&self) -> Uuid {
+        Uuid::from_bytes(self.uuid)
+    }
+
+    /// FSID of the filesystem this device belongs to.
+    pub fn dev_fsid(&self) -> Uuid {
+        Uuid::from_bytes(self.fsid)
+    }
+}
+
+/// `btrfs_root_backup`: one slot of the superblock's backup root ring.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+pub struct RootBackup {
+    pub(crate) tree_root: u64,
+    pub(crate) tree_root_gen: u64,
+    pub(crate) chunk_root: u64,
+    pub(crate) chunk_root_gen: u64,
+    pub(crate) extent_root: u64,
+    pub(crate) extent_root_gen: u64,
+    pub(crate) fs_root: u64,
+    pub(crate) fs_root_gen: u64,
+    pub(crate) dev_root: u64,
+    pub(crate) dev_root_gen: u64,
+    pub(crate) csum_root: u64,
+    pub(crate) csum_root_gen: u64,
+    pub(crate) total_bytes: u64,
+    pub(crate) bytes_used: u64,
+    pub(crate) num_devices: u64,
+    pub(crate) unused_64: [u64; 4],
+    pub(crate) tree_root_level: u8,
+    pub(crate) chunk_root_level: u8,
+    pub(crate) extent_root_level: u8,
+    pub(crate) fs_root_level: u8,
+    pub(crate) dev_root_level: u8,
+    pub(crate) csum_root_level: u8,
+    pub(crate) unused_8: [u8; 10],
+}
+
+impl RootBackup {
+    pub const SIZE: usize = 168;
+
+    pub fn tree_root(&self) -> u64 {
+        self.tree_root
+    }
+
+    pub fn tree_root_gen(&self) -> u64 {
+        self.tree_root_gen
+    }
+
+    pub fn chunk_root(&self) -> u64 {
+        self.chunk_root
+    }
+
+    pub fn chunk_root_gen(&self) -> u64 {
+        self.chunk_root_gen
+    }
+
+    pub fn extent_root(&self) -> u64 {
+        self.extent_root
+    }
+
+    pub fn extent_root_gen(&self) -> u64 {
+        self.extent_root_gen
+    }
+
+    pub fn fs_root(&self) -> u64 {
+        self.fs_root
+    }
+
+    pub fn fs_root_gen(&self) -> u64 {
+        self.fs_root_gen
+    }
+
+    pub fn dev_root(&self) -> u64 {
+        self.dev_root
+    }
+
+    pub fn dev_root_gen(&self) -> u64 {
+        self.dev_root_gen
+    }
+
+    pub fn csum_root(&self) -> u64 {
+        self.csum_root
+    }
+
+    pub fn csum_root_gen(&self) -> u64 {
+        self.csum_root_gen
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    pub fn bytes_used(&self) -> u64 {
+        self.bytes_used
+    }
+
+    pub fn num_devices(&self) -> u64 {
+        self.num_devices
+    }
+
+    pub fn tree_root_level(&self) -> u8 {
+        self.tree_root_level
+    }
+
+    pub fn chunk_root_level(&self) -> u8 {
+        self.chunk_root_level
+    }
+
+    pub fn extent_root_level(&self) -> u8 {
+        self.extent_root_level
+    }
+
+    pub fn fs_root_level(&self) -> u8 {
+        self.fs_root_level
+    }
+
+    pub fn dev_root_level(&self) -> u8 {
+        self.dev_root_level
+    }
+
+    pub fn csum_root_level(&self) -> u8 {
+        self.csum_root_level
+    }
+}
+
+/// `btrfs_super_block`: the 4096-byte block at the start of every device.
+///
+/// The fields are the raw on-disk values. The accessors of the same name,
+/// such as [`root`](Superblock::root) or
+/// [`total_bytes`](Superblock::total_bytes), return them as
+/// [`LogicalAddr`], [`ByteSize`] or [`Generation`] so that they cannot be
+/// mixed up.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[brw(little)]
+pub struct Superblock {
+    /// Checksum of everything past this field.
+    pub csum: [u8; BTRFS_CSUM_SIZE],
+    pub fsid: [u8; BTRFS_FSID_SIZE],
+    /// Physical address of this block.
+    pub bytenr: u64,
+    pub flags: u64,
+    pub magic: u64,
+    pub generation: u64,
+    /// Logical address of the root tree root.
+    pub root: u64,
+    /// Logical address of the chunk tree root.
+    pub chunk_root: u64,
+    /// Logical address of the log tree root.
+    pub log_root: u64,
+    /// Unused by the kernel since the log tree took its transid from the header.
+    pub log_root_transid: u64,
+    pub total_bytes: u64,
+    pub bytes_used: u64,
+    pub root_dir_objectid: u64,
+    pub num_devices: u64,
+    pub sectorsize: u32,
+    pub nodesize: u32,
+    /// Formerly `leafsize`, which had to equal `nodesize`.
+    pub unused_leafsize: u32,
+    pub stripesize: u32,
+    pub sys_chunk_array_size: u32,
+    pub chunk_root_generation: u64,
+    pub compat_flags: u64,
+    pub compat_ro_flags: u64,
+    pub incompat_flags: u64,
+    pub csum_type: u16,
+    pub root_level: u8,
+    pub chunk_root_level: u8,
+    pub log_root_level: u8,
+    pub dev_item: DevItem,
+    pub label: [u8; BTRFS_LABEL_SIZE],
+    pub cache_generation: u64,
+    pub uuid_tree_generation: u64,
+    pub metadata_uuid: [u8; BTRFS_FSID_SIZE],
+    pub nr_global_roots: u64,
+    pub block_group_root: u64,
+    pub block_group_root_generation: u64,
+    pub block_group_root_level: u8,
+    pub reserved8: [u8; 7],
+    pub reserved: [u64; 24],
+    pub sys_chunk_array: [u8; BTRFS_SYSTEM_CHUNK_ARRAY_SIZE],
+    pub super_roots: [RootBackup; BTRFS_NUM_BACKUP_ROOTS],
+    pub padding: [u8; 565],
+}
+
+/// The start of a superblock, up to and including the label, as read by
+/// [`Superblock::read_header_only`]. Fields are decoded when asked for.
+#[derive(Debug, Clone)]
+pub struct SuperblockHeader {
+    bytes: [u8; SuperblockHeader::SIZE],
+}
+
+impl SuperblockHeader {
+    /// Bytes from the start of the superblock to the end of the label.
+    pub const SIZE: usize = 555;
+
+    fn u64_at(&self, at: usize) -> u64 {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.bytes[at..at + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    #[cfg(feature = "uuid")]
+    pub fn fsid(&self) -> Uuid {
+        Uuid::from_bytes(self.bytes[32..48].try_into().expect("16 bytes"))
+    }
+
+    pub fn generation(&self) -> Generation {
+        Generation(self.u64_at(72))
+    }
+
+    pub fn total_bytes(&self) -> ByteSize {
+        ByteSize(self.u64_at(112))
+    }
+
+    pub fn num_devices(&self) -> u64 {
+        self.u64_at(136)
+    }
+
+    pub fn incompat_flags(&self) -> IncompatFlags {
+        IncompatFlags::from_bits_retain(self.u64_at(188))
+    }
+
+    /// The item describing the device the header was read from.
+    pub fn dev_item(&self) -> DevItem {
+        DevItem::from_bytes(&self.bytes[201..201 + DevItem::SIZE])
+            .expect("the header holds a dev item")
+    }
+
+    /// The filesystem label, up to the first NUL byte.
+    pub fn label(&self) -> Result<&str, Utf8Error> {
+        core::str::from_utf8(until_nul(&self.bytes[299..]))
+    }
+
+    pub fn label_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(until_nul(&self.bytes[299..]))
+    }
+}
+
+/// Which checks [`Superblock::from_reader_with`] applies. The default is
+/// strict: every check is on and a damaged primary copy is an error.
+///
+/// The checksum can only be verified if the backend for the superblock's
+/// checksum type is enabled; otherwise verifying fails with
+/// [`BtrfsError::UnsupportedChecksum`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    verify_checksum: bool,
+    check_magic: bool,
+    validate_geometry: bool,
+    prefer_backup_on_failure: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            verify_checksum: true,
+            check_magic: true,
+            validate_geometry: true,
+            prefer_backup_on_failure: false,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// The strict defaults.
+    pub fn new() -> ParseOptions {
+        ParseOptions::default()
+    }
+
+    /// Every check off, for inspecting damaged images. Still errors if the
+    /// block cannot be read at all.
+    pub fn lenient() -> ParseOptions {
+        ParseOptions {
+            verify_checksum: false,
+            check_magic: false,
+            validate_geometry: false,
+            prefer_backup_on_failure: false,
+        }
+    }
+
+    /// Check the stored checksum against the block.
+    pub fn verify_checksum(mut self, verify: bool) -> ParseOptions {
+        self.verify_checksum = verify;
+        self
+    }
+
+    /// Require the btrfs magic number.
+    pub fn check_magic(mut self, check: bool) -> ParseOptions {
+        self.check_magic = check;
+        self
+    }
+
+    /// Apply [`Superblock::validate_geometry`].
+    pub fn validate_geometry(mut self, validate: bool) -> ParseOptions {
+        self.validate_geometry = validate;
+        self
+    }
+
+    /// Fall back to the newest mirror copy that passes the checks when the
+    /// primary copy does not, as the kernel does after a torn write.
+    pub fn prefer_backup_on_failure(mut self, prefer: bool) -> ParseOptions {
+        self.prefer_backup_on_failure = prefer;
+        self
+    }
+}
+
+impl Superblock {
+    /// Size of the block on disk, padding included.
+    pub const SIZE: usize = BTRFS_SUPER_INFO_SIZE;
+
+    /// Reads the primary superblock from a device or image with the strict
+    /// default [`ParseOptions`].
+    pub fn from_reader<R: Read + Seek>(reader: &mut R) -> Result<Superblock, BtrfsError> {
+        Superblock::from_reader_with(reader, &ParseOptions::default())
+    }
+
+    /// Reads just the identifying start of the primary superblock, through
+    /// the label, for scanning many devices quickly. Only the magic is
+    /// checked: the checksum covers the whole block, and the chunk array
+    /// and backup roots after the label are not read at all.
+    pub fn read_header_only<R: Read + Seek>(
+        reader: &mut R,
+    ) -> Result<SuperblockHeader, BtrfsError> {
+        reader.seek(SeekFrom::Start(BTRFS_SUPER_INFO_OFFSET))?;
+        let mut header = SuperblockHeader {
+            bytes: [0; SuperblockHeader::SIZE],
+        };
+        let got = read_full(reader, &mut header.bytes)?;
+        if got < SuperblockHeader::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: SuperblockHeader::SIZE,
+                got,
+            });
+        }
+        let magic = header.u64_at(64);
+        if magic != BTRFS_MAGIC {
+            return Err(BtrfsError::BadMagic { found: magic });
+        }
+        Ok(header)
+    }
+
+    /// Reads the primary superblock, checking what `options` asks for. If
+    /// the primary copy fails and `options` prefers backups, the newest
+    /// mirror copy that passes the same checks is returned instead.
+    pub fn from_reader_with<R: Read + Seek>(
+        reader: &mut R,
+        options: &ParseOptions,
+    ) -> Result<Superblock, BtrfsError> {
+        let err = match Superblock::read_copy(reader, BTRFS_SUPER_INFO_OFFSET, options) {
+            Ok(sb) => return Ok(sb),
+            Err(err) => err,
+        };
+        if !options.prefer_backup_on_failure {
+            return Err(err);
+        }
+        let device_size = reader.seek(SeekFrom::End(0))?;
+        let mut best: Option<Superblock> = None;
+        for offset in mirror_offsets(device_size).skip(1) {
+            let Ok(sb) = Superblock::read_copy(reader, offset, options) else {
+                continue;
+            };
+            if sb.bytenr == offset && best.as_ref().is_none_or(|b| sb.generation > b.generation) {
+                best = Some(sb);
+            }
+        }
+        best.ok_or(err)
+    }
+
+    /// Reads and checks the copy at `offset`.
+    fn read_copy<R: Read + Seek>(
+        reader: &mut R,
+        offset: u64,
+        options: &ParseOptions,
+    ) -> Result<Superblock, BtrfsError> {
+        Superblock::check_copy(&read_block_at(reader, offset)?, offset, options)
+    }
+
+    /// Parses and checks the copy at `offset`, already read into `block`.
+    pub(crate) fn check_copy(
+        block: &[u8],
+        offset: u64,
+        options: &ParseOptions,
+    ) -> Result<Superblock, BtrfsError> {
+        let sb = if options.check_magic {
+            Superblock::from_bytes(block)?
+        } else {
+            Superblock::parse(block)?
+        };
+        if options.verify_checksum && !sb.verify_checksum(block)? {
+            return Err(BtrfsError::ChecksumMismatch { logical: offset });
+        }
+        if options.validate_geometry {
+            sb.validate_geometry()?;
+        }
+        Ok(sb)
+    }
+
+    /// Reads the primary superblock through `tokio::io`, checked as strictly
+    /// as by [`Superblock::from_reader`]. Only the read is asynchronous; the
+    /// block is parsed and checked in memory.
+    #[cfg(feature = "async-tokio")]
+    pub async fn from_async_reader<R>(reader: &mut R) -> Result<Superblock, BtrfsError>
+    where
+        R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        reader
+            .seek(SeekFrom::Start(BTRFS_SUPER_INFO_OFFSET))
+            .await?;
+        // A block cut short by the end of the device is left to `parse` to
+        // report.
+        let mut block = Vec::with_capacity(BTRFS_SUPER_INFO_SIZE);
+        reader
+            .take(BTRFS_SUPER_INFO_SIZE as u64)
+            .read_to_end(&mut block)
+            .await?;
+        Superblock::check_copy(&block, BTRFS_SUPER_INFO_OFFSET, &ParseOptions::default())
+    }
+
+    /// Reads every superblock copy that fits within `device_size` bytes, in
+    /// mirror order. Copies past the end of the device are not attempted,
+    /// except that a primary copy cut short by the end of the device is a
+    /// [`BtrfsError::ShortRead`]. A device too small to reach the primary
+    /// copy yields just [`BtrfsError::DeviceTooSmall`].
+    pub fn read_all_copies<R: Read + Seek>(
+        reader: &mut R,
+        device_size: u64,
+    ) -> Vec<Result<Superblock, BtrfsError>> {
+        let offsets = match copy_offsets(device_size) {
+            Ok(offsets) => offsets,
+            Err(err) => return vec![Err(err)],
+        };
+        offsets
+            .map(|offset| {
+                let block = read_copy_block(reader, offset, device_size)?;
+                Superblock::from_bytes(&block)
+            })
+            .collect()
+    }
+
+    /// The generation of every superblock copy that fits within
+    /// `device_size` bytes, with whether its checksum matches, keyed by
+    /// the copy's offset. A stale mirror shows up as a lower generation;
+    /// a copy that cannot be read or lacks the magic is an error. Short
+    /// devices are handled as by [`Superblock::read_all_copies`].
+    pub fn copies_with_generation<R: Read + Seek>(
+        reader: &mut R,
+        device_size: u64,
+    ) -> Vec<(u64, CopyStatus)> {
+        let offsets = match copy_offsets(device_size) {
+            Ok(offsets) => offsets,
+            Err(err) => return vec![(BTRFS_SUPER_INFO_OFFSET, Err(err))],
+        };
+        offsets
+            .map(|offset| {
+                let copy = read_copy_block(reader, offset, device_size).and_then(|block| {
+                    let sb = Superblock::from_bytes(&block)?;
+                    Ok((sb.generation, sb.verify_checksum(&block)?))
+                });
+                (offset, copy)
+            })
+            .collect()
+    }
+
+    /// Picks the newest superblock copy that passes validation: the magic,
+    /// the checksum, and `bytenr` matching where the copy was found. This is
+    /// how the kernel recovers from a torn write to the primary copy.
+    ///
+    /// If no copy is valid, the error from the primary copy is returned.
+    pub fn read_best<R: Read + Seek>(
+        reader: &mut R,
+        device_size: u64,
+    ) -> Result<Superblock, BtrfsError> {
+        let mut best: Option<Superblock> = None;
+        let mut first_err = None;
+
+        for offset in copy_offsets(device_size)? {
+            let result = read_copy_block(reader, offset, device_size).and_then(|block| {
+                let sb = Superblock::from_bytes(&block)?;
+                if sb.bytenr != offset || !sb.verify_checksum(&block)? {
+                    return Err(BtrfsError::ChecksumMismatch { logical: offset });
+                }
+                Ok(sb)
+            });
+            match result {
+                Ok(sb) => {
+                    if best.as_ref().is_none_or(|b| sb.generation > b.generation) {
+                        best = Some(sb);
+                    }
+                }
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+
+        // The primary copy is always attempted, so there is an error.
+        best.ok_or_else(|| first_err.unwrap())
+    }
+
+    /// Parses a superblock from a buffer that starts at the superblock itself.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Superblock, BtrfsError> {
+        let sb = Superblock::parse(bytes)?;
+        if !sb.is_valid_magic() {
+            return Err(BtrfsError::BadMagic { found: sb.magic });
+        }
+        Ok(sb)
+    }
+
+    /// Parses the fields without checking the magic.
+    fn parse(bytes: &[u8]) -> Result<Superblock, BtrfsError> {
+        if bytes.len() < BTRFS_SUPER_INFO_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: BTRFS_SUPER_INFO_SIZE,
+                got: bytes.len(),
+            });
+        }
+        let mut cursor = Cursor::new(&bytes[..BTRFS_SUPER_INFO_SIZE]);
+        Ok(Superblock::read(&mut cursor)?)
+    }
+
+    /// Checks that the block sizes are ones btrfs can have, so that a
+    /// corrupt superblock cannot cause huge allocations or divisions by
+    /// zero later:
+    ///
+    /// - `sectorsize` is a power of two from 512 bytes to 64KiB;
+    /// - `nodesize` is a power of two of at least `sectorsize`, up to 64KiB;
+    /// - `stripesize` is a power of two up to 64KiB;
+    /// - `sys_chunk_array_size` fits in the 2048-byte array.
+    pub fn validate_geometry(&self) -> Result<(), BtrfsError> {
+        const MAX_BLOCK_SIZE: u32 = 65536;
+        let sane =
+            |size: u32, min: u32| size.is_power_of_two() && (min..=MAX_BLOCK_SIZE).contains(&size);
+        if !sane(self.sectorsize, 512) {
+            return Err(BtrfsError::Malformed("sectorsize out of range"));
+        }
+        if !sane(self.nodesize, self.sectorsize) {
+            return Err(BtrfsError::Malformed("nodesize out of range"));
+        }
+        if !sane(self.stripesize, 1) {
+            return Err(BtrfsError::Malformed("stripesize out of range"));
+        }
+        if self.sys_chunk_array_size as usize > BTRFS_SYSTEM_CHUNK_ARRAY_SIZE {
+            return Err(BtrfsError::Malformed("sys_chunk_array_size out of range"));
+        }
+        Ok(())
+    }
+
+    /// Whether `magic` holds the btrfs signature. `magic` is decoded as
+    /// little-endian, so this holds on any host byte order.
+    pub fn is_valid_magic(&self) -> bool {
+        self.magic == BTRFS_MAGIC
+    }
+
+    /// The filesystem UUID, as printed by `btrfs filesystem show`.
+    #[cfg(feature = "uuid")]
+    pub fn fsid(&self) -> Uuid {
+        Uuid::from_bytes(self.fsid)
+    }
+
+    /// The raw `metadata_uuid` field. Like every btrfs UUID it is stored as
+    /// plain bytes, so `Uuid::from_bytes` gives the canonical string.
+    #[cfg(feature = "uuid")]
+    pub fn metadata_uuid(&self) -> Uuid {
+        Uuid::from_bytes(self.metadata_uuid)
+    }
+
+    /// The item describing the device this superblock was read from.
+    pub fn dev_item(&self) -> &DevItem {
+        &self.dev_item
+    }
+
+    /// The UUID stamped into tree block headers: `metadata_uuid` when the
+    /// METADATA_UUID incompat flag is set, otherwise the fsid. The flag is
+    /// set when the fsid is changed without rewriting every block, as
+    /// `btrfstune -m` does, so the blocks keep the original fsid.
+    pub fn effective_metadata_uuid(&self) -> [u8; BTRFS_FSID_SIZE] {
+        if self.incompat_flags().contains(IncompatFlags::METADATA_UUID) {
+            self.metadata_uuid
+        } else {
+            self.fsid
+        }
+    }
+
+    /// The filesystem label, up to the first NUL byte.
+    pub fn label(&self) -> Result<&str, Utf8Error> {
+        core::str::from_utf8(self.label_bytes())
+    }
+
+    /// Replaces the label, NUL-padded, once [`check_label`] accepts it.
+    pub fn set_label(&mut self, label: &str) -> Result<(), BtrfsError> {
+        check_label(label)?;
+        self.label = [0; BTRFS_LABEL_SIZE];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(())
+    }
+
+    /// Changes the fsid without touching any tree block. That is only
+    /// possible with the METADATA_UUID incompat flag, where blocks and
+    /// device items carry `metadata_uuid` instead; this just replaces
+    /// `fsid`, and clears the flag if `fsid` is the metadata UUID again.
+    ///
+    /// Without the flag every tree block has the fsid in its header, so
+    /// changing it means rewriting all metadata, which this crate does
+    /// not do; that is [`BtrfsError::Unsupported`].
+    pub fn set_fsid(&mut self, fsid: [u8; BTRFS_FSID_SIZE]) -> Result<(), BtrfsError> {
+        let metadata_uuid = IncompatFlags::METADATA_UUID;
+        if !self.incompat_flags().contains(metadata_uuid) {
+            return Err(BtrfsError::Unsupported(
+                "changing the fsid without METADATA_UUID requires rewriting every tree block",
+            ));
+        }
+        self.fsid = fsid;
+        if fsid == self.metadata_uuid {
+            self.incompat_flags &= !metadata_uuid.bits();
+            self.metadata_uuid = [0; BTRFS_FSID_SIZE];
+        }
+        Ok(())
+    }
+
+    /// Like [`Superblock::label`], replacing invalid UTF-8 sequences.
+    pub fn label_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.label_bytes())
+    }
+
+    fn label_bytes(&self) -> &[u8] {
+        until_nul(&self.label)
+    }
+
+    pub fn incompat_flags(&self) -> IncompatFlags {
+        IncompatFlags::from_bits_retain(self.incompat_flags)
+    }
+
+    /// Whether the filesystem was made for zoned devices (`ZONED`), where
+    /// every block group fills whole zones.
+    pub fn is_zoned(&self) -> bool {
+        self.incompat_flags().contains(IncompatFlags::ZONED)
+    }
+
+    /// Whether this is a seed filesystem (`SEEDING`). A sprout built on it
+    /// has a fsid of its own, while the seed's devices keep theirs.
+    pub fn is_seeding(&self) -> bool {
+        self.flags & BTRFS_SUPER_FLAG_SEEDING != 0
+    }
+
+    pub fn compat_ro_flags(&self) -> CompatRoFlags {
+        CompatRoFlags::from_bits_retain(self.compat_ro_flags)
+    }
+
+    pub fn compat_flags(&self) -> CompatFlags {
+        CompatFlags::from_bits_retain(self.compat_flags)
+    }
+
+    /// Logical address of the root tree root.
+    pub fn root(&self) -> LogicalAddr {
+        LogicalAddr(self.root)
+    }
+
+    pub fn chunk_root(&self) -> LogicalAddr {
+        LogicalAddr(self.chunk_root)
+    }
+
+    /// Logical address of the log root tree, or zero if there is no log.
+    pub fn log_root(&self) -> LogicalAddr {
+        LogicalAddr(self.log_root)
+    }
+
+    /// The transaction that wrote this superblock.
+    pub fn generation(&self) -> Generation {
+        Generation(self.generation)
+    }
+
+    pub fn chunk_root_generation(&self) -> Generation {
+        Generation(self.chunk_root_generation)
+    }
+
+    /// Size of the filesystem, summed over its devices.
+    pub fn total_bytes(&self) -> ByteSize {
+        ByteSize(self.total_bytes)
+    }
+
+    /// Bytes allocated to extents.
+    pub fn bytes_used(&self) -> ByteSize {
+        ByteSize(self.bytes_used)
+    }
+
+    pub fn num_devices(&self) -> u64 {
+        self.num_devices
+    }
+
+    /// The short names of the features this filesystem uses, such as
+    /// `"skinny-metadata"` or `"block-group-tree"`, from the incompat,
+    /// compat_ro and compat flags in that order. Bits this crate has no name
+    /// for are listed, per flag field, as one `"unknown:0x<hex>"` entry.
+    pub fn features(&self) -> Vec<Cow<'static, str>> {
+        features::feature_names(
+            self.incompat_flags(),
+            self.compat_ro_flags(),
+            self.compat_flags(),
+        )
+    }
+
+    /// Logical address of the block group tree root, if the filesystem
+    /// keeps its block group items there (`BLOCK_GROUP_TREE`) rather than
+    /// in the extent tree.
+    pub fn block_group_root(&self) -> Option<LogicalAddr> {
+        self.compat_ro_flags()
+            .contains(CompatRoFlags::BLOCK_GROUP_TREE)
+            .then_some(LogicalAddr(self.block_group_root))
+    }
+
+    /// Level of the block group tree root; meaningful only when
+    /// [`block_group_root`](Self::block_group_root) is `Some`.
+    pub fn block_group_root_level(&self) -> u8 {
+        self.block_group_root_level
+    }
+
+    /// Decodes the `(key, chunk)` records in the first
+    /// `sys_chunk_array_size` bytes of `sys_chunk_array`.
+    pub fn sys_chunk_array(&self) -> Result<Vec<(Key, Chunk)>, BtrfsError> {
+        let size = self.sys_chunk_array_size as usize;
+        if size > BTRFS_SYSTEM_CHUNK_ARRAY_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: size,
+                got: BTRFS_SYSTEM_CHUNK_ARRAY_SIZE,
+            });
+        }
+        SysChunkArray::new(&self.sys_chunk_array[..size]).collect()
+    }
+
+    /// The backup root ring, in slot order (not generation order).
+    pub fn backup_roots(&self) -> &[RootBackup; BTRFS_NUM_BACKUP_ROOTS] {
+        &self.super_roots
+    }
+
+    /// The most recently written backup slot, by `tree_root_gen`.
+    pub fn latest_backup(&self) -> &RootBackup {
+        self.super_roots
+            .iter()
+            .max_by_key(|backup| backup.tree_root_gen)
+            .expect("backup ring is never empty")
+    }
+
+    /// Guesses from the superblock alone whether the filesystem was
+    /// cleanly unmounted, which is cheap enough for a monitoring tool to
+    /// run on every image it sees. A leftover tree log means changes were
+    /// fsynced after the last commit and not yet replayed. A newest backup
+    /// root from another generation means the commit that wrote this
+    /// superblock did not also fill the backup ring, as the kernel does;
+    /// that takes precedence over the log. A ring that was never filled,
+    /// as in a superblock from [`SuperblockBuilder`], is no evidence
+    /// either way.
+    ///
+    /// This is a heuristic. Only the kernel knows whether it is still
+    /// mounted somewhere, and a crash between commits leaves a superblock
+    /// that looks clean.
+    pub fn is_clean_unmount(&self) -> UnmountState {
+        let latest = self.latest_backup().tree_root_gen;
+        if latest != 0 && latest != self.generation {
+            UnmountState::Inconsistent
+        } else if self.log_root != 0 {
+            UnmountState::NeedsLogReplay
+        } else {
+            UnmountState::Clean
+        }
+    }
+
+    pub fn checksum_type(&self) -> ChecksumType {
+        ChecksumType::from_u16(self.csum_type)
+    }
+
+    /// Serializes the superblock into a block the kernel will accept: the
+    /// magic is set and `csum` is recomputed over bytes `[32..4096]` with
+    /// the algorithm named by `csum_type`. The stored `csum` field is ignored.
+    pub fn to_bytes(&self) -> Result<[u8; BTRFS_SUPER_INFO_SIZE], BtrfsError> {
+        let mut block = [0u8; BTRFS_SUPER_INFO_SIZE];
+        self.write(&mut Cursor::new(&mut block[..]))?;
+        block[64..72].copy_from_slice(&BTRFS_MAGIC.to_le_bytes());
+
+        let csum = csum::checksum(self.checksum_type(), &block[BTRFS_CSUM_SIZE..])?;
+        block[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+        Ok(block)
+    }
+
+    /// Writes the superblock, with a fresh checksum, to the primary
+    /// superblock offset of `writer`.
+    pub fn write_to<W: Write + Seek>(&self, writer: &mut W) -> Result<(), BtrfsError> {
+        let block = self.to_bytes()?;
+        writer.seek(SeekFrom::Start(BTRFS_SUPER_INFO_OFFSET))?;
+        writer.write_all(&block)?;
+        Ok(())
+    }
+
+    /// Checks the stored `csum` against a checksum of `raw_block[32..4096]`,
+    /// where `raw_block` is the block this superblock was parsed from.
+    pub fn verify_checksum(&self, raw_block: &[u8]) -> Result<bool, BtrfsError> {
+        if raw_block.len() < BTRFS_SUPER_INFO_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: BTRFS_SUPER_INFO_SIZE,
+                got: raw_block.len(),
+            });
+        }
+
+        let csum_type = self.checksum_type();
+        let computed = csum::checksum(
+            csum_type,
+            &raw_block[BTRFS_CSUM_SIZE..BTRFS_SUPER_INFO_SIZE],
+        )?;
+        let len = csum_type.digest_len();
+        Ok(computed[..len] == self.csum[..len])
+    }
+}
+
+/// Superblock updates for a device opened for writing, such as a file
+/// opened read-write or an in-memory image.
+///
+/// Each update patches the primary copy and writes it to every mirror
+/// offset that fits within both the device and its `dev_item.total_bytes`,
+/// so that a mirror recovered later does not undo it. The primary copy
+/// must be intact, or nothing is written. The filesystem must be offline:
+/// a mounted one keeps its own superblock in memory and overwrites these
+/// at its next commit.
+pub trait WriteExt: Read + Write + Seek + Sized {
+    /// Sets the label, as `btrfs filesystem label` does; see
+    /// [`Superblock::set_label`].
+    fn write_label(&mut self, label: &str) -> Result<(), BtrfsError> {
+        rewrite_copies(self, |sb| sb.set_label(label))
+    }
+
+    /// Changes the fsid, as `btrfstune -m` does on a filesystem that
+    /// already has a `metadata_uuid`; see [`Superblock::set_fsid`].
+    fn write_fsid(&mut self, fsid: [u8; BTRFS_FSID_SIZE]) -> Result<(), BtrfsError> {
+        rewrite_copies(self, |sb| sb.set_fsid(fsid))
+    }
+}
+
+impl<T: Read + Write + Seek> WriteExt for T {}
+
+/// Applies `update` to the primary superblock of `device` and writes the
+/// result, resealed, to every copy in range.
+pub(crate) fn rewrite_copies<D: Read + Write + Seek>(
+    device: &mut D,
+    update: impl FnOnce(&mut Superblock) -> Result<(), BtrfsError>,
+) -> Result<(), BtrfsError> {
+    let block = read_block_at(device, BTRFS_SUPER_INFO_OFFSET)?;
+    let mut sb = Superblock::from_bytes(&block)?;
+    if !sb.verify_checksum(&block)? {
+        return Err(BtrfsError::ChecksumMismatch {
+            logical: BTRFS_SUPER_INFO_OFFSET,
+        });
+    }
+    update(&mut sb)?;
+    let device_size = device.seek(SeekFrom::End(0))?.min(sb.dev_item.total_bytes);
+    for offset in mirror_offsets(device_size) {
+        sb.bytenr = offset;
+        let block = sb.to_bytes()?;
+        device.seek(SeekFrom::Start(offset))?;
+        device.write_all(&block)?;
+    }
+    device.flush()?;
+    Ok(())
+}
+
+/// Checks that `label` can be stored as a filesystem label: shorter than
+/// [`BTRFS_LABEL_SIZE`] bytes, so that a NUL still ends it, with no NUL of
+/// its own, and with no `/` or newline, which the kernel's rules forbid.
+pub fn check_label(label: &str) -> Result<(), BtrfsError> {
+    if label.len() >= BTRFS_LABEL_SIZE {
+        return Err(BtrfsError::LabelTooLong { len: label.len() });
+    }
+    match label.chars().find(|c| matches!(c, '\0' | '/' | '\n')) {
+        Some(c) => Err(BtrfsError::InvalidLabel(c)),
+        None => Ok(()),
+    }
+}
+
+/// What a superblock suggests about the last unmount, from
+/// [`Superblock::is_clean_unmount`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnmountState {
+    /// No log is left and the backup ring holds the last commit.
+    Clean,
+    /// A tree log is left for the kernel to replay at the next mount, as
+    /// after a crash with fsynced changes.
+    NeedsLogReplay,
+    /// The newest backup root does not match the superblock's generation.
+    Inconsistent,
+}
+
+/// One field that differs between two superblocks, as found by
+/// [`Superblock::diff`]. Values are formatted as `dump-super` shows them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldDiff {
+    /// The field's name; `dev_item.` and `super_roots[N]` prefix the
+    /// embedded structures.
+    pub field: &'static str,
+    pub left: String,
+    pub right: String,
+}
+
+impl Superblock {
+    /// Every field that differs from `other`, in on-disk order, except the
+    /// checksum: copies at different offsets differ in `bytenr` and so in
+    /// `csum` as well.
+    pub fn diff(&self, other: &Superblock) -> Vec<FieldDiff> {
+        self.diff_with(other, false)
+    }
+
+    /// Like [`Superblock::diff`], also comparing `csum` if `include_csum`
+    /// is set.
+    pub fn diff_with(&self, other: &Superblock, include_csum: bool) -> Vec<FieldDiff> {
+        let mut diffs = Vec::new();
+        let mut compare = |field: &'static str, left: String, right: String| {
+            if left != right {
+                diffs.push(FieldDiff { field, left, right });
+            }
+        };
+        macro_rules! compare {
+            ($prefix:literal, $left:expr, $right:expr, $show:expr; $($name:ident),*) => {
+                $(compare(
+                    concat!($prefix, stringify!($name)),
+                    $show(&$left.$name),
+                    $show(&$right.$name),
+                );)*
+            };
+        }
+        fn number<T: fmt::Display>(value: &T) -> String {
+            value.to_string()
+        }
+        fn flags(value: &u64) -> String {
+            format!("{value:#x}")
+        }
+        fn bytes<const N: usize>(value: &[u8; N]) -> String {
+            hex(value)
+        }
+        let uuid = fmt_uuid;
+
+        if include_csum {
+            compare!("", self, other, bytes; csum);
+        }
+        compare!("", self, other, uuid; fsid);
+        compare!("", self, other, number; bytenr);
+        compare!("", self, other, flags; flags, magic);
+        compare!("", self, other, number;
+            generation, root, chunk_root, log_root, log_root_transid, total_bytes,
+            bytes_used, root_dir_objectid, num_devices, sectorsize, nodesize,
+            unused_leafsize, stripesize, sys_chunk_array_size, chunk_root_generation);
+        compare!("", self, other, flags; compat_flags, compat_ro_flags, incompat_flags);
+        compare!("", self, other, number; csum_type, root_level, chunk_root_level, log_root_level);
+
+        let (left, right) = (&self.dev_item, &other.dev_item);
+        compare!("dev_item.", left, right, number;
+            devid, total_bytes, bytes_used, io_align, io_width, sector_size, dev_type,
+            generation, start_offset, dev_group, seek_speed, bandwidth);
+        compare!("dev_item.", left, right, uuid; uuid, fsid);
+
+        compare(
+            "label",
+            self.label_lossy().into_owned(),
+            other.label_lossy().into_owned(),
+        );
+        compare!("", self, other, number; cache_generation, uuid_tree_generation);
+        compare!("", self, other, uuid; metadata_uuid);
+        compare!("", self, other, number;
+            nr_global_roots, block_group_root, block_group_root_generation,
+            block_group_root_level);
+        compare!("", self, other, bytes; reserved8);
+        compare!("", self, other, |v: &[u64; 24]| format!("{v:?}"); reserved);
+        compare!("", self, other, bytes; sys_chunk_array);
+        const BACKUPS: [&str; BTRFS_NUM_BACKUP_ROOTS] = [
+            "super_roots[0]",
+            "super_roots[1]",
+            "super_roots[2]",
+            "super_roots[3]",
+        ];
+        for (i, field) in BACKUPS.into_iter().enumerate() {
+            compare(
+                field,
+                format!("{:?}", self.super_roots[i]),
+                format!("{:?}", other.super_roots[i]),
+            );
+        }
+        compare!("", self, other, bytes; padding);
+        diffs
+    }
+}
+
+/// A non-fatal oddity in a superblock, as found by [`Superblock::lint`],
+/// in a tree block read while `Btrfs::set_warn_future_generations` is
+/// on, or in a UUID tree read by `Btrfs::uuid_tree`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// `unused_leafsize` is set but differs from `nodesize`. Filesystems
+    /// old enough to use the field kept the two equal.
+    LeafsizeMismatch {
+        leafsize: u32,
+        nodesize: u32,
+    },
+    ZeroSectorsize,
+    /// The label fills all of its bytes, with no NUL to end it.
+    UnterminatedLabel,
+    /// A reserved field is not zero, which a newer kernel may have
+    /// written, or corruption.
+    ReservedNonzero {
+        field: &'static str,
+    },
+    ZeroGeneration,
+    /// The tree block at `logical` was written in a transaction after the
+    /// superblock's last commit, by one that was interrupted, or is
+    /// corrupt. Falling back to a backup root may avoid it.
+    FutureGeneration {
+        logical: u64,
+        node_gen: u64,
+        sb_gen: u64,
+    },
+    /// The UUID tree was last updated before the superblock's last
+    /// commit, by a kernel without UUID tree support, and the next mount
+    /// rescans it. Its entries may be missing or out of date.
+    StaleUuidTree {
+        uuid_tree_gen: u64,
+        sb_gen: u64,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::LeafsizeMismatch { leafsize, nodesize } => {
+                write!(f, "leafsize {leafsize} differs from nodesize {nodesize}")
+            }
+            Warning::ZeroSectorsize => write!(f, "sectorsize is zero"),
+            Warning::UnterminatedLabel => write!(f, "label is not NUL-terminated"),
+            Warning::ReservedNonzero { field } => write!(f, "reserved field {field} is not zero"),
+            Warning::ZeroGeneration => write!(f, "generation is zero"),
+            Warning::FutureGeneration {
+                logical,
+                node_gen,
+                sb_gen,
+            } => write!(
+                f,
+                "tree block {logical} has generation {node_gen}, newer than the superblock's {sb_gen}"
+            ),
+            Warning::StaleUuidTree {
+                uuid_tree_gen,
+                sb_gen,
+            } => write!(
+                f,
+                "UUID tree has generation {uuid_tree_gen}, older than the superblock's {sb_gen}"
+            ),
+        }
+    }
+}
+
+impl Superblock {
+    /// Oddities that do not stop the filesystem from being read, for a
+    /// diagnostic tool to report. Unlike [`Superblock::validate_geometry`]
+    /// this never fails; an empty list means nothing stood out.
+    pub fn lint(&self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        if self.unused_leafsize != 0 && self.unused_leafsize != self.nodesize {
+            warnings.push(Warning::LeafsizeMismatch {
+                leafsize: self.unused_leafsize,
+                nodesize: self.nodesize,
+            });
+        }
+        if self.sectorsize == 0 {
+            warnings.push(Warning::ZeroSectorsize);
+        }
+        if !self.label.contains(&0) {
+            warnings.push(Warning::UnterminatedLabel);
+        }
+        let reserved = [
+            ("reserved8", self.reserved8.iter().any(|&b| b != 0)),
+            ("reserved", self.reserved.iter().any(|&word| word != 0)),
+            ("padding", self.padding.iter().any(|&b| b != 0)),
+        ];
+        for (field, nonzero) in reserved {
+            if nonzero {
+                warnings.push(Warning::ReservedNonzero { field });
+            }
+        }
+        if self.generation == 0 {
+            warnings.push(Warning::ZeroGeneration);
+        }
+        warnings
+    }
+}
+
+/// Builds a minimal superblock for tests and synthetic images.
+///
+/// The defaults describe a single-device filesystem with CRC32C checksums,
+/// 4KiB sectors and 16KiB nodes, and pass [`Superblock::validate_geometry`].
+/// Every field without a setter is zero, so the trees it points at are
+/// left to the caller. With `std` the default fsid is random; without it
+/// there is no entropy source and the fsid is zero until set.
+#[derive(Debug, Clone)]
+pub struct SuperblockBuilder {
+    fsid: [u8; BTRFS_FSID_SIZE],
+    label: [u8; BTRFS_LABEL_SIZE],
+    generation: u64,
+    total_bytes: u64,
+    bytes_used: u64,
+    sectorsize: u32,
+    nodesize: u32,
+    csum_type: ChecksumType,
+    incompat_flags: u64,
+}
+
+impl Default for SuperblockBuilder {
+    fn default() -> SuperblockBuilder {
+        SuperblockBuilder {
+            fsid: random_fsid(),
+            label: [0; BTRFS_LABEL_SIZE],
+            generation: 1,
+            total_bytes: 1 << 30,
+            bytes_used: 0,
+            sectorsize: 4096,
+            nodesize: 16384,
+            csum_type: ChecksumType::Crc32c,
+            incompat_flags: 0,
+        }
+    }
+}
+
+impl SuperblockBuilder {
+    pub fn new() -> SuperblockBuilder {
+        SuperblockBuilder::default()
+    }
+
+    pub fn fsid(mut self, fsid: [u8; BTRFS_FSID_SIZE]) -> SuperblockBuilder {
+        self.fsid = fsid;
+        self
+    }
+
+    /// Sets the label, once [`check_label`] accepts it.
+    pub fn label(mut self, label: &str) -> Result<SuperblockBuilder, BtrfsError> {
+        check_label(label)?;
+        self.label = [0; BTRFS_LABEL_SIZE];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(self)
+    }
+
+    pub fn generation(mut self, generation: u64) -> SuperblockBuilder {
+        self.generation = generation;
+        self
+    }
+
+    /// Size of the filesystem, and of its one device.
+    pub fn total_bytes(mut self, total_bytes: u64) -> SuperblockBuilder {
+        self.total_bytes = total_bytes;
+        self
+    }
+
+    pub fn bytes_used(mut self, bytes_used: u64) -> SuperblockBuilder {
+        self.bytes_used = bytes_used;
+        self
+    }
+
+    /// Sets `sectorsize`; `stripesize` follows it, as mkfs does.
+    pub fn sectorsize(mut self, sectorsize: u32) -> SuperblockBuilder {
+        self.sectorsize = sectorsize;
+        self
+    }
+
+    pub fn nodesize(mut self, nodesize: u32) -> SuperblockBuilder {
+        self.nodesize = nodesize;
+        self
+    }
+
+    pub fn checksum_type(mut self, csum_type: ChecksumType) -> SuperblockBuilder {
+        self.csum_type = csum_type;
+        self
+    }
+
+    pub fn incompat_flags(mut self, flags: IncompatFlags) -> SuperblockBuilder {
+        self.incompat_flags = flags.bits();
+        self
+    }
+
+    /// The superblock of the primary copy. `csum` is filled in when the
+    /// backend for the checksum type is enabled, and left zero otherwise;
+    /// [`Superblock::to_bytes`] recomputes it either way.
+    pub fn build(&self) -> Superblock {
+        let mut sb = Superblock::parse(&[0; BTRFS_SUPER_INFO_SIZE]).expect("a full block");
+        sb.fsid = self.fsid;
+        sb.bytenr = BTRFS_SUPER_INFO_OFFSET;
+        sb.magic = BTRFS_MAGIC;
+        sb.generation = self.generation;
+        sb.total_bytes = self.total_bytes;
+        sb.bytes_used = self.bytes_used;
+        // BTRFS_ROOT_TREE_DIR_OBJECTID, which holds the `default` dir item.
+        sb.root_dir_objectid = 6;
+        sb.num_devices = 1;
+        sb.sectorsize = self.sectorsize;
+        sb.nodesize = self.nodesize;
+        sb.unused_leafsize = self.nodesize;
+        sb.stripesize = self.sectorsize;
+        sb.incompat_flags = self.incompat_flags;
+        sb.csum_type = self.csum_type.to_u16();
+        sb.label = self.label;
+
+        let dev = &mut sb.dev_item;
+        dev.devid = 1;
+        dev.total_bytes = self.total_bytes;
+        dev.bytes_used = self.bytes_used;
+        dev.io_align = self.sectorsize;
+        dev.io_width = self.sectorsize;
+        dev.sector_size = self.sectorsize;
+        dev.fsid = self.fsid;
+
+        if let Ok(block) = sb.to_bytes() {
+            sb.csum.copy_from_slice(&block[..BTRFS_CSUM_SIZE]);
+        }
+        sb
+    }
+}
+
+/// Random bytes from the hasher keys std seeds from the OS.
+#[cfg(feature = "std")]
+fn random_fsid() -> [u8; BTRFS_FSID_SIZE] {
+    use std::collections::hash_map::RandomState;
+    use std::hash::BuildHasher;
+
+    let mut fsid = [0; BTRFS_FSID_SIZE];
+    for (i, half) in fsid.chunks_exact_mut(8).enumerate() {
+        half.copy_from_slice(&RandomState::new().hash_one(i).to_le_bytes());
+    }
+    // Mark it as a version 4 (random) UUID.
+    fsid[6] = (fsid[6] & 0x0f) | 0x40;
+    fsid[8] = (fsid[8] & 0x3f) | 0x80;
+    fsid
+}
+
+#[cfg(not(feature = "std"))]
+fn random_fsid() -> [u8; BTRFS_FSID_SIZE] {
+    [0; BTRFS_FSID_SIZE]
+}
+
+/// Prints the superblock in the layout of `btrfs inspect-internal
+/// dump-super`, so the output can be diffed against the real tool. The
+/// checksum is shown without a match marker, since verifying it needs the
+/// raw block (see [`Superblock::verify_checksum`]).
+impl fmt::Display for Superblock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let csum_type = self.checksum_type();
+        let csum_size = csum_type.digest_len();
+        let metadata_uuid = &self.effective_metadata_uuid();
+        let magic = self.magic.to_le_bytes();
+
+        writeln!(f, "csum_type\t\t{} ({})", self.csum_type, csum_type.name())?;
+        writeln!(f, "csum_size\t\t{csum_size}")?;
+        writeln!(f, "csum\t\t\t0x{}", hex(&self.csum[..csum_size]))?;
+        writeln!(f, "bytenr\t\t\t{}", self.bytenr)?;
+        writeln!(f, "flags\t\t\t{:#x}", self.flags)?;
+        let names: Vec<&str> = SUPER_FLAG_NAMES
+            .into_iter()
+            .filter(|&(bit, _)| self.flags & bit != 0)
+            .map(|(_, name)| name)
+            .collect();
+        if !names.is_empty() {
+            writeln!(f, "\t\t\t( {} )", names.join(" |\n\t\t\t  "))?;
+        }
+        writeln!(
+            f,
+            "magic\t\t\t{} [{}]",
+            String::from_utf8_lossy(&magic),
+            if self.is_valid_magic() {
+                "match"
+            } else {
+                "DON'T MATCH"
+            }
+        )?;
+        writeln!(f, "fsid\t\t\t{}", fmt_uuid(&self.fsid))?;
+        writeln!(f, "metadata_uuid\t\t{}", fmt_uuid(metadata_uuid))?;
+        writeln!(f, "label\t\t\t{}", self.label_lossy())?;
+        writeln!(f, "generation\t\t{}", self.generation)?;
+        writeln!(f, "root\t\t\t{}", self.root)?;
+        writeln!(f, "sys_array_size\t\t{}", self.sys_chunk_array_size)?;
+        writeln!(f, "chunk_root_generation\t{}", self.chunk_root_generation)?;
+        writeln!(f, "root_level\t\t{}", self.root_level)?;
+        writeln!(f, "chunk_root\t\t{}", self.chunk_root)?;
+        writeln!(f, "chunk_root_level\t{}", self.chunk_root_level)?;
+        writeln!(f, "log_root\t\t{}", self.log_root)?;
+        writeln!(
+            f,
+            "log_root_transid (deprecated)\t{}",
+            self.log_root_transid
+        )?;
+        writeln!(f, "log_root_level\t\t{}", self.log_root_level)?;
+        writeln!(f, "total_bytes\t\t{}", self.total_bytes)?;
+        writeln!(f, "bytes_used\t\t{}", self.bytes_used)?;
+        writeln!(f, "sectorsize\t\t{}", self.sectorsize)?;
+        writeln!(f, "nodesize\t\t{}", self.nodesize)?;
+        writeln!(f, "leafsize (deprecated)\t{}", self.unused_leafsize)?;
+        writeln!(f, "stripesize\t\t{}", self.stripesize)?;
+        writeln!(f, "root_dir\t\t{}", self.root_dir_objectid)?;
+        writeln!(f, "num_devices\t\t{}", self.num_devices)?;
+        writeln!(f, "compat_flags\t\t{:#x}", self.compat_flags)?;
+        write_flag_names(f, self.compat_flags())?;
+        writeln!(f, "compat_ro_flags\t\t{:#x}", self.compat_ro_flags)?;
+        write_flag_names(f, self.compat_ro_flags())?;
+        writeln!(f, "incompat_flags\t\t{:#x}", self.incompat_flags)?;
+        write_flag_names(f, self.incompat_flags())?;
+        writeln!(f, "cache_generation\t{}", self.cache_generation)?;
+        writeln!(f, "uuid_tree_generation\t{}", self.uuid_tree_generation)?;
+
+        let dev = &self.dev_item;
+        writeln!(f, "dev_item.uuid\t\t{}", fmt_uuid(&dev.uuid))?;
+        writeln!(
+            f,
+            "dev_item.fsid\t\t{} [{}]",
+            fmt_uuid(&dev.fsid),
+            if dev.fsid == *metadata_uuid {
+                "match"
+            } else {
+                "DON'T MATCH"
+            }
+        )?;
+        writeln!(f, "dev_item.type\t\t{}", dev.dev_type)?;
+        writeln!(f, "dev_item.total_bytes\t{}", dev.total_bytes)?;
+        writeln!(f, "dev_item.bytes_used\t{}", dev.bytes_used)?;
+        writeln!(f, "dev_item.io_align\t{}", dev.io_align)?;
+        writeln!(f, "dev_item.io_width\t{}", dev.io_width)?;
+        writeln!(f, "dev_item.sector_size\t{}", dev.sector_size)?;
+        writeln!(f, "dev_item.devid\t\t{}", dev.devid)?;
+        writeln!(f, "dev_item.dev_group\t{}", dev.dev_group)?;
+        writeln!(f, "dev_item.seek_speed\t{}", dev.seek_speed)?;
+        writeln!(f, "dev_item.bandwidth\t{}", dev.bandwidth)?;
+        writeln!(f, "dev_item.generation\t{}", dev.generation)
+    }
+}
+
+/// Prints decoded flag names the way dump-super does:
+/// `( A |\n  B )`, followed by any bits without a name.
+fn write_flag_names<F: Flags<Bits = u64>>(f: &mut fmt::Formatter<'_>, flags: F) -> fmt::Result {
+    let mut names: Vec<String> = flags
+        .iter_names()
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let unknown = flags.bits() & !named_bits::<F>();
+    if unknown != 0 {
+        names.push(format!("unknown flag: {unknown:#x}"));
+    }
+    if names.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "\t\t\t( {} )", names.join(" |\n\t\t\t  "))
+}
+
+/// Union of the bits that have a name in `F`.
+pub(crate) fn named_bits<F: Flags<Bits = u64>>() -> u64 {
+    F::FLAGS
+        .iter()
+        .filter(|flag| flag.is_named())
+        .fold(0, |acc, flag| acc | flag.value().bits())
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Formats 16 raw UUID bytes in the canonical hyphenated form.
+pub(crate) fn fmt_uuid(bytes: &[u8; 16]) -> String {
+    let h = hex(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &h[..8],
+        &h[8..12],
+        &h[12..16],
+        &h[16..20],
+        &h[20..]
+    )
+}
+
+/// Probes the canonical superblock offsets of a raw image, for finding a
+/// filesystem whose partition table is lost. Returns every copy within
+/// `device_size` bytes that has the magic and a matching checksum, grouped
+/// by fsid and in offset order within a group. Blocks that cannot be read
+/// are skipped.
+///
+/// Only the three copy offsets are read, not every byte of the image. A
+/// copy whose `bytenr` differs from its offset belongs to a device that
+/// starts `offset - bytenr` bytes into the image.
+pub fn scan_for_superblocks<R: Read + Seek>(
+    reader: &mut R,
+    device_size: u64,
+) -> Vec<(u64, Superblock)> {
+    let mut found: Vec<(u64, Superblock)> = mirror_offsets(device_size)
+        .filter_map(|offset| {
+            let block = read_block_at(reader, offset).ok()?;
+            let sb = Superblock::from_bytes(&block).ok()?;
+            sb.verify_checksum(&block).ok()?.then_some((offset, sb))
+        })
+        .collect();
+    // Stable, so each group stays in offset order.
+    found.sort_by_key(|(_, sb)| sb.fsid);
+    found
+}
+
+/// The generation of one superblock copy and whether its checksum
+/// matches, as listed by [`Superblock::copies_with_generation`].
+pub type CopyStatus = Result<(u64, bool), BtrfsError>;
+
+/// Mirror offsets whose whole block lies within `device_size`.
+fn mirror_offsets(device_size: u64) -> impl Iterator<Item = u64> {
+    BTRFS_SUPER_MIRROR_OFFSETS
+        .into_iter()
+        .filter(move |&offset| offset + BTRFS_SUPER_INFO_SIZE as u64 <= device_size)
+}
+
+/// Offsets of the copies worth reading on a device of `device_size`
+/// bytes: the primary whenever the device reaches it, so that a truncated
+/// primary is reported, and the mirrors that fit whole.
+fn copy_offsets(device_size: u64) -> Result<impl Iterator<Item = u64>, BtrfsError> {
+    if device_size <= BTRFS_SUPER_INFO_OFFSET {
+        return Err(BtrfsError::DeviceTooSmall { size: device_size });
+    }
+    let mirrors = mirror_offsets(device_size).filter(|&offset| offset != BTRFS_SUPER_INFO_OFFSET);
+    Ok(core::iter::once(BTRFS_SUPER_INFO_OFFSET).chain(mirrors))
+}
+
+/// Reads the copy at `offset`, treating the device as ending at
+/// `device_size` bytes whatever `reader` holds past it.
+fn read_copy_block<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    device_size: u64,
+) -> Result<[u8; BTRFS_SUPER_INFO_SIZE], BtrfsError> {
+    let available = device_size.saturating_sub(offset);
+    if available < BTRFS_SUPER_INFO_SIZE as u64 {
+        return Err(BtrfsError::ShortRead {
+            expected: BTRFS_SUPER_INFO_SIZE,
+            got: available as usize,
+        });
+    }
+    read_block_at(reader, offset)
+}
+
+/// `bytes` up to the first NUL byte, or all of them.
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// Reads the superblock-sized block at `offset`.
+pub(crate) fn read_block_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+) -> Result<[u8; BTRFS_SUPER_INFO_SIZE], BtrfsError> {
+    reader.seek(SeekFrom::Start(offset))?;
+
+    let mut block = [0u8; BTRFS_SUPER_INFO_SIZE];
+    let got = read_full(reader, &mut block)?;
+    if got < BTRFS_SUPER_INFO_SIZE {
+        return Err(BtrfsError::ShortRead {
+            expected: BTRFS_SUPER_INFO_SIZE,
+            got,
+        });
+    }
+    Ok(block)
+}
+
+/// Like `read_exact`, but reports how many bytes were available on EOF.
+pub(crate) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+
+    fn blank_block() -> Vec<u8> {
+        let mut block = vec![0u8; BTRFS_SUPER_INFO_SIZE];
+        block[64..72].copy_from_slice(b"_BHRfS_M");
+        block
+    }
+
+    #[test]
+    fn block_is_4096_bytes() {
+        let sb = Superblock::from_bytes(&blank_block()).unwrap();
+        let mut out = Cursor::new(Vec::new());
+        sb.write(&mut out).unwrap();
+        assert_eq!(out.into_inner().len(), BTRFS_SUPER_INFO_SIZE);
+    }
+
+    #[test]
+    fn parses_little_endian_fixture() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(sb.sectorsize, 4096);
+        assert_eq!(sb.nodesize, 16384);
+        assert_eq!(sb.total_bytes, 1 << 30);
+        assert_eq!(sb.bytenr, BTRFS_SUPER_INFO_OFFSET);
+        assert_eq!(sb.dev_item.devid, 1);
+        assert_eq!(sb.checksum_type(), ChecksumType::Crc32c);
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn verifies_fixture_checksum() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert!(sb.verify_checksum(FIXTURE).unwrap());
+
+        let mut corrupt = FIXTURE.to_vec();
+        corrupt[200] ^= 0xff;
+        assert!(!sb.verify_checksum(&corrupt).unwrap());
+    }
+
+    #[test]
+    fn label_stops_at_nul() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(sb.label().unwrap(), "testfs");
+
+        sb.label = [0; BTRFS_LABEL_SIZE];
+        assert_eq!(sb.label().unwrap(), "");
+
+        sb.label[..3].copy_from_slice(&[b'a', 0xff, b'b']);
+        assert!(sb.label().is_err());
+        assert_eq!(sb.label_lossy(), "a\u{fffd}b");
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuids_render_like_btrfs_progs() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(
+            sb.fsid().to_string(),
+            "2d3e9a4c-7b1f-4e0a-9c5d-8f6a1b2c3d4e"
+        );
+        assert_eq!(sb.dev_item.dev_fsid(), sb.fsid());
+        assert_eq!(
+            sb.dev_item.dev_uuid().to_string(),
+            "a1b2c3d4-e5f6-4789-8abc-def012345678"
+        );
+    }
+
+    #[test]
+    fn incompat_flags_keep_unknown_bits() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        let flags = sb.incompat_flags();
+        assert!(flags.contains(IncompatFlags::SKINNY_METADATA | IncompatFlags::NO_HOLES));
+        assert!(!flags.contains(IncompatFlags::RAID56));
+
+        sb.incompat_flags |= 1 << 63;
+        assert_eq!(sb.incompat_flags().bits(), sb.incompat_flags);
+    }
+
+    #[test]
+    fn types_fields_by_meaning() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(sb.root(), LogicalAddr(sb.root));
+        assert_eq!(sb.chunk_root().get(), sb.chunk_root);
+        assert_eq!(u64::from(sb.generation()), sb.generation);
+        assert_eq!(sb.total_bytes(), ByteSize(sb.total_bytes));
+        assert_eq!(sb.bytes_used().get(), sb.bytes_used);
+        assert_eq!(sb.num_devices(), 1);
+        assert!(sb.chunk_root_generation() <= sb.generation());
+        assert_eq!(sb.log_root(), LogicalAddr::default());
+    }
+
+    #[test]
+    fn lints_oddities_without_failing() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(sb.lint(), []);
+        sb.unused_leafsize = sb.nodesize;
+        assert_eq!(sb.lint(), []);
+
+        sb.unused_leafsize = 4096;
+        sb.sectorsize = 0;
+        sb.label = [b'x'; BTRFS_LABEL_SIZE];
+        sb.reserved[3] = 1;
+        sb.padding[564] = 1;
+        sb.generation = 0;
+        assert_eq!(
+            sb.lint(),
+            [
+                Warning::LeafsizeMismatch {
+                    leafsize: 4096,
+                    nodesize: 16384
+                },
+                Warning::ZeroSectorsize,
+                Warning::UnterminatedLabel,
+                Warning::ReservedNonzero { field: "reserved" },
+                Warning::ReservedNonzero { field: "padding" },
+                Warning::ZeroGeneration,
+            ]
+        );
+        assert!(sb.validate_geometry().is_err());
+        assert_eq!(
+            sb.lint()[0].to_string(),
+            "leafsize 4096 differs from nodesize 16384"
+        );
+    }
+
+    #[test]
+    fn summarizes_features_by_name() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        sb.incompat_flags = (IncompatFlags::SKINNY_METADATA | IncompatFlags::RAID1C34).bits();
+        sb.compat_ro_flags = CompatRoFlags::BLOCK_GROUP_TREE.bits();
+        assert_eq!(
+            sb.features(),
+            ["skinny-metadata", "raid1c34", "block-group-tree"]
+        );
+
+        sb.incompat_flags |= 1 << 15 | 1 << 40;
+        sb.compat_flags = 0x5;
+        assert_eq!(
+            sb.features(),
+            [
+                "skinny-metadata",
+                "raid1c34",
+                "unknown:0x10000008000",
+                "block-group-tree",
+                "unknown:0x5",
+            ]
+        );
+    }
+
+    #[test]
+    fn compat_flags_keep_unknown_bits() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(
+            sb.compat_ro_flags(),
+            CompatRoFlags::FREE_SPACE_TREE | CompatRoFlags::FREE_SPACE_TREE_VALID
+        );
+
+        sb.compat_ro_flags |= 1 << 40;
+        sb.compat_flags = 0x5;
+        assert_eq!(sb.compat_ro_flags().bits(), sb.compat_ro_flags);
+        assert_eq!(sb.compat_flags().bits(), 0x5);
+    }
+
+    #[test]
+    fn parses_sys_chunk_array() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        let chunks = sb.sys_chunk_array().unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        let (key, chunk) = &chunks[0];
+        assert_eq!(*key, Key::new(256, 228, 22020096));
+        assert_eq!(chunk.length, 8 << 20);
+        assert_eq!(chunk.stripes.len(), 2);
+        assert_eq!(chunk.stripes[1].offset, 30408704);
+
+        // Cut the last stripe short.
+        sb.sys_chunk_array_size -= 1;
+        assert!(matches!(
+            sb.sys_chunk_array(),
+            Err(BtrfsError::ShortRead { .. })
+        ));
+    }
+
+    /// The fixture relocated to `offset` with the given generation.
+    #[cfg(feature = "crc32c")]
+    fn fixture_copy(offset: u64, generation: u64) -> Vec<u8> {
+        let mut block = FIXTURE.to_vec();
+        block[48..56].copy_from_slice(&offset.to_le_bytes());
+        block[72..80].copy_from_slice(&generation.to_le_bytes());
+        let csum = csum::checksum(ChecksumType::Crc32c, &block[BTRFS_CSUM_SIZE..]).unwrap();
+        block[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+        block
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn picks_newest_valid_copy() {
+        let mirror = BTRFS_SUPER_MIRROR_OFFSETS[1];
+        let size = mirror + BTRFS_SUPER_INFO_SIZE as u64;
+        let mut image = vec![0u8; size as usize];
+        let primary = BTRFS_SUPER_INFO_OFFSET as usize;
+        image[primary..primary + BTRFS_SUPER_INFO_SIZE]
+            .copy_from_slice(&fixture_copy(BTRFS_SUPER_INFO_OFFSET, 10));
+        image[mirror as usize..].copy_from_slice(&fixture_copy(mirror, 11));
+        let mut dev = Cursor::new(image);
+
+        let copies = Superblock::read_all_copies(&mut dev, size);
+        assert_eq!(copies.len(), 2);
+        assert_eq!(
+            Superblock::read_best(&mut dev, size).unwrap().generation,
+            11
+        );
+
+        // A torn write to the newer copy falls back to the older one.
+        dev.get_mut()[mirror as usize + 300] ^= 0xff;
+        assert_eq!(
+            Superblock::read_best(&mut dev, size).unwrap().generation,
+            10
+        );
+
+        // Only the primary fits on a smaller device.
+        let copies = Superblock::read_all_copies(&mut dev, size - 1);
+        assert_eq!(copies.len(), 1);
+
+        let copies = Superblock::copies_with_generation(&mut dev, size);
+        assert!(matches!(
+            copies[..],
+            [(BTRFS_SUPER_INFO_OFFSET, Ok((10, true))), (offset, Ok((11, false)))]
+                if offset == mirror
+        ));
+        dev.get_mut()[mirror as usize + 64] ^= 0xff;
+        let copies = Superblock::copies_with_generation(&mut dev, size);
+        assert!(matches!(copies[1], (_, Err(BtrfsError::BadMagic { .. }))));
+    }
+
+    #[test]
+    fn reports_truncated_devices() {
+        // Too small to reach the primary copy.
+        let mut dev = Cursor::new(vec![0u8; 40000]);
+        assert!(matches!(
+            Superblock::read_all_copies(&mut dev, 40000)[..],
+            [Err(BtrfsError::DeviceTooSmall { size: 40000 })]
+        ));
+        assert!(matches!(
+            Superblock::read_best(&mut dev, 40000),
+            Err(BtrfsError::DeviceTooSmall { size: 40000 })
+        ));
+
+        // Reaches the primary copy but ends inside it.
+        let mut image = vec![0u8; 66000];
+        let primary = BTRFS_SUPER_INFO_OFFSET as usize;
+        image[primary..].copy_from_slice(&FIXTURE[..66000 - primary]);
+        let mut dev = Cursor::new(image);
+        let short = |result: &Result<Superblock, BtrfsError>| {
+            matches!(
+                result,
+                Err(BtrfsError::ShortRead {
+                    expected: 4096,
+                    got: 464
+                })
+            )
+        };
+        let copies = Superblock::read_all_copies(&mut dev, 66000);
+        assert!(copies.len() == 1 && short(&copies[0]));
+        assert!(short(&Superblock::read_best(&mut dev, 66000)));
+        assert!(short(&Superblock::from_reader(&mut dev)));
+        assert!(matches!(
+            Superblock::copies_with_generation(&mut dev, 66000)[..],
+            [(
+                BTRFS_SUPER_INFO_OFFSET,
+                Err(BtrfsError::ShortRead { got: 464, .. })
+            )]
+        ));
+    }
+
+    #[test]
+    fn diffs_fields_but_not_csum_by_default() {
+        let left = Superblock::from_bytes(FIXTURE).unwrap();
+        assert!(left.diff(&left.clone()).is_empty());
+
+        let mut right = left.clone();
+        right.bytenr = BTRFS_SUPER_MIRROR_OFFSETS[1];
+        right.generation += 1;
+        right.dev_item.devid = 2;
+        right.csum[0] ^= 0xff;
+        let fields: Vec<_> = left.diff(&right).iter().map(|d| d.field).collect();
+        assert_eq!(fields, ["bytenr", "generation", "dev_item.devid"]);
+        assert_eq!(
+            left.diff(&right)[1],
+            FieldDiff {
+                field: "generation",
+                left: left.generation.to_string(),
+                right: right.generation.to_string(),
+            }
+        );
+        assert_eq!(left.diff_with(&right, true)[0].field, "csum");
+        assert_eq!(left.diff_with(&right, true).len(), 4);
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn builds_a_superblock_that_parses_strictly() {
+        let built = SuperblockBuilder::new()
+            .label("scratch")
+            .unwrap()
+            .total_bytes(256 << 20)
+            .generation(7)
+            .build();
+        built.validate_geometry().unwrap();
+        let block = built.to_bytes().unwrap();
+        assert_eq!(block[..BTRFS_CSUM_SIZE], built.csum);
+
+        let mut dev = Cursor::new(vec![0u8; 1 << 20]);
+        built.write_to(&mut dev).unwrap();
+        let sb = Superblock::from_reader(&mut dev).unwrap();
+        assert_eq!(sb.label().unwrap(), "scratch");
+        assert_eq!((sb.generation, sb.total_bytes), (7, 256 << 20));
+        assert_eq!(sb.dev_item.fsid, sb.fsid);
+        assert!(sb.diff_with(&built, true).is_empty());
+
+        #[cfg(feature = "std")]
+        assert_ne!(SuperblockBuilder::new().build().fsid, built.fsid);
+        let long = "x".repeat(300);
+        assert!(matches!(
+            SuperblockBuilder::new().label(&long),
+            Err(BtrfsError::LabelTooLong { len: 300 })
+        ));
+        // 128 two-byte characters leave no room for the NUL.
+        let wide = "é".repeat(128);
+        assert!(matches!(
+            SuperblockBuilder::new().label(&wide),
+            Err(BtrfsError::LabelTooLong { len: 256 })
+        ));
+        assert!(matches!(
+            SuperblockBuilder::new().label("a/b"),
+            Err(BtrfsError::InvalidLabel('/'))
+        ));
+        assert!(matches!(
+            SuperblockBuilder::new().label("a\nb"),
+            Err(BtrfsError::InvalidLabel('\n'))
+        ));
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn scans_canonical_offsets_by_fsid() {
+        let mirror = BTRFS_SUPER_MIRROR_OFFSETS[1];
+        let mut image = vec![0u8; mirror as usize + BTRFS_SUPER_INFO_SIZE];
+        let primary = BTRFS_SUPER_INFO_OFFSET as usize;
+        let other = SuperblockBuilder::new().fsid([0x11; 16]).build();
+        image[primary..primary + BTRFS_SUPER_INFO_SIZE]
+            .copy_from_slice(&fixture_copy(BTRFS_SUPER_INFO_OFFSET, 10));
+        image[mirror as usize..].copy_from_slice(&other.to_bytes().unwrap());
+        let size = image.len() as u64;
+        let mut dev = Cursor::new(image);
+
+        let found = scan_for_superblocks(&mut dev, size);
+        let offsets: Vec<_> = found.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [mirror, BTRFS_SUPER_INFO_OFFSET]);
+        assert_eq!(found[0].1.fsid, [0x11; 16]);
+        // Written for the primary offset, so its device starts 64KiB below.
+        assert_eq!(found[0].1.bytenr, BTRFS_SUPER_INFO_OFFSET);
+
+        // A bad checksum or a truncated image hides a copy.
+        dev.get_mut()[primary + 300] ^= 0xff;
+        assert_eq!(scan_for_superblocks(&mut dev, size).len(), 1);
+        assert!(scan_for_superblocks(&mut dev, mirror).is_empty());
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn falls_back_to_mirror_when_asked() {
+        let mirror = BTRFS_SUPER_MIRROR_OFFSETS[1];
+        let mut image = vec![0u8; mirror as usize + BTRFS_SUPER_INFO_SIZE];
+        let primary = BTRFS_SUPER_INFO_OFFSET as usize;
+        image[primary..primary + BTRFS_SUPER_INFO_SIZE]
+            .copy_from_slice(&fixture_copy(BTRFS_SUPER_INFO_OFFSET, 10));
+        image[primary + 300] ^= 0xff;
+        image[mirror as usize..].copy_from_slice(&fixture_copy(mirror, 9));
+        let mut dev = Cursor::new(image);
+
+        assert!(matches!(
+            Superblock::from_reader(&mut dev),
+            Err(BtrfsError::ChecksumMismatch {
+                logical: BTRFS_SUPER_INFO_OFFSET
+            })
+        ));
+        let options = ParseOptions::new().prefer_backup_on_failure(true);
+        let sb = Superblock::from_reader_with(&mut dev, &options).unwrap();
+        assert_eq!(sb.generation, 9);
+        let unchecked = ParseOptions::new().verify_checksum(false);
+        let sb = Superblock::from_reader_with(&mut dev, &unchecked).unwrap();
+        assert_eq!(sb.generation, 10);
+    }
+
+    #[test]
+    fn latest_backup_has_highest_generation() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        let gens: Vec<_> = sb
+            .backup_roots()
+            .iter()
+            .map(|b| b.tree_root_gen())
+            .collect();
+        assert_eq!(gens, [5, 6, 3, 4]);
+
+        let latest = sb.latest_backup();
+        assert_eq!(latest.tree_root_gen(), 6);
+        assert_eq!(latest.tree_root(), sb.root);
+        assert_eq!(latest.chunk_root(), sb.chunk_root);
+    }
+
+    #[test]
+    fn guesses_unmount_state() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        assert_eq!(sb.is_clean_unmount(), UnmountState::Clean);
+        sb.log_root = 30605312;
+        assert_eq!(sb.is_clean_unmount(), UnmountState::NeedsLogReplay);
+        sb.generation = 7;
+        assert_eq!(sb.is_clean_unmount(), UnmountState::Inconsistent);
+
+        let mut fresh = SuperblockBuilder::new().generation(1).build();
+        assert!(fresh.backup_roots().iter().all(|b| b.tree_root_gen() == 0));
+        assert_eq!(fresh.is_clean_unmount(), UnmountState::Clean);
+        fresh.log_root = 30605312;
+        assert_eq!(fresh.is_clean_unmount(), UnmountState::NeedsLogReplay);
+    }
+
+    #[test]
+    fn display_matches_dump_super_layout() {
+        let dump = Superblock::from_bytes(FIXTURE).unwrap().to_string();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "csum_type\t\t0 (crc32c)");
+        assert_eq!(lines[2], "csum\t\t\t0x7051d3bf");
+        assert!(lines.contains(&"magic\t\t\t_BHRfS_M [match]"));
+        assert!(lines.contains(&"fsid\t\t\t2d3e9a4c-7b1f-4e0a-9c5d-8f6a1b2c3d4e"));
+        assert!(lines.contains(&"label\t\t\ttestfs"));
+        assert!(dump.contains(
+            "incompat_flags\t\t0x361\n\t\t\t( MIXED_BACKREF |\n\t\t\t  BIG_METADATA |\n"
+        ));
+        assert!(dump.contains("\t\t\t  FREE_SPACE_TREE_VALID )\n"));
+
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        sb.compat_flags = 1 << 62;
+        let dump = sb.to_string();
+        assert!(dump.contains("\t\t\t( unknown flag: 0x4000000000000000 )\n"));
+    }
+
+    #[cfg(feature = "crc32c")]
+    #[test]
+    fn write_round_trip_reseals_checksum() {
+        let mut sb = Superblock::from_bytes(FIXTURE).unwrap();
+        sb.label = [0; BTRFS_LABEL_SIZE];
+        sb.label[..7].copy_from_slice(b"renamed");
+
+        let mut dev = Cursor::new(Vec::new());
+        sb.write_to(&mut dev).unwrap();
+
+        let reread = Superblock::from_reader(&mut dev).unwrap();
+        assert_eq!(reread.label().unwrap(), "renamed");
+        let block = &dev.get_ref()[BTRFS_SUPER_INFO_OFFSET as usize..];
+        assert!(reread.verify_checksum(block).unwrap());
+        assert_ne!(reread.csum, sb.csum);
+    }
+
+    #[cfg(all(feature = "async-tokio", feature = "crc32c"))]
+    #[tokio::test]
+    async fn reads_through_tokio() {
+        let mut image = vec![0u8; BTRFS_SUPER_INFO_OFFSET as usize];
+        image.extend_from_slice(FIXTURE);
+        let sb = Superblock::from_async_reader(&mut Cursor::new(image.clone()))
+            .await
+            .unwrap();
+        assert_eq!(sb.label().unwrap(), "testfs");
+
+        image[BTRFS_SUPER_INFO_OFFSET as usize + 0x100] ^= 1;
+        assert!(matches!(
+            Superblock::from_async_reader(&mut Cursor::new(image)).await,
+            Err(BtrfsError::ChecksumMismatch {
+                logical: BTRFS_SUPER_INFO_OFFSET
+            })
+        ));
+
+        let mut short = Cursor::new(vec![0u8; BTRFS_SUPER_INFO_OFFSET as usize + 10]);
+        assert!(matches!(
+            Superblock::from_async_reader(&mut short).await,
+            Err(BtrfsError::ShortRead { got: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn reads_identity_from_the_header() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        let mut image = vec![0u8; BTRFS_SUPER_INFO_OFFSET as usize];
+        image.extend_from_slice(&FIXTURE[..SuperblockHeader::SIZE]);
+        let mut dev = Cursor::new(image);
+        // Too short for the whole block, but enough for the header.
+        assert!(Superblock::from_reader(&mut dev).is_err());
+
+        let header = Superblock::read_header_only(&mut dev).unwrap();
+        assert_eq!(header.label().unwrap(), "testfs");
+        assert_eq!(header.label_lossy(), "testfs");
+        #[cfg(feature = "uuid")]
+        assert_eq!(header.fsid(), sb.fsid());
+        assert_eq!(header.generation(), sb.generation());
+        assert_eq!(header.total_bytes(), sb.total_bytes());
+        assert_eq!(header.num_devices(), 1);
+        assert_eq!(header.incompat_flags(), sb.incompat_flags());
+        assert_eq!(header.dev_item().devid(), 1);
+
+        dev.get_mut()[BTRFS_SUPER_INFO_OFFSET as usize + 64] = b'X';
+        assert!(matches!(
+            Superblock::read_header_only(&mut dev),
+            Err(BtrfsError::BadMagic { .. })
+        ));
+        dev.get_mut()
+            .truncate(BTRFS_SUPER_INFO_OFFSET as usize + 100);
+        assert!(matches!(
+            Superblock::read_header_only(&mut dev),
+            Err(BtrfsError::ShortRead { got: 100, .. })
+        ));
+    }
+
+    #[test]
+    fn short_stream_is_an_error() {
+        let mut dev = Cursor::new(vec![0u8; BTRFS_SUPER_INFO_OFFSET as usize + 100]);
+        match Superblock::from_reader(&mut dev) {
+            Err(BtrfsError::ShortRead { expected, got }) => {
+                assert_eq!(expected, BTRFS_SUPER_INFO_SIZE);
+                assert_eq!(got, 100);
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn validates_geometry() {
+        let sb = Superblock::from_bytes(FIXTURE).unwrap();
+        sb.validate_geometry().unwrap();
+
+        let broken: [fn(&mut Superblock); 5] = [
+            |sb| sb.sectorsize = 0,
+            |sb| sb.sectorsize = 6000,
+            |sb| sb.nodesize = 2048,
+            |sb| sb.nodesize = 1 << 20,
+            |sb| sb.sys_chunk_array_size = 4096,
+        ];
+        for breakage in broken {
+            let mut bad = sb.clone();
+            breakage(&mut bad);
+            assert!(matches!(
+                bad.validate_geometry(),
+                Err(BtrfsError::Malformed(_))
+            ));
+        }
+
+        let mut image = vec![0u8; BTRFS_SUPER_INFO_OFFSET as usize];
+        image.extend_from_slice(&blank_block());
+        let mut dev = Cursor::new(image);
+        let unchecked = ParseOptions::new().verify_checksum(false);
+        assert!(matches!(
+            Superblock::from_reader_with(&mut dev, &unchecked),
+            Err(BtrfsError::Malformed("sectorsize out of range"))
+        ));
+        let unchecked = unchecked.validate_geometry(false);
+        assert!(Superblock::from_reader_with(&mut dev, &unchecked).is_ok());
+
+        let lenient = ParseOptions::lenient();
+        dev.get_mut()[BTRFS_SUPER_INFO_OFFSET as usize + 64] = b'X';
+        assert!(Superblock::from_reader_with(&mut dev, &unchecked).is_err());
+        assert!(Superblock::from_reader_with(&mut dev, &lenient).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut block = blank_block();
+        block[64] = b'X';
+        match Superblock::from_bytes(&block) {
+            Err(BtrfsError::BadMagic { found }) => assert_ne!(found, BTRFS_MAGIC),
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+}
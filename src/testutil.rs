@@ -0,0 +1,248 @@
+//! Helpers for building synthetic filesystem images in tests.
+
+use std::io::{Cursor, Write};
+
+use binrw::BinWrite;
+
+use crate::chunk::{BlockGroupFlags, Chunk, Stripe};
+use crate::csum::{self, ChecksumType};
+use crate::features::{CompatRoFlags, IncompatFlags};
+use crate::key::Key;
+use crate::node::{KeyPtr, NodeHeader};
+use crate::root::RootItem;
+use crate::superblock::{BTRFS_CSUM_SIZE, BTRFS_SUPER_INFO_OFFSET, BTRFS_SUPER_INFO_SIZE};
+
+/// A superblock as written by mkfs for a 1GiB single-device filesystem.
+pub const SUPERBLOCK: &[u8] = include_bytes!("../tests/fixtures/superblock.bin");
+
+/// Recomputes the CRC32C in the first bytes of a superblock or tree block.
+pub fn reseal(block: &mut [u8]) {
+    let csum = csum::checksum(ChecksumType::Crc32c, &block[BTRFS_CSUM_SIZE..]).unwrap();
+    block[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+}
+
+/// Patches a little-endian `u64` into `buf` at `at`.
+pub fn put_u64(buf: &mut [u8], at: usize, value: u64) {
+    buf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// A zeroed device image of `size` bytes with `superblock` at the primary
+/// superblock offset.
+pub fn device_image(superblock: &[u8], size: usize) -> Vec<u8> {
+    let mut image = vec![0u8; size];
+    let start = BTRFS_SUPER_INFO_OFFSET as usize;
+    image[start..start + BTRFS_SUPER_INFO_SIZE].copy_from_slice(superblock);
+    image
+}
+
+/// An 8MiB chunk of block group type `type_flags` with 64KiB stripes at
+/// `stripes`, given as `(devid, physical offset)`. RAID10 chunks mirror
+/// each pair of stripes.
+pub fn test_chunk(type_flags: u64, stripes: &[(u64, u64)]) -> Chunk {
+    let raid10 = type_flags & BlockGroupFlags::RAID10.bits() != 0;
+    Chunk {
+        length: 8 << 20,
+        owner: 2,
+        stripe_len: 65536,
+        type_flags,
+        io_align: 65536,
+        io_width: 65536,
+        sector_size: 4096,
+        num_stripes: stripes.len() as u16,
+        sub_stripes: if raid10 { 2 } else { 1 },
+        stripes: stripes
+            .iter()
+            .map(|&(devid, offset)| Stripe {
+                devid,
+                offset,
+                dev_uuid: [0; 16],
+            })
+            .collect(),
+    }
+}
+
+/// The item bytes of `chunk`, for a chunk tree leaf.
+pub fn chunk_item(chunk: &Chunk) -> Vec<u8> {
+    let mut bytes = Cursor::new(Vec::new());
+    chunk.write(&mut bytes).unwrap();
+    bytes.into_inner()
+}
+
+/// Logical address of the fixture's SYSTEM chunk, where test tree blocks go.
+pub const SYSTEM_CHUNK: u64 = 22020096;
+/// Physical offset [`TestImage`] maps the SYSTEM chunk to, instead of the
+/// fixture's 21MiB, to keep images small.
+pub const SYSTEM_CHUNK_PHYSICAL: u64 = 1 << 20;
+/// Size of the SYSTEM chunk.
+const SYSTEM_CHUNK_LEN: u64 = 8 << 20;
+/// Offset of the two stripe offsets of the sys chunk array's only chunk.
+const SYS_STRIPE_OFFSETS: [usize; 2] = [884, 916];
+pub const NODESIZE: usize = 16384;
+
+/// Logical address of the `n`th tree block of the SYSTEM chunk.
+pub fn block(n: u64) -> u64 {
+    SYSTEM_CHUNK + n * NODESIZE as u64
+}
+
+/// A single-device image built from the fixture superblock plus tree blocks
+/// placed in its SYSTEM chunk.
+pub struct TestImage {
+    superblock: Vec<u8>,
+    image: Vec<u8>,
+}
+
+impl TestImage {
+    pub fn new() -> TestImage {
+        let mut superblock = SUPERBLOCK.to_vec();
+        for at in SYS_STRIPE_OFFSETS {
+            put_u64(&mut superblock, at, SYSTEM_CHUNK_PHYSICAL);
+        }
+        TestImage {
+            superblock,
+            image: vec![0u8; SYSTEM_CHUNK_PHYSICAL as usize],
+        }
+    }
+
+    /// Points the superblock's root tree at `logical`.
+    pub fn set_root_tree(&mut self, logical: u64, level: u8) -> &mut TestImage {
+        put_u64(&mut self.superblock, 80, logical);
+        self.superblock[198] = level;
+        self
+    }
+
+    /// Points the superblock's log root tree at `logical`, as after a crash
+    /// with fsynced changes.
+    pub fn set_log_root(&mut self, logical: u64, level: u8) -> &mut TestImage {
+        put_u64(&mut self.superblock, 96, logical);
+        self.superblock[200] = level;
+        self
+    }
+
+    /// Points the superblock's block group tree at `logical` and sets the
+    /// BLOCK_GROUP_TREE compat_ro flag.
+    pub fn set_block_group_tree(&mut self, logical: u64, level: u8) -> &mut TestImage {
+        put_u64(&mut self.superblock, 595, logical);
+        self.superblock[611] = level;
+        self.superblock[180] |= 1 << 3;
+        self
+    }
+
+    /// Replaces the fixture's incompat flags, which include
+    /// `SKINNY_METADATA` and `NO_HOLES`.
+    pub fn set_incompat_flags(&mut self, flags: IncompatFlags) -> &mut TestImage {
+        put_u64(&mut self.superblock, 188, flags.bits());
+        self
+    }
+
+    /// Replaces the fixture's compat_ro flags, `FREE_SPACE_TREE` and
+    /// `FREE_SPACE_TREE_VALID`.
+    pub fn set_compat_ro_flags(&mut self, flags: CompatRoFlags) -> &mut TestImage {
+        put_u64(&mut self.superblock, 180, flags.bits());
+        self
+    }
+
+    /// Sets the generation the UUID tree was last updated in; the
+    /// fixture's matches its superblock generation.
+    #[cfg(feature = "uuid")]
+    pub fn set_uuid_tree_generation(&mut self, generation: u64) -> &mut TestImage {
+        put_u64(&mut self.superblock, 563, generation);
+        self
+    }
+
+    /// Changes the fsid the way `btrfstune -m` does: the old one moves to
+    /// `metadata_uuid`, which tree blocks keep carrying, and the
+    /// METADATA_UUID incompat flag is set.
+    pub fn change_fsid(&mut self, fsid: [u8; 16]) -> &mut TestImage {
+        let old = SUPERBLOCK[32..48].to_vec();
+        self.superblock[571..587].copy_from_slice(&old);
+        self.superblock[32..48].copy_from_slice(&fsid);
+        self.superblock[189] |= 1 << 2;
+        self
+    }
+
+    /// Places `block` at `logical`, which must be inside the SYSTEM chunk.
+    pub fn put_block(&mut self, logical: u64, block: &[u8]) -> &mut TestImage {
+        assert!((SYSTEM_CHUNK..SYSTEM_CHUNK + SYSTEM_CHUNK_LEN).contains(&logical));
+        self.put_physical(SYSTEM_CHUNK_PHYSICAL + logical - SYSTEM_CHUNK, block)
+    }
+
+    /// Writes `bytes` at physical offset `physical`, for data outside the
+    /// SYSTEM chunk.
+    pub fn put_physical(&mut self, physical: u64, bytes: &[u8]) -> &mut TestImage {
+        let at = physical as usize;
+        if self.image.len() < at + bytes.len() {
+            self.image.resize(at + bytes.len(), 0);
+        }
+        self.image[at..at + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut superblock = self.superblock.clone();
+        reseal(&mut superblock);
+        let mut image = self.image.clone();
+        let start = BTRFS_SUPER_INFO_OFFSET as usize;
+        image[start..start + BTRFS_SUPER_INFO_SIZE].copy_from_slice(&superblock);
+        image
+    }
+}
+
+/// A sealed tree block header for `bytenr`, with the fixture's fsid.
+fn tree_block(bytenr: u64, owner: u64, nritems: usize, level: u8) -> Vec<u8> {
+    let mut block = vec![0u8; NODESIZE];
+    block[32..48].copy_from_slice(&SUPERBLOCK[32..48]);
+    put_u64(&mut block, 48, bytenr);
+    put_u64(&mut block, 80, 6);
+    put_u64(&mut block, 88, owner);
+    block[96..100].copy_from_slice(&(nritems as u32).to_le_bytes());
+    block[100] = level;
+    block
+}
+
+/// A leaf at `bytenr` holding `items`, which must be sorted by key.
+pub fn leaf<D: AsRef<[u8]>>(bytenr: u64, owner: u64, items: &[(Key, D)]) -> Vec<u8> {
+    let mut block = tree_block(bytenr, owner, items.len(), 0);
+    let mut data_end = NODESIZE - NodeHeader::SIZE;
+    for (i, (key, data)) in items.iter().enumerate() {
+        let data = data.as_ref();
+        data_end -= data.len();
+        let at = NodeHeader::SIZE + i * 25;
+        let mut cursor = Cursor::new(&mut block[at..at + 25]);
+        key.write(&mut cursor).unwrap();
+        cursor.write_all(&(data_end as u32).to_le_bytes()).unwrap();
+        cursor
+            .write_all(&(data.len() as u32).to_le_bytes())
+            .unwrap();
+        let start = NodeHeader::SIZE + data_end;
+        block[start..start + data.len()].copy_from_slice(data);
+    }
+    reseal(&mut block);
+    block
+}
+
+/// An internal node at `bytenr` pointing at `(lowest key, child)` pairs.
+pub fn node(bytenr: u64, owner: u64, level: u8, ptrs: &[(Key, u64)]) -> Vec<u8> {
+    let mut block = tree_block(bytenr, owner, ptrs.len(), level);
+    let mut cursor = Cursor::new(&mut block[NodeHeader::SIZE..]);
+    for &(key, blockptr) in ptrs {
+        let ptr = KeyPtr {
+            key,
+            blockptr,
+            generation: 6,
+        };
+        ptr.write(&mut cursor).unwrap();
+    }
+    reseal(&mut block);
+    block
+}
+
+/// A root item for a tree whose root block is at `bytenr`, with the usual
+/// root directory inode 256.
+pub fn root_item(bytenr: u64, level: u8) -> Vec<u8> {
+    let mut item = vec![0u8; RootItem::SIZE];
+    put_u64(&mut item, 160, 6);
+    put_u64(&mut item, 168, 256);
+    put_u64(&mut item, 176, bytenr);
+    item[238] = level;
+    item
+}
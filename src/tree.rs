@@ -0,0 +1,1200 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::{Arc, PoisonError};
+
+use crate::csum;
+use crate::error::BtrfsError;
+use crate::fs::Btrfs;
+use crate::key::{Key, KeyType};
+use crate::log_tree::TREE_LOG_OBJECTID;
+use crate::node::{InternalNode, KeyPtr, Leaf, NodeHeader};
+use crate::qgroup_tree::QUOTA_TREE_OBJECTID;
+use crate::root::RootItem;
+use crate::scrub::CSUM_TREE_OBJECTID;
+use crate::space::{DEV_TREE_OBJECTID, EXTENT_TREE_OBJECTID, FREE_SPACE_TREE_OBJECTID};
+use crate::subvol::{is_subvolume, FS_TREE_OBJECTID, UUID_TREE_OBJECTID};
+use crate::superblock::{Warning, BTRFS_CSUM_SIZE};
+
+/// A B-tree rooted at a logical address, such as the root tree or a
+/// subvolume's fs tree. Items are returned as owned `(key, data)` pairs,
+/// since the blocks they come from are read on demand.
+#[derive(Clone, Copy)]
+pub struct Tree<'a> {
+    fs: &'a Btrfs,
+    root: u64,
+    level: u8,
+}
+
+/// A parsed tree block.
+pub(crate) enum Block {
+    Leaf(Vec<(Key, Vec<u8>)>),
+    Node(Vec<KeyPtr>),
+}
+
+impl Block {
+    /// The items of a leaf; empty for a node.
+    fn items(&self) -> &[(Key, Vec<u8>)] {
+        match self {
+            Block::Leaf(items) => items,
+            Block::Node(_) => &[],
+        }
+    }
+
+    /// The key pointers of a node; empty for a leaf.
+    fn ptrs(&self) -> &[KeyPtr] {
+        match self {
+            Block::Leaf(_) => &[],
+            Block::Node(ptrs) => ptrs,
+        }
+    }
+}
+
+/// A verified, parsed block as the node cache holds it, with its level.
+pub(crate) type CachedBlock = (u8, Arc<Block>);
+
+impl<'a> Tree<'a> {
+    /// A tree whose root block is at `root`, with the level recorded by its
+    /// parent (a root item or the superblock).
+    pub fn new(fs: &'a Btrfs, root: u64, level: u8) -> Tree<'a> {
+        Tree { fs, root, level }
+    }
+
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Finds the item with exactly `key`.
+    pub fn search(&self, key: &Key) -> Result<Option<(Key, Vec<u8>)>, BtrfsError> {
+        let mut logical = self.root;
+        let mut level = self.level;
+        loop {
+            match &*self.read_block(logical, level)? {
+                Block::Node(ptrs) => {
+                    let Some(ptr) = ptrs.get(child_index(ptrs, key)) else {
+                        return Ok(None);
+                    };
+                    logical = ptr.blockptr;
+                    level -= 1;
+                }
+                Block::Leaf(items) => {
+                    return Ok(items
+                        .binary_search_by(|(item_key, _)| item_key.cmp(key))
+                        .ok()
+                        .map(|i| items[i].clone()));
+                }
+            }
+        }
+    }
+
+    /// Finds the item with the greatest key not above `key`, such as the
+    /// checksum item whose range covers an address.
+    pub fn search_le(&self, key: &Key) -> Result<Option<(Key, Vec<u8>)>, BtrfsError> {
+        let mut logical = self.root;
+        let mut level = self.level;
+        loop {
+            match &*self.read_block(logical, level)? {
+                Block::Node(ptrs) => {
+                    let Some(ptr) = ptrs.get(child_index(ptrs, key)) else {
+                        return Ok(None);
+                    };
+                    logical = ptr.blockptr;
+                    level -= 1;
+                }
+                // Node keys are the lowest key of each child, so unless `key`
+                // precedes the whole tree the answer is in this leaf.
+                Block::Leaf(items) => {
+                    let index = items.partition_point(|(item_key, _)| item_key <= key);
+                    return Ok(index.checked_sub(1).map(|i| items[i].clone()));
+                }
+            }
+        }
+    }
+
+    /// The items with keys in `min..=max`, in key order.
+    pub fn search_range(&self, min: Key, max: Key) -> TreeIter<'a> {
+        TreeIter {
+            tree: *self,
+            min,
+            max,
+            path: Vec::new(),
+            leaf: Arc::new(Block::Leaf(Vec::new())),
+            next: 0,
+            ahead: BTreeMap::new(),
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Every item of the tree in key order, starting from the leftmost leaf.
+    pub fn iter(&self) -> TreeIter<'a> {
+        self.search_range(Key::MIN, Key::MAX)
+    }
+
+    /// A cursor before the first item of the tree.
+    pub fn cursor(&self) -> TreeCursor<'a> {
+        TreeCursor {
+            iter: self.iter(),
+            last: None,
+        }
+    }
+
+    /// Reads and parses the block at `logical`, or takes it from the node
+    /// cache. Cached blocks were verified when read, so a hit only checks
+    /// the level the parent expects.
+    fn read_block(&self, logical: u64, level: u8) -> Result<Arc<Block>, BtrfsError> {
+        let cache = self.fs.node_cache();
+        let cached = cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&logical);
+        if let Some((cached_level, block)) = cached {
+            if cached_level != level {
+                return Err(BtrfsError::Malformed("tree block has an unexpected level")
+                    .in_tree_block(logical));
+            }
+            return Ok(block);
+        }
+
+        let raw = self.fs.read_tree_block(logical, level)?;
+        self.parse_block(logical, level, &raw)
+    }
+
+    /// Parses the verified block `raw` at `logical` and adds it to the node
+    /// cache.
+    fn parse_block(&self, logical: u64, level: u8, raw: &[u8]) -> Result<Arc<Block>, BtrfsError> {
+        let block = Arc::new(if raw[100] == 0 {
+            let leaf = Leaf::new(raw)?;
+            let items = leaf
+                .items()
+                .map(|item| (item.key(), item.data().to_vec()))
+                .collect();
+            Block::Leaf(items)
+        } else {
+            Block::Node(InternalNode::new(raw)?.key_ptrs().collect())
+        });
+        self.fs
+            .node_cache()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(logical, (level, Arc::clone(&block)));
+        Ok(block)
+    }
+}
+
+impl Btrfs {
+    /// Reads the tree block at `logical`, checking it is intact and is the
+    /// block its parent expects. Errors are wrapped in
+    /// [`BtrfsError::ReadNode`] unless they already give the address.
+    pub(crate) fn read_tree_block(
+        &self,
+        logical: u64,
+        level: u8,
+    ) -> Result<Cow<'_, [u8]>, BtrfsError> {
+        self.load_tree_block(logical, level)
+            .map_err(|err| err.in_tree_block(logical))
+    }
+
+    fn load_tree_block(&self, logical: u64, level: u8) -> Result<Cow<'_, [u8]>, BtrfsError> {
+        let block = self.logical_bytes(logical, self.superblock().nodesize as usize)?;
+        self.check_tree_block(logical, level, block)
+    }
+
+    /// Checks that `block`, read from `logical`, is intact and is the block
+    /// its parent expects.
+    fn check_tree_block<'b>(
+        &self,
+        logical: u64,
+        level: u8,
+        mut block: Cow<'b, [u8]>,
+    ) -> Result<Cow<'b, [u8]>, BtrfsError> {
+        if self.verify_checksums() && !self.checksum_matches(&block)? {
+            // A bad strip of a RAID5/6 chunk may be rebuilt from the others.
+            #[cfg(feature = "raid56")]
+            let rebuilt = self
+                .rebuild_raid56(logical, block.len())
+                .filter(|rebuilt| self.checksum_matches(rebuilt).unwrap_or(false));
+            #[cfg(not(feature = "raid56"))]
+            let rebuilt: Option<Vec<u8>> = None;
+            block = Cow::Owned(rebuilt.ok_or(BtrfsError::ChecksumMismatch { logical })?);
+        }
+        let header = NodeHeader::from_bytes(&block)?;
+        // An intact block that belongs elsewhere was misdirected.
+        if header.bytenr != logical {
+            return Err(BtrfsError::Malformed(
+                "tree block bytenr does not match its address",
+            ));
+        }
+        if !self.owns_metadata(&header.fsid) {
+            return Err(BtrfsError::Malformed(
+                "tree block belongs to another filesystem",
+            ));
+        }
+        if header.level != level {
+            return Err(BtrfsError::Malformed("tree block has an unexpected level"));
+        }
+        if self.warn_future_generations() {
+            let sb_gen = self.superblock().generation;
+            // The log tree holds the fsyncs of the transaction after it.
+            let allowed = match header.owner {
+                TREE_LOG_OBJECTID => sb_gen.saturating_add(1),
+                _ => sb_gen,
+            };
+            if header.generation > allowed {
+                self.warn(Warning::FutureGeneration {
+                    logical,
+                    node_gen: header.generation,
+                    sb_gen,
+                });
+            }
+        }
+        Ok(block)
+    }
+
+    /// Reads the `count` tree blocks at `level` that follow each other from
+    /// `logical`, for read-ahead, and passes each intact one to `each`.
+    /// Every run of them on one device is read at once, up to the end of
+    /// its stripe, past which the next bytes are on another device. Blocks
+    /// that cannot be read this way or fail their checks are left out, for
+    /// a normal read to retry and report.
+    pub(crate) fn read_tree_run(
+        &self,
+        logical: u64,
+        count: usize,
+        level: u8,
+        mut each: impl FnMut(u64, &[u8]),
+    ) {
+        let nodesize = u64::from(self.superblock().nodesize);
+        let end = logical.saturating_add(count as u64 * nodesize);
+        let mut at = logical;
+        while at < end {
+            let Some(len) = self.chunk_map().contiguous_len(at) else {
+                return;
+            };
+            // A block straddling two stripes is left to the normal read,
+            // which gathers it piece by piece.
+            let len = len.min(end - at) / nodesize * nodesize;
+            if len == 0 {
+                return;
+            }
+            let Ok(run) = self.copy_bytes(at, len as usize) else {
+                return;
+            };
+            for (bytenr, raw) in (at..)
+                .step_by(nodesize as usize)
+                .zip(run.chunks_exact(nodesize as usize))
+            {
+                if let Ok(block) = self.check_tree_block(bytenr, level, Cow::Borrowed(raw)) {
+                    each(bytenr, &block);
+                }
+            }
+            at += len;
+        }
+    }
+
+    /// Whether the checksum in the header of tree block `block` is right.
+    pub(crate) fn checksum_matches(&self, block: &[u8]) -> Result<bool, BtrfsError> {
+        let header = NodeHeader::from_bytes(block)?;
+        let csum_type = self.superblock().checksum_type();
+        let computed = csum::checksum(csum_type, &block[BTRFS_CSUM_SIZE..])?;
+        let len = csum_type.digest_len();
+        Ok(computed[..len] == header.csum[..len])
+    }
+}
+
+/// Index of the child of an internal node that may hold `key`: the last one
+/// whose lowest key is not greater than it.
+fn child_index(ptrs: &[KeyPtr], key: &Key) -> usize {
+    ptrs.partition_point(|ptr| ptr.key <= *key)
+        .saturating_sub(1)
+}
+
+/// Iterator over a key range of a [`Tree`], returned by
+/// [`Tree::search_range`].
+///
+/// Leaves have no sibling pointers, so moving to the next leaf climbs the
+/// saved path to the nearest node with another child and descends again.
+pub struct TreeIter<'a> {
+    tree: Tree<'a>,
+    min: Key,
+    max: Key,
+    /// Each internal node above the current leaf, with the index of the
+    /// child being visited.
+    path: Vec<(Arc<Block>, usize)>,
+    /// The current leaf, and the index of its next item.
+    leaf: Arc<Block>,
+    next: usize,
+    /// Leaves read ahead and not visited yet, by address.
+    ahead: BTreeMap<u64, Arc<Block>>,
+    started: bool,
+    done: bool,
+}
+
+impl TreeIter<'_> {
+    /// Descends from the block at `logical` to a leaf, along the children
+    /// that may hold `self.min`, and makes that leaf the current one.
+    fn descend(&mut self, mut logical: u64, mut level: u8) -> Result<(), BtrfsError> {
+        loop {
+            let block = match self.ahead.remove(&logical).filter(|_| level == 0) {
+                Some(block) => block,
+                None => {
+                    let block = self.tree.read_block(logical, level)?;
+                    if level == 0 {
+                        self.read_ahead();
+                    }
+                    block
+                }
+            };
+            match &*block {
+                Block::Node(ptrs) => {
+                    if ptrs.is_empty() {
+                        return Err(BtrfsError::Malformed("internal node has no children"));
+                    }
+                    let index = child_index(ptrs, &self.min);
+                    logical = ptrs[index].blockptr;
+                    self.path.push((block, index));
+                    level -= 1;
+                }
+                Block::Leaf(_) => {
+                    self.leaf = block;
+                    self.next = 0;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Reads the leaves after the one being entered under the same parent,
+    /// up to [`Btrfs::readahead`] of them and none past `max`, skipping
+    /// those already in the node cache. Runs of them at consecutive
+    /// addresses are read together.
+    fn read_ahead(&mut self) {
+        let count = self.tree.fs.readahead();
+        let Some((parent, index)) = self.path.last() else {
+            return;
+        };
+        if count == 0 {
+            return;
+        }
+        let wanted: Vec<u64> = {
+            let cache = self
+                .tree
+                .fs
+                .node_cache()
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            parent.ptrs()[index + 1..]
+                .iter()
+                .take(count)
+                .take_while(|ptr| ptr.key <= self.max)
+                .map(|ptr| ptr.blockptr)
+                .filter(|blockptr| !cache.contains(blockptr))
+                .collect()
+        };
+        self.ahead.clear();
+        let nodesize = u64::from(self.tree.fs.superblock().nodesize);
+        let mut rest = &wanted[..];
+        while let Some(&start) = rest.first() {
+            let run = 1 + rest
+                .windows(2)
+                .take_while(|pair| pair[1] == pair[0].wrapping_add(nodesize))
+                .count();
+            let (tree, ahead) = (self.tree, &mut self.ahead);
+            tree.fs.read_tree_run(start, run, 0, |logical, raw| {
+                if let Ok(block) = tree.parse_block(logical, 0, raw) {
+                    ahead.insert(logical, block);
+                }
+            });
+            rest = &rest[run..];
+        }
+    }
+
+    /// Moves to the leaf after the current one. Returns `false` at the end
+    /// of the tree or once the remaining leaves are all past `max`.
+    fn next_leaf(&mut self) -> Result<bool, BtrfsError> {
+        while let Some((node, index)) = self.path.last_mut() {
+            *index += 1;
+            if let Some(&ptr) = node.ptrs().get(*index) {
+                if ptr.key > self.max {
+                    return Ok(false);
+                }
+                let child = ptr.blockptr;
+                let level = (self.tree.level as usize - self.path.len()) as u8;
+                self.descend(child, level)?;
+                return Ok(true);
+            }
+            self.path.pop();
+        }
+        Ok(false)
+    }
+
+    fn advance(&mut self) -> Result<Option<(Key, Vec<u8>)>, BtrfsError> {
+        if !self.started {
+            self.started = true;
+            if self.min > self.max {
+                return Ok(None);
+            }
+            self.descend(self.tree.root, self.tree.level)?;
+        }
+        loop {
+            while let Some((key, data)) = self.leaf.items().get(self.next) {
+                self.next += 1;
+                if *key > self.max {
+                    return Ok(None);
+                }
+                if *key >= self.min {
+                    return Ok(Some((*key, data.clone())));
+                }
+            }
+            if !self.next_leaf()? {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl TreeIter<'_> {
+    /// Raises `min` to `key` if the buffered leaf still holds an item at or
+    /// past it, so iteration continues there without a new descent. The
+    /// caller must know that nothing between the last returned item and
+    /// `key` was skipped. Returns whether the leaf could be reused.
+    fn skip_within_leaf(&mut self, key: &Key) -> bool {
+        let reusable = self.started
+            && !self.done
+            && self.leaf.items()[self.next..]
+                .last()
+                .is_some_and(|(last, _)| last >= key);
+        if reusable {
+            self.min = self.min.max(*key);
+        }
+        reusable
+    }
+}
+
+impl Iterator for TreeIter<'_> {
+    type Item = Result<(Key, Vec<u8>), BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.advance();
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+}
+
+/// A resumable position in a [`Tree`], returned by [`Tree::cursor`], for
+/// reading a large tree a page at a time.
+///
+/// The cursor keeps the path to its current leaf, so it can be left idle
+/// between pages and picked up again without touching the root. Seeking
+/// forward within the current leaf reuses it; any other seek descends from
+/// the root. To resume after the cursor is gone, persist
+/// [`TreeCursor::last_key`] and seek just past it on a new cursor.
+pub struct TreeCursor<'a> {
+    iter: TreeIter<'a>,
+    /// Key of the item most recently returned since the last descent.
+    last: Option<Key>,
+}
+
+impl TreeCursor<'_> {
+    /// The next item in key order, or `None` at the end of the tree or
+    /// after an error.
+    pub fn next_item(&mut self) -> Option<Result<(Key, Vec<u8>), BtrfsError>> {
+        let item = self.iter.next();
+        if let Some(Ok((key, _))) = &item {
+            self.last = Some(*key);
+        }
+        item
+    }
+
+    /// Moves the cursor so that the next item is the first one with a key
+    /// not below `key`. Seeking backwards is allowed.
+    pub fn seek(&mut self, key: &Key) {
+        let forward = self.last.is_some_and(|last| *key > last);
+        if !(forward && self.iter.skip_within_leaf(key)) {
+            self.iter = self.iter.tree.search_range(*key, Key::MAX);
+            self.last = None;
+        }
+    }
+
+    /// The key of the item [`TreeCursor::next_item`] last returned, if it
+    /// has returned one since the cursor was made or last moved elsewhere.
+    pub fn last_key(&self) -> Option<Key> {
+        self.last
+    }
+}
+
+impl Iterator for TreeCursor<'_> {
+    type Item = Result<(Key, Vec<u8>), BtrfsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item()
+    }
+}
+
+/// Objectids of the trees rooted in the superblock rather than the root
+/// tree.
+pub(crate) const ROOT_TREE_OBJECTID: u64 = 1;
+pub(crate) const CHUNK_TREE_OBJECTID: u64 = 3;
+const BLOCK_GROUP_TREE_OBJECTID: u64 = 11;
+
+/// Where the root block of one tree is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeRoot {
+    pub bytenr: u64,
+    pub level: u8,
+}
+
+/// The root blocks of every tree of the filesystem, from
+/// [`Btrfs::tree_roots`]. Trees the filesystem does not have, such as the
+/// quota tree before quotas are enabled, are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeRoots {
+    pub root: TreeRoot,
+    pub chunk: TreeRoot,
+    pub extent: Option<TreeRoot>,
+    pub dev: Option<TreeRoot>,
+    pub csum: Option<TreeRoot>,
+    pub quota: Option<TreeRoot>,
+    pub uuid: Option<TreeRoot>,
+    pub free_space: Option<TreeRoot>,
+    pub block_group: Option<TreeRoot>,
+    /// The log root tree, left behind by fsyncs since the last commit.
+    pub log: Option<TreeRoot>,
+    /// The fs tree of every subvolume by id, the top level (5) included.
+    pub subvolumes: BTreeMap<u64, TreeRoot>,
+}
+
+impl TreeRoots {
+    /// The root of the tree with objectid `objectid`. The log root tree
+    /// has none of its own here, since only its root items carry one.
+    pub fn get(&self, objectid: u64) -> Option<TreeRoot> {
+        match objectid {
+            ROOT_TREE_OBJECTID => Some(self.root),
+            CHUNK_TREE_OBJECTID => Some(self.chunk),
+            EXTENT_TREE_OBJECTID => self.extent,
+            DEV_TREE_OBJECTID => self.dev,
+            CSUM_TREE_OBJECTID => self.csum,
+            QUOTA_TREE_OBJECTID => self.quota,
+            UUID_TREE_OBJECTID => self.uuid,
+            FREE_SPACE_TREE_OBJECTID => self.free_space,
+            BLOCK_GROUP_TREE_OBJECTID => self.block_group,
+            _ => self.subvolumes.get(&objectid).copied(),
+        }
+    }
+
+    /// The root of a tree named as `btrfs inspect-internal dump-tree -t`
+    /// takes it: `root`, `chunk`, `extent`, `dev`, `fs`, `csum`, `quota`,
+    /// `uuid`, `free-space`, `block-group` or `log`, in any case and with
+    /// an optional `_tree` suffix, or an objectid in decimal.
+    pub fn by_name(&self, name: &str) -> Option<TreeRoot> {
+        if let Ok(objectid) = name.parse() {
+            return self.get(objectid);
+        }
+        let name = name.to_ascii_lowercase().replace('_', "-");
+        let objectid = match name.strip_suffix("-tree").unwrap_or(&name) {
+            "root" => ROOT_TREE_OBJECTID,
+            "chunk" => CHUNK_TREE_OBJECTID,
+            "extent" => EXTENT_TREE_OBJECTID,
+            "dev" | "device" => DEV_TREE_OBJECTID,
+            "fs" => FS_TREE_OBJECTID,
+            "csum" | "checksum" => CSUM_TREE_OBJECTID,
+            "quota" => QUOTA_TREE_OBJECTID,
+            "uuid" => UUID_TREE_OBJECTID,
+            "free-space" => FREE_SPACE_TREE_OBJECTID,
+            "block-group" => BLOCK_GROUP_TREE_OBJECTID,
+            "log" | "tree-log" => return self.log,
+            _ => return None,
+        };
+        self.get(objectid)
+    }
+}
+
+impl Btrfs {
+    /// The tree whose root block is at `root`.
+    pub fn tree(&self, root: u64, level: u8) -> Tree<'_> {
+        Tree::new(self, root, level)
+    }
+
+    /// The tree with objectid `objectid`: the root, chunk or block group
+    /// tree from the superblock, or any other from its root item.
+    pub(crate) fn tree_by_id(&self, objectid: u64) -> Result<Tree<'_>, BtrfsError> {
+        let sb = self.superblock();
+        Ok(match objectid {
+            ROOT_TREE_OBJECTID => self.tree(sb.root, sb.root_level),
+            CHUNK_TREE_OBJECTID => self.tree(sb.chunk_root, sb.chunk_root_level),
+            BLOCK_GROUP_TREE_OBJECTID if sb.block_group_root().is_some() => {
+                self.tree(sb.block_group_root, sb.block_group_root_level)
+            }
+            _ => {
+                let root = self.root_item(objectid)?.ok_or(BtrfsError::NotFound)?;
+                self.tree(root.bytenr(), root.level())
+            }
+        })
+    }
+
+    /// The root blocks of the trees the superblock points at and of those
+    /// with a root item in the root tree, which is read in full.
+    pub fn tree_roots(&self) -> Result<TreeRoots, BtrfsError> {
+        let sb = self.superblock();
+        let from_superblock = |bytenr, level| (bytenr != 0).then_some(TreeRoot { bytenr, level });
+        let mut roots = BTreeMap::new();
+        for item in self.root_tree().iter() {
+            let (key, data) = item?;
+            if key.item_type() == KeyType::RootItem {
+                // Keys ascend, so the current root item of each id wins.
+                let root = RootItem::from_bytes(&data)?;
+                let root = TreeRoot {
+                    bytenr: root.bytenr(),
+                    level: root.level(),
+                };
+                roots.insert(key.objectid, root);
+            }
+        }
+
+        let mut take = |objectid| roots.remove(&objectid);
+        let block_group = match sb.block_group_root() {
+            Some(_) => from_superblock(sb.block_group_root, sb.block_group_root_level),
+            None => take(BLOCK_GROUP_TREE_OBJECTID),
+        };
+        Ok(TreeRoots {
+            root: TreeRoot {
+                bytenr: sb.root,
+                level: sb.root_level,
+            },
+            chunk: TreeRoot {
+                bytenr: sb.chunk_root,
+                level: sb.chunk_root_level,
+            },
+            extent: take(EXTENT_TREE_OBJECTID),
+            dev: take(DEV_TREE_OBJECTID),
+            csum: take(CSUM_TREE_OBJECTID),
+            quota: take(QUOTA_TREE_OBJECTID),
+            uuid: take(UUID_TREE_OBJECTID),
+            free_space: take(FREE_SPACE_TREE_OBJECTID),
+            block_group,
+            log: from_superblock(sb.log_root, sb.log_root_level),
+            subvolumes: roots
+                .into_iter()
+                .filter(|&(objectid, _)| is_subvolume(objectid))
+                .collect(),
+        })
+    }
+
+    /// Every item with a key in `min..=max` of tree `tree_root` (an objectid
+    /// such as 1 for the root tree or 5 for the top-level subvolume), as raw
+    /// bytes.
+    ///
+    /// This is the low-level counterpart of the typed queries: nothing is
+    /// interpreted, so it works for item types this crate does not model.
+    /// A missing tree is reported as the only item.
+    pub fn raw_items(
+        &self,
+        tree_root: u64,
+        min: Key,
+        max: Key,
+    ) -> impl Iterator<Item = Result<(Key, Vec<u8>), BtrfsError>> + '_ {
+        let (items, error) = match self.tree_by_id(tree_root) {
+            Ok(tree) => (Some(tree.search_range(min, max)), None),
+            Err(err) => (None, Some(Err(err))),
+        };
+        error.into_iter().chain(items.into_iter().flatten())
+    }
+}
+
+#[cfg(all(test, feature = "crc32c"))]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::cache::CacheStats;
+    use crate::device::BlockDevice;
+    use crate::features::IncompatFlags;
+    use crate::testutil::{block, leaf, node, put_u64, reseal, root_item, TestImage, NODESIZE};
+
+    /// A two-level tree: a root node over three leaves of two items each,
+    /// with objectids 1..=6.
+    fn two_level_fs() -> Btrfs {
+        let key = |objectid| Key::new(objectid, 1, 0);
+        let mut image = TestImage::new();
+        let mut ptrs = Vec::new();
+        for n in 0..3u64 {
+            let first = 2 * n + 1;
+            let items = [(key(first), &b"a"[..]), (key(first + 1), &b"b"[..])];
+            image.put_block(block(n + 1), &leaf(block(n + 1), 5, &items));
+            ptrs.push((key(first), block(n + 1)));
+        }
+        image.put_block(block(0), &node(block(0), 5, 1, &ptrs));
+        Btrfs::from_reader(Cursor::new(image.build())).unwrap()
+    }
+
+    #[test]
+    fn searches_through_internal_nodes() {
+        let fs = two_level_fs();
+        let tree = fs.tree(block(0), 1);
+
+        let (key, data) = tree.search(&Key::new(4, 1, 0)).unwrap().unwrap();
+        assert_eq!(key, Key::new(4, 1, 0));
+        assert_eq!(data, b"b");
+        assert!(tree.search(&Key::new(1, 1, 0)).unwrap().is_some());
+        assert!(tree.search(&Key::new(6, 1, 0)).unwrap().is_some());
+
+        assert!(tree.search(&Key::new(0, 0, 0)).unwrap().is_none());
+        assert!(tree.search(&Key::new(4, 1, 1)).unwrap().is_none());
+        assert!(tree.search(&Key::new(7, 1, 0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn finds_nearest_preceding_item() {
+        let fs = two_level_fs();
+        let tree = fs.tree(block(0), 1);
+
+        let found = |key| tree.search_le(&key).unwrap().map(|(key, _)| key);
+        assert_eq!(found(Key::new(4, 1, 0)), Some(Key::new(4, 1, 0)));
+        // Past the last item of the middle leaf, and of the whole tree.
+        assert_eq!(found(Key::new(4, 200, 0)), Some(Key::new(4, 1, 0)));
+        assert_eq!(found(Key::new(5, 0, 0)), Some(Key::new(4, 1, 0)));
+        assert_eq!(found(Key::MAX), Some(Key::new(6, 1, 0)));
+        assert_eq!(found(Key::new(1, 0, 0)), None);
+    }
+
+    #[test]
+    fn range_spans_leaves() {
+        let fs = two_level_fs();
+        let tree = fs.tree(block(0), 1);
+
+        let objectids = |min, max| {
+            tree.search_range(Key::new(min, 0, 0), Key::new(max, 255, u64::MAX))
+                .map(|item| item.unwrap().0.objectid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(objectids(2, 5), [2, 3, 4, 5]);
+        assert_eq!(objectids(0, 100), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(objectids(6, 6), [6]);
+        assert!(objectids(7, 9).is_empty());
+        assert!(objectids(5, 2).is_empty());
+    }
+
+    #[test]
+    fn iterates_every_item() {
+        let fs = two_level_fs();
+        let keys: Vec<_> = fs
+            .tree(block(0), 1)
+            .iter()
+            .map(|item| item.unwrap().0.objectid)
+            .collect();
+        assert_eq!(keys, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn cursor_pages_and_resumes() {
+        let fs = two_level_fs();
+        let tree = fs.tree(block(0), 1);
+        let objectid = |item: Option<Result<(Key, Vec<u8>), BtrfsError>>| {
+            item.map(|item| item.unwrap().0.objectid)
+        };
+
+        // A first page of three items, then a resume from the saved key.
+        let mut cursor = tree.cursor();
+        let page: Vec<_> = cursor
+            .by_ref()
+            .take(3)
+            .map(|i| i.unwrap().0.objectid)
+            .collect();
+        assert_eq!(page, [1, 2, 3]);
+        let saved = cursor.last_key().unwrap();
+        assert_eq!(objectid(cursor.next_item()), Some(4));
+
+        let mut resumed = tree.cursor();
+        resumed.seek(&saved.next().unwrap());
+        let rest: Vec<_> = resumed.map(|i| i.unwrap().0.objectid).collect();
+        assert_eq!(rest, [4, 5, 6]);
+
+        // Forward within the leaf, forward across leaves, and backwards.
+        let mut cursor = tree.cursor();
+        assert_eq!(objectid(cursor.next_item()), Some(1));
+        cursor.seek(&Key::new(2, 0, 0));
+        assert_eq!(objectid(cursor.next_item()), Some(2));
+        cursor.seek(&Key::new(5, 1, 1));
+        assert_eq!(objectid(cursor.next_item()), Some(6));
+        cursor.seek(&Key::new(3, 1, 0));
+        assert_eq!(objectid(cursor.next_item()), Some(3));
+        cursor.seek(&Key::new(7, 0, 0));
+        assert!(cursor.next_item().is_none());
+        assert_eq!(cursor.last_key(), None);
+    }
+
+    #[test]
+    fn iterates_empty_and_single_leaf_trees() {
+        let mut image = TestImage::new();
+        image.put_block(block(0), &leaf::<&[u8]>(block(0), 5, &[]));
+        let items = [
+            (Key::new(256, 1, 0), &b"inode"[..]),
+            (Key::new(256, 12, 256), b"ref"),
+        ];
+        image.put_block(block(1), &leaf(block(1), 5, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        assert_eq!(fs.tree(block(0), 0).iter().count(), 0);
+
+        let items: Vec<_> = fs
+            .tree(block(1), 0)
+            .iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            items,
+            [
+                (Key::new(256, 1, 0), b"inode".to_vec()),
+                (Key::new(256, 12, 256), b"ref".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn iterates_three_levels() {
+        // root (level 2) -> two level 1 nodes -> two leaves each.
+        let key = |objectid| Key::new(objectid, 1, 0);
+        let mut image = TestImage::new();
+        let mut upper = Vec::new();
+        for n in 0..2u64 {
+            let mut lower = Vec::new();
+            for m in 0..2u64 {
+                let leaf_at = block(3 + 2 * n + m);
+                let objectid = 10 * (2 * n + m);
+                image.put_block(leaf_at, &leaf(leaf_at, 5, &[(key(objectid), &[][..])]));
+                lower.push((key(objectid), leaf_at));
+            }
+            image.put_block(block(1 + n), &node(block(1 + n), 5, 1, &lower));
+            upper.push((lower[0].0, block(1 + n)));
+        }
+        image.put_block(block(0), &node(block(0), 5, 2, &upper));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let tree = fs.tree(block(0), 2);
+        let keys: Vec<_> = tree.iter().map(|item| item.unwrap().0.objectid).collect();
+        assert_eq!(keys, [0, 10, 20, 30]);
+        assert_eq!(
+            tree.search(&key(20)).unwrap().map(|(key, _)| key),
+            Some(key(20))
+        );
+    }
+
+    #[test]
+    fn caches_parsed_blocks() {
+        let fs = two_level_fs().with_cache_capacity(8);
+        let tree = fs.tree(block(0), 1);
+        let stats = |hits, misses| CacheStats { hits, misses };
+
+        assert!(tree.search(&Key::new(4, 1, 0)).unwrap().is_some());
+        assert_eq!(fs.cache_stats(), stats(0, 2));
+        assert!(tree.search(&Key::new(3, 1, 0)).unwrap().is_some());
+        assert_eq!(fs.cache_stats(), stats(2, 2));
+        // Only the first and last leaves are new to a full walk.
+        assert_eq!(tree.iter().count(), 6);
+        assert_eq!(fs.cache_stats(), stats(4, 4));
+
+        // A cached leaf is still checked against the level asked for.
+        let err = fs.tree(block(2), 1).search(&Key::MIN).unwrap_err();
+        assert!(matches!(err, BtrfsError::ReadNode { logical, .. } if logical == block(2)));
+
+        assert_eq!(two_level_fs().cache_stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn verifies_block_checksums() {
+        let mut image = TestImage::new();
+        let mut corrupt = leaf(block(0), 5, &[(Key::new(256, 1, 0), b"inode")]);
+        corrupt[NODESIZE - 1] ^= 1;
+        image.put_block(block(0), &corrupt);
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let logical = block(0);
+        assert!(matches!(
+            fs.tree(logical, 0).search(&Key::new(256, 1, 0)),
+            Err(BtrfsError::ChecksumMismatch { logical: at }) if at == logical
+        ));
+
+        fs.set_verify_checksums(false);
+        assert!(fs
+            .tree(logical, 0)
+            .search(&Key::new(256, 1, 0))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn rejects_foreign_blocks() {
+        let mut foreign = leaf(block(0), 5, &[(Key::new(256, 1, 0), b"inode")]);
+        foreign[32] ^= 0xff;
+        crate::testutil::reseal(&mut foreign);
+        let mut image = TestImage::new();
+        image.put_block(block(0), &foreign);
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let err = fs
+            .tree(block(0), 0)
+            .search(&Key::new(256, 1, 0))
+            .unwrap_err();
+        assert!(matches!(err, BtrfsError::ReadNode { logical, .. } if logical == block(0)));
+        assert!(matches!(err.root_cause(), BtrfsError::Malformed(_)));
+    }
+
+    #[test]
+    fn accepts_blocks_after_fsid_change() {
+        let items = [(Key::new(256, 1, 0), b"inode")];
+        let mut image = TestImage::new();
+        image
+            .change_fsid([0x42; 16])
+            .put_block(block(0), &leaf(block(0), 5, &items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        assert_eq!(fs.superblock().fsid, [0x42; 16]);
+        assert!(fs
+            .tree(block(0), 0)
+            .search(&Key::new(256, 1, 0))
+            .unwrap()
+            .is_some());
+
+        // Without the flag the blocks' original fsid no longer matches.
+        image.set_incompat_flags(IncompatFlags::empty());
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        let err = fs
+            .tree(block(0), 0)
+            .search(&Key::new(256, 1, 0))
+            .unwrap_err();
+        assert!(matches!(err.root_cause(), BtrfsError::Malformed(_)));
+    }
+
+    #[test]
+    fn rejects_misplaced_blocks() {
+        let fs = two_level_fs();
+        let err = fs.tree(block(0), 0).search(&Key::new(1, 1, 0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "tree block at {}: malformed structure: tree block has an unexpected level",
+                block(0)
+            )
+        );
+
+        let mut range = fs
+            .tree(block(1), 0)
+            .search_range(Key::new(0, 0, 0), Key::new(9, 0, 0));
+        assert_eq!(range.by_ref().count(), 2);
+
+        let mut range = fs
+            .tree(block(2), 1)
+            .search_range(Key::new(0, 0, 0), Key::new(9, 0, 0));
+        let err = range.next().unwrap().unwrap_err();
+        assert!(matches!(err.root_cause(), BtrfsError::Malformed(_)));
+        assert!(range.next().is_none());
+    }
+
+    #[test]
+    fn returns_raw_items_by_tree_id() {
+        let root_tree = block(4);
+        let fs_tree = block(5);
+        let fs_items = [
+            (Key::new(256, 1, 0), &b"inode"[..]),
+            (Key::new(256, 255, 0), &b"future"[..]),
+            (Key::new(257, 1, 0), &b"other"[..]),
+        ];
+        let root = root_item(fs_tree, 0);
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(root_tree, 0)
+            .put_block(
+                root_tree,
+                &leaf(root_tree, 1, &[(Key::new(5, 132, 0), &root)]),
+            )
+            .put_block(fs_tree, &leaf(fs_tree, 5, &fs_items));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let items: Vec<_> = fs
+            .raw_items(5, Key::new(256, 0, 0), Key::new(256, 255, u64::MAX))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            items,
+            [
+                (Key::new(256, 1, 0), b"inode".to_vec()),
+                (Key::new(256, 255, 0), b"future".to_vec()),
+            ]
+        );
+        assert_eq!(fs.raw_items(1, Key::MIN, Key::MAX).count(), 1);
+
+        let mut missing = fs.raw_items(7, Key::MIN, Key::MAX);
+        assert!(matches!(missing.next(), Some(Err(BtrfsError::NotFound))));
+        assert!(missing.next().is_none());
+    }
+
+    #[test]
+    fn lists_every_tree_root() {
+        let roots = [
+            (Key::new(2, 132, 0), root_item(block(3), 1)),
+            (Key::new(5, 132, 0), root_item(block(4), 0)),
+            (Key::new(7, 132, 0), root_item(block(5), 0)),
+            // A snapshot's root item again after it changed.
+            (Key::new(256, 132, 0), root_item(block(6), 0)),
+            (Key::new(256, 132, 9), root_item(block(7), 2)),
+        ];
+        let mut image = TestImage::new();
+        image
+            .set_root_tree(block(2), 0)
+            .set_log_root(block(8), 0)
+            .put_block(block(2), &leaf(block(2), 1, &roots));
+        let fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+
+        let at = |n, level| TreeRoot {
+            bytenr: block(n),
+            level,
+        };
+        let trees = fs.tree_roots().unwrap();
+        assert_eq!(trees.root, at(2, 0));
+        assert_eq!(trees.chunk, at(1, 0));
+        assert_eq!((trees.extent, trees.csum), (Some(at(3, 1)), Some(at(5, 0))));
+        assert_eq!((trees.dev, trees.quota, trees.uuid), (None, None, None));
+        assert_eq!(trees.log, Some(at(8, 0)));
+        assert_eq!(
+            trees.subvolumes.into_iter().collect::<Vec<_>>(),
+            [(5, at(4, 0)), (256, at(7, 2))]
+        );
+
+        let trees = fs.tree_roots().unwrap();
+        assert_eq!(trees.by_name("fs"), Some(at(4, 0)));
+        assert_eq!(trees.by_name("CSUM_TREE"), Some(at(5, 0)));
+        assert_eq!(trees.by_name("256"), Some(at(7, 2)));
+        assert_eq!(trees.by_name("chunk"), Some(at(1, 0)));
+        assert_eq!(trees.by_name("log"), Some(at(8, 0)));
+        assert_eq!(trees.by_name("quota"), None);
+        assert_eq!(trees.by_name("bogus"), None);
+    }
+
+    /// An in-memory device that counts its reads.
+    struct CountingDevice {
+        bytes: Vec<u8>,
+        reads: AtomicUsize,
+    }
+
+    impl BlockDevice for CountingDevice {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.bytes.read_at(offset, buf)
+        }
+    }
+
+    #[test]
+    fn reads_sibling_leaves_ahead() {
+        // Six leaves under one node, all back to back but the fourth.
+        let at = [3, 4, 5, 9, 6, 7];
+        let key = |n: u64| Key::new(n, 1, 0);
+        let mut image = TestImage::new();
+        let mut ptrs = Vec::new();
+        for (n, &b) in at.iter().enumerate() {
+            let n = n as u64;
+            let items = [(key(2 * n), &b"a"[..]), (key(2 * n + 1), &b"b"[..])];
+            image.put_block(block(b), &leaf(block(b), 5, &items));
+            ptrs.push((key(2 * n), block(b)));
+        }
+        image.put_block(block(2), &node(block(2), 5, 1, &ptrs));
+        let device = Arc::new(CountingDevice {
+            bytes: image.build(),
+            reads: Default::default(),
+        });
+        let mut fs = Btrfs::from_device(Arc::clone(&device)).unwrap();
+
+        let mut walk = |readahead, max| {
+            fs.set_readahead(readahead);
+            device.reads.store(0, Ordering::Relaxed);
+            let keys: Vec<_> = fs
+                .tree(block(2), 1)
+                .search_range(Key::MIN, max)
+                .map(|item| item.unwrap().0.objectid)
+                .collect();
+            (keys, device.reads.load(Ordering::Relaxed))
+        };
+        let all: Vec<u64> = (0..12).collect();
+        assert_eq!(walk(0, Key::MAX), (all.clone(), 7));
+        // The node, the first leaf, then the runs at blocks 4-5, 9 and 6-7.
+        assert_eq!(walk(8, Key::MAX), (all.clone(), 5));
+        assert_eq!(walk(2, Key::MAX), (all, 5));
+        // Nothing is read for leaves past the end of the range.
+        assert_eq!(walk(8, key(2)), (vec![0, 1, 2], 3));
+    }
+
+    #[test]
+    fn splits_read_ahead_at_stripe_boundaries() {
+        use crate::superblock::Superblock;
+        use crate::testutil::{chunk_item, test_chunk, SUPERBLOCK};
+
+        // RAID0 over two stripes of 64KiB, four blocks each, on device 1.
+        let chunk_root = Superblock::from_bytes(SUPERBLOCK).unwrap().chunk_root;
+        let raid0 = test_chunk(0x9, &[(1, 40 << 20), (1, 48 << 20)]);
+        let items = [(Key::new(256, 228, 1 << 30), chunk_item(&raid0))];
+
+        let mut image = TestImage::new();
+        image.put_block(chunk_root, &leaf(chunk_root, 3, &items));
+        let logical = |n: u64| (1 << 30) + n * NODESIZE as u64;
+        for n in 0..6 {
+            let physical = [40 << 20, 48 << 20][n as usize / 4] + n % 4 * NODESIZE as u64;
+            image.put_physical(physical, &leaf::<&[u8]>(logical(n), 5, &[]));
+        }
+        let device = Arc::new(CountingDevice {
+            bytes: image.build(),
+            reads: Default::default(),
+        });
+        let mut fs = Btrfs::from_device(Arc::clone(&device)).unwrap();
+        fs.load_chunk_tree().unwrap();
+
+        device.reads.store(0, Ordering::Relaxed);
+        let mut read = Vec::new();
+        fs.read_tree_run(logical(1), 5, 0, |bytenr, _| read.push(bytenr));
+        assert_eq!(read, (1..6).map(logical).collect::<Vec<_>>());
+        assert_eq!(device.reads.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn warns_of_blocks_from_future_generations() {
+        let from = |bytenr, owner, generation| {
+            let item = [(Key::new(256, 1, 0), &b"a"[..])];
+            let mut block = leaf(bytenr, owner, &item);
+            put_u64(&mut block, 80, generation);
+            reseal(&mut block);
+            block
+        };
+        // The superblock is from generation 6.
+        let mut image = TestImage::new();
+        image
+            .put_block(block(2), &from(block(2), 5, 9))
+            .put_block(block(3), &from(block(3), TREE_LOG_OBJECTID, 7))
+            .put_block(block(4), &from(block(4), TREE_LOG_OBJECTID, 8))
+            .put_block(block(5), &from(block(5), 5, 6));
+        let mut fs = Btrfs::from_reader(Cursor::new(image.build())).unwrap();
+        let walk = |fs: &Btrfs| {
+            for n in 2..6 {
+                assert_eq!(fs.tree(block(n), 0).iter().count(), 1);
+            }
+        };
+
+        walk(&fs);
+        assert!(fs.take_warnings().is_empty());
+        fs.set_warn_future_generations(true);
+        walk(&fs);
+        walk(&fs);
+        let future = |n, node_gen| Warning::FutureGeneration {
+            logical: block(n),
+            node_gen,
+            sb_gen: 6,
+        };
+        assert_eq!(fs.take_warnings(), [future(2, 9), future(4, 8)]);
+        assert!(fs.take_warnings().is_empty());
+    }
+}
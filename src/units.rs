@@ -0,0 +1,85 @@
+use core::fmt;
+
+/// An address in the filesystem's logical address space, which the chunk
+/// tree maps onto devices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogicalAddr(pub u64);
+
+/// A count of bytes. `Display` prints it the way btrfs-progs does, with
+/// two decimals and a binary unit, e.g. `1.50GiB`; the alternate form
+/// (`{:#}`) prints the exact count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+/// A transaction id. Every tree block and item written in a transaction
+/// carries its generation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Generation(pub u64);
+
+macro_rules! raw_u64 {
+    ($($name:ident),*) => {$(
+        impl $name {
+            pub fn get(self) -> u64 {
+                self.0
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(value: u64) -> $name {
+                $name(value)
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(value: $name) -> u64 {
+                value.0
+            }
+        }
+    )*};
+}
+
+raw_u64!(LogicalAddr, ByteSize, Generation);
+
+impl fmt::Display for LogicalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for Generation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+        if f.alternate() {
+            return write!(f, "{}", self.0);
+        }
+        let mut unit = 0;
+        while unit + 1 < UNITS.len() && self.0 >> (10 * (unit + 1)) != 0 {
+            unit += 1;
+        }
+        let value = self.0 as f64 / (1u64 << (10 * unit)) as f64;
+        write!(f, "{value:.2}{}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_sizes_like_btrfs_progs() {
+        let size = |bytes| ByteSize(bytes).to_string();
+        assert_eq!(size(0), "0.00B");
+        assert_eq!(size(1023), "1023.00B");
+        assert_eq!(size(16384), "16.00KiB");
+        assert_eq!(size(3 << 29), "1.50GiB");
+        assert_eq!(size(u64::MAX), "16.00EiB");
+        assert_eq!(format!("{:#}", ByteSize(3 << 29)), "1610612736");
+        assert_eq!(LogicalAddr(22020096).to_string(), "22020096");
+    }
+}
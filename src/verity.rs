@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+
+use binrw::io::Cursor;
+use binrw::{BinRead, BinWrite};
+
+use crate::error::BtrfsError;
+
+/// `btrfs_verity_descriptor_item`, keyed `(ino, VERITY_DESC_ITEM, 0)`. The
+/// fs-verity descriptor itself follows in items at offsets from 1.
+#[derive(BinRead, BinWrite, Debug, Clone, Copy, PartialEq, Eq)]
+#[brw(little)]
+pub struct VerityDescriptorItem {
+    /// Length of the fs-verity descriptor.
+    pub size: u64,
+    pub reserved: [u64; 2],
+    /// Always 0; encrypted descriptors are not implemented by the kernel.
+    pub encryption: u8,
+}
+
+impl VerityDescriptorItem {
+    pub const SIZE: usize = 25;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<VerityDescriptorItem, BtrfsError> {
+        if bytes.len() < VerityDescriptorItem::SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: VerityDescriptorItem::SIZE,
+                got: bytes.len(),
+            });
+        }
+        Ok(VerityDescriptorItem::read(&mut Cursor::new(
+            &bytes[..VerityDescriptorItem::SIZE],
+        ))?)
+    }
+}
+
+/// Hash algorithm of an fs-verity Merkle tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VerityHashAlgorithm {
+    Sha256,
+    Sha512,
+    Unknown(u8),
+}
+
+impl VerityHashAlgorithm {
+    pub fn from_u8(value: u8) -> VerityHashAlgorithm {
+        match value {
+            1 => VerityHashAlgorithm::Sha256,
+            2 => VerityHashAlgorithm::Sha512,
+            other => VerityHashAlgorithm::Unknown(other),
+        }
+    }
+
+    /// Length of a digest in bytes, if the algorithm is known.
+    pub fn digest_size(&self) -> Option<usize> {
+        match self {
+            VerityHashAlgorithm::Sha256 => Some(32),
+            VerityHashAlgorithm::Sha512 => Some(64),
+            VerityHashAlgorithm::Unknown(_) => None,
+        }
+    }
+}
+
+/// The fs-verity descriptor of a file: what `FS_IOC_READ_VERITY_METADATA`
+/// returns for `FS_VERITY_METADATA_TYPE_DESCRIPTOR`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerityInfo {
+    pub version: u8,
+    pub hash_algorithm: VerityHashAlgorithm,
+    /// Size of the data and Merkle tree blocks the hashes cover.
+    pub block_size: u32,
+    /// Size of the file the tree was built over.
+    pub data_size: u64,
+    pub salt: Vec<u8>,
+    /// Root of the Merkle tree, or `None` if the field is zeroed or the
+    /// hash algorithm, and so the digest length, is unknown.
+    pub root_hash: Option<Vec<u8>>,
+    /// PKCS#7 signature of the file digest; empty if the file is unsigned.
+    pub signature: Vec<u8>,
+}
+
+impl VerityInfo {
+    /// Size of `struct fsverity_descriptor` before the signature.
+    pub const DESCRIPTOR_SIZE: usize = 256;
+
+    /// Parses a `struct fsverity_descriptor` and its trailing signature.
+    pub fn from_descriptor(bytes: &[u8]) -> Result<VerityInfo, BtrfsError> {
+        if bytes.len() < VerityInfo::DESCRIPTOR_SIZE {
+            return Err(BtrfsError::ShortRead {
+                expected: VerityInfo::DESCRIPTOR_SIZE,
+                got: bytes.len(),
+            });
+        }
+        let log_blocksize = bytes[2];
+        if log_blocksize >= 32 {
+            return Err(BtrfsError::Malformed("verity block size out of range"));
+        }
+        let salt_size = bytes[3] as usize;
+        if salt_size > 32 {
+            return Err(BtrfsError::Malformed("verity salt longer than 32 bytes"));
+        }
+        let sig_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let signature = bytes
+            .get(VerityInfo::DESCRIPTOR_SIZE..VerityInfo::DESCRIPTOR_SIZE + sig_size)
+            .ok_or(BtrfsError::Malformed(
+                "verity signature runs past descriptor",
+            ))?;
+
+        let hash_algorithm = VerityHashAlgorithm::from_u8(bytes[1]);
+        let root_hash = hash_algorithm
+            .digest_size()
+            .map(|len| &bytes[16..16 + len])
+            .filter(|hash| hash.iter().any(|&b| b != 0))
+            .map(<[u8]>::to_vec);
+        Ok(VerityInfo {
+            version: bytes[0],
+            hash_algorithm,
+            block_size: 1 << log_blocksize,
+            data_size: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            salt: bytes[80..80 + salt_size].to_vec(),
+            root_hash,
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fsverity_descriptor() {
+        let mut desc = vec![0u8; VerityInfo::DESCRIPTOR_SIZE + 3];
+        desc[0] = 1;
+        desc[1] = 1;
+        desc[2] = 12;
+        desc[3] = 4;
+        desc[4] = 3;
+        desc[8..16].copy_from_slice(&10000u64.to_le_bytes());
+        desc[16..48].fill(0xab);
+        desc[80..84].copy_from_slice(b"salt");
+        desc[256..].copy_from_slice(b"sig");
+
+        let info = VerityInfo::from_descriptor(&desc).unwrap();
+        assert_eq!(info.hash_algorithm, VerityHashAlgorithm::Sha256);
+        assert_eq!((info.block_size, info.data_size), (4096, 10000));
+        assert_eq!(info.salt, b"salt");
+        assert_eq!(info.root_hash, Some(vec![0xab; 32]));
+        assert_eq!(info.signature, b"sig");
+
+        desc[4] = 4;
+        assert!(matches!(
+            VerityInfo::from_descriptor(&desc),
+            Err(BtrfsError::Malformed(_))
+        ));
+        desc[1] = 9;
+        desc[4] = 0;
+        assert_eq!(VerityInfo::from_descriptor(&desc).unwrap().root_hash, None);
+    }
+}